@@ -0,0 +1,763 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::core::csr::address_constants::{CYCLE, INSTRET, TIME};
+use crate::core::csr::csr_name;
+use crate::core::encoder::*;
+use crate::core::instruction::Instruction;
+use crate::core::register_file::{ABI_NAMES, RA, T1, ZERO};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AssemblyError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Assembled code and data, laid out the way a minimal linker script would: .text at the origin and .data right
+/// after it.
+pub struct Program {
+    pub origin: u32,
+    pub data_address: u32,
+    /// .text followed by .data, to be loaded at `origin`.
+    pub bytes: Vec<u8>,
+    pub symbols: HashMap<String, u32>,
+    pub globals: Vec<String>,
+}
+
+impl Program {
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).copied()
+    }
+
+    /// `size` bytes of memory from address zero with the program loaded at its origin, for
+    /// `Memory::with_initial_values`.
+    pub fn memory_image(&self, size: usize) -> Vec<u8> {
+        let start = self.origin as usize;
+        assert!(start + self.bytes.len() <= size, "the program does not fit in {size} bytes of memory");
+
+        let mut image = vec![0; size];
+        image[start..start + self.bytes.len()].copy_from_slice(&self.bytes);
+        image
+    }
+}
+
+/// Assembles RV32I source with the Zicsr and privileged instructions, the usual pseudo-instructions, labels
+/// (including numeric `1:` labels referenced as `1b` and `1f`) and %hi/%lo.
+pub fn assemble(source: &str, origin: u32) -> Result<Program, AssemblyError> {
+    let mut layout = Layout::default();
+    for (index, line) in source.lines().enumerate() {
+        layout.add_line(index + 1, line).map_err(|message| AssemblyError { line: index + 1, message })?;
+    }
+
+    let data_address = (origin + layout.sizes[TEXT]).next_multiple_of(4);
+    let bases = [origin, data_address];
+    let resolver = Resolver {
+        symbols: layout
+            .labels
+            .iter()
+            .map(|(name, &(section, offset))| (name.clone(), bases[section] + offset))
+            .collect(),
+        numeric_labels: layout
+            .numeric_labels
+            .iter()
+            .map(|(name, line, section, offset)| (name.clone(), *line, bases[*section] + offset))
+            .collect(),
+    };
+
+    let mut sections = [Vec::new(), Vec::new()];
+    for Statement { line, section, offset, size, item } in &layout.statements {
+        let pc = bases[*section] + offset;
+        let bytes = resolver.emit(item, pc, *line, *size).map_err(|message| AssemblyError { line: *line, message })?;
+        sections[*section].extend(bytes);
+    }
+
+    let [mut bytes, data] = sections;
+    bytes.resize((data_address - origin) as usize, 0);
+    bytes.extend(data);
+
+    Ok(Program { origin, data_address, bytes, symbols: resolver.symbols, globals: layout.globals })
+}
+
+const TEXT: usize = 0;
+const DATA: usize = 1;
+
+enum Item<'a> {
+    Instruction { mnemonic: String, operands: Vec<&'a str> },
+    Values { width: u32, operands: Vec<&'a str> },
+    Bytes(Vec<u8>),
+}
+
+struct Statement<'a> {
+    line: usize,
+    section: usize,
+    offset: u32,
+    size: u32,
+    item: Item<'a>,
+}
+
+/// The first pass: places every statement and label in its section without evaluating operands.
+#[derive(Default)]
+struct Layout<'a> {
+    statements: Vec<Statement<'a>>,
+    labels: HashMap<String, (usize, u32)>,
+    numeric_labels: Vec<(String, usize, usize, u32)>,
+    globals: Vec<String>,
+    section: usize,
+    sizes: [u32; 2],
+}
+
+impl<'a> Layout<'a> {
+    fn add_line(&mut self, line: usize, text: &'a str) -> Result<(), String> {
+        let mut rest = strip_comment(text).trim();
+
+        while let Some((name, after)) = label_prefix(rest) {
+            let position = (self.section, self.sizes[self.section]);
+            if name.bytes().all(|byte| byte.is_ascii_digit()) {
+                self.numeric_labels.push((name.to_string(), line, position.0, position.1));
+            } else if self.labels.insert(name.to_string(), position).is_some() {
+                return Err(format!("symbol {name} is already defined"));
+            }
+            rest = after.trim_start();
+        }
+
+        if rest.is_empty() {
+            return Ok(());
+        }
+
+        let (mnemonic, operands) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let mnemonic = mnemonic.to_ascii_lowercase();
+        let operands = split_operands(operands);
+
+        let item = match mnemonic.as_str() {
+            ".text" => return self.switch(TEXT),
+            ".data" | ".rodata" | ".bss" => return self.switch(DATA),
+            ".section" => match operands.first() {
+                Some(name) if name.starts_with(".text") => return self.switch(TEXT),
+                Some(_) => return self.switch(DATA),
+                None => return Err(".section needs a name".to_string()),
+            },
+            ".globl" | ".global" => {
+                self.globals.extend(operands.iter().map(|name| name.to_string()));
+                return Ok(());
+            }
+            ".align" | ".p2align" => self.padding(1 << constant(&operands, 0)?.min(12) as u32)?,
+            ".balign" => self.padding(constant(&operands, 0)? as u32)?,
+            ".word" => Item::Values { width: 4, operands },
+            ".half" => Item::Values { width: 2, operands },
+            ".byte" => Item::Values { width: 1, operands },
+            ".space" | ".zero" => Item::Bytes(vec![0; constant(&operands, 0)? as usize]),
+            ".ascii" | ".asciz" | ".string" => {
+                let mut bytes = Vec::new();
+                for operand in &operands {
+                    bytes.extend(string_literal(operand)?);
+                    if mnemonic != ".ascii" {
+                        bytes.push(0);
+                    }
+                }
+                Item::Bytes(bytes)
+            }
+            directive if directive.starts_with('.') => return Err(format!("unknown directive {directive}")),
+            _ => Item::Instruction { mnemonic, operands },
+        };
+
+        let size = match &item {
+            Item::Instruction { mnemonic, operands } => instruction_size(mnemonic, operands),
+            Item::Values { width, operands } => width * operands.len() as u32,
+            Item::Bytes(bytes) => bytes.len() as u32,
+        };
+
+        let offset = self.sizes[self.section];
+        self.statements.push(Statement { line, section: self.section, offset, size, item });
+        self.sizes[self.section] += size;
+        Ok(())
+    }
+
+    fn switch(&mut self, section: usize) -> Result<(), String> {
+        self.section = section;
+        Ok(())
+    }
+
+    /// Code is padded with nops so execution can run through the gap.
+    fn padding(&self, alignment: u32) -> Result<Item<'a>, String> {
+        if !alignment.is_power_of_two() {
+            return Err(format!("alignment {alignment} is not a power of two"));
+        }
+
+        let offset = self.sizes[self.section];
+        let size = (offset.next_multiple_of(alignment) - offset) as usize;
+        match self.section == TEXT && offset.is_multiple_of(4) {
+            true => Ok(Item::Bytes(NOP.to_le_bytes().repeat(size / 4))),
+            false => Ok(Item::Bytes(vec![0; size])),
+        }
+    }
+}
+
+/// `addi zero, zero, 0`
+const NOP: u32 = 0x0000_0013;
+
+/// `li` takes a single instruction for constants that fit, everything that needs a symbol is sized for two.
+fn instruction_size(mnemonic: &str, operands: &[&str]) -> u32 {
+    match mnemonic {
+        "la" | "call" | "tail" => 8,
+        "li" => match operands.get(1).map(|operand| Resolver::default().evaluate(operand, 0, 0)) {
+            Some(Ok(value)) if fits_signed(value, 12) || value & 0xfff == 0 => 4,
+            _ => 8,
+        },
+        _ => 4,
+    }
+}
+
+/// Directive operands that decide the layout cannot refer to labels.
+fn constant(operands: &[&str], index: usize) -> Result<i64, String> {
+    let operand = operands.get(index).ok_or("missing operand")?;
+    match Resolver::default().evaluate(operand, 0, 0)? {
+        value if value >= 0 => Ok(value),
+        value => Err(format!("{value} is negative")),
+    }
+}
+
+/// The second pass: evaluates operands now every label has an address.
+#[derive(Default)]
+struct Resolver {
+    symbols: HashMap<String, u32>,
+    /// Each definition of a numeric label with the line it is on.
+    numeric_labels: Vec<(String, usize, u32)>,
+}
+
+impl Resolver {
+    fn emit(&self, item: &Item, pc: u32, line: usize, size: u32) -> Result<Vec<u8>, String> {
+        match item {
+            Item::Instruction { mnemonic, operands } => {
+                let operands = Operands { resolver: self, operands, pc, line };
+                let words = operands.encode(mnemonic, size)?;
+                Ok(words.iter().flat_map(|word| word.to_le_bytes()).collect())
+            }
+            Item::Values { width, operands } => {
+                let mut bytes = Vec::new();
+                for operand in operands {
+                    let value = self.evaluate(operand, pc, line)?;
+                    let bits = 8 * width;
+                    if value < -(1 << (bits - 1)) || value >= 1 << bits {
+                        return Err(format!("{value} does not fit in {width} bytes"));
+                    }
+                    bytes.extend_from_slice(&(value as u32).to_le_bytes()[..*width as usize]);
+                }
+                Ok(bytes)
+            }
+            Item::Bytes(bytes) => Ok(bytes.clone()),
+        }
+    }
+
+    /// Sums and differences of numbers, characters, symbols, `.` and %hi/%lo of those.
+    fn evaluate(&self, text: &str, pc: u32, line: usize) -> Result<i64, String> {
+        let mut total = 0;
+        let mut negative = false;
+        let mut term = String::new();
+        let mut depth = 0;
+        let mut quoted = false;
+
+        for character in text.trim().chars().chain(Some('+')) {
+            match character {
+                '\'' => quoted = !quoted,
+                '(' if !quoted => depth += 1,
+                ')' if !quoted => depth -= 1,
+                '+' | '-' if !quoted && depth == 0 => {
+                    if term.trim().is_empty() {
+                        negative ^= character == '-';
+                    } else {
+                        let value = self.term(term.trim(), pc, line)?;
+                        total += if negative { -value } else { value };
+                        negative = character == '-';
+                        term.clear();
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            term.push(character);
+        }
+
+        match term.trim().is_empty() {
+            true => Ok(total),
+            false => Err(format!("invalid expression {text}")),
+        }
+    }
+
+    fn term(&self, text: &str, pc: u32, line: usize) -> Result<i64, String> {
+        if let Some(inner) = text.strip_prefix("%hi(").and_then(|rest| rest.strip_suffix(')')) {
+            return Ok(hi(self.evaluate(inner, pc, line)? as u32) as i64);
+        }
+        if let Some(inner) = text.strip_prefix("%lo(").and_then(|rest| rest.strip_suffix(')')) {
+            return Ok(lo(self.evaluate(inner, pc, line)? as u32) as i64);
+        }
+        if let Some(inner) = text.strip_prefix('(').and_then(|rest| rest.strip_suffix(')')) {
+            return self.evaluate(inner, pc, line);
+        }
+        if text.starts_with('\'') {
+            return match string_literal(&format!("\"{}\"", text.trim_matches('\'')))?.as_slice() {
+                [byte] => Ok(*byte as i64),
+                _ => Err(format!("invalid character {text}")),
+            };
+        }
+        if text == "." {
+            return Ok(pc as i64);
+        }
+
+        let number = match text.get(..2) {
+            Some("0x" | "0X") => i64::from_str_radix(&text[2..], 16).ok(),
+            Some("0b" | "0B") if text.len() > 2 => i64::from_str_radix(&text[2..], 2).ok(),
+            _ => text.parse().ok(),
+        };
+        if let Some(number) = number {
+            return Ok(number);
+        }
+
+        if let Some(name) = text.strip_suffix('b').filter(|name| name.bytes().all(|byte| byte.is_ascii_digit())) {
+            let definition = self.numeric_labels.iter().rev().find(|(label, at, _)| label == name && *at <= line);
+            return definition.map(|&(_, _, address)| address as i64).ok_or(format!("undefined label {text}"));
+        }
+        if let Some(name) = text.strip_suffix('f').filter(|name| name.bytes().all(|byte| byte.is_ascii_digit())) {
+            let definition = self.numeric_labels.iter().find(|(label, at, _)| label == name && *at > line);
+            return definition.map(|&(_, _, address)| address as i64).ok_or(format!("undefined label {text}"));
+        }
+
+        match self.symbols.get(text) {
+            Some(&address) => Ok(address as i64),
+            None if text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.') => {
+                Err(format!("undefined symbol {text}"))
+            }
+            None => Err(format!("invalid number {text}")),
+        }
+    }
+}
+
+/// An instruction's operands, with the address it is placed at for pc-relative ones.
+struct Operands<'a> {
+    resolver: &'a Resolver,
+    operands: &'a [&'a str],
+    pc: u32,
+    line: usize,
+}
+
+impl Operands<'_> {
+    fn encode(&self, mnemonic: &str, size: u32) -> Result<Vec<u32>, String> {
+        let count = match mnemonic {
+            "nop" | "ret" | "fence.i" | "ecall" | "ebreak" | "sret" | "mret" | "wfi" => 0,
+            "j" | "jr" | "call" | "tail" | "rdcycle" | "rdtime" | "rdinstret" => 1,
+            "jal" | "jalr" | "fence" | "sfence.vma" => self.operands.len(),
+            "li" | "la" | "mv" | "not" | "neg" | "seqz" | "snez" | "sltz" | "sgtz" | "lui" | "auipc" | "csrr"
+            | "csrw" | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci" | "beqz" | "bnez" | "blez" | "bgez" | "bltz"
+            | "bgtz" | "lb" | "lh" | "lw" | "lbu" | "lhu" | "sb" | "sh" | "sw" => 2,
+            _ => 3,
+        };
+        if self.operands.len() != count {
+            return Err(format!("{mnemonic} takes {count} operands, not {}", self.operands.len()));
+        }
+
+        let instructions = match mnemonic {
+            "li" => self.load_immediate(size)?,
+            "la" => {
+                let destination = self.register(0)?;
+                let offset = self.relative(1)?;
+                vec![
+                    auipc(destination, hi(offset) << 12),
+                    addi(destination, destination, lo(offset)),
+                ]
+            }
+            "call" | "tail" => {
+                let (link, scratch) = if mnemonic == "call" { (RA, RA) } else { (ZERO, T1) };
+                let offset = self.relative(0)?;
+                vec![auipc(scratch, hi(offset) << 12), jalr(link, lo(offset), scratch)]
+            }
+            _ => vec![self.instruction(mnemonic, count)?],
+        };
+
+        instructions.into_iter().map(|instruction| encode(instruction).map_err(|error| error.to_string())).collect()
+    }
+
+    fn instruction(&self, mnemonic: &str, count: usize) -> Result<Instruction, String> {
+        Ok(match mnemonic {
+            "lui" => lui(self.register(0)?, self.upper(1)? << 12),
+            "auipc" => auipc(self.register(0)?, self.upper(1)? << 12),
+            "addi" => addi(self.register(0)?, self.register(1)?, self.signed(2, 12)?),
+            "slti" => slti(self.register(0)?, self.register(1)?, self.signed(2, 12)?),
+            "sltiu" => sltiu(self.register(0)?, self.register(1)?, self.signed(2, 12)?),
+            "xori" => xori(self.register(0)?, self.register(1)?, self.signed(2, 12)?),
+            "ori" => ori(self.register(0)?, self.register(1)?, self.signed(2, 12)?),
+            "andi" => andi(self.register(0)?, self.register(1)?, self.signed(2, 12)?),
+            "slli" => slli(self.register(0)?, self.register(1)?, self.unsigned(2, 5)?),
+            "srli" => srli(self.register(0)?, self.register(1)?, self.unsigned(2, 5)?),
+            "srai" => srai(self.register(0)?, self.register(1)?, self.unsigned(2, 5)?),
+            "add" => add(self.register(0)?, self.register(1)?, self.register(2)?),
+            "sub" => sub(self.register(0)?, self.register(1)?, self.register(2)?),
+            "sll" => sll(self.register(0)?, self.register(1)?, self.register(2)?),
+            "slt" => slt(self.register(0)?, self.register(1)?, self.register(2)?),
+            "sltu" => sltu(self.register(0)?, self.register(1)?, self.register(2)?),
+            "xor" => xor(self.register(0)?, self.register(1)?, self.register(2)?),
+            "srl" => srl(self.register(0)?, self.register(1)?, self.register(2)?),
+            "sra" => sra(self.register(0)?, self.register(1)?, self.register(2)?),
+            "or" => or(self.register(0)?, self.register(1)?, self.register(2)?),
+            "and" => and(self.register(0)?, self.register(1)?, self.register(2)?),
+            "lb" => self.memory_access(lb)?,
+            "lh" => self.memory_access(lh)?,
+            "lw" => self.memory_access(lw)?,
+            "lbu" => self.memory_access(lbu)?,
+            "lhu" => self.memory_access(lhu)?,
+            "sb" => self.memory_access(sb)?,
+            "sh" => self.memory_access(sh)?,
+            "sw" => self.memory_access(sw)?,
+            "beq" => beq(self.register(0)?, self.register(1)?, self.branch_offset(2)?),
+            "bne" => bne(self.register(0)?, self.register(1)?, self.branch_offset(2)?),
+            "blt" => blt(self.register(0)?, self.register(1)?, self.branch_offset(2)?),
+            "bge" => bge(self.register(0)?, self.register(1)?, self.branch_offset(2)?),
+            "bltu" => bltu(self.register(0)?, self.register(1)?, self.branch_offset(2)?),
+            "bgeu" => bgeu(self.register(0)?, self.register(1)?, self.branch_offset(2)?),
+            "bgt" => blt(self.register(1)?, self.register(0)?, self.branch_offset(2)?),
+            "ble" => bge(self.register(1)?, self.register(0)?, self.branch_offset(2)?),
+            "bgtu" => bltu(self.register(1)?, self.register(0)?, self.branch_offset(2)?),
+            "bleu" => bgeu(self.register(1)?, self.register(0)?, self.branch_offset(2)?),
+            "beqz" => beq(self.register(0)?, ZERO, self.branch_offset(1)?),
+            "bnez" => bne(self.register(0)?, ZERO, self.branch_offset(1)?),
+            "bltz" => blt(self.register(0)?, ZERO, self.branch_offset(1)?),
+            "bgez" => bge(self.register(0)?, ZERO, self.branch_offset(1)?),
+            "bgtz" => blt(ZERO, self.register(0)?, self.branch_offset(1)?),
+            "blez" => bge(ZERO, self.register(0)?, self.branch_offset(1)?),
+            "jal" if count == 1 => jal(RA, self.jump_offset(0)?),
+            "jal" if count == 2 => jal(self.register(0)?, self.jump_offset(1)?),
+            "j" => jal(ZERO, self.jump_offset(0)?),
+            "jalr" if count == 1 => jalr(RA, 0, self.register(0)?),
+            "jalr" if count == 2 => {
+                let (offset, base) = self.memory(1)?;
+                jalr(self.register(0)?, offset, base)
+            }
+            "jalr" if count == 3 => jalr(self.register(0)?, self.signed(2, 12)?, self.register(1)?),
+            "jr" => jalr(ZERO, 0, self.register(0)?),
+            "ret" => jalr(ZERO, 0, RA),
+            "nop" => addi(ZERO, ZERO, 0),
+            "mv" => addi(self.register(0)?, self.register(1)?, 0),
+            "not" => xori(self.register(0)?, self.register(1)?, -1),
+            "neg" => sub(self.register(0)?, ZERO, self.register(1)?),
+            "seqz" => sltiu(self.register(0)?, self.register(1)?, 1),
+            "snez" => sltu(self.register(0)?, ZERO, self.register(1)?),
+            "sltz" => slt(self.register(0)?, self.register(1)?, ZERO),
+            "sgtz" => slt(self.register(0)?, ZERO, self.register(1)?),
+            "fence" if count == 0 => fence(0b1111, 0b1111),
+            "fence" if count == 2 => fence(fence_set(self.operands[0])?, fence_set(self.operands[1])?),
+            "fence.i" => fence_i(),
+            "ecall" => ecall(),
+            "ebreak" => ebreak(),
+            "sret" => sret(),
+            "mret" => mret(),
+            "wfi" => wfi(),
+            "sfence.vma" if count <= 2 => {
+                let rs1 = if count > 0 { self.register(0)? } else { ZERO };
+                let rs2 = if count > 1 { self.register(1)? } else { ZERO };
+                sfence_vma(rs1, rs2)
+            }
+            "csrrw" => csrrw(self.register(0)?, self.csr(1)?, self.register(2)?),
+            "csrrs" => csrrs(self.register(0)?, self.csr(1)?, self.register(2)?),
+            "csrrc" => csrrc(self.register(0)?, self.csr(1)?, self.register(2)?),
+            "csrrwi" => csrrwi(self.register(0)?, self.csr(1)?, self.unsigned(2, 5)?),
+            "csrrsi" => csrrsi(self.register(0)?, self.csr(1)?, self.unsigned(2, 5)?),
+            "csrrci" => csrrci(self.register(0)?, self.csr(1)?, self.unsigned(2, 5)?),
+            "csrr" => csrrs(self.register(0)?, self.csr(1)?, ZERO),
+            "csrw" => csrrw(ZERO, self.csr(0)?, self.register(1)?),
+            "csrs" => csrrs(ZERO, self.csr(0)?, self.register(1)?),
+            "csrc" => csrrc(ZERO, self.csr(0)?, self.register(1)?),
+            "csrwi" => csrrwi(ZERO, self.csr(0)?, self.unsigned(1, 5)?),
+            "csrsi" => csrrsi(ZERO, self.csr(0)?, self.unsigned(1, 5)?),
+            "csrci" => csrrci(ZERO, self.csr(0)?, self.unsigned(1, 5)?),
+            "rdcycle" => csrrs(self.register(0)?, CYCLE, ZERO),
+            "rdtime" => csrrs(self.register(0)?, TIME, ZERO),
+            "rdinstret" => csrrs(self.register(0)?, INSTRET, ZERO),
+            "jal" | "jalr" | "fence" | "sfence.vma" => {
+                return Err(format!("{mnemonic} does not take {count} operands"))
+            }
+            _ => return Err(format!("unknown instruction {mnemonic}")),
+        })
+    }
+
+    /// The same size as the first pass picked: one instruction when the value was a constant that fits.
+    fn load_immediate(&self, size: u32) -> Result<Vec<Instruction>, String> {
+        let destination = self.register(0)?;
+        let value = self.value(1)?;
+        if value < i32::MIN as i64 || value > u32::MAX as i64 {
+            return Err(format!("{value} does not fit in 32 bits"));
+        }
+
+        let value = value as u32;
+        Ok(match (size, fits_signed(value as i32 as i64, 12)) {
+            (4, true) => vec![addi(destination, ZERO, value as i32)],
+            (4, false) => vec![lui(destination, value)],
+            _ => vec![
+                lui(destination, hi(value) << 12),
+                addi(destination, destination, lo(value)),
+            ],
+        })
+    }
+
+    /// Loads and stores, both written `register, offset(base)`.
+    fn memory_access(&self, build: fn(u32, i32, u32) -> Instruction) -> Result<Instruction, String> {
+        let (offset, base) = self.memory(1)?;
+        Ok(build(self.register(0)?, offset, base))
+    }
+
+    fn register(&self, index: usize) -> Result<u32, String> {
+        let name = self.operands[index];
+        let number = match name {
+            "fp" => Some(8),
+            _ => match name.strip_prefix('x').and_then(|number| number.parse().ok()) {
+                Some(number) if number < 32 => Some(number),
+                _ => ABI_NAMES.iter().position(|abi_name| *abi_name == name).map(|number| number as u32),
+            },
+        };
+        number.ok_or(format!("invalid register {name}"))
+    }
+
+    fn value(&self, index: usize) -> Result<i64, String> {
+        self.resolver.evaluate(self.operands[index], self.pc, self.line)
+    }
+
+    fn signed(&self, index: usize, bits: u32) -> Result<i32, String> {
+        match self.value(index)? {
+            value if fits_signed(value, bits) => Ok(value as i32),
+            value => Err(format!("{value} does not fit in a {bits} bit signed immediate")),
+        }
+    }
+
+    fn unsigned(&self, index: usize, bits: u32) -> Result<u32, String> {
+        match self.value(index)? {
+            value if (0..1 << bits).contains(&value) => Ok(value as u32),
+            value => Err(format!("{value} does not fit in a {bits} bit unsigned immediate")),
+        }
+    }
+
+    fn upper(&self, index: usize) -> Result<u32, String> {
+        self.unsigned(index, 20)
+    }
+
+    /// `offset(base)`, either part may be left out.
+    fn memory(&self, index: usize) -> Result<(i32, u32), String> {
+        let operand = self.operands[index];
+        let (offset, base) = match operand.strip_suffix(')').and_then(|rest| rest.rsplit_once('(')) {
+            Some((offset, base)) => (offset.trim(), base.trim()),
+            None => (operand, "zero"),
+        };
+
+        let offset = match offset.is_empty() {
+            true => 0,
+            false => self.resolver.evaluate(offset, self.pc, self.line)?,
+        };
+        if !fits_signed(offset, 12) {
+            return Err(format!("offset {offset} does not fit in 12 bits"));
+        }
+
+        let base = Operands { operands: &[base], ..*self }.register(0)?;
+        Ok((offset as i32, base))
+    }
+
+    fn csr(&self, index: usize) -> Result<u32, String> {
+        let name = self.operands[index];
+        match (0..0x1000).find(|&address| csr_name(address).is_some_and(|known| known == name)) {
+            Some(address) => Ok(address),
+            None => self.unsigned(index, 12),
+        }
+    }
+
+    fn relative(&self, index: usize) -> Result<u32, String> {
+        Ok((self.value(index)? as u32).wrapping_sub(self.pc))
+    }
+
+    fn branch_offset(&self, index: usize) -> Result<i32, String> {
+        self.checked_offset(index, 13)
+    }
+
+    fn jump_offset(&self, index: usize) -> Result<i32, String> {
+        self.checked_offset(index, 21)
+    }
+
+    fn checked_offset(&self, index: usize, bits: u32) -> Result<i32, String> {
+        let offset = self.value(index)? - self.pc as i64;
+        if offset % 2 != 0 {
+            return Err(format!("target {} is not aligned to 2 bytes", self.operands[index]));
+        }
+        if !fits_signed(offset, bits) {
+            return Err(format!("target {} is out of range", self.operands[index]));
+        }
+        Ok(offset as i32)
+    }
+}
+
+fn fits_signed(value: i64, bits: u32) -> bool {
+    (-(1 << (bits - 1))..1 << (bits - 1)).contains(&value)
+}
+
+/// The upper 20 bits, rounded so that adding the sign extended `lo` gives the value back.
+fn hi(value: u32) -> u32 {
+    value.wrapping_add(0x800) >> 12
+}
+
+fn lo(value: u32) -> i32 {
+    ((value << 20) as i32) >> 20
+}
+
+fn fence_set(text: &str) -> Result<u32, String> {
+    text.chars().try_fold(0, |bits, access| match access {
+        'i' => Ok(bits | 0b1000),
+        'o' => Ok(bits | 0b0100),
+        'r' => Ok(bits | 0b0010),
+        'w' => Ok(bits | 0b0001),
+        _ => Err(format!("invalid fence operand {text}")),
+    })
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = None;
+    for (index, character) in line.char_indices() {
+        match (character, quoted) {
+            ('"' | '\'', None) => quoted = Some(character),
+            (_, Some(quote)) if character == quote => quoted = None,
+            ('#', None) => return &line[..index],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn label_prefix(text: &str) -> Option<(&str, &str)> {
+    let end = text.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$'))?;
+    match text[end..].strip_prefix(':') {
+        Some(rest) if end > 0 => Some((&text[..end], rest)),
+        _ => None,
+    }
+}
+
+/// Splits on commas outside of parentheses and quotes.
+fn split_operands(text: &str) -> Vec<&str> {
+    let mut operands = Vec::new();
+    let (mut start, mut depth, mut quoted) = (0, 0, None);
+
+    for (index, character) in text.char_indices() {
+        match (character, quoted) {
+            ('"' | '\'', None) => quoted = Some(character),
+            (_, Some(quote)) if character == quote => quoted = None,
+            ('(', None) => depth += 1,
+            (')', None) => depth -= 1,
+            (',', None) if depth == 0 => {
+                operands.push(text[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+
+    match text[start..].trim() {
+        "" if operands.is_empty() => operands,
+        last => {
+            operands.push(last);
+            operands
+        }
+    }
+}
+
+fn string_literal(text: &str) -> Result<Vec<u8>, String> {
+    let inner =
+        text.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')).ok_or(format!("invalid string {text}"))?;
+
+    let mut bytes = Vec::new();
+    let mut characters = inner.chars();
+    while let Some(character) = characters.next() {
+        let character = match character {
+            '\\' => match characters.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('r') => '\r',
+                Some('0') => '\0',
+                Some(escaped @ ('\\' | '"' | '\'')) => escaped,
+                _ => return Err(format!("invalid escape in {text}")),
+            },
+            character => character,
+        };
+        let mut buffer = [0; 4];
+        bytes.extend_from_slice(character.encode_utf8(&mut buffer).as_bytes());
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(program: &Program) -> Vec<u32> {
+        program.bytes.chunks(4).map(|word| u32::from_le_bytes(word.try_into().unwrap())).collect()
+    }
+
+    #[test]
+    fn assembles_labels_pseudo_instructions_and_data() {
+        let source = "
+                .text
+                .globl _start
+            _start:
+                li      a0, 5           # fits in addi
+                li      a1, 0x12345678
+                la      a2, value
+            1:  addi    a0, a0, -1
+                bnez    a0, 1b
+                lw      a3, %lo(value)(zero)
+                call    done
+            done:
+                j       done
+
+                .data
+                .byte   1
+                .align  2
+            value:
+                .word   0xdeadbeef, _start
+        ";
+
+        let program = assemble(source, 0).unwrap();
+
+        assert_eq!(program.symbol("done"), Some(0x28));
+        assert_eq!(program.data_address, 0x2c);
+        assert_eq!(program.symbol("value"), Some(0x30));
+        assert_eq!(program.globals, vec!["_start"]);
+        assert_eq!(
+            words(&program),
+            vec![
+                0x0050_0513,
+                0x1234_55b7,
+                0x6785_8593,
+                0x0000_0617,
+                0x0246_0613,
+                0xfff5_0513,
+                0xfe05_1ee3,
+                0x0300_2683,
+                0x0000_0097,
+                0x0080_80e7,
+                0x0000_006f,
+                0x0000_0001,
+                0xdead_beef,
+                0x0000_0000,
+            ]
+        );
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let error = |source| assemble(source, 0).err().unwrap();
+
+        assert_eq!(
+            error("nop\naddi a0, a0, 4096"),
+            AssemblyError { line: 2, message: "4096 does not fit in a 12 bit signed immediate".to_string() }
+        );
+        assert_eq!(error("j nowhere").message, "undefined symbol nowhere");
+        assert_eq!(error("add a0, a1, q1").message, "invalid register q1");
+        assert_eq!(error("a:\na:").line, 2);
+    }
+}
@@ -0,0 +1,217 @@
+use crate::core::bus::{BusInterface, BusReadResponse};
+use crate::core::hart::Hart;
+use crate::elf::{Elf, SEGMENT_LOAD};
+use crate::htif::{host_addresses, Htif};
+use crate::memory::Memory;
+use crate::simple_pipeline::SimplePipeline;
+
+/// Cycles a test may run before it counts as hung.
+pub const DEFAULT_CYCLE_LIMIT: u64 = 5_000_000;
+/// Memory past the end of the loaded segments, for tests that use space they do not load anything into.
+const SPARE_MEMORY: usize = 64 * 1024;
+
+/// How a test ended, from the exit code it passed to the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    /// riscv-tests report the number of the first failing case, `TESTNUM`.
+    Fail {
+        test_number: u32,
+    },
+    /// The test did not exit within the cycle limit.
+    Timeout,
+}
+
+/// A test loaded for running: riscv-tests and riscv-arch-test ELFs, or anything else following their conventions.
+#[derive(Clone, Debug)]
+pub struct TestProgram {
+    /// Where `image` is loaded, the lowest address of the loaded segments.
+    pub base: u32,
+    pub image: Vec<u8>,
+    pub entry: u32,
+    pub tohost: u32,
+    pub fromhost: Option<u32>,
+    /// `begin_signature` and `end_signature` of riscv-arch-test, the words a test leaves for comparison.
+    pub signature: Option<(u32, u32)>,
+}
+
+impl TestProgram {
+    pub fn from_elf(elf: &Elf) -> Result<TestProgram, String> {
+        let segments: Vec<_> = elf.segments.iter().filter(|segment| segment.kind == SEGMENT_LOAD).collect();
+        let base = segments.iter().map(|segment| segment.physical_address).min().ok_or("nothing to load")?;
+        let end = segments
+            .iter()
+            .map(|segment| segment.physical_address as usize + segment.memory_size as usize)
+            .max()
+            .unwrap();
+
+        let mut image = vec![0; end - base as usize];
+        for segment in segments {
+            let data = elf.segment_data(segment).map_err(|error| error.to_string())?;
+            if data.len() > segment.memory_size as usize {
+                return Err(format!("segment at {:#x} has more data than memory", segment.physical_address));
+            }
+            let start = (segment.physical_address - base) as usize;
+            image[start..start + data.len()].copy_from_slice(data);
+        }
+
+        let (tohost, fromhost) = host_addresses(elf).ok_or("no tohost symbol")?;
+        let symbol = |name| elf.symbol(name).map(|symbol| symbol.value);
+        let signature = symbol("begin_signature").zip(symbol("end_signature"));
+        Ok(TestProgram { base, image, entry: elf.entry, tohost, fromhost, signature })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TestReport {
+    pub outcome: Outcome,
+    pub cycles: u64,
+    /// The signature region once the test ended, empty for tests without one.
+    pub signature: Vec<u32>,
+}
+
+/// Runs a test on a hart without caches until it exits through HTIF or runs out of cycles. Console output and system
+/// calls go to the host.
+pub fn run(program: &TestProgram, cycle_limit: u64) -> TestReport {
+    let mut image = program.image.clone();
+    image.resize(image.len() + SPARE_MEMORY, 0);
+    let mut memory = Htif::new(Memory::at(program.base, image), program.tohost, program.fromhost);
+    let mut hart = Hart::<Htif<Memory>, SimplePipeline>::new();
+    hart.set_program_counter(program.entry);
+
+    let mut outcome = Outcome::Timeout;
+    let mut cycles = 0;
+    while cycles < cycle_limit {
+        hart.execute(&mut memory);
+        cycles += 1;
+
+        if let Some(exit_code) = memory.exit_code() {
+            outcome = match exit_code {
+                0 => Outcome::Pass,
+                test_number => Outcome::Fail { test_number },
+            };
+            break;
+        }
+    }
+
+    let signature = match program.signature {
+        Some((start, end)) => {
+            (start..end).step_by(4).map(|address| read_word(memory.inner(), address).unwrap_or(0)).collect()
+        }
+        None => Vec::new(),
+    };
+    TestReport { outcome, cycles, signature }
+}
+
+/// The signature as riscv-arch-test reference signatures have it, one word per line in hex.
+pub fn format_signature(signature: &[u32]) -> String {
+    signature.iter().map(|word| format!("{word:08x}\n")).collect()
+}
+
+fn read_word(memory: &Memory, address: u32) -> Option<u32> {
+    match <Memory as BusInterface<u32, u32>>::read(memory, address) {
+        BusReadResponse::Success(value) => Some(value),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+
+    /// Assembles a test at 0x8000_0000 in the style of riscv-tests: `gp` holds the number of the case running, and a
+    /// failing case jumps to `fail`.
+    fn test_program(cases: &str) -> TestProgram {
+        let source = format!(
+            "
+            {cases}
+            li      gp, 1
+            j       write_tohost
+        fail:
+            slli    gp, gp, 1
+            ori     gp, gp, 1
+        write_tohost:
+            la      t0, tohost
+            sw      gp, 0(t0)
+            j       write_tohost
+
+            .data
+        tohost:
+            .word   0
+        begin_signature:
+            .word   0xdeadbeef
+            .word   0xdeadbeef
+        end_signature:
+            "
+        );
+        let program = assemble(&source, 0x8000_0000).unwrap();
+        let symbol = |name| program.symbol(name).unwrap();
+        TestProgram {
+            base: program.origin,
+            image: program.bytes.clone(),
+            entry: program.origin,
+            tohost: symbol("tohost"),
+            fromhost: None,
+            signature: Some((symbol("begin_signature"), symbol("end_signature"))),
+        }
+    }
+
+    #[test]
+    fn passes_when_the_alu_matches_the_spec() {
+        let program = test_program(
+            "
+            li      gp, 2
+            li      a0, 13
+            li      a1, 11
+            add     a2, a0, a1
+            li      t2, 24
+            bne     a2, t2, fail
+
+            li      gp, 3
+            sub     a2, a1, a0
+            li      t2, -2
+            bne     a2, t2, fail
+
+            li      gp, 4
+            li      a0, -64
+            srai    a2, a0, 3
+            li      t2, -8
+            bne     a2, t2, fail
+
+            li      gp, 5
+            li      a1, 33
+            sll     a2, a0, a1
+            li      t2, -128
+            bne     a2, t2, fail
+
+            li      gp, 6
+            slli    a2, a1, 4
+            srli    a3, a0, 28
+            li      t2, 528
+            bne     a2, t2, fail
+            li      t2, 15
+            bne     a3, t2, fail
+
+            la      t0, begin_signature
+            sw      a2, 4(t0)
+            ",
+        );
+
+        let report = run(&program, 10_000);
+        assert_eq!(report.outcome, Outcome::Pass);
+        assert_eq!(format_signature(&report.signature), "deadbeef\n00000210\n");
+    }
+
+    #[test]
+    fn reports_the_failing_case() {
+        let program = test_program("li gp, 7\nli a0, 1\nbnez a0, fail\n");
+        assert_eq!(run(&program, 10_000).outcome, Outcome::Fail { test_number: 7 });
+
+        let program = test_program("j .\n");
+        assert_eq!(
+            run(&program, 1_000),
+            TestReport { outcome: Outcome::Timeout, cycles: 1_000, signature: vec![0xdeadbeef; 2] }
+        );
+    }
+}
@@ -1,6 +1,15 @@
+pub mod branch_predictor;
 pub mod bus;
+pub mod cache;
+pub mod commit_log;
+pub mod csr;
+pub mod encoder;
+pub mod exception;
 pub mod hart;
 pub mod instruction;
+pub mod mmu;
 pub mod pipeline;
+pub mod pmp;
 pub mod register_file;
+pub mod snapshot;
 pub mod unit;
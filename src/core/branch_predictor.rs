@@ -0,0 +1,519 @@
+use super::instruction::{BType, BranchingInstruction, IType, JType};
+use super::snapshot::{Snapshot, SnapshotError, SnapshotReader, SnapshotWriter};
+use super::unit::FetchResult;
+
+/// Registers the calling convention uses for return addresses, see the RAS hints in the JALR description.
+const LINK_REGISTERS: [u32; 2] = [1, 5];
+
+pub const DEFAULT_BTB_ENTRIES: usize = 64;
+pub const DEFAULT_RAS_DEPTH: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BranchKind {
+    Conditional,
+    Jump,
+    Call,
+    Return,
+}
+
+impl BranchKind {
+    /// The kind of a control transfer and where it goes when taken.
+    pub fn of(fetch_result: FetchResult, instr: BranchingInstruction) -> (BranchKind, u32) {
+        use BranchingInstruction::*;
+
+        let pc = fetch_result.captured_pc;
+        match instr {
+            JAL(JType { register_destination_index, immediate, .. }) => {
+                let kind = match LINK_REGISTERS.contains(&register_destination_index) {
+                    true => BranchKind::Call,
+                    false => BranchKind::Jump,
+                };
+                (kind, pc.wrapping_add(immediate))
+            }
+            JALR(IType { register_destination_index, register_source_one, immediate, .. }) => {
+                let kind = match (
+                    LINK_REGISTERS.contains(&register_destination_index),
+                    LINK_REGISTERS.contains(&register_source_one.index),
+                ) {
+                    (true, _) => BranchKind::Call,
+                    (false, true) if register_destination_index == 0 => BranchKind::Return,
+                    _ => BranchKind::Jump,
+                };
+                (kind, register_source_one.value.wrapping_add(immediate) & !1)
+            }
+            BEQ(BType { immediate, .. })
+            | BNE(BType { immediate, .. })
+            | BLT(BType { immediate, .. })
+            | BGE(BType { immediate, .. })
+            | BLTU(BType { immediate, .. })
+            | BGEU(BType { immediate, .. }) => (BranchKind::Conditional, pc.wrapping_add(immediate)),
+        }
+    }
+}
+
+/// Predicts whether a conditional branch is taken. `target` is where it goes when taken.
+pub trait DirectionPredictor {
+    fn predict(&self, pc: u32, target: u32) -> bool;
+    fn update(&mut self, pc: u32, target: u32, taken: bool);
+    fn clone_box(&self) -> Box<dyn DirectionPredictor>;
+
+    /// Saves the predictor's state for a snapshot, predictors without any save nothing.
+    fn save(&self, _: &mut SnapshotWriter) {}
+
+    fn restore(&mut self, _: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        Ok(())
+    }
+}
+
+impl Clone for Box<dyn DirectionPredictor> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct AlwaysNotTaken;
+
+impl DirectionPredictor for AlwaysNotTaken {
+    fn predict(&self, _: u32, _: u32) -> bool {
+        false
+    }
+
+    fn update(&mut self, _: u32, _: u32, _: bool) {}
+
+    fn clone_box(&self) -> Box<dyn DirectionPredictor> {
+        Box::new(*self)
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct AlwaysTaken;
+
+impl DirectionPredictor for AlwaysTaken {
+    fn predict(&self, _: u32, _: u32) -> bool {
+        true
+    }
+
+    fn update(&mut self, _: u32, _: u32, _: bool) {}
+
+    fn clone_box(&self) -> Box<dyn DirectionPredictor> {
+        Box::new(*self)
+    }
+}
+
+/// Backward taken, forward not taken, which gets loops right.
+#[derive(Clone, Copy, Default)]
+pub struct BackwardTakenForwardNotTaken;
+
+impl DirectionPredictor for BackwardTakenForwardNotTaken {
+    fn predict(&self, pc: u32, target: u32) -> bool {
+        target <= pc
+    }
+
+    fn update(&mut self, _: u32, _: u32, _: bool) {}
+
+    fn clone_box(&self) -> Box<dyn DirectionPredictor> {
+        Box::new(*self)
+    }
+}
+
+/// A table of 2-bit saturating counters, starting weakly not taken.
+#[derive(Clone)]
+struct CounterTable {
+    counters: Box<[u8]>,
+}
+
+impl CounterTable {
+    fn new(entries: usize) -> Self {
+        assert!(entries.is_power_of_two(), "predictor tables must have a power of two entries");
+        CounterTable { counters: vec![1; entries].into_boxed_slice() }
+    }
+
+    fn index(&self, value: u32) -> usize {
+        value as usize & (self.counters.len() - 1)
+    }
+
+    fn predict(&self, index: u32) -> bool {
+        self.counters[self.index(index)] >= 2
+    }
+
+    fn update(&mut self, index: u32, taken: bool) {
+        let counter = &mut self.counters[self.index(index)];
+        *counter = match taken {
+            true => (*counter + 1).min(3),
+            false => counter.saturating_sub(1),
+        };
+    }
+
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.bytes(&self.counters);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let counters = snapshot.bytes()?;
+        if counters.len() != self.counters.len() {
+            let message = format!("{} predictor counters, the snapshot has {}", self.counters.len(), counters.len());
+            return Err(SnapshotError::Mismatch(message));
+        }
+        self.counters.copy_from_slice(counters);
+        Ok(())
+    }
+}
+
+/// 2-bit counters indexed by the branch address.
+#[derive(Clone)]
+pub struct Bimodal {
+    table: CounterTable,
+}
+
+impl Bimodal {
+    pub fn new(entries: usize) -> Self {
+        Bimodal { table: CounterTable::new(entries) }
+    }
+}
+
+impl DirectionPredictor for Bimodal {
+    fn predict(&self, pc: u32, _: u32) -> bool {
+        self.table.predict(pc >> 2)
+    }
+
+    fn update(&mut self, pc: u32, _: u32, taken: bool) {
+        self.table.update(pc >> 2, taken);
+    }
+
+    fn clone_box(&self) -> Box<dyn DirectionPredictor> {
+        Box::new(self.clone())
+    }
+
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("bimodal");
+        self.table.save(snapshot);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        snapshot.section("bimodal")?;
+        self.table.restore(snapshot)
+    }
+}
+
+/// 2-bit counters indexed by the branch address XORed with the outcomes of the most recent branches.
+#[derive(Clone)]
+pub struct Gshare {
+    table: CounterTable,
+    history: u32,
+    history_bits: u32,
+}
+
+impl Gshare {
+    pub fn new(entries: usize, history_bits: u32) -> Self {
+        assert!(history_bits <= 32, "gshare history is at most 32 bits");
+        Gshare { table: CounterTable::new(entries), history: 0, history_bits }
+    }
+
+    fn index(&self, pc: u32) -> u32 {
+        (pc >> 2) ^ self.history
+    }
+}
+
+impl DirectionPredictor for Gshare {
+    fn predict(&self, pc: u32, _: u32) -> bool {
+        self.table.predict(self.index(pc))
+    }
+
+    fn update(&mut self, pc: u32, _: u32, taken: bool) {
+        self.table.update(self.index(pc), taken);
+        let mask = 1u32.checked_shl(self.history_bits).map_or(u32::MAX, |bit| bit - 1);
+        self.history = ((self.history << 1) | taken as u32) & mask;
+    }
+
+    fn clone_box(&self) -> Box<dyn DirectionPredictor> {
+        Box::new(self.clone())
+    }
+
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("gshare");
+        self.table.save(snapshot);
+        snapshot.u32(self.history);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        snapshot.section("gshare")?;
+        self.table.restore(snapshot)?;
+        self.history = snapshot.u32()?;
+        Ok(())
+    }
+}
+
+/// Chooses per branch between a bimodal and a gshare predictor, whichever has been right more often.
+#[derive(Clone)]
+pub struct Tournament {
+    bimodal: Bimodal,
+    gshare: Gshare,
+    chooser: CounterTable,
+}
+
+impl Tournament {
+    pub fn new(entries: usize, history_bits: u32) -> Self {
+        Tournament {
+            bimodal: Bimodal::new(entries),
+            gshare: Gshare::new(entries, history_bits),
+            chooser: CounterTable::new(entries),
+        }
+    }
+}
+
+impl DirectionPredictor for Tournament {
+    fn predict(&self, pc: u32, target: u32) -> bool {
+        match self.chooser.predict(pc >> 2) {
+            true => self.gshare.predict(pc, target),
+            false => self.bimodal.predict(pc, target),
+        }
+    }
+
+    fn update(&mut self, pc: u32, target: u32, taken: bool) {
+        let bimodal_correct = self.bimodal.predict(pc, target) == taken;
+        let gshare_correct = self.gshare.predict(pc, target) == taken;
+        if bimodal_correct != gshare_correct {
+            self.chooser.update(pc >> 2, gshare_correct);
+        }
+
+        self.bimodal.update(pc, target, taken);
+        self.gshare.update(pc, target, taken);
+    }
+
+    fn clone_box(&self) -> Box<dyn DirectionPredictor> {
+        Box::new(self.clone())
+    }
+
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("tournament");
+        self.bimodal.save(snapshot);
+        self.gshare.save(snapshot);
+        self.chooser.save(snapshot);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        snapshot.section("tournament")?;
+        self.bimodal.restore(snapshot)?;
+        self.gshare.restore(snapshot)?;
+        self.chooser.restore(snapshot)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct BtbEntry {
+    pc: u32,
+    target: u32,
+    kind: BranchKind,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BranchStatistics {
+    /// Control transfer instructions that were resolved.
+    pub branches: u64,
+    pub mispredictions: u64,
+}
+
+/// Predicts the address to fetch next from the fetch address alone.
+///
+/// Fetch only knows an instruction is a branch once the direct mapped branch target buffer has seen it taken, so
+/// every predictor falls back to not taken on a BTB miss. Returns are predicted from the return address stack.
+/// All state is updated when branches resolve in decode, one cycle after they are fetched.
+#[derive(Clone)]
+pub struct BranchPredictor {
+    direction: Box<dyn DirectionPredictor>,
+    btb: Box<[Option<BtbEntry>]>,
+    return_address_stack: Vec<u32>,
+    return_address_stack_depth: usize,
+    statistics: BranchStatistics,
+}
+
+impl BranchPredictor {
+    pub fn new(direction: Box<dyn DirectionPredictor>) -> Self {
+        Self::with_sizes(direction, DEFAULT_BTB_ENTRIES, DEFAULT_RAS_DEPTH)
+    }
+
+    pub fn with_sizes(direction: Box<dyn DirectionPredictor>, btb_entries: usize, ras_depth: usize) -> Self {
+        assert!(btb_entries.is_power_of_two(), "the BTB must have a power of two entries");
+        BranchPredictor {
+            direction,
+            btb: vec![None; btb_entries].into_boxed_slice(),
+            return_address_stack: Vec::with_capacity(ras_depth),
+            return_address_stack_depth: ras_depth,
+            statistics: BranchStatistics::default(),
+        }
+    }
+
+    pub fn statistics(&self) -> BranchStatistics {
+        self.statistics
+    }
+
+    /// The predicted address of the instruction after the one at `pc`.
+    pub fn predict(&self, pc: u32) -> u32 {
+        let fall_through = pc.wrapping_add(4);
+
+        match self.btb[self.btb_index(pc)] {
+            Some(entry) if entry.pc == pc => match entry.kind {
+                BranchKind::Conditional if !self.direction.predict(pc, entry.target) => fall_through,
+                BranchKind::Return => self.return_address_stack.last().copied().unwrap_or(entry.target),
+                _ => entry.target,
+            },
+            _ => fall_through,
+        }
+    }
+
+    /// Trains on a control transfer that resolved to `next_pc`, after `predicted_next_pc` had been fetched.
+    pub fn resolve(&mut self, pc: u32, kind: BranchKind, target: u32, next_pc: u32, predicted_next_pc: u32) {
+        let taken = next_pc != pc.wrapping_add(4) || (kind != BranchKind::Conditional);
+
+        self.statistics.branches += 1;
+        self.record(next_pc, predicted_next_pc);
+
+        if kind == BranchKind::Conditional {
+            self.direction.update(pc, target, taken);
+        }
+
+        match kind {
+            BranchKind::Call => {
+                if self.return_address_stack.len() == self.return_address_stack_depth {
+                    self.return_address_stack.remove(0);
+                }
+                if self.return_address_stack_depth > 0 {
+                    self.return_address_stack.push(pc.wrapping_add(4));
+                }
+            }
+            BranchKind::Return => {
+                self.return_address_stack.pop();
+            }
+            _ => {}
+        }
+
+        if taken {
+            let index = self.btb_index(pc);
+            self.btb[index] = Some(BtbEntry { pc, target, kind });
+        }
+    }
+
+    /// Counts a misprediction if fetch did not continue at `next_pc`, e.g. after a stale BTB entry.
+    pub fn record(&mut self, next_pc: u32, predicted_next_pc: u32) {
+        if next_pc != predicted_next_pc {
+            self.statistics.mispredictions += 1;
+        }
+    }
+
+    fn btb_index(&self, pc: u32) -> usize {
+        (pc >> 2) as usize & (self.btb.len() - 1)
+    }
+}
+
+impl Snapshot for BranchPredictor {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("branch predictor");
+        self.direction.save(snapshot);
+        snapshot.usize(self.btb.len());
+        for entry in self.btb.iter() {
+            snapshot.option(entry, |snapshot, entry| {
+                snapshot.u32(entry.pc);
+                snapshot.u32(entry.target);
+                snapshot.u8(entry.kind as u8);
+            });
+        }
+        snapshot.usize(self.return_address_stack_depth);
+        snapshot.usize(self.return_address_stack.len());
+        self.return_address_stack.iter().for_each(|&address| snapshot.u32(address));
+        snapshot.u64(self.statistics.branches);
+        snapshot.u64(self.statistics.mispredictions);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        snapshot.section("branch predictor")?;
+        self.direction.restore(snapshot)?;
+        snapshot.expect("the number of BTB entries", self.btb.len(), SnapshotReader::usize)?;
+        for entry in self.btb.iter_mut() {
+            *entry = snapshot.option(|snapshot| {
+                Ok(BtbEntry {
+                    pc: snapshot.u32()?,
+                    target: snapshot.u32()?,
+                    kind: match snapshot.u8()? {
+                        0 => BranchKind::Conditional,
+                        1 => BranchKind::Jump,
+                        2 => BranchKind::Call,
+                        _ => BranchKind::Return,
+                    },
+                })
+            })?;
+        }
+        snapshot.expect("the return address stack depth", self.return_address_stack_depth, SnapshotReader::usize)?;
+        let length = snapshot.usize()?;
+        self.return_address_stack = (0..length).map(|_| snapshot.u32()).collect::<Result<_, _>>()?;
+        self.statistics = BranchStatistics { branches: snapshot.u64()?, mispredictions: snapshot.u64()? };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOOP_BRANCH: u32 = 0x100;
+    const LOOP_START: u32 = 0xf0;
+
+    /// A loop branch taken `iterations - 1` times then falling through, repeated `runs` times.
+    fn run_loop(predictor: &mut BranchPredictor, iterations: usize, runs: usize) {
+        for _ in 0..runs {
+            for iteration in 0..iterations {
+                let next_pc = match iteration + 1 < iterations {
+                    true => LOOP_START,
+                    false => LOOP_BRANCH + 4,
+                };
+                let predicted = predictor.predict(LOOP_BRANCH);
+                predictor.resolve(LOOP_BRANCH, BranchKind::Conditional, LOOP_START, next_pc, predicted);
+            }
+        }
+    }
+
+    #[test]
+    fn bimodal_mispredicts_loop_exits_only() {
+        let mut predictor = BranchPredictor::new(Box::new(Bimodal::new(256)));
+
+        run_loop(&mut predictor, 10, 10);
+
+        // The first taken branch misses in the BTB, afterwards only the exits go wrong.
+        assert_eq!(predictor.statistics(), BranchStatistics { branches: 100, mispredictions: 11 });
+    }
+
+    #[test]
+    fn gshare_learns_short_loops_completely() {
+        let mut predictor = BranchPredictor::new(Box::new(Gshare::new(1024, 8)));
+
+        run_loop(&mut predictor, 4, 50);
+        let warmed_up = predictor.statistics().mispredictions;
+        run_loop(&mut predictor, 4, 50);
+
+        assert_eq!(predictor.statistics().mispredictions, warmed_up);
+    }
+
+    #[test]
+    fn returns_are_predicted_from_the_return_address_stack() {
+        let mut predictor = BranchPredictor::new(Box::new(AlwaysNotTaken));
+        let call_sites = [0x400, 0x808];
+
+        for call_site in call_sites.into_iter().chain(call_sites) {
+            let predicted = predictor.predict(call_site);
+            predictor.resolve(call_site, BranchKind::Call, 0x1000, 0x1000, predicted);
+            let predicted = predictor.predict(0x1010);
+            predictor.resolve(0x1010, BranchKind::Return, call_site + 4, call_site + 4, predicted);
+        }
+
+        // Only the first return, unknown to the BTB, and the first call to each site are mispredicted.
+        assert_eq!(predictor.statistics().mispredictions, 3);
+    }
+
+    #[test]
+    fn backward_branches_are_predicted_taken() {
+        let predictor = BackwardTakenForwardNotTaken;
+
+        assert!(predictor.predict(0x100, 0xf0));
+        assert!(!predictor.predict(0x100, 0x110));
+    }
+}
@@ -1,5 +1,7 @@
 use num::PrimInt;
 
+use super::exception::Exception;
+
 pub enum BusReadResponse<BusSize: PrimInt> {
     Success(BusSize),
     Deferred,
@@ -19,6 +21,25 @@ pub trait BusInterface<BusSize: PrimInt, ValueSize: PrimInt + Value> {
     fn write(&mut self, address: BusSize, value: ValueSize) -> BusWriteResponse;
 }
 
+/// Devices that model time, e.g. to complete deferred transactions, advance once per hart cycle.
+pub trait Clocked {
+    fn tick(&mut self);
+}
+
+/// Why an access through the memory system did not complete this cycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessError {
+    Exception(Exception),
+    /// The bus deferred the transaction, the access has to be repeated on a later cycle.
+    Deferred,
+}
+
+impl From<Exception> for AccessError {
+    fn from(exception: Exception) -> Self {
+        AccessError::Exception(exception)
+    }
+}
+
 pub trait Value {
     const WIDTH: usize;
     fn from_bytes(bytes: &[u8]) -> Self;
@@ -0,0 +1,680 @@
+use super::bus::{BusInterface, BusReadResponse, BusWriteResponse};
+use super::snapshot::{Snapshot, SnapshotError, SnapshotReader, SnapshotWriter};
+
+/// Which line of a full set is replaced on a miss.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Replacement {
+    #[default]
+    Lru,
+    Fifo,
+    Random,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WritePolicy {
+    /// Stores only update the line, which is written to the bus when it is evicted or flushed.
+    #[default]
+    WriteBack,
+    /// Stores go to the bus as well as to the line.
+    WriteThrough,
+}
+
+/// Sizes are in bytes and must be powers of two, with `size` holding at least one set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheConfiguration {
+    pub size: u32,
+    pub line_size: u32,
+    pub associativity: u32,
+    pub replacement: Replacement,
+    /// Cycles a miss waits before the line is read from the bus, on top of any wait states of the bus itself.
+    pub refill_latency: u32,
+    pub write_policy: WritePolicy,
+    /// Whether a store that misses fetches the line first. Without it the store goes straight to the bus.
+    pub write_allocate: bool,
+}
+
+impl Default for CacheConfiguration {
+    fn default() -> Self {
+        CacheConfiguration {
+            size: 4096,
+            line_size: 32,
+            associativity: 2,
+            replacement: Replacement::Lru,
+            refill_latency: 4,
+            write_policy: WritePolicy::WriteBack,
+            write_allocate: true,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStatistics {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Dirty lines written to the bus, on eviction or flush.
+    pub writebacks: u64,
+}
+
+impl CacheStatistics {
+    pub fn accesses(&self) -> u64 {
+        self.hits + self.misses
+    }
+
+    pub fn hit_rate(&self) -> f64 {
+        match self.accesses() {
+            0 => 0.0,
+            accesses => self.hits as f64 / accesses as f64,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: u32,
+    data: Box<[u8]>,
+    last_used: u64,
+    inserted: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Refill {
+    line_address: u32,
+    way: usize,
+    remaining: u32,
+}
+
+#[derive(Clone, Copy)]
+struct UncacheableRegion {
+    start: u32,
+    size: u32,
+}
+
+/// A set-associative, physically addressed cache.
+///
+/// Misses are answered with `Deferred` while the line is being refilled, so the requester has to repeat the
+/// access until it completes. A repeated access is only counted once. Only one refill is in flight; asking for a
+/// different line abandons it.
+#[derive(Clone)]
+pub struct Cache {
+    configuration: CacheConfiguration,
+    sets: Box<[Box<[Line]>]>,
+    uncacheable_regions: Vec<UncacheableRegion>,
+    statistics: CacheStatistics,
+    refill: Option<Refill>,
+    in_flight: Option<(u32, bool)>,
+    clock: u64,
+    random_state: u32,
+}
+
+impl Cache {
+    /// Panics if the configuration does not describe a realizable cache.
+    pub fn new(configuration: CacheConfiguration) -> Self {
+        let CacheConfiguration { size, line_size, associativity, .. } = configuration;
+        assert!(line_size.is_power_of_two() && line_size >= 4, "cache line size must be a power of two of at least 4");
+        assert!(associativity.is_power_of_two(), "cache associativity must be a power of two");
+        assert!(size.is_power_of_two() && size >= line_size * associativity, "cache size must hold at least one set");
+
+        let line = Line {
+            valid: false,
+            dirty: false,
+            tag: 0,
+            data: vec![0; line_size as usize].into_boxed_slice(),
+            last_used: 0,
+            inserted: 0,
+        };
+        let set = vec![line; associativity as usize].into_boxed_slice();
+        let set_count = size / (line_size * associativity);
+
+        Cache {
+            configuration,
+            sets: vec![set; set_count as usize].into_boxed_slice(),
+            uncacheable_regions: Vec::new(),
+            statistics: CacheStatistics::default(),
+            refill: None,
+            in_flight: None,
+            clock: 0,
+            random_state: 0x2545_f491,
+        }
+    }
+
+    pub fn configuration(&self) -> &CacheConfiguration {
+        &self.configuration
+    }
+
+    pub fn statistics(&self) -> CacheStatistics {
+        self.statistics
+    }
+
+    pub fn reset_statistics(&mut self) {
+        self.statistics = CacheStatistics::default();
+    }
+
+    /// Accesses to `[start, start + size)`, e.g. MMIO devices, bypass the cache.
+    pub fn add_uncacheable_region(&mut self, start: u32, size: u32) {
+        self.uncacheable_regions.push(UncacheableRegion { start, size });
+    }
+
+    pub fn is_cacheable(&self, address: u32) -> bool {
+        !self.uncacheable_regions.iter().any(|region| address.wrapping_sub(region.start) < region.size)
+    }
+
+    /// Drops every line, e.g. for FENCE.I. Dirty data is lost, so a write-back cache has to be flushed first.
+    pub fn invalidate(&mut self) {
+        for line in self.lines_mut() {
+            line.valid = false;
+            line.dirty = false;
+        }
+        self.refill = None;
+    }
+
+    /// Writes every dirty line to the bus. Lines stay valid.
+    pub fn flush<M: BusInterface<u32, u32>>(&mut self, memory: &mut M) -> BusWriteResponse {
+        for set in 0..self.sets.len() {
+            for way in 0..self.sets[set].len() {
+                match self.write_back(set, way, memory) {
+                    BusWriteResponse::Success => {}
+                    response => return response,
+                }
+            }
+        }
+
+        BusWriteResponse::Success
+    }
+
+    /// The cached copy of the byte at `address`, if its line is present. Neither replacement state nor statistics
+    /// change, so hosts and debuggers can look at data a write-back cache holds without disturbing it.
+    pub fn peek(&self, address: u32) -> Option<u8> {
+        let (set, tag) = self.locate(address);
+        let line = self.sets[set].iter().find(|line| line.valid && line.tag == tag)?;
+        Some(line.data[(address & (self.configuration.line_size - 1)) as usize])
+    }
+
+    /// Updates the cached copy of the byte at `address`, if its line is present, like `peek` without side effects.
+    /// The line stays clean or dirty as it was, so the byte has to be written to the bus as well.
+    pub fn poke(&mut self, address: u32, value: u8) {
+        let (set, tag) = self.locate(address);
+        let offset = (address & (self.configuration.line_size - 1)) as usize;
+        if let Some(line) = self.sets[set].iter_mut().find(|line| line.valid && line.tag == tag) {
+            line.data[offset] = value;
+        }
+    }
+
+    /// Reads `size` bytes that do not cross a line boundary, zero extended.
+    pub fn read<M: BusInterface<u32, u32>>(&mut self, address: u32, size: u32, memory: &mut M) -> BusReadResponse<u32> {
+        let response = self.read_line(address, size, memory);
+        self.in_flight = matches!(response, BusReadResponse::Deferred).then_some((address, false));
+        response
+    }
+
+    /// Writes the low `size` bytes of `value`, which must not cross a line boundary.
+    pub fn write<M>(&mut self, address: u32, size: u32, value: u32, memory: &mut M) -> BusWriteResponse
+    where
+        M: BusInterface<u32, u8>,
+        M: BusInterface<u32, u16>,
+        M: BusInterface<u32, u32>,
+    {
+        let response = self.write_line(address, size, value, memory);
+        self.in_flight = matches!(response, BusWriteResponse::Deferred).then_some((address, true));
+        response
+    }
+
+    fn read_line<M: BusInterface<u32, u32>>(
+        &mut self,
+        address: u32,
+        size: u32,
+        memory: &mut M,
+    ) -> BusReadResponse<u32> {
+        let (set, _) = self.locate(address);
+        let way = match self.find(address) {
+            Some(way) => {
+                self.record(address, false, true);
+                way
+            }
+            None => {
+                self.record(address, false, false);
+                match self.fill(address, memory) {
+                    BusReadResponse::Success(way) => way as usize,
+                    BusReadResponse::Deferred => return BusReadResponse::Deferred,
+                    BusReadResponse::InvalidAddress => return BusReadResponse::InvalidAddress,
+                    BusReadResponse::ReadOutOfBounds => return BusReadResponse::ReadOutOfBounds,
+                }
+            }
+        };
+
+        let offset = (address & (self.configuration.line_size - 1)) as usize;
+        let bytes = &self.sets[set][way].data[offset..offset + size as usize];
+        BusReadResponse::Success(bytes.iter().rev().fold(0, |value, byte| (value << 8) | *byte as u32))
+    }
+
+    fn write_line<M>(&mut self, address: u32, size: u32, value: u32, memory: &mut M) -> BusWriteResponse
+    where
+        M: BusInterface<u32, u8>,
+        M: BusInterface<u32, u16>,
+        M: BusInterface<u32, u32>,
+    {
+        let (set, _) = self.locate(address);
+        let way = match self.find(address) {
+            Some(way) => {
+                self.record(address, true, true);
+                Some(way)
+            }
+            None => {
+                self.record(address, true, false);
+                match self.configuration.write_allocate {
+                    true => match self.fill(address, memory) {
+                        BusReadResponse::Success(way) => Some(way as usize),
+                        BusReadResponse::Deferred => return BusWriteResponse::Deferred,
+                        BusReadResponse::InvalidAddress => return BusWriteResponse::InvalidAddress,
+                        BusReadResponse::ReadOutOfBounds => return BusWriteResponse::WriteOutOfBounds,
+                    },
+                    false => None,
+                }
+            }
+        };
+
+        if self.configuration.write_policy == WritePolicy::WriteThrough || way.is_none() {
+            let response = match size {
+                1 => memory.write(address, value as u8),
+                2 => memory.write(address, value as u16),
+                _ => memory.write(address, value),
+            };
+            if !matches!(response, BusWriteResponse::Success) {
+                return response;
+            }
+        }
+
+        if let Some(way) = way {
+            let offset = (address & (self.configuration.line_size - 1)) as usize;
+            let line = &mut self.sets[set][way];
+            line.data[offset..offset + size as usize].copy_from_slice(&value.to_le_bytes()[..size as usize]);
+            line.dirty |= self.configuration.write_policy == WritePolicy::WriteBack;
+        }
+
+        BusWriteResponse::Success
+    }
+
+    /// The set index and tag of an address.
+    fn locate(&self, address: u32) -> (usize, u32) {
+        let line_number = address / self.configuration.line_size;
+        let set_count = self.sets.len() as u32;
+        ((line_number % set_count) as usize, line_number / set_count)
+    }
+
+    fn line_address(&self, set: usize, tag: u32) -> u32 {
+        (tag * self.sets.len() as u32 + set as u32) * self.configuration.line_size
+    }
+
+    fn lines_mut(&mut self) -> impl Iterator<Item = &mut Line> {
+        self.sets.iter_mut().flat_map(|set| set.iter_mut())
+    }
+
+    fn find(&mut self, address: u32) -> Option<usize> {
+        let (set, tag) = self.locate(address);
+        self.clock += 1;
+
+        let way = self.sets[set].iter().position(|line| line.valid && line.tag == tag)?;
+        self.sets[set][way].last_used = self.clock;
+        Some(way)
+    }
+
+    fn record(&mut self, address: u32, write: bool, hit: bool) {
+        if self.in_flight == Some((address, write)) {
+            return;
+        }
+
+        match hit {
+            true => self.statistics.hits += 1,
+            false => self.statistics.misses += 1,
+        }
+    }
+
+    /// Brings the line holding `address` in, writing back the line it replaces if that is dirty.
+    fn fill<M: BusInterface<u32, u32>>(&mut self, address: u32, memory: &mut M) -> BusReadResponse<u32> {
+        let (set, tag) = self.locate(address);
+        let line_address = address & !(self.configuration.line_size - 1);
+
+        let refill = match self.refill {
+            Some(refill) if refill.line_address == line_address => refill,
+            _ => Refill { line_address, way: self.victim(set), remaining: self.configuration.refill_latency },
+        };
+        self.refill = Some(refill);
+
+        match self.write_back(set, refill.way, memory) {
+            BusWriteResponse::Success => {}
+            BusWriteResponse::Deferred => return BusReadResponse::Deferred,
+            _ => {
+                self.refill = None;
+                return BusReadResponse::InvalidAddress;
+            }
+        }
+
+        if refill.remaining > 0 {
+            self.refill = Some(Refill { remaining: refill.remaining - 1, ..refill });
+            return BusReadResponse::Deferred;
+        }
+
+        let mut data = vec![0; self.configuration.line_size as usize];
+        for (word, chunk) in data.chunks_exact_mut(4).enumerate() {
+            match memory.read(line_address + 4 * word as u32) {
+                BusReadResponse::Success(value) => chunk.copy_from_slice(&value.to_le_bytes()),
+                BusReadResponse::Deferred => return BusReadResponse::Deferred,
+                error => {
+                    self.refill = None;
+                    return error;
+                }
+            }
+        }
+        self.refill = None;
+
+        let line = &mut self.sets[set][refill.way];
+        if line.valid {
+            self.statistics.evictions += 1;
+        }
+        *line = Line {
+            valid: true,
+            dirty: false,
+            tag,
+            data: data.into_boxed_slice(),
+            last_used: self.clock,
+            inserted: self.clock,
+        };
+
+        BusReadResponse::Success(refill.way as u32)
+    }
+
+    fn write_back<M: BusInterface<u32, u32>>(&mut self, set: usize, way: usize, memory: &mut M) -> BusWriteResponse {
+        let line = &self.sets[set][way];
+        if !line.valid || !line.dirty {
+            return BusWriteResponse::Success;
+        }
+
+        let line_address = self.line_address(set, line.tag);
+        for (word, chunk) in line.data.chunks_exact(4).enumerate() {
+            let value = u32::from_le_bytes(chunk.try_into().unwrap());
+            match memory.write(line_address + 4 * word as u32, value) {
+                BusWriteResponse::Success => {}
+                response => return response,
+            }
+        }
+
+        self.sets[set][way].dirty = false;
+        self.statistics.writebacks += 1;
+        BusWriteResponse::Success
+    }
+
+    fn victim(&mut self, set: usize) -> usize {
+        let lines = &self.sets[set];
+        if let Some(way) = lines.iter().position(|line| !line.valid) {
+            return way;
+        }
+
+        match self.configuration.replacement {
+            Replacement::Lru => (0..lines.len()).min_by_key(|&way| lines[way].last_used).unwrap_or(0),
+            Replacement::Fifo => (0..lines.len()).min_by_key(|&way| lines[way].inserted).unwrap_or(0),
+            Replacement::Random => {
+                // xorshift32, deterministic so runs can be reproduced.
+                self.random_state ^= self.random_state << 13;
+                self.random_state ^= self.random_state >> 17;
+                self.random_state ^= self.random_state << 5;
+                self.random_state as usize % lines.len()
+            }
+        }
+    }
+}
+
+impl Snapshot for Cache {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        let CacheConfiguration {
+            size,
+            line_size,
+            associativity,
+            replacement,
+            refill_latency,
+            write_policy,
+            write_allocate,
+        } = self.configuration;
+
+        snapshot.section("cache");
+        [
+            size,
+            line_size,
+            associativity,
+            replacement as u32,
+            refill_latency,
+            write_policy as u32,
+            write_allocate as u32,
+        ]
+        .into_iter()
+        .for_each(|field| snapshot.u32(field));
+
+        for line in self.sets.iter().flat_map(|set| set.iter()) {
+            snapshot.bool(line.valid);
+            snapshot.bool(line.dirty);
+            snapshot.u32(line.tag);
+            snapshot.bytes(&line.data);
+            snapshot.u64(line.last_used);
+            snapshot.u64(line.inserted);
+        }
+
+        snapshot.usize(self.uncacheable_regions.len());
+        for region in &self.uncacheable_regions {
+            snapshot.u32(region.start);
+            snapshot.u32(region.size);
+        }
+
+        let CacheStatistics { hits, misses, evictions, writebacks } = self.statistics;
+        [hits, misses, evictions, writebacks].into_iter().for_each(|statistic| snapshot.u64(statistic));
+        snapshot.option(&self.refill, |snapshot, refill| {
+            snapshot.u32(refill.line_address);
+            snapshot.usize(refill.way);
+            snapshot.u32(refill.remaining);
+        });
+        snapshot.option(&self.in_flight, |snapshot, &(address, write)| {
+            snapshot.u32(address);
+            snapshot.bool(write);
+        });
+        snapshot.u64(self.clock);
+        snapshot.u32(self.random_state);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        snapshot.section("cache")?;
+        snapshot.expect("the cache configuration", self.configuration, |snapshot| {
+            Ok(CacheConfiguration {
+                size: snapshot.u32()?,
+                line_size: snapshot.u32()?,
+                associativity: snapshot.u32()?,
+                replacement: match snapshot.u32()? {
+                    0 => Replacement::Lru,
+                    1 => Replacement::Fifo,
+                    _ => Replacement::Random,
+                },
+                refill_latency: snapshot.u32()?,
+                write_policy: match snapshot.u32()? {
+                    0 => WritePolicy::WriteBack,
+                    _ => WritePolicy::WriteThrough,
+                },
+                write_allocate: snapshot.u32()? != 0,
+            })
+        })?;
+
+        for line in self.lines_mut() {
+            line.valid = snapshot.bool()?;
+            line.dirty = snapshot.bool()?;
+            line.tag = snapshot.u32()?;
+            let data = snapshot.bytes()?;
+            if data.len() != line.data.len() {
+                return Err(SnapshotError::Truncated);
+            }
+            line.data.copy_from_slice(data);
+            line.last_used = snapshot.u64()?;
+            line.inserted = snapshot.u64()?;
+        }
+
+        let regions = snapshot.usize()?;
+        self.uncacheable_regions = (0..regions)
+            .map(|_| Ok(UncacheableRegion { start: snapshot.u32()?, size: snapshot.u32()? }))
+            .collect::<Result<_, SnapshotError>>()?;
+
+        self.statistics = CacheStatistics {
+            hits: snapshot.u64()?,
+            misses: snapshot.u64()?,
+            evictions: snapshot.u64()?,
+            writebacks: snapshot.u64()?,
+        };
+        self.refill = snapshot.option(|snapshot| {
+            Ok(Refill { line_address: snapshot.u32()?, way: snapshot.usize()?, remaining: snapshot.u32()? })
+        })?;
+        self.in_flight = snapshot.option(|snapshot| Ok((snapshot.u32()?, snapshot.bool()?)))?;
+        self.clock = snapshot.u64()?;
+        self.random_state = snapshot.u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    fn word_memory() -> Memory {
+        Memory::with_initial_values((0..0x400u32).flat_map(|word| word.to_le_bytes()).collect())
+    }
+
+    fn read(cache: &mut Cache, address: u32, memory: &mut Memory) -> Option<u32> {
+        match cache.read(address, 4, memory) {
+            BusReadResponse::Success(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn two_way(replacement: Replacement) -> Cache {
+        Cache::new(CacheConfiguration {
+            size: 64,
+            line_size: 16,
+            associativity: 2,
+            replacement,
+            refill_latency: 0,
+            ..CacheConfiguration::default()
+        })
+    }
+
+    #[test]
+    fn misses_wait_for_the_refill_latency() {
+        let mut memory = word_memory();
+        let mut cache = Cache::new(CacheConfiguration { refill_latency: 2, ..CacheConfiguration::default() });
+
+        assert_eq!(read(&mut cache, 0x14, &mut memory), None);
+        assert_eq!(read(&mut cache, 0x14, &mut memory), None);
+        assert_eq!(read(&mut cache, 0x14, &mut memory), Some(5));
+        assert_eq!(read(&mut cache, 0x18, &mut memory), Some(6));
+        assert_eq!(cache.statistics(), CacheStatistics { hits: 1, misses: 1, evictions: 0, writebacks: 0 });
+    }
+
+    #[test]
+    fn lru_and_fifo_pick_different_victims() {
+        let mut memory = word_memory();
+        let mut lru = two_way(Replacement::Lru);
+        let mut fifo = two_way(Replacement::Fifo);
+
+        // 0x00, 0x20 and 0x40 share a set. Touching 0x00 again makes 0x20 the least recently used line.
+        for cache in [&mut lru, &mut fifo] {
+            for address in [0x00, 0x20, 0x00, 0x40] {
+                read(cache, address, &mut memory);
+            }
+            cache.reset_statistics();
+            read(cache, 0x00, &mut memory);
+        }
+
+        assert_eq!(lru.statistics().hits, 1);
+        assert_eq!(fifo.statistics().misses, 1);
+    }
+
+    #[test]
+    fn peeks_and_pokes_leave_the_cache_as_it_is() {
+        let mut memory = word_memory();
+        let mut cache = two_way(Replacement::Lru);
+        cache.write(0x10, 1, 0xaa, &mut memory);
+
+        assert_eq!(cache.peek(0x10), Some(0xaa));
+        assert_eq!(cache.peek(0x40), None);
+        cache.poke(0x11, 0xbb);
+        cache.poke(0x40, 0xcc);
+        assert_eq!(read(&mut cache, 0x10, &mut memory), Some(0xbbaa));
+        assert_eq!(cache.peek(0x40), None);
+        assert_eq!(cache.statistics(), CacheStatistics { hits: 1, misses: 1, evictions: 0, writebacks: 0 });
+    }
+
+    #[test]
+    fn invalidation_forces_a_refill() {
+        let mut memory = word_memory();
+        let mut cache = two_way(Replacement::Lru);
+
+        read(&mut cache, 0x30, &mut memory);
+        BusInterface::<u32, u32>::write(&mut memory, 0x30, 0xabcd);
+        assert_eq!(read(&mut cache, 0x30, &mut memory), Some(0xc));
+
+        cache.invalidate();
+        assert_eq!(read(&mut cache, 0x30, &mut memory), Some(0xabcd));
+    }
+
+    fn memory_word(memory: &Memory, address: u32) -> Option<u32> {
+        match BusInterface::<u32, u32>::read(memory, address) {
+            BusReadResponse::Success(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn write_back_lines_reach_memory_on_eviction() {
+        let mut memory = word_memory();
+        let mut cache = two_way(Replacement::Lru);
+
+        cache.write(0x04, 4, 0xdead, &mut memory);
+        assert_eq!(memory_word(&memory, 0x04), Some(1));
+
+        read(&mut cache, 0x20, &mut memory);
+        read(&mut cache, 0x40, &mut memory);
+        assert_eq!(memory_word(&memory, 0x04), Some(0xdead));
+        assert_eq!(cache.statistics(), CacheStatistics { hits: 0, misses: 3, evictions: 1, writebacks: 1 });
+    }
+
+    #[test]
+    fn write_through_without_allocation_bypasses_on_a_miss() {
+        let mut memory = word_memory();
+        let mut cache = Cache::new(CacheConfiguration {
+            refill_latency: 0,
+            write_policy: WritePolicy::WriteThrough,
+            write_allocate: false,
+            ..CacheConfiguration::default()
+        });
+
+        cache.write(0x08, 2, 0xbeef, &mut memory);
+        assert_eq!(memory_word(&memory, 0x08), Some(0xbeef));
+        assert_eq!(read(&mut cache, 0x08, &mut memory), Some(0xbeef));
+
+        cache.write(0x08, 1, 0x11, &mut memory);
+        assert_eq!(memory_word(&memory, 0x08), Some(0xbe11));
+        assert_eq!(read(&mut cache, 0x08, &mut memory), Some(0xbe11));
+        assert!(matches!(cache.flush(&mut memory), BusWriteResponse::Success));
+        assert_eq!(cache.statistics().writebacks, 0);
+    }
+
+    #[test]
+    fn flushing_writes_dirty_lines_once() {
+        let mut memory = word_memory();
+        let mut cache = two_way(Replacement::Lru);
+        cache.add_uncacheable_region(0x100, 0x10);
+
+        cache.write(0x10, 4, 7, &mut memory);
+        cache.flush(&mut memory);
+        cache.flush(&mut memory);
+
+        assert_eq!(memory_word(&memory, 0x10), Some(7));
+        assert_eq!(cache.statistics().writebacks, 1);
+        assert!(!cache.is_cacheable(0x104));
+    }
+}
@@ -0,0 +1,201 @@
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use super::csr::{csr_name, PrivilegeLevel};
+use super::instruction::{IType, Instruction, MemoryLoadInstruction, MemoryStoreInstruction, SType};
+use super::unit::RegisterWrite;
+use crate::symbols::Symbols;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryAccess {
+    Load {
+        address: u32,
+        size: u32,
+    },
+    /// `value` holds the `size` bytes written in its low bits.
+    Store {
+        address: u32,
+        value: u32,
+        size: u32,
+    },
+}
+
+impl MemoryAccess {
+    /// The access a load or store makes, from its decoded operands.
+    pub fn of(instruction: Instruction) -> Option<MemoryAccess> {
+        use MemoryLoadInstruction::*;
+        use MemoryStoreInstruction::*;
+
+        match instruction {
+            Instruction::MemoryLoad(load) => {
+                let (LB(instr) | LBU(instr) | LH(instr) | LHU(instr) | LW(instr)) = load;
+                let IType { register_source_one, immediate, .. } = instr;
+                let size = match load {
+                    LB(_) | LBU(_) => 1,
+                    LH(_) | LHU(_) => 2,
+                    LW(_) => 4,
+                };
+                Some(MemoryAccess::Load { address: register_source_one.value.wrapping_add(immediate), size })
+            }
+            Instruction::MemoryStore(store) => {
+                let (SB(instr) | SH(instr) | SW(instr)) = store;
+                let SType { register_source_one, register_source_two, immediate, .. } = instr;
+                let size = match store {
+                    SB(_) => 1,
+                    SH(_) => 2,
+                    SW(_) => 4,
+                };
+                let value = match size {
+                    4 => register_source_two.value,
+                    _ => register_source_two.value & ((1 << (8 * size)) - 1),
+                };
+                Some(MemoryAccess::Store { address: register_source_one.value.wrapping_add(immediate), value, size })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The bytes a store overwrote, with their physical addresses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OverwrittenMemory {
+    bytes: [(u32, u8); 4],
+    length: usize,
+}
+
+impl OverwrittenMemory {
+    pub fn push(&mut self, address: u32, byte: u8) {
+        self.bytes[self.length] = (address, byte);
+        self.length += 1;
+    }
+
+    pub fn bytes(&self) -> &[(u32, u8)] {
+        &self.bytes[..self.length]
+    }
+}
+
+/// What a retired instruction overwrote, enough to take it back.
+#[derive(Clone, Copy, Default)]
+pub struct Undo {
+    /// The register written, with the value it held before.
+    pub register: Option<RegisterWrite>,
+    pub memory: OverwrittenMemory,
+}
+
+/// What an instruction did when it retired, reported by the pipeline at write back.
+#[derive(Clone, Copy)]
+pub struct Retirement {
+    /// The privilege level the instruction executed in, before any change it made.
+    pub privilege_level: PrivilegeLevel,
+    pub pc: u32,
+    pub instruction: u32,
+    pub register_write: Option<RegisterWrite>,
+    pub csr_write: Option<(u32, u32)>,
+    pub memory_access: Option<MemoryAccess>,
+    /// Only recorded while `HartState::record_undo` is set, and only for instructions that do no more than write a
+    /// register and store to memory.
+    pub undo: Option<Undo>,
+}
+
+/// Writes retired instructions in the format of spike's `--log-commits`, one line per instruction.
+pub struct CommitLog {
+    output: Box<dyn Write>,
+    symbols: Option<Rc<Symbols>>,
+    error: Option<io::Error>,
+}
+
+impl CommitLog {
+    pub fn new(output: impl Write + 'static) -> Self {
+        CommitLog { output: Box::new(output), symbols: None, error: None }
+    }
+
+    /// Ends each line with the function and source line of the pc, which spike does not.
+    pub fn set_symbols(&mut self, symbols: Rc<Symbols>) {
+        self.symbols = Some(symbols);
+    }
+
+    pub fn log(&mut self, retirement: &Retirement) {
+        if self.error.is_some() {
+            return;
+        }
+        let mut line = format_commit(retirement);
+        if let Some(annotation) = self.symbols.as_ref().and_then(|symbols| symbols.annotate(retirement.pc)) {
+            line.insert_str(line.len() - 1, &format!(" <{annotation}>"));
+        }
+        self.error = self.output.write_all(line.as_bytes()).err();
+    }
+
+    /// Returns the first write error, logging stopped there.
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.output.flush(),
+        }
+    }
+}
+
+pub fn format_commit(retirement: &Retirement) -> String {
+    let Retirement { privilege_level, pc, instruction, register_write, csr_write, memory_access, .. } = *retirement;
+    let mut line = format!("core   0: {} 0x{pc:08x} (0x{instruction:08x})", privilege_level.bits());
+
+    // Writes to x0 are dropped, as spike does.
+    if let Some(RegisterWrite { index, value }) = register_write.filter(|write| write.index != 0) {
+        line += &format!(" x{index:<2} 0x{value:08x}");
+    }
+
+    if let Some((address, value)) = csr_write {
+        let name = csr_name(address).unwrap_or_else(|| "unknown".to_string());
+        line += &format!(" c{address}_{name} 0x{value:08x}");
+    }
+
+    match memory_access {
+        Some(MemoryAccess::Load { address, .. }) => line += &format!(" mem 0x{address:08x}"),
+        Some(MemoryAccess::Store { address, value, size }) => {
+            line += &format!(" mem 0x{address:08x} 0x{value:0width$x}", width = 2 * size as usize)
+        }
+        None => {}
+    }
+
+    line.push('\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::csr::address_constants::MSCRATCH;
+
+    fn retirement(pc: u32, instruction: u32) -> Retirement {
+        Retirement {
+            privilege_level: PrivilegeLevel::Machine,
+            pc,
+            instruction,
+            register_write: None,
+            csr_write: None,
+            memory_access: None,
+            undo: None,
+        }
+    }
+
+    #[test]
+    fn lines_match_spike() {
+        let addi = Retirement {
+            register_write: Some(RegisterWrite { index: 5, value: 0x8000_0000 }),
+            ..retirement(0x8000_0000, 0x0000_0297)
+        };
+        let store_byte = Retirement {
+            memory_access: Some(MemoryAccess::Store { address: 0x8000_1000, value: 0x5, size: 1 }),
+            ..retirement(0x8000_0004, 0x00b5_0023)
+        };
+        let csr_write = Retirement {
+            privilege_level: PrivilegeLevel::Supervisor,
+            register_write: Some(RegisterWrite { index: 0, value: 0 }),
+            csr_write: Some((MSCRATCH, 0x10)),
+            ..retirement(0x8000_0008, 0x3405_1073)
+        };
+
+        assert_eq!(format_commit(&addi), "core   0: 3 0x80000000 (0x00000297) x5  0x80000000\n");
+        assert_eq!(format_commit(&store_byte), "core   0: 3 0x80000004 (0x00b50023) mem 0x80001000 0x05\n");
+        assert_eq!(format_commit(&csr_write), "core   0: 1 0x80000008 (0x34051073) c832_mscratch 0x00000010\n");
+    }
+}
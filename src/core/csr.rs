@@ -0,0 +1,556 @@
+pub mod address_constants;
+
+use address_constants::*;
+
+use super::pmp::{PMP_ADDRESS_MATCHING, PMP_ENTRIES, PMP_LOCK, PMP_R, PMP_TOR, PMP_W};
+use super::snapshot::{Snapshot, SnapshotError, SnapshotReader, SnapshotWriter};
+
+pub const MSTATUS_SIE: u32 = 1 << 1;
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_SPIE: u32 = 1 << 5;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_SPP: u32 = 1 << 8;
+pub const MSTATUS_MPP: u32 = 0b11 << 11;
+pub const MSTATUS_MPRV: u32 = 1 << 17;
+pub const MSTATUS_SUM: u32 = 1 << 18;
+pub const MSTATUS_MXR: u32 = 1 << 19;
+pub const MSTATUS_TVM: u32 = 1 << 20;
+pub const MSTATUS_TW: u32 = 1 << 21;
+pub const MSTATUS_TSR: u32 = 1 << 22;
+
+const MSTATUS_WRITABLE: u32 = MSTATUS_SIE
+    | MSTATUS_MIE
+    | MSTATUS_SPIE
+    | MSTATUS_MPIE
+    | MSTATUS_SPP
+    | MSTATUS_MPP
+    | MSTATUS_MPRV
+    | MSTATUS_SUM
+    | MSTATUS_MXR
+    | MSTATUS_TVM
+    | MSTATUS_TW
+    | MSTATUS_TSR;
+const SSTATUS_VISIBLE: u32 = MSTATUS_SIE | MSTATUS_SPIE | MSTATUS_SPP | MSTATUS_SUM | MSTATUS_MXR;
+const SUPERVISOR_INTERRUPTS: u32 = (1 << 1) | (1 << 5) | (1 << 9);
+
+// RV32 with the I base plus supervisor and user modes.
+const MISA_VALUE: u32 = (1 << 30) | (1 << 8) | (1 << 18) | (1 << 20);
+
+const COUNTERS: usize = 32;
+const CYCLE_COUNTER: usize = 0;
+const TIME_COUNTER: usize = 1;
+const INSTRET_COUNTER: usize = 2;
+const FIRST_HPM_COUNTER: usize = 3;
+
+/// Events the mhpmevent registers select. Zero, and any value not listed, counts nothing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HpmEvent {
+    /// Cycles in which the pipeline held its instructions in place.
+    Stall = 1,
+    /// Redirects that discarded fetched instructions, from mispredictions, traps and serializing instructions.
+    Flush = 2,
+    BranchMispredict = 3,
+    Load = 4,
+    Store = 5,
+    InstructionCacheMiss = 6,
+    DataCacheMiss = 7,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum PrivilegeLevel {
+    User = 0,
+    Supervisor = 1,
+    Machine = 3,
+}
+
+impl PrivilegeLevel {
+    pub fn from_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            0 => PrivilegeLevel::User,
+            1 => PrivilegeLevel::Supervisor,
+            _ => PrivilegeLevel::Machine,
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        self as u32
+    }
+}
+
+#[derive(Clone)]
+pub struct ControlStatusRegisters {
+    privilege_level: PrivilegeLevel,
+    mstatus: u32,
+    medeleg: u32,
+    mideleg: u32,
+    mie: u32,
+    mip: u32,
+    mtvec: u32,
+    mcounteren: u32,
+    mscratch: u32,
+    mepc: u32,
+    mcause: u32,
+    mtval: u32,
+    stvec: u32,
+    scounteren: u32,
+    sscratch: u32,
+    sepc: u32,
+    scause: u32,
+    stval: u32,
+    satp: u32,
+    pmpcfg: [u8; PMP_ENTRIES],
+    pmpaddr: [u32; PMP_ENTRIES],
+    /// Indexed like the counter CSRs, so 0 is mcycle and 2 minstret. Index 1 is unused, time reads mcycle.
+    counters: [u64; COUNTERS],
+    mhpmevent: [u32; COUNTERS],
+    mcountinhibit: u32,
+    /// Counters written by an instruction this cycle, which then do not also count.
+    counters_written: u32,
+}
+
+impl Default for ControlStatusRegisters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ControlStatusRegisters {
+    pub fn new() -> Self {
+        ControlStatusRegisters {
+            privilege_level: PrivilegeLevel::Machine,
+            mstatus: 0,
+            medeleg: 0,
+            mideleg: 0,
+            mie: 0,
+            mip: 0,
+            mtvec: 0,
+            mcounteren: 0,
+            mscratch: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+            stvec: 0,
+            scounteren: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+            pmpcfg: [0; PMP_ENTRIES],
+            pmpaddr: [0; PMP_ENTRIES],
+            counters: [0; COUNTERS],
+            mhpmevent: [0; COUNTERS],
+            mcountinhibit: 0,
+            counters_written: 0,
+        }
+    }
+
+    pub fn privilege_level(&self) -> PrivilegeLevel {
+        self.privilege_level
+    }
+
+    pub fn set_privilege_level(&mut self, privilege_level: PrivilegeLevel) {
+        self.privilege_level = privilege_level;
+    }
+
+    pub fn mstatus(&self) -> u32 {
+        self.mstatus
+    }
+
+    pub fn satp(&self) -> u32 {
+        self.satp
+    }
+
+    pub fn pmp_configuration(&self, index: usize) -> u8 {
+        self.pmpcfg[index]
+    }
+
+    pub fn pmp_address(&self, index: usize) -> u32 {
+        self.pmpaddr[index]
+    }
+
+    /// Ends a cycle: advances mcycle and forgets which counters were written.
+    pub fn count_cycle(&mut self) {
+        self.count(CYCLE_COUNTER);
+        self.counters_written = 0;
+    }
+
+    pub fn count_retired(&mut self) {
+        self.count(INSTRET_COUNTER);
+    }
+
+    pub fn count_event(&mut self, event: HpmEvent) {
+        for counter in FIRST_HPM_COUNTER..COUNTERS {
+            if self.mhpmevent[counter] == event as u32 {
+                self.count(counter);
+            }
+        }
+    }
+
+    fn count(&mut self, counter: usize) {
+        if (self.mcountinhibit | self.counters_written) & (1 << counter) == 0 {
+            self.counters[counter] = self.counters[counter].wrapping_add(1);
+        }
+    }
+
+    /// The user counter aliases need their bit in mcounteren below M mode, and in scounteren as well in U mode.
+    pub fn counter_enabled(&self, address: u32) -> bool {
+        if !(CYCLE..=HPMCOUNTER31H).contains(&address) {
+            return true;
+        }
+
+        let bit = 1 << (address & 0x1f);
+        match self.privilege_level {
+            PrivilegeLevel::Machine => true,
+            PrivilegeLevel::Supervisor => self.mcounteren & bit != 0,
+            PrivilegeLevel::User => self.mcounteren & self.scounteren & bit != 0,
+        }
+    }
+
+    /// Returns `None` for addresses that are not implemented, which the caller turns into an illegal instruction.
+    pub fn read(&self, address: u32) -> Option<u32> {
+        let value = match address {
+            SSTATUS => self.mstatus & SSTATUS_VISIBLE,
+            SIE => self.mie & self.mideleg & SUPERVISOR_INTERRUPTS,
+            STVEC => self.stvec,
+            SCOUNTEREN => self.scounteren,
+            SSCRATCH => self.sscratch,
+            SEPC => self.sepc,
+            SCAUSE => self.scause,
+            STVAL => self.stval,
+            SIP => self.mip & self.mideleg & SUPERVISOR_INTERRUPTS,
+            SATP => self.satp,
+            MSTATUS => self.mstatus,
+            MISA => MISA_VALUE,
+            MEDELEG => self.medeleg,
+            MIDELEG => self.mideleg,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MCOUNTEREN => self.mcounteren,
+            MSTATUSH => 0,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.mip,
+            PMPCFG0..=PMPCFG3 => {
+                let first = (address - PMPCFG0) as usize * 4;
+                u32::from_le_bytes(self.pmpcfg[first..first + 4].try_into().unwrap())
+            }
+            PMPADDR0..=PMPADDR15 => self.pmpaddr[(address - PMPADDR0) as usize],
+            MCOUNTINHIBIT => self.mcountinhibit,
+            MHPMEVENT3..=MHPMEVENT31 => self.mhpmevent[(address - MCOUNTINHIBIT) as usize],
+            MCYCLE | MINSTRET | MHPMCOUNTER3..=MHPMCOUNTER31 => self.counters[(address - MCYCLE) as usize] as u32,
+            MCYCLEH | MINSTRETH | MHPMCOUNTER3H..=MHPMCOUNTER31H => {
+                (self.counters[(address - MCYCLEH) as usize] >> 32) as u32
+            }
+            CYCLE..=HPMCOUNTER31 => self.counters[user_counter(address - CYCLE)] as u32,
+            CYCLEH..=HPMCOUNTER31H => (self.counters[user_counter(address - CYCLEH)] >> 32) as u32,
+            MVENDORID | MARCHID | MIMPID | MHARTID => 0,
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    /// Writes go through the WARL masks of each register. Privilege and read-only checks are the caller's job.
+    pub fn write(&mut self, address: u32, value: u32) {
+        match address {
+            SSTATUS => self.mstatus = (self.mstatus & !SSTATUS_VISIBLE) | (value & SSTATUS_VISIBLE),
+            SIE => {
+                let mask = self.mideleg & SUPERVISOR_INTERRUPTS;
+                self.mie = (self.mie & !mask) | (value & mask);
+            }
+            STVEC => self.stvec = value & !0b10,
+            SCOUNTEREN => self.scounteren = value,
+            SSCRATCH => self.sscratch = value,
+            SEPC => self.sepc = value & !0b11,
+            SCAUSE => self.scause = value,
+            STVAL => self.stval = value,
+            SIP => {
+                let mask = self.mideleg & (1 << 1);
+                self.mip = (self.mip & !mask) | (value & mask);
+            }
+            SATP => self.satp = value,
+            MSTATUS => {
+                let mut value = value & MSTATUS_WRITABLE;
+                // MPP only holds supported modes, the reserved encoding falls back to user mode.
+                if (value & MSTATUS_MPP) >> 11 == 0b10 {
+                    value &= !MSTATUS_MPP;
+                }
+                self.mstatus = value;
+            }
+            MEDELEG => self.medeleg = value & !(1 << 11),
+            MIDELEG => self.mideleg = value & SUPERVISOR_INTERRUPTS,
+            MIE => self.mie = value & 0xaaa,
+            MTVEC => self.mtvec = value & !0b10,
+            MCOUNTEREN => self.mcounteren = value,
+            MSCRATCH => self.mscratch = value,
+            MEPC => self.mepc = value & !0b11,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            MIP => self.mip = (self.mip & !0x222) | (value & 0x222),
+            PMPCFG0..=PMPCFG3 => {
+                let first = (address - PMPCFG0) as usize * 4;
+                for (index, configuration) in value.to_le_bytes().into_iter().enumerate() {
+                    self.write_pmp_configuration(first + index, configuration);
+                }
+            }
+            PMPADDR0..=PMPADDR15 => {
+                let index = (address - PMPADDR0) as usize;
+                if !self.pmp_address_locked(index) {
+                    self.pmpaddr[index] = value;
+                }
+            }
+            MCOUNTINHIBIT => self.mcountinhibit = value & !(1 << TIME_COUNTER),
+            MHPMEVENT3..=MHPMEVENT31 => self.mhpmevent[(address - MCOUNTINHIBIT) as usize] = value,
+            MCYCLE | MINSTRET | MHPMCOUNTER3..=MHPMCOUNTER31 => {
+                let counter = (address - MCYCLE) as usize;
+                self.counters[counter] = (self.counters[counter] & !0xffff_ffff) | value as u64;
+                self.counters_written |= 1 << counter;
+            }
+            MCYCLEH | MINSTRETH | MHPMCOUNTER3H..=MHPMCOUNTER31H => {
+                let counter = (address - MCYCLEH) as usize;
+                self.counters[counter] = (self.counters[counter] & 0xffff_ffff) | (value as u64) << 32;
+                self.counters_written |= 1 << counter;
+            }
+            _ => {}
+        }
+    }
+
+    fn write_pmp_configuration(&mut self, index: usize, configuration: u8) {
+        if self.pmpcfg[index] & PMP_LOCK != 0 {
+            return;
+        }
+
+        // Write-only permissions are reserved, such entries keep neither read nor write access.
+        let configuration = match configuration & (PMP_R | PMP_W) {
+            PMP_W => configuration & !PMP_W,
+            _ => configuration,
+        };
+
+        self.pmpcfg[index] = configuration & !0b0110_0000;
+    }
+
+    /// A locked entry also locks the address below it when that address is the bottom of its TOR range.
+    fn pmp_address_locked(&self, index: usize) -> bool {
+        let locked = self.pmpcfg[index] & PMP_LOCK != 0;
+        let next_is_locked_tor = self
+            .pmpcfg
+            .get(index + 1)
+            .is_some_and(|next| next & PMP_LOCK != 0 && next & PMP_ADDRESS_MATCHING == PMP_TOR);
+
+        locked || next_is_locked_tor
+    }
+}
+
+impl Snapshot for ControlStatusRegisters {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("csrs");
+        snapshot.u8(self.privilege_level.bits() as u8);
+        for register in self.registers() {
+            snapshot.u32(*register);
+        }
+        snapshot.bytes(&self.pmpcfg);
+        self.pmpaddr.iter().for_each(|&address| snapshot.u32(address));
+        self.counters.iter().for_each(|&counter| snapshot.u64(counter));
+        self.mhpmevent.iter().for_each(|&event| snapshot.u32(event));
+        snapshot.u32(self.mcountinhibit);
+        snapshot.u32(self.counters_written);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        snapshot.section("csrs")?;
+        self.privilege_level = PrivilegeLevel::from_bits(snapshot.u8()? as u32);
+        for register in self.registers_mut() {
+            *register = snapshot.u32()?;
+        }
+        let pmpcfg = snapshot.bytes()?;
+        if pmpcfg.len() != PMP_ENTRIES {
+            return Err(SnapshotError::Mismatch(format!(
+                "{} PMP entries, the snapshot has {}",
+                PMP_ENTRIES,
+                pmpcfg.len()
+            )));
+        }
+        self.pmpcfg.copy_from_slice(pmpcfg);
+        for address in self.pmpaddr.iter_mut() {
+            *address = snapshot.u32()?;
+        }
+        for counter in self.counters.iter_mut() {
+            *counter = snapshot.u64()?;
+        }
+        for event in self.mhpmevent.iter_mut() {
+            *event = snapshot.u32()?;
+        }
+        self.mcountinhibit = snapshot.u32()?;
+        self.counters_written = snapshot.u32()?;
+        Ok(())
+    }
+}
+
+impl ControlStatusRegisters {
+    /// The plain 32 bit registers, in the order snapshots hold them.
+    fn registers(&self) -> [&u32; 18] {
+        [
+            &self.mstatus,
+            &self.medeleg,
+            &self.mideleg,
+            &self.mie,
+            &self.mip,
+            &self.mtvec,
+            &self.mcounteren,
+            &self.mscratch,
+            &self.mepc,
+            &self.mcause,
+            &self.mtval,
+            &self.stvec,
+            &self.scounteren,
+            &self.sscratch,
+            &self.sepc,
+            &self.scause,
+            &self.stval,
+            &self.satp,
+        ]
+    }
+
+    fn registers_mut(&mut self) -> [&mut u32; 18] {
+        [
+            &mut self.mstatus,
+            &mut self.medeleg,
+            &mut self.mideleg,
+            &mut self.mie,
+            &mut self.mip,
+            &mut self.mtvec,
+            &mut self.mcounteren,
+            &mut self.mscratch,
+            &mut self.mepc,
+            &mut self.mcause,
+            &mut self.mtval,
+            &mut self.stvec,
+            &mut self.scounteren,
+            &mut self.sscratch,
+            &mut self.sepc,
+            &mut self.scause,
+            &mut self.stval,
+            &mut self.satp,
+        ]
+    }
+}
+
+/// The counter behind a user alias, time has no timer device of its own and follows mcycle.
+fn user_counter(offset: u32) -> usize {
+    match offset as usize {
+        TIME_COUNTER => CYCLE_COUNTER,
+        counter => counter,
+    }
+}
+
+/// The two bits above the register number encode the lowest privilege level allowed to access it.
+pub fn required_privilege_level(address: u32) -> PrivilegeLevel {
+    PrivilegeLevel::from_bits((address >> 8) & 0b11)
+}
+
+pub fn is_read_only(address: u32) -> bool {
+    (address >> 10) & 0b11 == 0b11
+}
+
+/// The name assemblers and spike use for a CSR, if it is one this hart knows about.
+pub fn csr_name(address: u32) -> Option<String> {
+    let name = match address {
+        SSTATUS => "sstatus",
+        SIE => "sie",
+        STVEC => "stvec",
+        SCOUNTEREN => "scounteren",
+        SSCRATCH => "sscratch",
+        SEPC => "sepc",
+        SCAUSE => "scause",
+        STVAL => "stval",
+        SIP => "sip",
+        SATP => "satp",
+        MSTATUS => "mstatus",
+        MISA => "misa",
+        MEDELEG => "medeleg",
+        MIDELEG => "mideleg",
+        MIE => "mie",
+        MTVEC => "mtvec",
+        MCOUNTEREN => "mcounteren",
+        MSTATUSH => "mstatush",
+        MCOUNTINHIBIT => "mcountinhibit",
+        MSCRATCH => "mscratch",
+        MEPC => "mepc",
+        MCAUSE => "mcause",
+        MTVAL => "mtval",
+        MIP => "mip",
+        MCYCLE => "mcycle",
+        MINSTRET => "minstret",
+        MCYCLEH => "mcycleh",
+        MINSTRETH => "minstreth",
+        CYCLE => "cycle",
+        TIME => "time",
+        INSTRET => "instret",
+        CYCLEH => "cycleh",
+        TIMEH => "timeh",
+        INSTRETH => "instreth",
+        MVENDORID => "mvendorid",
+        MARCHID => "marchid",
+        MIMPID => "mimpid",
+        MHARTID => "mhartid",
+        PMPCFG0..=PMPCFG3 => return Some(format!("pmpcfg{}", address - PMPCFG0)),
+        PMPADDR0..=PMPADDR15 => return Some(format!("pmpaddr{}", address - PMPADDR0)),
+        MHPMEVENT3..=MHPMEVENT31 => return Some(format!("mhpmevent{}", address - MCOUNTINHIBIT)),
+        MHPMCOUNTER3..=MHPMCOUNTER31 => return Some(format!("mhpmcounter{}", address - MCYCLE)),
+        MHPMCOUNTER3H..=MHPMCOUNTER31H => return Some(format!("mhpmcounter{}h", address - MCYCLEH)),
+        HPMCOUNTER3..=HPMCOUNTER31 => return Some(format!("hpmcounter{}", address - CYCLE)),
+        HPMCOUNTER3H..=HPMCOUNTER31H => return Some(format!("hpmcounter{}h", address - CYCLEH)),
+        _ => return None,
+    };
+    Some(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn written_counters_skip_that_cycles_increment() {
+        let mut csrs = ControlStatusRegisters::new();
+
+        csrs.write(MCYCLE, 0xffff_ffff);
+        csrs.count_cycle();
+        csrs.count_cycle();
+
+        assert_eq!(csrs.read(MCYCLE), Some(0));
+        assert_eq!(csrs.read(MCYCLEH), Some(1));
+        assert_eq!(csrs.read(CYCLEH), Some(1));
+        assert_eq!(csrs.read(TIME), Some(0));
+    }
+
+    #[test]
+    fn hpm_counters_count_their_selected_event_unless_inhibited() {
+        let mut csrs = ControlStatusRegisters::new();
+        csrs.write(MHPMEVENT3, HpmEvent::Load as u32);
+        csrs.write(MHPMEVENT3 + 1, HpmEvent::Store as u32);
+
+        csrs.count_event(HpmEvent::Load);
+        csrs.write(MCOUNTINHIBIT, 1 << 3);
+        csrs.count_event(HpmEvent::Load);
+        csrs.count_event(HpmEvent::Store);
+
+        assert_eq!(csrs.read(MHPMCOUNTER3), Some(1));
+        assert_eq!(csrs.read(HPMCOUNTER3 + 1), Some(1));
+    }
+
+    #[test]
+    fn user_counters_need_both_enables() {
+        let mut csrs = ControlStatusRegisters::new();
+        csrs.write(MCOUNTEREN, 0b101);
+        csrs.set_privilege_level(PrivilegeLevel::User);
+
+        assert!(!csrs.counter_enabled(CYCLE));
+        csrs.write(SCOUNTEREN, 0b1);
+        assert!(csrs.counter_enabled(CYCLE));
+        assert!(csrs.counter_enabled(MSCRATCH));
+        assert!(!csrs.counter_enabled(INSTRETH));
+    }
+}
@@ -0,0 +1,52 @@
+pub const SSTATUS: u32 = 0x100;
+pub const SIE: u32 = 0x104;
+pub const STVEC: u32 = 0x105;
+pub const SCOUNTEREN: u32 = 0x106;
+pub const SSCRATCH: u32 = 0x140;
+pub const SEPC: u32 = 0x141;
+pub const SCAUSE: u32 = 0x142;
+pub const STVAL: u32 = 0x143;
+pub const SIP: u32 = 0x144;
+pub const SATP: u32 = 0x180;
+pub const MSTATUS: u32 = 0x300;
+pub const MISA: u32 = 0x301;
+pub const MEDELEG: u32 = 0x302;
+pub const MIDELEG: u32 = 0x303;
+pub const MIE: u32 = 0x304;
+pub const MTVEC: u32 = 0x305;
+pub const MCOUNTEREN: u32 = 0x306;
+pub const MSTATUSH: u32 = 0x310;
+pub const MCOUNTINHIBIT: u32 = 0x320;
+pub const MHPMEVENT3: u32 = 0x323;
+pub const MHPMEVENT31: u32 = 0x33f;
+pub const MSCRATCH: u32 = 0x340;
+pub const MEPC: u32 = 0x341;
+pub const MCAUSE: u32 = 0x342;
+pub const MTVAL: u32 = 0x343;
+pub const MIP: u32 = 0x344;
+pub const PMPCFG0: u32 = 0x3a0;
+pub const PMPCFG3: u32 = 0x3a3;
+pub const PMPADDR0: u32 = 0x3b0;
+pub const PMPADDR15: u32 = 0x3bf;
+pub const MCYCLE: u32 = 0xb00;
+pub const MINSTRET: u32 = 0xb02;
+pub const MHPMCOUNTER3: u32 = 0xb03;
+pub const MHPMCOUNTER31: u32 = 0xb1f;
+pub const MCYCLEH: u32 = 0xb80;
+pub const MINSTRETH: u32 = 0xb82;
+pub const MHPMCOUNTER3H: u32 = 0xb83;
+pub const MHPMCOUNTER31H: u32 = 0xb9f;
+pub const CYCLE: u32 = 0xc00;
+pub const TIME: u32 = 0xc01;
+pub const INSTRET: u32 = 0xc02;
+pub const HPMCOUNTER3: u32 = 0xc03;
+pub const HPMCOUNTER31: u32 = 0xc1f;
+pub const CYCLEH: u32 = 0xc80;
+pub const TIMEH: u32 = 0xc81;
+pub const INSTRETH: u32 = 0xc82;
+pub const HPMCOUNTER3H: u32 = 0xc83;
+pub const HPMCOUNTER31H: u32 = 0xc9f;
+pub const MVENDORID: u32 = 0xf11;
+pub const MARCHID: u32 = 0xf12;
+pub const MIMPID: u32 = 0xf13;
+pub const MHARTID: u32 = 0xf14;
@@ -0,0 +1,616 @@
+use std::fmt;
+
+use super::instruction::full_opcode_constants as opcodes;
+use super::instruction::*;
+
+use AluInstruction::*;
+use BranchingInstruction::*;
+use FenceInstruction::*;
+use MemoryLoadInstruction::*;
+use MemoryStoreInstruction::*;
+use SystemInstruction::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    InvalidRegister {
+        index: u32,
+    },
+    ImmediateOutOfRange {
+        immediate: i32,
+        bits: u32,
+    },
+    /// Branch and jump offsets are multiples of two, upper immediates of 4096.
+    MisalignedImmediate {
+        immediate: u32,
+        alignment: u32,
+    },
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EncodeError::InvalidRegister { index } => write!(f, "x{index} is not a register"),
+            EncodeError::ImmediateOutOfRange { immediate, bits } => {
+                write!(f, "{immediate} does not fit in a {bits} bit immediate")
+            }
+            EncodeError::MisalignedImmediate { immediate, alignment } => {
+                write!(f, "0x{immediate:x} is not a multiple of {alignment}")
+            }
+        }
+    }
+}
+
+/// Packs an instruction back into its 32 bit encoding, the inverse of `decode_instruction`. Register values are
+/// ignored, only the indices are encoded.
+pub fn encode(instruction: Instruction) -> Result<u32, EncodeError> {
+    match instruction {
+        Instruction::Alu(instr) => match instr {
+            LUI(instr) => u_type(opcodes::LUI, instr),
+            AUIPC(instr) => u_type(opcodes::AUIPC, instr),
+            ADDI(instr) => i_type(opcodes::ADDI, instr),
+            SLTI(instr) => i_type(opcodes::SLTI, instr),
+            SLTIU(instr) => i_type(opcodes::SLTIU, instr),
+            XORI(instr) => i_type(opcodes::XORI, instr),
+            ORI(instr) => i_type(opcodes::ORI, instr),
+            ANDI(instr) => i_type(opcodes::ANDI, instr),
+            SLLI(instr) => shift(opcodes::SLLI, instr),
+            SRLI(instr) => shift(opcodes::SRLI, instr),
+            SRAI(instr) => shift(opcodes::SRAI, instr),
+            ADD(instr) => r_type(opcodes::ADD, instr),
+            SUB(instr) => r_type(opcodes::SUB, instr),
+            SLL(instr) => r_type(opcodes::SLL, instr),
+            SLT(instr) => r_type(opcodes::SLT, instr),
+            SLTU(instr) => r_type(opcodes::SLTU, instr),
+            XOR(instr) => r_type(opcodes::XOR, instr),
+            SRL(instr) => r_type(opcodes::SRL, instr),
+            SRA(instr) => r_type(opcodes::SRA, instr),
+            OR(instr) => r_type(opcodes::OR, instr),
+            AND(instr) => r_type(opcodes::AND, instr),
+        },
+        Instruction::Branching(instr) => match instr {
+            JAL(instr) => j_type(opcodes::JAL, instr),
+            JALR(instr) => i_type(opcodes::JALR, instr),
+            BEQ(instr) => b_type(opcodes::BEQ, instr),
+            BNE(instr) => b_type(opcodes::BNE, instr),
+            BLT(instr) => b_type(opcodes::BLT, instr),
+            BGE(instr) => b_type(opcodes::BGE, instr),
+            BLTU(instr) => b_type(opcodes::BLTU, instr),
+            BGEU(instr) => b_type(opcodes::BGEU, instr),
+        },
+        Instruction::MemoryLoad(instr) => match instr {
+            LB(instr) => i_type(opcodes::LB, instr),
+            LH(instr) => i_type(opcodes::LH, instr),
+            LW(instr) => i_type(opcodes::LW, instr),
+            LBU(instr) => i_type(opcodes::LBU, instr),
+            LHU(instr) => i_type(opcodes::LHU, instr),
+        },
+        Instruction::MemoryStore(instr) => match instr {
+            SB(instr) => s_type(opcodes::SB, instr),
+            SH(instr) => s_type(opcodes::SH, instr),
+            SW(instr) => s_type(opcodes::SW, instr),
+        },
+        Instruction::Fence(instr) => match instr {
+            FENCE(instr) => i_type(opcodes::FENCE, instr),
+            FENCE_I(instr) => i_type(opcodes::FENCE_I, instr),
+        },
+        Instruction::System(instr) => match instr {
+            ECALL(_) => Ok(opcodes::ECALL),
+            EBREAK(_) => Ok(opcodes::EBREAK),
+            SRET(_) => Ok(opcodes::SRET),
+            MRET(_) => Ok(opcodes::MRET),
+            WFI(_) => Ok(opcodes::WFI),
+            SFENCE_VMA(instr) => r_type(opcodes::SFENCE_VMA, instr),
+            CSRRW(instr) => i_type(opcodes::CSRRW, instr),
+            CSRRS(instr) => i_type(opcodes::CSRRS, instr),
+            CSRRC(instr) => i_type(opcodes::CSRRC, instr),
+            CSRRWI(instr) => csr_immediate(opcodes::CSRRWI, instr),
+            CSRRSI(instr) => csr_immediate(opcodes::CSRRSI, instr),
+            CSRRCI(instr) => csr_immediate(opcodes::CSRRCI, instr),
+        },
+    }
+}
+
+fn r_type(full_opcode: u32, instr: RType) -> Result<u32, EncodeError> {
+    let RType { register_destination_index: rd, register_source_one: rs1, register_source_two: rs2, .. } = instr;
+    Ok(((full_opcode >> 10) << 25)
+        | (register(rs2.index)? << 20)
+        | (register(rs1.index)? << 15)
+        | (funct_3(full_opcode) << 12)
+        | (register(rd)? << 7)
+        | (full_opcode & 0x7f))
+}
+
+/// The shift amount takes the place of rs2.
+fn shift(full_opcode: u32, instr: RType) -> Result<u32, EncodeError> {
+    unsigned(instr.register_source_two.index, 5)?;
+    r_type(full_opcode, instr)
+}
+
+/// The immediate takes the place of rs1.
+fn csr_immediate(full_opcode: u32, instr: IType) -> Result<u32, EncodeError> {
+    unsigned(instr.register_source_one.index, 5)?;
+    i_type(full_opcode, instr)
+}
+
+fn i_type(full_opcode: u32, instr: IType) -> Result<u32, EncodeError> {
+    let IType { register_destination_index: rd, register_source_one: rs1, immediate, .. } = instr;
+    Ok((signed(immediate, 12)? << 20)
+        | (register(rs1.index)? << 15)
+        | (funct_3(full_opcode) << 12)
+        | (register(rd)? << 7)
+        | (full_opcode & 0x7f))
+}
+
+fn s_type(full_opcode: u32, instr: SType) -> Result<u32, EncodeError> {
+    let SType { register_source_one: rs1, register_source_two: rs2, immediate, .. } = instr;
+    let immediate = signed(immediate, 12)?;
+    Ok(((immediate >> 5) << 25)
+        | (register(rs2.index)? << 20)
+        | (register(rs1.index)? << 15)
+        | (funct_3(full_opcode) << 12)
+        | ((immediate & 0x1f) << 7)
+        | (full_opcode & 0x7f))
+}
+
+fn b_type(full_opcode: u32, instr: BType) -> Result<u32, EncodeError> {
+    let BType { register_source_one: rs1, register_source_two: rs2, immediate, .. } = instr;
+    let offset = signed(aligned(immediate, 2)?, 13)?;
+    Ok(((offset >> 12) << 31)
+        | (((offset >> 5) & 0x3f) << 25)
+        | (register(rs2.index)? << 20)
+        | (register(rs1.index)? << 15)
+        | (funct_3(full_opcode) << 12)
+        | (((offset >> 1) & 0xf) << 8)
+        | (((offset >> 11) & 0x1) << 7)
+        | (full_opcode & 0x7f))
+}
+
+fn u_type(opcode: u32, instr: UType) -> Result<u32, EncodeError> {
+    let UType { register_destination_index: rd, immediate, .. } = instr;
+    Ok(aligned(immediate, 1 << 12)? | (register(rd)? << 7) | opcode)
+}
+
+fn j_type(opcode: u32, instr: JType) -> Result<u32, EncodeError> {
+    let JType { register_destination_index: rd, immediate, .. } = instr;
+    let offset = signed(aligned(immediate, 2)?, 21)?;
+    Ok(((offset >> 20) << 31)
+        | (((offset >> 1) & 0x3ff) << 21)
+        | (((offset >> 11) & 0x1) << 20)
+        | (((offset >> 12) & 0xff) << 12)
+        | (register(rd)? << 7)
+        | opcode)
+}
+
+fn funct_3(full_opcode: u32) -> u32 {
+    (full_opcode >> 7) & 0x7
+}
+
+fn register(index: u32) -> Result<u32, EncodeError> {
+    match index < 32 {
+        true => Ok(index),
+        false => Err(EncodeError::InvalidRegister { index }),
+    }
+}
+
+/// Checks a sign extended immediate fits in `bits` and returns just those bits.
+fn signed(immediate: u32, bits: u32) -> Result<u32, EncodeError> {
+    let value = immediate as i32;
+    match (-(1 << (bits - 1))..1 << (bits - 1)).contains(&value) {
+        true => Ok(immediate & ((1 << bits) - 1)),
+        false => Err(EncodeError::ImmediateOutOfRange { immediate: value, bits }),
+    }
+}
+
+fn unsigned(immediate: u32, bits: u32) -> Result<u32, EncodeError> {
+    match immediate < 1 << bits {
+        true => Ok(immediate),
+        false => Err(EncodeError::ImmediateOutOfRange { immediate: immediate as i32, bits }),
+    }
+}
+
+fn aligned(immediate: u32, alignment: u32) -> Result<u32, EncodeError> {
+    match immediate.is_multiple_of(alignment) {
+        true => Ok(immediate),
+        false => Err(EncodeError::MisalignedImmediate { immediate, alignment }),
+    }
+}
+
+// Builders taking operands in assembler order, e.g. `addi(A0, A0, 4)` or `sw(A0, 8, SP)` for `sw a0, 8(sp)`. The
+// result is what `decode_instruction` returns for the encoding with every register reading zero.
+
+fn source(index: u32) -> DecodedRegisterValue {
+    DecodedRegisterValue { index, value: 0 }
+}
+
+fn r(full_opcode: u32, rd: u32, rs1: u32, rs2: u32) -> RType {
+    RType {
+        opcode: full_opcode & 0x7f,
+        full_opcode,
+        register_destination_index: rd,
+        register_source_one: source(rs1),
+        register_source_two: source(rs2),
+    }
+}
+
+fn i(full_opcode: u32, rd: u32, rs1: u32, immediate: i32) -> IType {
+    IType {
+        opcode: full_opcode & 0x7f,
+        full_opcode,
+        register_destination_index: rd,
+        register_source_one: source(rs1),
+        immediate: immediate as u32,
+    }
+}
+
+fn s(full_opcode: u32, rs2: u32, offset: i32, rs1: u32) -> SType {
+    SType {
+        opcode: full_opcode & 0x7f,
+        full_opcode,
+        register_source_one: source(rs1),
+        register_source_two: source(rs2),
+        immediate: offset as u32,
+    }
+}
+
+fn b(full_opcode: u32, rs1: u32, rs2: u32, offset: i32) -> BType {
+    BType {
+        opcode: full_opcode & 0x7f,
+        full_opcode,
+        register_source_one: source(rs1),
+        register_source_two: source(rs2),
+        immediate: offset as u32,
+    }
+}
+
+/// CSR addresses are decoded like any other I-type immediate, so sign extended.
+fn csr(full_opcode: u32, rd: u32, address: u32, rs1: u32) -> IType {
+    i(full_opcode, rd, rs1, ((address << 20) as i32) >> 20)
+}
+
+/// `immediate` is the value loaded, its low 12 bits must be clear.
+pub fn lui(rd: u32, immediate: u32) -> Instruction {
+    let opcode = opcodes::LUI;
+    Instruction::Alu(LUI(UType { opcode, full_opcode: opcode, register_destination_index: rd, immediate }))
+}
+
+/// `immediate` is the value added to the pc, its low 12 bits must be clear.
+pub fn auipc(rd: u32, immediate: u32) -> Instruction {
+    let opcode = opcodes::AUIPC;
+    Instruction::Alu(AUIPC(UType { opcode, full_opcode: opcode, register_destination_index: rd, immediate }))
+}
+
+pub fn addi(rd: u32, rs1: u32, immediate: i32) -> Instruction {
+    Instruction::Alu(ADDI(i(opcodes::ADDI, rd, rs1, immediate)))
+}
+
+pub fn slti(rd: u32, rs1: u32, immediate: i32) -> Instruction {
+    Instruction::Alu(SLTI(i(opcodes::SLTI, rd, rs1, immediate)))
+}
+
+pub fn sltiu(rd: u32, rs1: u32, immediate: i32) -> Instruction {
+    Instruction::Alu(SLTIU(i(opcodes::SLTIU, rd, rs1, immediate)))
+}
+
+pub fn xori(rd: u32, rs1: u32, immediate: i32) -> Instruction {
+    Instruction::Alu(XORI(i(opcodes::XORI, rd, rs1, immediate)))
+}
+
+pub fn ori(rd: u32, rs1: u32, immediate: i32) -> Instruction {
+    Instruction::Alu(ORI(i(opcodes::ORI, rd, rs1, immediate)))
+}
+
+pub fn andi(rd: u32, rs1: u32, immediate: i32) -> Instruction {
+    Instruction::Alu(ANDI(i(opcodes::ANDI, rd, rs1, immediate)))
+}
+
+pub fn slli(rd: u32, rs1: u32, shift_amount: u32) -> Instruction {
+    Instruction::Alu(SLLI(r(opcodes::SLLI, rd, rs1, shift_amount)))
+}
+
+pub fn srli(rd: u32, rs1: u32, shift_amount: u32) -> Instruction {
+    Instruction::Alu(SRLI(r(opcodes::SRLI, rd, rs1, shift_amount)))
+}
+
+pub fn srai(rd: u32, rs1: u32, shift_amount: u32) -> Instruction {
+    Instruction::Alu(SRAI(r(opcodes::SRAI, rd, rs1, shift_amount)))
+}
+
+pub fn add(rd: u32, rs1: u32, rs2: u32) -> Instruction {
+    Instruction::Alu(ADD(r(opcodes::ADD, rd, rs1, rs2)))
+}
+
+pub fn sub(rd: u32, rs1: u32, rs2: u32) -> Instruction {
+    Instruction::Alu(SUB(r(opcodes::SUB, rd, rs1, rs2)))
+}
+
+pub fn sll(rd: u32, rs1: u32, rs2: u32) -> Instruction {
+    Instruction::Alu(SLL(r(opcodes::SLL, rd, rs1, rs2)))
+}
+
+pub fn slt(rd: u32, rs1: u32, rs2: u32) -> Instruction {
+    Instruction::Alu(SLT(r(opcodes::SLT, rd, rs1, rs2)))
+}
+
+pub fn sltu(rd: u32, rs1: u32, rs2: u32) -> Instruction {
+    Instruction::Alu(SLTU(r(opcodes::SLTU, rd, rs1, rs2)))
+}
+
+pub fn xor(rd: u32, rs1: u32, rs2: u32) -> Instruction {
+    Instruction::Alu(XOR(r(opcodes::XOR, rd, rs1, rs2)))
+}
+
+pub fn srl(rd: u32, rs1: u32, rs2: u32) -> Instruction {
+    Instruction::Alu(SRL(r(opcodes::SRL, rd, rs1, rs2)))
+}
+
+pub fn sra(rd: u32, rs1: u32, rs2: u32) -> Instruction {
+    Instruction::Alu(SRA(r(opcodes::SRA, rd, rs1, rs2)))
+}
+
+pub fn or(rd: u32, rs1: u32, rs2: u32) -> Instruction {
+    Instruction::Alu(OR(r(opcodes::OR, rd, rs1, rs2)))
+}
+
+pub fn and(rd: u32, rs1: u32, rs2: u32) -> Instruction {
+    Instruction::Alu(AND(r(opcodes::AND, rd, rs1, rs2)))
+}
+
+/// `offset` is relative to the jump itself, as for the branches.
+pub fn jal(rd: u32, offset: i32) -> Instruction {
+    let opcode = opcodes::JAL;
+    Instruction::Branching(JAL(JType {
+        opcode,
+        full_opcode: opcode,
+        register_destination_index: rd,
+        immediate: offset as u32,
+    }))
+}
+
+pub fn jalr(rd: u32, offset: i32, rs1: u32) -> Instruction {
+    Instruction::Branching(JALR(i(opcodes::JALR, rd, rs1, offset)))
+}
+
+pub fn beq(rs1: u32, rs2: u32, offset: i32) -> Instruction {
+    Instruction::Branching(BEQ(b(opcodes::BEQ, rs1, rs2, offset)))
+}
+
+pub fn bne(rs1: u32, rs2: u32, offset: i32) -> Instruction {
+    Instruction::Branching(BNE(b(opcodes::BNE, rs1, rs2, offset)))
+}
+
+pub fn blt(rs1: u32, rs2: u32, offset: i32) -> Instruction {
+    Instruction::Branching(BLT(b(opcodes::BLT, rs1, rs2, offset)))
+}
+
+pub fn bge(rs1: u32, rs2: u32, offset: i32) -> Instruction {
+    Instruction::Branching(BGE(b(opcodes::BGE, rs1, rs2, offset)))
+}
+
+pub fn bltu(rs1: u32, rs2: u32, offset: i32) -> Instruction {
+    Instruction::Branching(BLTU(b(opcodes::BLTU, rs1, rs2, offset)))
+}
+
+pub fn bgeu(rs1: u32, rs2: u32, offset: i32) -> Instruction {
+    Instruction::Branching(BGEU(b(opcodes::BGEU, rs1, rs2, offset)))
+}
+
+pub fn lb(rd: u32, offset: i32, rs1: u32) -> Instruction {
+    Instruction::MemoryLoad(LB(i(opcodes::LB, rd, rs1, offset)))
+}
+
+pub fn lh(rd: u32, offset: i32, rs1: u32) -> Instruction {
+    Instruction::MemoryLoad(LH(i(opcodes::LH, rd, rs1, offset)))
+}
+
+pub fn lw(rd: u32, offset: i32, rs1: u32) -> Instruction {
+    Instruction::MemoryLoad(LW(i(opcodes::LW, rd, rs1, offset)))
+}
+
+pub fn lbu(rd: u32, offset: i32, rs1: u32) -> Instruction {
+    Instruction::MemoryLoad(LBU(i(opcodes::LBU, rd, rs1, offset)))
+}
+
+pub fn lhu(rd: u32, offset: i32, rs1: u32) -> Instruction {
+    Instruction::MemoryLoad(LHU(i(opcodes::LHU, rd, rs1, offset)))
+}
+
+pub fn sb(rs2: u32, offset: i32, rs1: u32) -> Instruction {
+    Instruction::MemoryStore(SB(s(opcodes::SB, rs2, offset, rs1)))
+}
+
+pub fn sh(rs2: u32, offset: i32, rs1: u32) -> Instruction {
+    Instruction::MemoryStore(SH(s(opcodes::SH, rs2, offset, rs1)))
+}
+
+pub fn sw(rs2: u32, offset: i32, rs1: u32) -> Instruction {
+    Instruction::MemoryStore(SW(s(opcodes::SW, rs2, offset, rs1)))
+}
+
+/// `predecessor` and `successor` are sets of the bits i, o, r and w from most to least significant.
+pub fn fence(predecessor: u32, successor: u32) -> Instruction {
+    Instruction::Fence(FENCE(i(opcodes::FENCE, 0, 0, ((predecessor << 4) | successor) as i32)))
+}
+
+pub fn fence_i() -> Instruction {
+    Instruction::Fence(FENCE_I(i(opcodes::FENCE_I, 0, 0, 0)))
+}
+
+/// The operand-less privileged instructions decode with whatever their fixed rs2 field holds.
+fn privileged(full_opcode: u32) -> RType {
+    r(full_opcode, 0, 0, (full_opcode >> 20) & 0x1f)
+}
+
+pub fn ecall() -> Instruction {
+    Instruction::System(ECALL(privileged(opcodes::ECALL)))
+}
+
+pub fn ebreak() -> Instruction {
+    Instruction::System(EBREAK(privileged(opcodes::EBREAK)))
+}
+
+pub fn sret() -> Instruction {
+    Instruction::System(SRET(privileged(opcodes::SRET)))
+}
+
+pub fn mret() -> Instruction {
+    Instruction::System(MRET(privileged(opcodes::MRET)))
+}
+
+pub fn wfi() -> Instruction {
+    Instruction::System(WFI(privileged(opcodes::WFI)))
+}
+
+pub fn sfence_vma(rs1: u32, rs2: u32) -> Instruction {
+    Instruction::System(SFENCE_VMA(r(opcodes::SFENCE_VMA, 0, rs1, rs2)))
+}
+
+pub fn csrrw(rd: u32, address: u32, rs1: u32) -> Instruction {
+    Instruction::System(CSRRW(csr(opcodes::CSRRW, rd, address, rs1)))
+}
+
+pub fn csrrs(rd: u32, address: u32, rs1: u32) -> Instruction {
+    Instruction::System(CSRRS(csr(opcodes::CSRRS, rd, address, rs1)))
+}
+
+pub fn csrrc(rd: u32, address: u32, rs1: u32) -> Instruction {
+    Instruction::System(CSRRC(csr(opcodes::CSRRC, rd, address, rs1)))
+}
+
+/// The immediate forms take a 5 bit value where rs1 would be.
+pub fn csrrwi(rd: u32, address: u32, immediate: u32) -> Instruction {
+    Instruction::System(CSRRWI(csr(opcodes::CSRRWI, rd, address, immediate)))
+}
+
+pub fn csrrsi(rd: u32, address: u32, immediate: u32) -> Instruction {
+    Instruction::System(CSRRSI(csr(opcodes::CSRRSI, rd, address, immediate)))
+}
+
+pub fn csrrci(rd: u32, address: u32, immediate: u32) -> Instruction {
+    Instruction::System(CSRRCI(csr(opcodes::CSRRCI, rd, address, immediate)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::register_file::*;
+    use crate::core::unit::{decode_instruction, FetchResult};
+
+    /// Xorshift, enough to spread operands over their ranges without a dependency.
+    struct Random(u32);
+
+    impl Random {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        fn register(&mut self) -> u32 {
+            self.next() % 32
+        }
+
+        /// A signed value that fits in `bits`, a multiple of `alignment`.
+        fn signed(&mut self, bits: u32, alignment: i32) -> i32 {
+            ((self.next() << (32 - bits)) as i32 >> (32 - bits)) / alignment * alignment
+        }
+    }
+
+    fn decode(word: u32) -> Option<Instruction> {
+        decode_instruction(FetchResult { captured_pc: 0, instruction: word }, &RegisterFile::new(32)).ok()
+    }
+
+    #[test]
+    fn decoding_an_encoding_gives_the_instruction_back() {
+        let mut random = Random(0x1234_5678);
+
+        for _ in 0..1000 {
+            let (rd, rs1, rs2) = (random.register(), random.register(), random.register());
+            let immediate = random.signed(12, 1);
+            let (branch, jump) = (random.signed(13, 2), random.signed(21, 2));
+            let upper = random.next() & 0xffff_f000;
+            let (shift, address) = (random.next() % 32, random.next() % 0x1000);
+
+            let instructions = [
+                lui(rd, upper),
+                auipc(rd, upper),
+                addi(rd, rs1, immediate),
+                slti(rd, rs1, immediate),
+                sltiu(rd, rs1, immediate),
+                xori(rd, rs1, immediate),
+                ori(rd, rs1, immediate),
+                andi(rd, rs1, immediate),
+                slli(rd, rs1, shift),
+                srli(rd, rs1, shift),
+                srai(rd, rs1, shift),
+                add(rd, rs1, rs2),
+                sub(rd, rs1, rs2),
+                sll(rd, rs1, rs2),
+                slt(rd, rs1, rs2),
+                sltu(rd, rs1, rs2),
+                xor(rd, rs1, rs2),
+                srl(rd, rs1, rs2),
+                sra(rd, rs1, rs2),
+                or(rd, rs1, rs2),
+                and(rd, rs1, rs2),
+                jal(rd, jump),
+                jalr(rd, immediate, rs1),
+                beq(rs1, rs2, branch),
+                bne(rs1, rs2, branch),
+                blt(rs1, rs2, branch),
+                bge(rs1, rs2, branch),
+                bltu(rs1, rs2, branch),
+                bgeu(rs1, rs2, branch),
+                lb(rd, immediate, rs1),
+                lh(rd, immediate, rs1),
+                lw(rd, immediate, rs1),
+                lbu(rd, immediate, rs1),
+                lhu(rd, immediate, rs1),
+                sb(rs2, immediate, rs1),
+                sh(rs2, immediate, rs1),
+                sw(rs2, immediate, rs1),
+                fence(random.next() % 16, random.next() % 16),
+                fence_i(),
+                ecall(),
+                ebreak(),
+                sret(),
+                mret(),
+                wfi(),
+                sfence_vma(rs1, rs2),
+                csrrw(rd, address, rs1),
+                csrrs(rd, address, rs1),
+                csrrc(rd, address, rs1),
+                csrrwi(rd, address, shift),
+                csrrsi(rd, address, shift),
+                csrrci(rd, address, shift),
+            ];
+
+            for instruction in instructions {
+                assert_eq!(decode(encode(instruction).unwrap()), Some(instruction));
+            }
+        }
+    }
+
+    #[test]
+    fn matches_known_encodings() {
+        assert_eq!(encode(addi(A0, A0, 4)), Ok(0x0045_0513));
+        assert_eq!(encode(sw(RA, 12, SP)), Ok(0x0011_2623));
+        assert_eq!(encode(bne(A0, A1, -4)), Ok(0xfeb5_1ee3));
+        assert_eq!(encode(jal(RA, -8)), Ok(0xff9f_f0ef));
+        assert_eq!(encode(csrrs(A1, 0xc00, ZERO)), Ok(0xc000_25f3));
+    }
+
+    #[test]
+    fn rejects_what_cannot_be_encoded() {
+        use EncodeError::*;
+
+        assert_eq!(encode(addi(A0, A0, 2048)), Err(ImmediateOutOfRange { immediate: 2048, bits: 12 }));
+        assert_eq!(encode(sw(A0, -2049, SP)), Err(ImmediateOutOfRange { immediate: -2049, bits: 12 }));
+        assert_eq!(encode(beq(A0, A1, 4096)), Err(ImmediateOutOfRange { immediate: 4096, bits: 13 }));
+        assert_eq!(encode(beq(A0, A1, 3)), Err(MisalignedImmediate { immediate: 3, alignment: 2 }));
+        assert_eq!(encode(jal(RA, 1 << 20)), Err(ImmediateOutOfRange { immediate: 1 << 20, bits: 21 }));
+        assert_eq!(encode(lui(A0, 0x123)), Err(MisalignedImmediate { immediate: 0x123, alignment: 4096 }));
+        assert_eq!(encode(add(32, A0, A1)), Err(InvalidRegister { index: 32 }));
+        assert_eq!(encode(slli(A0, A0, 32)), Err(ImmediateOutOfRange { immediate: 32, bits: 5 }));
+    }
+}
@@ -0,0 +1,81 @@
+use Exception::*;
+
+/// Synchronous exceptions in the order of their `mcause` codes. Variants that carry an address or instruction
+/// report it through `mtval`/`stval` when the trap is taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exception {
+    InstructionAddressMisaligned { address: u32 },
+    InstructionAccessFault { address: u32 },
+    IllegalInstruction { instruction: u32 },
+    Breakpoint { address: u32 },
+    LoadAddressMisaligned { address: u32 },
+    LoadAccessFault { address: u32 },
+    StoreAddressMisaligned { address: u32 },
+    StoreAccessFault { address: u32 },
+    EnvironmentCallFromUMode,
+    EnvironmentCallFromSMode,
+    EnvironmentCallFromMMode,
+    InstructionPageFault { address: u32 },
+    LoadPageFault { address: u32 },
+    StorePageFault { address: u32 },
+}
+
+impl Exception {
+    pub fn cause(&self) -> u32 {
+        match self {
+            InstructionAddressMisaligned { .. } => 0,
+            InstructionAccessFault { .. } => 1,
+            IllegalInstruction { .. } => 2,
+            Breakpoint { .. } => 3,
+            LoadAddressMisaligned { .. } => 4,
+            LoadAccessFault { .. } => 5,
+            StoreAddressMisaligned { .. } => 6,
+            StoreAccessFault { .. } => 7,
+            EnvironmentCallFromUMode => 8,
+            EnvironmentCallFromSMode => 9,
+            EnvironmentCallFromMMode => 11,
+            InstructionPageFault { .. } => 12,
+            LoadPageFault { .. } => 13,
+            StorePageFault { .. } => 15,
+        }
+    }
+
+    /// The exception with `cause` reporting `trap_value`, the inverse of the two accessors.
+    pub fn from_cause(cause: u32, trap_value: u32) -> Option<Exception> {
+        let (address, instruction) = (trap_value, trap_value);
+        Some(match cause {
+            0 => InstructionAddressMisaligned { address },
+            1 => InstructionAccessFault { address },
+            2 => IllegalInstruction { instruction },
+            3 => Breakpoint { address },
+            4 => LoadAddressMisaligned { address },
+            5 => LoadAccessFault { address },
+            6 => StoreAddressMisaligned { address },
+            7 => StoreAccessFault { address },
+            8 => EnvironmentCallFromUMode,
+            9 => EnvironmentCallFromSMode,
+            11 => EnvironmentCallFromMMode,
+            12 => InstructionPageFault { address },
+            13 => LoadPageFault { address },
+            15 => StorePageFault { address },
+            _ => return None,
+        })
+    }
+
+    pub fn trap_value(&self) -> u32 {
+        match *self {
+            InstructionAddressMisaligned { address }
+            | InstructionAccessFault { address }
+            | Breakpoint { address }
+            | LoadAddressMisaligned { address }
+            | LoadAccessFault { address }
+            | StoreAddressMisaligned { address }
+            | StoreAccessFault { address }
+            | InstructionPageFault { address }
+            | LoadPageFault { address }
+            | StorePageFault { address } => address,
+            IllegalInstruction { instruction } => instruction,
+            EnvironmentCallFromUMode | EnvironmentCallFromSMode | EnvironmentCallFromMMode => 0,
+        }
+    }
+}
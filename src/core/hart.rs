@@ -1,8 +1,129 @@
 use std::marker::PhantomData;
 
-use super::bus::BusInterface;
+use super::branch_predictor::BranchPredictor;
+use super::bus::{BusInterface, BusReadResponse, BusWriteResponse, Clocked};
+use super::cache::Cache;
+use super::commit_log::{CommitLog, Retirement};
+use super::csr::{ControlStatusRegisters, HpmEvent};
+use super::mmu::Mmu;
 use super::pipeline::Pipeline;
 use super::register_file::RegisterFile;
+use super::snapshot::{Snapshot, SnapshotError, SnapshotReader, SnapshotWriter};
+use super::unit::MisalignedAccessPolicy;
+
+/// `slli x0, x0, 0x1f`, which starts the semihosting sequence right before its EBREAK.
+const SEMIHOSTING_ENTRY: u32 = 0x01f0_1013;
+
+/// Everything a pipeline reads and updates besides memory.
+#[derive(Clone)]
+pub struct HartState {
+    pub register_file: RegisterFile,
+    pub csrs: ControlStatusRegisters,
+    pub mmu: Mmu,
+    pub misaligned_access_policy: MisalignedAccessPolicy,
+    /// Fetches go straight to the bus without one.
+    pub instruction_cache: Option<Cache>,
+    /// Loads and stores go straight to the bus without one. Page table walks always do, so page tables written
+    /// through a write-back cache have to be flushed before they are used.
+    pub data_cache: Option<Cache>,
+    /// Without one fetch always continues with the next instruction.
+    pub branch_predictor: Option<BranchPredictor>,
+    /// Leaves EBREAKs in the semihosting sequence to the host instead of trapping, see `take_semihosting_call`.
+    pub semihosting: bool,
+    /// Has retirements record what they overwrote, see `Retirement::undo`. Not part of snapshots.
+    pub record_undo: bool,
+    last_retired_instruction: u32,
+    semihosting_call: bool,
+    /// Instructions retired since they were last taken, only kept while a commit log is attached or the hart records
+    /// them.
+    retirements: Option<Vec<Retirement>>,
+    traps: u64,
+}
+
+impl Default for HartState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HartState {
+    pub fn new() -> Self {
+        HartState {
+            register_file: RegisterFile::new(32),
+            csrs: ControlStatusRegisters::new(),
+            mmu: Mmu::default(),
+            misaligned_access_policy: MisalignedAccessPolicy::default(),
+            instruction_cache: None,
+            data_cache: None,
+            branch_predictor: None,
+            semihosting: false,
+            record_undo: false,
+            last_retired_instruction: 0,
+            semihosting_call: false,
+            retirements: None,
+            traps: 0,
+        }
+    }
+
+    /// Called by pipelines at write back for every instruction that completes without a trap.
+    pub fn retire(&mut self, retirement: Retirement) {
+        self.csrs.count_retired();
+        self.last_retired_instruction = retirement.instruction;
+        if let Some(retirements) = &mut self.retirements {
+            retirements.push(retirement);
+        }
+    }
+
+    /// Called by pipelines for every trap they take.
+    pub fn count_trap(&mut self) {
+        self.traps += 1;
+    }
+
+    /// The traps taken so far, for tools that follow execution. Not part of snapshots.
+    pub fn traps(&self) -> u64 {
+        self.traps
+    }
+
+    /// Whether an EBREAK retiring now is a semihosting call: semihosting is on and the instruction retired right
+    /// before it is the `slli` starting the sequence. The `srai` after it does nothing either way.
+    pub fn is_semihosting_call(&self) -> bool {
+        self.semihosting && self.last_retired_instruction == SEMIHOSTING_ENTRY
+    }
+
+    /// Reads a byte of physical memory as loads see it, from the data cache while it holds the line. For hosts and
+    /// debuggers, the caches are left as they are.
+    pub fn read_byte<M: BusInterface<u32, u8>>(&self, memory: &M, address: u32) -> Option<u8> {
+        if let Some(byte) = self.data_cache.as_ref().and_then(|cache| cache.peek(address)) {
+            return Some(byte);
+        }
+        match memory.read(address) {
+            BusReadResponse::Success(value) => Some(value as u8),
+            _ => None,
+        }
+    }
+
+    /// Writes a byte of physical memory and every cached copy of it, so later loads and fetches see it.
+    pub fn write_byte<M: BusInterface<u32, u8>>(&mut self, memory: &mut M, address: u32, value: u8) -> bool {
+        if !matches!(memory.write(address, value), BusWriteResponse::Success) {
+            return false;
+        }
+        for cache in [&mut self.instruction_cache, &mut self.data_cache].into_iter().flatten() {
+            cache.poke(address, value);
+        }
+        true
+    }
+
+    /// Called by pipelines that retire a semihosting EBREAK instead of trapping.
+    pub fn request_semihosting_call(&mut self) {
+        self.semihosting_call = true;
+    }
+
+    /// Whether the last cycle retired a semihosting call the host has yet to serve. Taken after every cycle, before
+    /// the instructions after the call read its result.
+    pub fn take_semihosting_call(&mut self) -> bool {
+        std::mem::take(&mut self.semihosting_call)
+    }
+}
 
 pub struct Hart<M, P: Pipeline<M>>
 where
@@ -13,11 +134,28 @@ where
     M: BusInterface<u32, u32>,
 {
     program_counter: u32,
-    register_file: RegisterFile,
+    state: HartState,
     pipeline: P,
+    commit_log: Option<CommitLog>,
+    /// What the last cycle retired, while `recording`.
+    retired: Vec<Retirement>,
+    recording: bool,
     phantom: PhantomData<M>,
 }
 
+impl<M, P: Pipeline<M>> Default for Hart<M, P>
+where
+    M: BusInterface<u32, i8>,
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, i16>,
+    M: BusInterface<u32, u16>,
+    M: BusInterface<u32, u32>,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<M, P: Pipeline<M>> Hart<M, P>
 where
     M: BusInterface<u32, i8>,
@@ -27,10 +165,162 @@ where
     M: BusInterface<u32, u32>,
 {
     pub fn new() -> Self {
-        Hart { program_counter: 0, register_file: RegisterFile::new(32), pipeline: P::new(), phantom: PhantomData }
+        Self::with_state(HartState::new())
+    }
+
+    pub fn with_state(state: HartState) -> Self {
+        Hart {
+            program_counter: 0,
+            state,
+            pipeline: P::new(),
+            commit_log: None,
+            retired: Vec::new(),
+            recording: false,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn program_counter(&self) -> u32 {
+        self.program_counter
     }
 
-    pub fn execute(&mut self, memory: &mut M) {
-        self.program_counter = self.pipeline.execute(self.program_counter, &mut self.register_file, memory);
+    /// Moves fetch to `pc`, meant for setting the entry point before the first cycle.
+    pub fn set_program_counter(&mut self, pc: u32) {
+        self.program_counter = pc;
     }
+
+    pub fn state(&self) -> &HartState {
+        &self.state
+    }
+
+    pub fn state_mut(&mut self) -> &mut HartState {
+        &mut self.state
+    }
+
+    pub fn pipeline(&self) -> &P {
+        &self.pipeline
+    }
+
+    pub fn pipeline_mut(&mut self) -> &mut P {
+        &mut self.pipeline
+    }
+
+    /// Logs every instruction retired from the next cycle on.
+    pub fn set_commit_log(&mut self, commit_log: CommitLog) {
+        self.state.retirements.get_or_insert_with(Vec::new);
+        self.commit_log = Some(commit_log);
+    }
+
+    pub fn take_commit_log(&mut self) -> Option<CommitLog> {
+        if !self.recording {
+            self.state.retirements = None;
+        }
+        self.commit_log.take()
+    }
+
+    /// Keeps what each cycle retires from the next cycle on, for tools like debuggers that follow execution.
+    pub fn record_retirements(&mut self) {
+        self.state.retirements.get_or_insert_with(Vec::new);
+        self.recording = true;
+    }
+
+    /// The instructions retired by the last cycle, oldest first. Empty unless recording.
+    pub fn retired(&self) -> &[Retirement] {
+        &self.retired
+    }
+
+    /// Runs one cycle of the pipeline, then advances the memory system by one cycle.
+    pub fn execute(&mut self, memory: &mut M)
+    where
+        M: Clocked,
+    {
+        let instruction_cache_misses = cache_misses(&self.state.instruction_cache);
+        let data_cache_misses = cache_misses(&self.state.data_cache);
+
+        self.program_counter = self.pipeline.execute(self.program_counter, &mut self.state, memory);
+        memory.tick();
+
+        for _ in instruction_cache_misses..cache_misses(&self.state.instruction_cache) {
+            self.state.csrs.count_event(HpmEvent::InstructionCacheMiss);
+        }
+        for _ in data_cache_misses..cache_misses(&self.state.data_cache) {
+            self.state.csrs.count_event(HpmEvent::DataCacheMiss);
+        }
+        self.state.csrs.count_cycle();
+
+        self.retired.clear();
+        if let Some(retirements) = &mut self.state.retirements {
+            if let Some(commit_log) = &mut self.commit_log {
+                retirements.iter().for_each(|retirement| commit_log.log(retirement));
+            }
+            match self.recording {
+                true => self.retired.append(retirements),
+                false => retirements.clear(),
+            }
+        }
+    }
+}
+
+/// Semihosting calls are taken by the host between cycles and not saved.
+impl Snapshot for HartState {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        self.register_file.save(snapshot);
+        self.csrs.save(snapshot);
+        self.mmu.save(snapshot);
+        snapshot.u8(self.misaligned_access_policy as u8);
+        snapshot.bool(self.semihosting);
+        snapshot.u32(self.last_retired_instruction);
+        snapshot.option(&self.instruction_cache, |snapshot, cache| cache.save(snapshot));
+        snapshot.option(&self.data_cache, |snapshot, cache| cache.save(snapshot));
+        snapshot.option(&self.branch_predictor, |snapshot, predictor| predictor.save(snapshot));
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.register_file.restore(snapshot)?;
+        self.csrs.restore(snapshot)?;
+        self.mmu.restore(snapshot)?;
+        self.misaligned_access_policy = match snapshot.u8()? {
+            0 => MisalignedAccessPolicy::Trap,
+            _ => MisalignedAccessPolicy::Emulate,
+        };
+        self.semihosting = snapshot.bool()?;
+        self.last_retired_instruction = snapshot.u32()?;
+        snapshot.restore_option("whether there is an instruction cache", &mut self.instruction_cache)?;
+        snapshot.restore_option("whether there is a data cache", &mut self.data_cache)?;
+        snapshot.restore_option("whether there is a branch predictor", &mut self.branch_predictor)?;
+        if let Some(retirements) = &mut self.retirements {
+            retirements.clear();
+        }
+        Ok(())
+    }
+}
+
+/// The commit log and recorded retirements are outputs and stay as they are.
+impl<M, P: Pipeline<M> + Snapshot> Snapshot for Hart<M, P>
+where
+    M: BusInterface<u32, i8>,
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, i16>,
+    M: BusInterface<u32, u16>,
+    M: BusInterface<u32, u32>,
+{
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("hart");
+        snapshot.u32(self.program_counter);
+        self.state.save(snapshot);
+        self.pipeline.save(snapshot);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        snapshot.section("hart")?;
+        self.program_counter = snapshot.u32()?;
+        self.state.restore(snapshot)?;
+        self.pipeline.restore(snapshot)?;
+        self.retired.clear();
+        Ok(())
+    }
+}
+
+fn cache_misses(cache: &Option<Cache>) -> u64 {
+    cache.as_ref().map_or(0, |cache| cache.statistics().misses)
 }
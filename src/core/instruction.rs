@@ -1,13 +1,16 @@
 pub mod full_opcode_constants;
 pub mod opcode_group_constants;
 
-#[derive(Copy, Clone)]
+/// Without the C extension every instruction, and so every jump target, is word aligned.
+pub const INSTRUCTION_ALIGNMENT: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct DecodedRegisterValue {
     pub index: u32,
     pub value: u32,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct JType {
     pub opcode: u32,
     pub full_opcode: u32,
@@ -15,7 +18,7 @@ pub struct JType {
     pub immediate: u32,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct UType {
     pub opcode: u32,
     pub full_opcode: u32,
@@ -23,7 +26,7 @@ pub struct UType {
     pub immediate: u32,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct BType {
     pub opcode: u32,
     pub full_opcode: u32,
@@ -32,7 +35,7 @@ pub struct BType {
     pub immediate: u32,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct SType {
     pub opcode: u32,
     pub full_opcode: u32,
@@ -41,7 +44,7 @@ pub struct SType {
     pub immediate: u32,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct IType {
     pub opcode: u32,
     pub full_opcode: u32,
@@ -50,7 +53,7 @@ pub struct IType {
     pub immediate: u32,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct RType {
     pub opcode: u32,
     pub full_opcode: u32,
@@ -59,15 +62,18 @@ pub struct RType {
     pub register_source_two: DecodedRegisterValue,
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Instruction {
     Alu(AluInstruction),
     Branching(BranchingInstruction),
     MemoryLoad(MemoryLoadInstruction),
     MemoryStore(MemoryStoreInstruction),
+    Fence(FenceInstruction),
+    System(SystemInstruction),
 }
 
-#[derive(Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum AluInstruction {
     LUI(UType),
     AUIPC(UType),
@@ -92,7 +98,8 @@ pub enum AluInstruction {
     AND(RType),
 }
 
-#[derive(Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BranchingInstruction {
     JAL(JType),
     JALR(IType),
@@ -104,7 +111,8 @@ pub enum BranchingInstruction {
     BGEU(BType),
 }
 
-#[derive(Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum MemoryLoadInstruction {
     LB(IType),
     LH(IType),
@@ -113,9 +121,227 @@ pub enum MemoryLoadInstruction {
     LHU(IType),
 }
 
-#[derive(Clone, Copy)]
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum MemoryStoreInstruction {
     SB(SType),
     SH(SType),
     SW(SType),
 }
+
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FenceInstruction {
+    FENCE(IType),
+    FENCE_I(IType),
+}
+
+#[allow(non_camel_case_types, clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SystemInstruction {
+    ECALL(RType),
+    EBREAK(RType),
+    SRET(RType),
+    MRET(RType),
+    WFI(RType),
+    SFENCE_VMA(RType),
+    CSRRW(IType),
+    CSRRS(IType),
+    CSRRC(IType),
+    CSRRWI(IType),
+    CSRRSI(IType),
+    CSRRCI(IType),
+}
+
+impl Instruction {
+    pub fn register_destination_index(&self) -> Option<u32> {
+        let index = match self {
+            Instruction::Alu(instr) => match instr {
+                AluInstruction::LUI(instr) | AluInstruction::AUIPC(instr) => instr.register_destination_index,
+                AluInstruction::ADDI(instr)
+                | AluInstruction::SLTI(instr)
+                | AluInstruction::SLTIU(instr)
+                | AluInstruction::XORI(instr)
+                | AluInstruction::ORI(instr)
+                | AluInstruction::ANDI(instr) => instr.register_destination_index,
+                AluInstruction::SLLI(instr)
+                | AluInstruction::SRLI(instr)
+                | AluInstruction::SRAI(instr)
+                | AluInstruction::ADD(instr)
+                | AluInstruction::SUB(instr)
+                | AluInstruction::SLL(instr)
+                | AluInstruction::SLT(instr)
+                | AluInstruction::SLTU(instr)
+                | AluInstruction::XOR(instr)
+                | AluInstruction::SRL(instr)
+                | AluInstruction::SRA(instr)
+                | AluInstruction::OR(instr)
+                | AluInstruction::AND(instr) => instr.register_destination_index,
+            },
+            Instruction::Branching(BranchingInstruction::JAL(instr)) => instr.register_destination_index,
+            Instruction::Branching(BranchingInstruction::JALR(instr)) => instr.register_destination_index,
+            Instruction::Branching(_) => return None,
+            Instruction::MemoryLoad(
+                MemoryLoadInstruction::LB(instr)
+                | MemoryLoadInstruction::LH(instr)
+                | MemoryLoadInstruction::LW(instr)
+                | MemoryLoadInstruction::LBU(instr)
+                | MemoryLoadInstruction::LHU(instr),
+            ) => instr.register_destination_index,
+            Instruction::MemoryStore(_) | Instruction::Fence(_) => return None,
+            Instruction::System(
+                SystemInstruction::CSRRW(instr)
+                | SystemInstruction::CSRRS(instr)
+                | SystemInstruction::CSRRC(instr)
+                | SystemInstruction::CSRRWI(instr)
+                | SystemInstruction::CSRRSI(instr)
+                | SystemInstruction::CSRRCI(instr),
+            ) => instr.register_destination_index,
+            Instruction::System(_) => return None,
+        };
+
+        Some(index)
+    }
+
+    /// The register indices this instruction reads, used by the pipeline to detect hazards.
+    pub fn register_source_indices(&self) -> [Option<u32>; 2] {
+        match self {
+            Instruction::Alu(instr) => match instr {
+                AluInstruction::LUI(_) | AluInstruction::AUIPC(_) => [None, None],
+                AluInstruction::ADDI(instr)
+                | AluInstruction::SLTI(instr)
+                | AluInstruction::SLTIU(instr)
+                | AluInstruction::XORI(instr)
+                | AluInstruction::ORI(instr)
+                | AluInstruction::ANDI(instr) => [Some(instr.register_source_one.index), None],
+                AluInstruction::SLLI(instr) | AluInstruction::SRLI(instr) | AluInstruction::SRAI(instr) => {
+                    [Some(instr.register_source_one.index), None]
+                }
+                AluInstruction::ADD(instr)
+                | AluInstruction::SUB(instr)
+                | AluInstruction::SLL(instr)
+                | AluInstruction::SLT(instr)
+                | AluInstruction::SLTU(instr)
+                | AluInstruction::XOR(instr)
+                | AluInstruction::SRL(instr)
+                | AluInstruction::SRA(instr)
+                | AluInstruction::OR(instr)
+                | AluInstruction::AND(instr) => [
+                    Some(instr.register_source_one.index),
+                    Some(instr.register_source_two.index),
+                ],
+            },
+            Instruction::Branching(BranchingInstruction::JAL(_)) => [None, None],
+            Instruction::Branching(BranchingInstruction::JALR(instr)) => [Some(instr.register_source_one.index), None],
+            Instruction::Branching(
+                BranchingInstruction::BEQ(instr)
+                | BranchingInstruction::BNE(instr)
+                | BranchingInstruction::BLT(instr)
+                | BranchingInstruction::BGE(instr)
+                | BranchingInstruction::BLTU(instr)
+                | BranchingInstruction::BGEU(instr),
+            ) => [
+                Some(instr.register_source_one.index),
+                Some(instr.register_source_two.index),
+            ],
+            Instruction::MemoryLoad(
+                MemoryLoadInstruction::LB(instr)
+                | MemoryLoadInstruction::LH(instr)
+                | MemoryLoadInstruction::LW(instr)
+                | MemoryLoadInstruction::LBU(instr)
+                | MemoryLoadInstruction::LHU(instr),
+            ) => [Some(instr.register_source_one.index), None],
+            Instruction::MemoryStore(
+                MemoryStoreInstruction::SB(instr)
+                | MemoryStoreInstruction::SH(instr)
+                | MemoryStoreInstruction::SW(instr),
+            ) => [
+                Some(instr.register_source_one.index),
+                Some(instr.register_source_two.index),
+            ],
+            Instruction::Fence(_) => [None, None],
+            Instruction::System(
+                SystemInstruction::CSRRW(instr) | SystemInstruction::CSRRS(instr) | SystemInstruction::CSRRC(instr),
+            ) => [Some(instr.register_source_one.index), None],
+            Instruction::System(SystemInstruction::SFENCE_VMA(instr)) => [
+                Some(instr.register_source_one.index),
+                Some(instr.register_source_two.index),
+            ],
+            Instruction::System(_) => [None, None],
+        }
+    }
+
+    /// The register values read while decoding, by operand slot. Unlike `register_source_indices` this includes
+    /// reads the operation ignores, e.g. the register at a shift amount's index, so decoding the same word with these
+    /// values gives back this instruction.
+    pub fn register_source_values(&self) -> [Option<DecodedRegisterValue>; 2] {
+        let r_type = |instr: &RType| [Some(instr.register_source_one), Some(instr.register_source_two)];
+        let i_type = |instr: &IType| [Some(instr.register_source_one), None];
+        let s_type = |instr: &SType| [Some(instr.register_source_one), Some(instr.register_source_two)];
+        let b_type = |instr: &BType| [Some(instr.register_source_one), Some(instr.register_source_two)];
+
+        match self {
+            Instruction::Alu(instr) => match instr {
+                AluInstruction::LUI(_) | AluInstruction::AUIPC(_) => [None, None],
+                AluInstruction::ADDI(instr)
+                | AluInstruction::SLTI(instr)
+                | AluInstruction::SLTIU(instr)
+                | AluInstruction::XORI(instr)
+                | AluInstruction::ORI(instr)
+                | AluInstruction::ANDI(instr) => i_type(instr),
+                AluInstruction::SLLI(instr)
+                | AluInstruction::SRLI(instr)
+                | AluInstruction::SRAI(instr)
+                | AluInstruction::ADD(instr)
+                | AluInstruction::SUB(instr)
+                | AluInstruction::SLL(instr)
+                | AluInstruction::SLT(instr)
+                | AluInstruction::SLTU(instr)
+                | AluInstruction::XOR(instr)
+                | AluInstruction::SRL(instr)
+                | AluInstruction::SRA(instr)
+                | AluInstruction::OR(instr)
+                | AluInstruction::AND(instr) => r_type(instr),
+            },
+            Instruction::Branching(BranchingInstruction::JAL(_)) => [None, None],
+            Instruction::Branching(BranchingInstruction::JALR(instr)) => i_type(instr),
+            Instruction::Branching(
+                BranchingInstruction::BEQ(instr)
+                | BranchingInstruction::BNE(instr)
+                | BranchingInstruction::BLT(instr)
+                | BranchingInstruction::BGE(instr)
+                | BranchingInstruction::BLTU(instr)
+                | BranchingInstruction::BGEU(instr),
+            ) => b_type(instr),
+            Instruction::MemoryLoad(
+                MemoryLoadInstruction::LB(instr)
+                | MemoryLoadInstruction::LH(instr)
+                | MemoryLoadInstruction::LW(instr)
+                | MemoryLoadInstruction::LBU(instr)
+                | MemoryLoadInstruction::LHU(instr),
+            ) => i_type(instr),
+            Instruction::MemoryStore(
+                MemoryStoreInstruction::SB(instr)
+                | MemoryStoreInstruction::SH(instr)
+                | MemoryStoreInstruction::SW(instr),
+            ) => s_type(instr),
+            Instruction::Fence(FenceInstruction::FENCE(instr) | FenceInstruction::FENCE_I(instr)) => i_type(instr),
+            Instruction::System(
+                SystemInstruction::ECALL(instr)
+                | SystemInstruction::EBREAK(instr)
+                | SystemInstruction::SRET(instr)
+                | SystemInstruction::MRET(instr)
+                | SystemInstruction::WFI(instr)
+                | SystemInstruction::SFENCE_VMA(instr),
+            ) => r_type(instr),
+            Instruction::System(
+                SystemInstruction::CSRRW(instr)
+                | SystemInstruction::CSRRS(instr)
+                | SystemInstruction::CSRRC(instr)
+                | SystemInstruction::CSRRWI(instr)
+                | SystemInstruction::CSRRSI(instr)
+                | SystemInstruction::CSRRCI(instr),
+            ) => i_type(instr),
+        }
+    }
+}
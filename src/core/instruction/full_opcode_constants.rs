@@ -1,3 +1,5 @@
+#![allow(clippy::unusual_byte_groupings)]
+
 pub const LUI: u32 = 0b0110111;
 pub const AUIPC: u32 = 0b0010111;
 pub const JAL: u32 = 0b1101111;
@@ -35,3 +37,19 @@ pub const SRL: u32 = 0b101_0110011;
 pub const SRA: u32 = 0b0100000_101_0110011;
 pub const OR: u32 = 0b110_0110011;
 pub const AND: u32 = 0b111_0110011;
+pub const FENCE: u32 = 0b000_0001111;
+pub const FENCE_I: u32 = 0b001_0001111;
+pub const SFENCE_VMA: u32 = 0b0001001_000_1110011;
+pub const CSRRW: u32 = 0b001_1110011;
+pub const CSRRS: u32 = 0b010_1110011;
+pub const CSRRC: u32 = 0b011_1110011;
+pub const CSRRWI: u32 = 0b101_1110011;
+pub const CSRRSI: u32 = 0b110_1110011;
+pub const CSRRCI: u32 = 0b111_1110011;
+
+// The remaining privileged instructions have no operands, so their full opcode is the whole instruction.
+pub const ECALL: u32 = 0x0000_0073;
+pub const EBREAK: u32 = 0x0010_0073;
+pub const SRET: u32 = 0x1020_0073;
+pub const MRET: u32 = 0x3020_0073;
+pub const WFI: u32 = 0x1050_0073;
@@ -7,3 +7,5 @@ pub const LOAD: u32 = 0b0000011;
 pub const STORE: u32 = 0b0100011;
 pub const ALU_IMMEDIATE: u32 = 0b0010011;
 pub const ALU: u32 = 0b0110011;
+pub const MISC_MEM: u32 = 0b0001111;
+pub const SYSTEM: u32 = 0b1110011;
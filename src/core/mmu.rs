@@ -0,0 +1,506 @@
+use super::bus::{AccessError, BusInterface, BusReadResponse, BusWriteResponse};
+use super::csr::{ControlStatusRegisters, PrivilegeLevel, MSTATUS_MPP, MSTATUS_MPRV, MSTATUS_MXR, MSTATUS_SUM};
+use super::exception::Exception;
+use super::pmp;
+use super::snapshot::{Snapshot, SnapshotError, SnapshotReader, SnapshotWriter};
+
+const PAGE_SIZE: u64 = 4096;
+const PTE_SIZE: u64 = 4;
+const LEVELS: usize = 2;
+
+const PTE_V: u32 = 1 << 0;
+const PTE_R: u32 = 1 << 1;
+const PTE_W: u32 = 1 << 2;
+const PTE_X: u32 = 1 << 3;
+const PTE_U: u32 = 1 << 4;
+const PTE_G: u32 = 1 << 5;
+const PTE_A: u32 = 1 << 6;
+const PTE_D: u32 = 1 << 7;
+
+pub const DEFAULT_TLB_ENTRIES: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessType {
+    Instruction,
+    Load,
+    Store,
+}
+
+impl AccessType {
+    pub fn page_fault(self, address: u32) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionPageFault { address },
+            AccessType::Load => Exception::LoadPageFault { address },
+            AccessType::Store => Exception::StorePageFault { address },
+        }
+    }
+
+    pub fn access_fault(self, address: u32) -> Exception {
+        match self {
+            AccessType::Instruction => Exception::InstructionAccessFault { address },
+            AccessType::Load => Exception::LoadAccessFault { address },
+            AccessType::Store => Exception::StoreAccessFault { address },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MmuStatistics {
+    pub hits: u64,
+    pub misses: u64,
+    pub walks: u64,
+}
+
+#[derive(Clone, Copy)]
+struct TlbEntry {
+    virtual_page_number: u32,
+    physical_page_number: u32,
+    asid: u32,
+    flags: u32,
+    level: usize,
+    pte_address: u32,
+}
+
+impl TlbEntry {
+    fn matches(&self, virtual_page_number: u32, asid: u32) -> bool {
+        let shift = 10 * self.level;
+        (self.virtual_page_number >> shift) == (virtual_page_number >> shift)
+            && (self.flags & PTE_G != 0 || self.asid == asid)
+    }
+
+    fn physical_address(&self, address: u32) -> u64 {
+        let page_offset_bits = 12 + 10 * self.level;
+        let base = (self.physical_page_number as u64) << 12;
+        let offset_mask = (1u64 << page_offset_bits) - 1;
+        (base & !offset_mask) | (address as u64 & offset_mask)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct HeldTranslation {
+    address: u32,
+    access: AccessType,
+    physical_address: u32,
+}
+
+/// Sv32 address translation with a fully associative TLB that is refilled round robin.
+#[derive(Clone)]
+pub struct Mmu {
+    tlb: Box<[Option<TlbEntry>]>,
+    next_victim: usize,
+    statistics: MmuStatistics,
+    held_instruction: Option<HeldTranslation>,
+    held_data: Option<HeldTranslation>,
+}
+
+impl Default for Mmu {
+    fn default() -> Self {
+        Self::new(DEFAULT_TLB_ENTRIES)
+    }
+}
+
+impl Mmu {
+    pub fn new(tlb_entries: usize) -> Self {
+        Mmu {
+            tlb: vec![None; tlb_entries].into_boxed_slice(),
+            next_victim: 0,
+            statistics: MmuStatistics::default(),
+            held_instruction: None,
+            held_data: None,
+        }
+    }
+
+    pub fn statistics(&self) -> MmuStatistics {
+        self.statistics
+    }
+
+    /// SFENCE.VMA semantics: `None` for either argument means every address or every address space. Global
+    /// mappings survive a fence that names an address space.
+    pub fn flush(&mut self, address: Option<u32>, asid: Option<u32>) {
+        self.held_instruction = None;
+        self.held_data = None;
+
+        for slot in self.tlb.iter_mut() {
+            let remove = match slot {
+                Some(entry) => {
+                    let address_matches = address.is_none_or(|address| entry.matches(address >> 12, entry.asid));
+                    let asid_matches = asid.is_none_or(|asid| entry.flags & PTE_G == 0 && entry.asid == asid);
+                    address_matches && asid_matches
+                }
+                None => false,
+            };
+
+            if remove {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Keeps the translation of an access the bus deferred, so repeating the access neither walks nor counts again.
+    pub fn hold_translation(&mut self, address: u32, access: AccessType, physical_address: u32) {
+        *self.held_slot(access) = Some(HeldTranslation { address, access, physical_address });
+    }
+
+    fn held_slot(&mut self, access: AccessType) -> &mut Option<HeldTranslation> {
+        match access {
+            AccessType::Instruction => &mut self.held_instruction,
+            AccessType::Load | AccessType::Store => &mut self.held_data,
+        }
+    }
+
+    pub fn translate<M>(
+        &mut self,
+        address: u32,
+        access: AccessType,
+        csrs: &ControlStatusRegisters,
+        memory: &mut M,
+    ) -> Result<u32, AccessError>
+    where
+        M: BusInterface<u32, u32>,
+    {
+        if let Some(held) = self.held_slot(access).take() {
+            if held.address == address && held.access == access {
+                return Ok(held.physical_address);
+            }
+        }
+
+        let privilege_level = effective_privilege_level(access, csrs);
+        let satp = csrs.satp();
+
+        if privilege_level == PrivilegeLevel::Machine || satp >> 31 == 0 {
+            return Ok(address);
+        }
+
+        let asid = (satp >> 22) & 0x1ff;
+        let virtual_page_number = address >> 12;
+
+        let cached = self.tlb.iter().flatten().find(|entry| entry.matches(virtual_page_number, asid)).copied();
+
+        let entry = match cached {
+            // A store to a clean page goes back to the page table so the dirty bit gets recorded.
+            Some(entry) if !(access == AccessType::Store && entry.flags & PTE_D == 0) => {
+                self.statistics.hits += 1;
+                entry
+            }
+            _ => {
+                // A walk waiting on the bus is repeated from the start next cycle, it only counts once it finishes.
+                let walk = self.walk(address, access, privilege_level, csrs, memory);
+                if !matches!(walk, Err(AccessError::Deferred)) {
+                    self.statistics.misses += 1;
+                    self.statistics.walks += 1;
+                }
+
+                let entry = walk?;
+                self.insert(entry);
+                entry
+            }
+        };
+
+        check_permissions(entry.flags, access, privilege_level, csrs.mstatus())
+            .ok_or_else(|| access.page_fault(address))?;
+
+        Ok(u32::try_from(entry.physical_address(address)).map_err(|_| access.access_fault(address))?)
+    }
+
+    fn walk<M>(
+        &mut self,
+        address: u32,
+        access: AccessType,
+        privilege_level: PrivilegeLevel,
+        csrs: &ControlStatusRegisters,
+        memory: &mut M,
+    ) -> Result<TlbEntry, AccessError>
+    where
+        M: BusInterface<u32, u32>,
+    {
+        let satp = csrs.satp();
+        let virtual_page_numbers = [(address >> 12) & 0x3ff, (address >> 22) & 0x3ff];
+        let mut table = (satp & 0x3f_ffff) as u64 * PAGE_SIZE;
+
+        for level in (0..LEVELS).rev() {
+            let pte_address = u32::try_from(table + virtual_page_numbers[level] as u64 * PTE_SIZE)
+                .map_err(|_| access.access_fault(address))?;
+
+            // The walk itself is an implicit supervisor access and subject to PMP.
+            if !pmp::permits(csrs, pte_address, 4, AccessType::Load, PrivilegeLevel::Supervisor) {
+                return Err(access.access_fault(address).into());
+            }
+
+            let pte = match BusInterface::<u32, u32>::read(memory, pte_address) {
+                BusReadResponse::Success(pte) => pte,
+                BusReadResponse::Deferred => return Err(AccessError::Deferred),
+                _ => return Err(access.access_fault(address).into()),
+            };
+
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+                return Err(access.page_fault(address).into());
+            }
+
+            let physical_page_number = pte >> 10;
+
+            if pte & (PTE_R | PTE_X) == 0 {
+                table = physical_page_number as u64 * PAGE_SIZE;
+                continue;
+            }
+
+            // Superpages must be aligned to their size.
+            if level > 0 && physical_page_number & ((1 << (10 * level)) - 1) != 0 {
+                return Err(access.page_fault(address).into());
+            }
+
+            check_permissions(pte, access, privilege_level, csrs.mstatus())
+                .ok_or_else(|| access.page_fault(address))?;
+
+            let mut flags = pte & 0xff;
+            let updated = flags | PTE_A | if access == AccessType::Store { PTE_D } else { 0 };
+
+            if updated != flags {
+                if !pmp::permits(csrs, pte_address, 4, AccessType::Store, PrivilegeLevel::Supervisor) {
+                    return Err(access.access_fault(address).into());
+                }
+
+                let updated_pte = (pte & !0xff) | updated;
+                match BusInterface::<u32, u32>::write(memory, pte_address, updated_pte) {
+                    BusWriteResponse::Success => flags = updated,
+                    BusWriteResponse::Deferred => return Err(AccessError::Deferred),
+                    _ => return Err(access.access_fault(address).into()),
+                }
+            }
+
+            return Ok(TlbEntry {
+                virtual_page_number: address >> 12,
+                physical_page_number,
+                asid: (satp >> 22) & 0x1ff,
+                flags,
+                level,
+                pte_address,
+            });
+        }
+
+        Err(access.page_fault(address).into())
+    }
+
+    fn insert(&mut self, entry: TlbEntry) {
+        if self.tlb.is_empty() {
+            return;
+        }
+
+        // Refreshing an existing mapping, e.g. after the dirty bit was set, reuses its slot.
+        let existing = self.tlb.iter().position(|slot| slot.is_some_and(|slot| slot.pte_address == entry.pte_address));
+
+        let index = match existing {
+            Some(index) => index,
+            None => {
+                let index = self.next_victim;
+                self.next_victim = (self.next_victim + 1) % self.tlb.len();
+                index
+            }
+        };
+
+        self.tlb[index] = Some(entry);
+    }
+}
+
+impl Snapshot for Mmu {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("mmu");
+        snapshot.usize(self.tlb.len());
+        for entry in self.tlb.iter() {
+            snapshot.option(entry, |snapshot, entry| {
+                snapshot.u32(entry.virtual_page_number);
+                snapshot.u32(entry.physical_page_number);
+                snapshot.u32(entry.asid);
+                snapshot.u32(entry.flags);
+                snapshot.usize(entry.level);
+                snapshot.u32(entry.pte_address);
+            });
+        }
+        snapshot.usize(self.next_victim);
+        snapshot.u64(self.statistics.hits);
+        snapshot.u64(self.statistics.misses);
+        snapshot.u64(self.statistics.walks);
+        for held in [&self.held_instruction, &self.held_data] {
+            snapshot.option(held, |snapshot, held| {
+                snapshot.u32(held.address);
+                snapshot.u8(held.access as u8);
+                snapshot.u32(held.physical_address);
+            });
+        }
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        snapshot.section("mmu")?;
+        snapshot.expect("the number of TLB entries", self.tlb.len(), SnapshotReader::usize)?;
+        for entry in self.tlb.iter_mut() {
+            *entry = snapshot.option(|snapshot| {
+                Ok(TlbEntry {
+                    virtual_page_number: snapshot.u32()?,
+                    physical_page_number: snapshot.u32()?,
+                    asid: snapshot.u32()?,
+                    flags: snapshot.u32()?,
+                    level: snapshot.usize()?,
+                    pte_address: snapshot.u32()?,
+                })
+            })?;
+        }
+        self.next_victim = snapshot.usize()?;
+        self.statistics = MmuStatistics { hits: snapshot.u64()?, misses: snapshot.u64()?, walks: snapshot.u64()? };
+        for held in [&mut self.held_instruction, &mut self.held_data] {
+            *held = snapshot.option(|snapshot| {
+                Ok(HeldTranslation {
+                    address: snapshot.u32()?,
+                    access: match snapshot.u8()? {
+                        0 => AccessType::Instruction,
+                        1 => AccessType::Load,
+                        _ => AccessType::Store,
+                    },
+                    physical_address: snapshot.u32()?,
+                })
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Loads and stores from M mode use the privilege level in `mstatus.MPP` while `mstatus.MPRV` is set.
+pub fn effective_privilege_level(access: AccessType, csrs: &ControlStatusRegisters) -> PrivilegeLevel {
+    let mstatus = csrs.mstatus();
+
+    match access {
+        AccessType::Load | AccessType::Store
+            if csrs.privilege_level() == PrivilegeLevel::Machine && mstatus & MSTATUS_MPRV != 0 =>
+        {
+            PrivilegeLevel::from_bits((mstatus & MSTATUS_MPP) >> 11)
+        }
+        _ => csrs.privilege_level(),
+    }
+}
+
+fn check_permissions(flags: u32, access: AccessType, privilege_level: PrivilegeLevel, mstatus: u32) -> Option<()> {
+    let user_page = flags & PTE_U != 0;
+
+    let privilege_allowed = match privilege_level {
+        PrivilegeLevel::User => user_page,
+        PrivilegeLevel::Supervisor => !user_page || (access != AccessType::Instruction && mstatus & MSTATUS_SUM != 0),
+        PrivilegeLevel::Machine => true,
+    };
+
+    let access_allowed = match access {
+        AccessType::Instruction => flags & PTE_X != 0,
+        AccessType::Load => flags & PTE_R != 0 || (mstatus & MSTATUS_MXR != 0 && flags & PTE_X != 0),
+        AccessType::Store => flags & PTE_W != 0,
+    };
+
+    (privilege_allowed && access_allowed).then_some(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::csr::address_constants::{MSTATUS, PMPADDR0, PMPCFG0, SATP};
+    use crate::core::pmp::{PMP_NAPOT, PMP_R, PMP_W};
+    use crate::memory::Memory;
+
+    const ROOT_TABLE: u32 = 0x1000;
+    const LEAF_TABLE: u32 = 0x2000;
+    const VIRTUAL_ADDRESS: u32 = 0x0040_1234;
+    const PHYSICAL_PAGE: u32 = 0x3000;
+
+    fn read_word(memory: &Memory, address: u32) -> u32 {
+        match BusInterface::<u32, u32>::read(memory, address) {
+            BusReadResponse::Success(value) => value,
+            _ => panic!("bad read at {:x}", address),
+        }
+    }
+
+    fn map_page(memory: &mut Memory, flags: u32) -> u32 {
+        let root_pte = ((LEAF_TABLE >> 12) << 10) | PTE_V;
+        let leaf_pte_address = LEAF_TABLE + ((VIRTUAL_ADDRESS >> 12) & 0x3ff) * 4;
+        BusInterface::<u32, u32>::write(memory, ROOT_TABLE + (VIRTUAL_ADDRESS >> 22) * 4, root_pte);
+        BusInterface::<u32, u32>::write(memory, leaf_pte_address, ((PHYSICAL_PAGE >> 12) << 10) | flags | PTE_V);
+        leaf_pte_address
+    }
+
+    fn supervisor_csrs() -> ControlStatusRegisters {
+        let mut csrs = ControlStatusRegisters::new();
+        csrs.write(SATP, (1 << 31) | (ROOT_TABLE >> 12));
+        csrs.write(PMPADDR0, u32::MAX);
+        csrs.write(PMPCFG0, (PMP_NAPOT | PMP_R | PMP_W) as u32);
+        csrs.set_privilege_level(PrivilegeLevel::Supervisor);
+        csrs
+    }
+
+    #[test]
+    fn bare_mode_does_not_translate() {
+        let mut memory = Memory::new(0x4000);
+        let mut mmu = Mmu::new(4);
+        let mut csrs = ControlStatusRegisters::new();
+        csrs.set_privilege_level(PrivilegeLevel::User);
+
+        let translated = mmu.translate(0x1234, AccessType::Load, &csrs, &mut memory);
+
+        assert_eq!(translated, Ok(0x1234));
+        assert_eq!(mmu.statistics(), MmuStatistics::default());
+    }
+
+    #[test]
+    fn two_level_walk_translates_and_sets_accessed_and_dirty() {
+        let mut memory = Memory::new(0x4000);
+        let pte_address = map_page(&mut memory, PTE_R | PTE_W);
+        let mut mmu = Mmu::new(4);
+        let csrs = supervisor_csrs();
+
+        let loaded = mmu.translate(VIRTUAL_ADDRESS, AccessType::Load, &csrs, &mut memory);
+        assert_eq!(loaded, Ok(PHYSICAL_PAGE | 0x234));
+        assert_eq!(read_word(&memory, pte_address) & (PTE_A | PTE_D), PTE_A);
+
+        let stored = mmu.translate(VIRTUAL_ADDRESS, AccessType::Store, &csrs, &mut memory);
+        assert_eq!(stored, Ok(PHYSICAL_PAGE | 0x234));
+        assert_eq!(read_word(&memory, pte_address) & (PTE_A | PTE_D), PTE_A | PTE_D);
+    }
+
+    #[test]
+    fn permission_violations_raise_page_faults() {
+        let mut memory = Memory::new(0x4000);
+        map_page(&mut memory, PTE_R);
+        let mut mmu = Mmu::new(4);
+        let csrs = supervisor_csrs();
+
+        let stored = mmu.translate(VIRTUAL_ADDRESS, AccessType::Store, &csrs, &mut memory);
+        let fetched = mmu.translate(VIRTUAL_ADDRESS, AccessType::Instruction, &csrs, &mut memory);
+
+        assert_eq!(stored, Err(Exception::StorePageFault { address: VIRTUAL_ADDRESS }.into()));
+        assert_eq!(fetched, Err(Exception::InstructionPageFault { address: VIRTUAL_ADDRESS }.into()));
+    }
+
+    #[test]
+    fn supervisor_needs_sum_to_load_from_user_pages() {
+        let mut memory = Memory::new(0x4000);
+        map_page(&mut memory, PTE_R | PTE_U);
+        let mut mmu = Mmu::new(4);
+        let mut csrs = supervisor_csrs();
+
+        let without_sum = mmu.translate(VIRTUAL_ADDRESS, AccessType::Load, &csrs, &mut memory);
+        csrs.write(MSTATUS, MSTATUS_SUM);
+        let with_sum = mmu.translate(VIRTUAL_ADDRESS, AccessType::Load, &csrs, &mut memory);
+
+        assert_eq!(without_sum, Err(Exception::LoadPageFault { address: VIRTUAL_ADDRESS }.into()));
+        assert_eq!(with_sum, Ok(PHYSICAL_PAGE | 0x234));
+    }
+
+    #[test]
+    fn tlb_hits_until_flushed() {
+        let mut memory = Memory::new(0x4000);
+        map_page(&mut memory, PTE_R | PTE_A);
+        let mut mmu = Mmu::new(4);
+        let csrs = supervisor_csrs();
+
+        for _ in 0..3 {
+            mmu.translate(VIRTUAL_ADDRESS, AccessType::Load, &csrs, &mut memory).unwrap();
+        }
+        mmu.flush(Some(VIRTUAL_ADDRESS), None);
+        mmu.translate(VIRTUAL_ADDRESS, AccessType::Load, &csrs, &mut memory).unwrap();
+
+        assert_eq!(mmu.statistics(), MmuStatistics { hits: 2, misses: 2, walks: 2 });
+    }
+}
@@ -1,4 +1,4 @@
-use super::{bus::BusInterface, register_file::RegisterFile};
+use super::{bus::BusInterface, hart::HartState};
 
 pub trait Pipeline<M>
 where
@@ -9,5 +9,5 @@ where
     M: BusInterface<u32, u32>,
 {
     fn new() -> Self;
-    fn execute(&mut self, pc: u32, register_file: &mut RegisterFile, memory: &mut M) -> u32;
+    fn execute(&mut self, pc: u32, state: &mut HartState, memory: &mut M) -> u32;
 }
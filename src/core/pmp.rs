@@ -0,0 +1,186 @@
+use super::csr::{ControlStatusRegisters, PrivilegeLevel};
+use super::exception::Exception;
+use super::mmu::{effective_privilege_level, AccessType};
+
+pub const PMP_ENTRIES: usize = 16;
+
+pub const PMP_R: u8 = 1 << 0;
+pub const PMP_W: u8 = 1 << 1;
+pub const PMP_X: u8 = 1 << 2;
+pub const PMP_ADDRESS_MATCHING: u8 = 0b11 << 3;
+pub const PMP_OFF: u8 = 0b00 << 3;
+pub const PMP_TOR: u8 = 0b01 << 3;
+pub const PMP_NA4: u8 = 0b10 << 3;
+pub const PMP_NAPOT: u8 = 0b11 << 3;
+pub const PMP_LOCK: u8 = 1 << 7;
+
+/// Checks a physical access of `size` bytes against the PMP entries, reporting the fault at `virtual_address`.
+pub fn check(
+    csrs: &ControlStatusRegisters,
+    virtual_address: u32,
+    physical_address: u32,
+    size: u32,
+    access: AccessType,
+) -> Result<(), Exception> {
+    let privilege_level = effective_privilege_level(access, csrs);
+
+    match permits(csrs, physical_address, size, access, privilege_level) {
+        true => Ok(()),
+        false => Err(access.access_fault(virtual_address)),
+    }
+}
+
+/// The lowest numbered entry that overlaps the access decides it. An access that only partially falls inside
+/// that entry fails. Without a matching entry only M mode is allowed through.
+pub fn permits(
+    csrs: &ControlStatusRegisters,
+    address: u32,
+    size: u32,
+    access: AccessType,
+    privilege_level: PrivilegeLevel,
+) -> bool {
+    let start = address as u64;
+    let end = start + size as u64;
+
+    for index in 0..PMP_ENTRIES {
+        let configuration = csrs.pmp_configuration(index);
+
+        let (low, high) = match entry_range(csrs, index, configuration) {
+            Some(range) => range,
+            None => continue,
+        };
+
+        if end <= low || high <= start {
+            continue;
+        }
+
+        if start < low || high < end {
+            return false;
+        }
+
+        if privilege_level == PrivilegeLevel::Machine && configuration & PMP_LOCK == 0 {
+            return true;
+        }
+
+        let required = match access {
+            AccessType::Instruction => PMP_X,
+            AccessType::Load => PMP_R,
+            AccessType::Store => PMP_W,
+        };
+
+        return configuration & required != 0;
+    }
+
+    privilege_level == PrivilegeLevel::Machine
+}
+
+/// The byte range `[low, high)` covered by an entry. The address registers hold bits 33:2 of the address.
+fn entry_range(csrs: &ControlStatusRegisters, index: usize, configuration: u8) -> Option<(u64, u64)> {
+    let address = csrs.pmp_address(index) as u64;
+
+    match configuration & PMP_ADDRESS_MATCHING {
+        PMP_TOR => {
+            let low = match index {
+                0 => 0,
+                _ => (csrs.pmp_address(index - 1) as u64) << 2,
+            };
+            let high = address << 2;
+            (low < high).then_some((low, high))
+        }
+        PMP_NA4 => Some((address << 2, (address << 2) + 4)),
+        PMP_NAPOT => {
+            let trailing_ones = address.trailing_ones().min(32);
+            let size = 1u64 << (trailing_ones + 3);
+            let low = (address << 2) & !(size - 1);
+            Some((low, low + size))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::csr::address_constants::{PMPADDR0, PMPCFG0};
+
+    fn napot(base: u32, size: u32) -> u32 {
+        (base >> 2) | ((size >> 3) - 1)
+    }
+
+    fn csrs_with_user_region() -> ControlStatusRegisters {
+        let mut csrs = ControlStatusRegisters::new();
+        csrs.write(PMPADDR0, napot(0x8000_0000, 0x1000));
+        csrs.write(PMPCFG0, (PMP_NAPOT | PMP_R | PMP_X) as u32);
+        csrs
+    }
+
+    #[test]
+    fn user_mode_is_denied_outside_granted_regions() {
+        let csrs = csrs_with_user_region();
+
+        assert!(permits(&csrs, 0x8000_0000, 4, AccessType::Load, PrivilegeLevel::User));
+        assert!(permits(&csrs, 0x8000_0ffc, 4, AccessType::Instruction, PrivilegeLevel::User));
+        assert!(!permits(&csrs, 0x8000_1000, 4, AccessType::Load, PrivilegeLevel::User));
+        assert!(!permits(&csrs, 0x7fff_fffc, 4, AccessType::Instruction, PrivilegeLevel::User));
+        assert!(!permits(&csrs, 0x8000_0000, 4, AccessType::Store, PrivilegeLevel::User));
+    }
+
+    #[test]
+    fn accesses_straddling_a_region_boundary_are_denied() {
+        let csrs = csrs_with_user_region();
+
+        assert!(!permits(&csrs, 0x8000_0ffe, 4, AccessType::Load, PrivilegeLevel::User));
+        assert!(!permits(&csrs, 0x8000_0ffe, 4, AccessType::Load, PrivilegeLevel::Machine));
+    }
+
+    #[test]
+    fn machine_mode_is_only_checked_against_locked_entries() {
+        let mut csrs = csrs_with_user_region();
+
+        assert!(permits(&csrs, 0x8000_0000, 4, AccessType::Store, PrivilegeLevel::Machine));
+        assert!(permits(&csrs, 0x1000, 4, AccessType::Store, PrivilegeLevel::Machine));
+
+        csrs.write(PMPCFG0, (PMP_LOCK | PMP_NAPOT | PMP_R | PMP_X) as u32);
+
+        assert!(!permits(&csrs, 0x8000_0000, 4, AccessType::Store, PrivilegeLevel::Machine));
+        assert!(permits(&csrs, 0x8000_0000, 4, AccessType::Load, PrivilegeLevel::Machine));
+    }
+
+    #[test]
+    fn locked_entries_ignore_writes() {
+        let mut csrs = ControlStatusRegisters::new();
+        csrs.write(PMPADDR0, 0x100);
+        csrs.write(PMPADDR0 + 1, 0x200);
+        csrs.write(PMPCFG0, ((PMP_LOCK | PMP_TOR | PMP_R) as u32) << 8);
+
+        csrs.write(PMPADDR0, 0x0);
+        csrs.write(PMPADDR0 + 1, 0x300);
+        csrs.write(PMPCFG0, 0);
+
+        assert_eq!(csrs.read(PMPADDR0), Some(0x100));
+        assert_eq!(csrs.read(PMPADDR0 + 1), Some(0x200));
+        assert_eq!(csrs.pmp_configuration(1), PMP_LOCK | PMP_TOR | PMP_R);
+    }
+
+    #[test]
+    fn lower_numbered_entries_take_priority() {
+        let mut csrs = ControlStatusRegisters::new();
+        csrs.write(PMPADDR0, 0x1000 >> 2);
+        csrs.write(PMPADDR0 + 1, napot(0, 0x1_0000));
+        csrs.write(PMPCFG0, (PMP_TOR as u32) | ((PMP_NAPOT | PMP_R | PMP_W) as u32) << 8);
+
+        assert!(!permits(&csrs, 0x800, 4, AccessType::Load, PrivilegeLevel::User));
+        assert!(permits(&csrs, 0x1800, 4, AccessType::Store, PrivilegeLevel::User));
+        assert!(!permits(&csrs, 0x2_0000, 4, AccessType::Load, PrivilegeLevel::Supervisor));
+    }
+
+    #[test]
+    fn na4_covers_a_single_word() {
+        let mut csrs = ControlStatusRegisters::new();
+        csrs.write(PMPADDR0, 0x40 >> 2);
+        csrs.write(PMPCFG0, (PMP_NA4 | PMP_R) as u32);
+
+        assert!(permits(&csrs, 0x40, 4, AccessType::Load, PrivilegeLevel::User));
+        assert!(!permits(&csrs, 0x44, 1, AccessType::Load, PrivilegeLevel::User));
+    }
+}
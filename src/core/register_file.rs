@@ -1,3 +1,46 @@
+use super::snapshot::{Snapshot, SnapshotError, SnapshotReader, SnapshotWriter};
+
+/// The calling convention names of x0 to x31, as assemblers print them.
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7", "s2",
+    "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+// Register indices by ABI name, for building instructions in code.
+pub const ZERO: u32 = 0;
+pub const RA: u32 = 1;
+pub const SP: u32 = 2;
+pub const GP: u32 = 3;
+pub const TP: u32 = 4;
+pub const T0: u32 = 5;
+pub const T1: u32 = 6;
+pub const T2: u32 = 7;
+pub const S0: u32 = 8;
+pub const S1: u32 = 9;
+pub const A0: u32 = 10;
+pub const A1: u32 = 11;
+pub const A2: u32 = 12;
+pub const A3: u32 = 13;
+pub const A4: u32 = 14;
+pub const A5: u32 = 15;
+pub const A6: u32 = 16;
+pub const A7: u32 = 17;
+pub const S2: u32 = 18;
+pub const S3: u32 = 19;
+pub const S4: u32 = 20;
+pub const S5: u32 = 21;
+pub const S6: u32 = 22;
+pub const S7: u32 = 23;
+pub const S8: u32 = 24;
+pub const S9: u32 = 25;
+pub const S10: u32 = 26;
+pub const S11: u32 = 27;
+pub const T3: u32 = 28;
+pub const T4: u32 = 29;
+pub const T5: u32 = 30;
+pub const T6: u32 = 31;
+
+#[derive(Clone)]
 pub struct RegisterFile {
     registers: Box<[u32]>,
 }
@@ -37,6 +80,23 @@ impl RegisterFile {
     }
 }
 
+impl Snapshot for RegisterFile {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("registers");
+        snapshot.usize(self.registers.len());
+        self.registers.iter().for_each(|&register| snapshot.u32(register));
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        snapshot.section("registers")?;
+        snapshot.expect("the number of registers", self.registers.len(), SnapshotReader::usize)?;
+        for register in self.registers.iter_mut() {
+            *register = snapshot.u32()?;
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,304 @@
+use std::fmt;
+
+const MAGIC: [u8; 8] = *b"RVVMSNAP";
+/// Bumped whenever the layout of any part changes, older snapshots are then rejected.
+pub const VERSION: u32 = 5;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SnapshotError {
+    NotASnapshot,
+    UnsupportedVersion(u32),
+    Truncated,
+    /// The snapshot holds more than the machine restored into it.
+    TrailingData,
+    /// The machine is configured differently from the one the snapshot was taken of, e.g. it has another cache size.
+    Mismatch(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::NotASnapshot => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "snapshot version {version} is not supported, expected version {VERSION}")
+            }
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::TrailingData => write!(f, "snapshot has data left over"),
+            SnapshotError::Mismatch(message) => write!(f, "snapshot does not match the machine: {message}"),
+        }
+    }
+}
+
+/// Machine state that can be saved and later restored exactly.
+///
+/// Restoring happens in place, into a machine built with the same configuration as the saved one: the same memory
+/// size, caches, predictor and so on. Configuration is saved as well so a mismatch is reported rather than restored
+/// into the wrong shape. Outputs like commit logs and tracers are not machine state and stay as they are.
+pub trait Snapshot {
+    fn save(&self, snapshot: &mut SnapshotWriter);
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError>;
+}
+
+/// Saves `parts`, e.g. the hart and its memory, into a versioned snapshot.
+pub fn save(parts: &[&dyn Snapshot]) -> Vec<u8> {
+    let mut snapshot = SnapshotWriter { bytes: MAGIC.to_vec() };
+    snapshot.u32(VERSION);
+    for part in parts {
+        part.save(&mut snapshot);
+    }
+    snapshot.bytes
+}
+
+/// Restores `parts` from a snapshot saved with the same parts in the same order.
+pub fn restore(parts: &mut [&mut dyn Snapshot], bytes: &[u8]) -> Result<(), SnapshotError> {
+    if !bytes.starts_with(&MAGIC) {
+        return Err(SnapshotError::NotASnapshot);
+    }
+
+    let mut snapshot = SnapshotReader { bytes, position: MAGIC.len() };
+    match snapshot.u32()? {
+        VERSION => {}
+        version => return Err(SnapshotError::UnsupportedVersion(version)),
+    }
+    for part in parts {
+        part.restore(&mut snapshot)?;
+    }
+
+    match snapshot.position == bytes.len() {
+        true => Ok(()),
+        false => Err(SnapshotError::TrailingData),
+    }
+}
+
+/// Appends little endian values.
+pub struct SnapshotWriter {
+    bytes: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    /// Marks the start of a part, so restoring into the wrong part is reported by name.
+    pub fn section(&mut self, name: &str) {
+        self.bytes(name.as_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Lengths are saved as 64 bit values so snapshots do not depend on the host.
+    pub fn usize(&mut self, value: usize) {
+        self.u64(value as u64);
+    }
+
+    /// Saved with their length.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.usize(bytes.len());
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Saves whether there is a value, then the value.
+    pub fn option<T>(&mut self, value: &Option<T>, save: impl FnOnce(&mut Self, &T)) {
+        self.bool(value.is_some());
+        if let Some(value) = value {
+            save(self, value);
+        }
+    }
+}
+
+pub struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl SnapshotReader<'_> {
+    pub fn section(&mut self, name: &str) -> Result<(), SnapshotError> {
+        let found = self.bytes()?;
+        match found == name.as_bytes() {
+            true => Ok(()),
+            false => Err(SnapshotError::Mismatch(format!("expected {name}, found {}", String::from_utf8_lossy(found)))),
+        }
+    }
+
+    pub fn bool(&mut self) -> Result<bool, SnapshotError> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, SnapshotError> {
+        usize::try_from(self.u64()?).map_err(|_| SnapshotError::Truncated)
+    }
+
+    pub fn bytes(&mut self) -> Result<&[u8], SnapshotError> {
+        let length = self.usize()?;
+        self.take(length)
+    }
+
+    pub fn option<T>(
+        &mut self,
+        restore: impl FnOnce(&mut Self) -> Result<T, SnapshotError>,
+    ) -> Result<Option<T>, SnapshotError> {
+        match self.bool()? {
+            true => restore(self).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    /// Reads a saved configuration value and checks the machine has the same.
+    pub fn expect<T: PartialEq + fmt::Debug>(
+        &mut self,
+        what: &str,
+        expected: T,
+        read: impl FnOnce(&mut Self) -> Result<T, SnapshotError>,
+    ) -> Result<(), SnapshotError> {
+        let found = read(self)?;
+        match found == expected {
+            true => Ok(()),
+            false => Err(SnapshotError::Mismatch(format!("{what} is {expected:?}, the snapshot has {found:?}"))),
+        }
+    }
+
+    /// Restores an optional part that the machine has to have exactly when the snapshot does.
+    pub fn restore_option<T: Snapshot>(&mut self, what: &str, part: &mut Option<T>) -> Result<(), SnapshotError> {
+        self.expect(what, part.is_some(), Self::bool)?;
+        match part {
+            Some(part) => part.restore(self),
+            None => Ok(()),
+        }
+    }
+
+    fn take(&mut self, length: usize) -> Result<&[u8], SnapshotError> {
+        let end = self.position.checked_add(length).ok_or(SnapshotError::Truncated)?;
+        let bytes = self.bytes.get(self.position..end).ok_or(SnapshotError::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::core::branch_predictor::{BranchPredictor, Tournament};
+    use crate::core::cache::{Cache, CacheConfiguration};
+    use crate::core::commit_log::format_commit;
+    use crate::core::hart::Hart;
+    use crate::memory::Memory;
+    use crate::simple_pipeline::SimplePipeline;
+    use crate::wait_states::WaitStates;
+
+    type Machine = Hart<WaitStates<Memory>, SimplePipeline>;
+
+    const PROGRAM: &str = "
+        li      sp, 1024
+        li      s0, 0
+loop:
+        addi    sp, sp, -8
+        sw      s0, 0(sp)
+        call    square
+        sw      a0, 4(sp)
+        lw      s0, 0(sp)
+        addi    sp, sp, 8
+        addi    s0, s0, 1
+        j       loop
+
+square:
+        mv      a0, zero
+        mv      t0, s0
+1:
+        beqz    t0, 2f
+        add     a0, a0, s0
+        addi    t0, t0, -1
+        j       1b
+2:
+        ret
+";
+
+    fn machine(memory_size: usize) -> (Machine, WaitStates<Memory>) {
+        let program = assemble(PROGRAM, 0).unwrap();
+        let mut memory = WaitStates::new(Memory::with_initial_values(program.memory_image(memory_size)), 1);
+        memory.add_region(0, 256, 2);
+
+        let mut hart = Machine::new();
+        let state = hart.state_mut();
+        state.instruction_cache = Some(Cache::new(CacheConfiguration { size: 256, ..CacheConfiguration::default() }));
+        state.data_cache = Some(Cache::new(CacheConfiguration::default()));
+        state.branch_predictor = Some(BranchPredictor::new(Box::new(Tournament::new(64, 4))));
+        hart.record_retirements();
+        (hart, memory)
+    }
+
+    fn run(hart: &mut Machine, memory: &mut WaitStates<Memory>, cycles: usize) -> Vec<String> {
+        let mut trace = Vec::new();
+        for _ in 0..cycles {
+            hart.execute(memory);
+            trace.extend(hart.retired().iter().map(format_commit));
+        }
+        trace
+    }
+
+    #[test]
+    fn restored_machine_continues_exactly_like_the_original() {
+        let (mut hart, mut memory) = machine(2048);
+        let mut continuous = run(&mut hart, &mut memory, 2000);
+        let later = run(&mut hart, &mut memory, 2000);
+        continuous.extend(later.iter().cloned());
+
+        let (mut hart, mut memory) = machine(2048);
+        run(&mut hart, &mut memory, 1777);
+        let snapshot = save(&[&hart, &memory]);
+        run(&mut hart, &mut memory, 500);
+
+        let (mut restored, mut restored_memory) = machine(2048);
+        restore(&mut [&mut restored, &mut restored_memory], &snapshot).unwrap();
+        let mut resumed = run(&mut restored, &mut restored_memory, 223);
+        assert!(!resumed.is_empty());
+        resumed.extend(run(&mut restored, &mut restored_memory, 2000));
+        assert_eq!(resumed, continuous[continuous.len() - resumed.len()..]);
+
+        // The machine snapshotted partway still continues, and restoring twice is the same as once.
+        restore(&mut [&mut hart, &mut memory], &snapshot).unwrap();
+        assert_eq!(run(&mut hart, &mut memory, 2223), resumed);
+    }
+
+    #[test]
+    fn snapshots_of_other_machines_are_rejected() {
+        let (hart, memory) = machine(2048);
+        let snapshot = save(&[&hart, &memory]);
+
+        let (mut smaller, mut smaller_memory) = machine(1024);
+        let error = restore(&mut [&mut smaller, &mut smaller_memory], &snapshot).unwrap_err();
+        assert_eq!(error, SnapshotError::Mismatch("memory is 1024 bytes, the snapshot has 2048".to_string()));
+
+        let mut newer = snapshot.clone();
+        newer[MAGIC.len()] = VERSION as u8 + 1;
+        let (mut hart, mut memory) = machine(2048);
+        let error = restore(&mut [&mut hart, &mut memory], &newer).unwrap_err();
+        assert_eq!(error, SnapshotError::UnsupportedVersion(VERSION + 1));
+        assert_eq!(restore(&mut [&mut hart], &snapshot), Err(SnapshotError::TrailingData));
+        assert_eq!(restore(&mut [&mut hart, &mut memory], &snapshot[..100]), Err(SnapshotError::Truncated));
+        assert_eq!(restore(&mut [&mut hart, &mut memory], b"RVVM"), Err(SnapshotError::NotASnapshot));
+    }
+}
@@ -2,12 +2,16 @@ pub use alu::*;
 pub use branching::*;
 pub use decoder::*;
 pub use memory_access::*;
+pub use system::*;
+pub use trap::*;
 pub use write_back::*;
 
 mod alu;
 mod branching;
 mod decoder;
 mod memory_access;
+mod system;
+mod trap;
 mod write_back;
 
 #[derive(Clone, Copy)]
@@ -20,4 +24,4 @@ pub struct RegisterWrite {
 pub struct FetchResult {
     pub captured_pc: u32,
     pub instruction: u32,
-}
\ No newline at end of file
+}
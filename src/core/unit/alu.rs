@@ -73,17 +73,18 @@ fn andi(instr: IType) -> RegisterWrite {
     RegisterWrite { index: instr.register_destination_index, value: instr.register_source_one.value & instr.immediate }
 }
 
+// The immediate shifts decode as R-type with the shift amount in the rs2 field, so it is the index, not the value.
 fn slli(instr: RType) -> RegisterWrite {
     RegisterWrite {
         index: instr.register_destination_index,
-        value: instr.register_source_one.value << instr.register_source_two.value,
+        value: instr.register_source_one.value << shift_amount(instr.register_source_two.index),
     }
 }
 
 fn srli(instr: RType) -> RegisterWrite {
     RegisterWrite {
         index: instr.register_destination_index,
-        value: instr.register_source_one.value >> instr.register_source_two.value,
+        value: instr.register_source_one.value >> shift_amount(instr.register_source_two.index),
     }
 }
 
@@ -111,14 +112,14 @@ fn sra(instr: RType) -> RegisterWrite {
 fn srl(instr: RType) -> RegisterWrite {
     RegisterWrite {
         index: instr.register_destination_index,
-        value: instr.register_source_one.value >> instr.register_source_two.value,
+        value: instr.register_source_one.value >> shift_amount(instr.register_source_two.value),
     }
 }
 
 fn sll(instr: RType) -> RegisterWrite {
     RegisterWrite {
         index: instr.register_destination_index,
-        value: instr.register_source_one.value << instr.register_source_two.value,
+        value: instr.register_source_one.value << shift_amount(instr.register_source_two.value),
     }
 }
 
@@ -146,21 +147,21 @@ fn slt(instr: RType) -> RegisterWrite {
 fn sub(instr: RType) -> RegisterWrite {
     RegisterWrite {
         index: instr.register_destination_index,
-        value: instr.register_source_two.value.wrapping_sub(instr.register_source_one.value),
+        value: instr.register_source_one.value.wrapping_sub(instr.register_source_two.value),
     }
 }
 
 fn add(instr: RType) -> RegisterWrite {
     RegisterWrite {
         index: instr.register_destination_index,
-        value: instr.register_source_one.value.wrapping_add(instr.register_source_one.value),
+        value: instr.register_source_one.value.wrapping_add(instr.register_source_two.value),
     }
 }
 
 fn srai(instr: RType) -> RegisterWrite {
     RegisterWrite {
         index: instr.register_destination_index,
-        value: arithmetic_shift(instr.register_source_one.value, instr.register_source_two.value),
+        value: arithmetic_shift(instr.register_source_one.value, instr.register_source_two.index),
     }
 }
 
@@ -173,5 +174,10 @@ fn set_less_than_unsigned(a: u32, b: u32) -> u32 {
 }
 
 fn arithmetic_shift(a: u32, shift_by: u32) -> u32 {
-    ((a as i32) >> shift_by) as u32
+    ((a as i32) >> shift_amount(shift_by)) as u32
+}
+
+/// Only the low five bits count, RV32 shifts by 0 to 31.
+fn shift_amount(shift_by: u32) -> u32 {
+    shift_by & 0x1f
 }
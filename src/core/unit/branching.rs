@@ -1,4 +1,4 @@
-use super::{super::instruction::*, FetchResult};
+use super::{super::instruction::*, FetchResult, RegisterWrite};
 use BranchingInstruction::*;
 
 pub fn branch(fetch_result: FetchResult, decode_result: BranchingInstruction) -> Option<u32> {
@@ -19,7 +19,7 @@ fn jal(fetch_result: FetchResult, instr: JType) -> Option<u32> {
 }
 
 fn jalr(instr: IType) -> Option<u32> {
-    Some(instr.register_source_one.value.wrapping_add(instr.immediate))
+    Some(instr.register_source_one.value.wrapping_add(instr.immediate) & !1)
 }
 
 fn beq(fetch_result: FetchResult, instr: BType) -> Option<u32> {
@@ -63,3 +63,14 @@ fn bgeu(fetch_result: FetchResult, instr: BType) -> Option<u32> {
         false => None,
     }
 }
+
+/// JAL and JALR write the address of the following instruction to `rd`.
+pub fn link(fetch_result: FetchResult, decode_result: BranchingInstruction) -> Option<RegisterWrite> {
+    match decode_result {
+        JAL(JType { register_destination_index: index, .. })
+        | JALR(IType { register_destination_index: index, .. }) => {
+            Some(RegisterWrite { index, value: fetch_result.captured_pc.wrapping_add(4) })
+        }
+        _ => None,
+    }
+}
@@ -8,9 +8,11 @@ use super::FetchResult;
 
 use super::super::instruction::AluInstruction::*;
 use super::super::instruction::BranchingInstruction::*;
+use super::super::instruction::FenceInstruction::*;
 use super::super::instruction::Instruction::*;
 use super::super::instruction::MemoryLoadInstruction::*;
 use super::super::instruction::MemoryStoreInstruction::*;
+use super::super::instruction::SystemInstruction::*;
 
 use DecodeError::*;
 
@@ -32,10 +34,12 @@ pub fn decode_instruction(fetch_result: FetchResult, register_file: &RegisterFil
             funct_3 if funct_3 == 0b01 || funct_3 == 0b101 => r_type(fetch_result, register_file),
             _ => i_type(fetch_result, register_file),
         },
-        _ => {
-            println!("{:b}", opcode(fetch_result.instruction));
-            bad_instruction(fetch_result)
-        }
+        opcode_group_constants::MISC_MEM => i_type(fetch_result, register_file),
+        opcode_group_constants::SYSTEM => match funct_3(fetch_result.instruction) {
+            0 => privileged_type(fetch_result, register_file),
+            _ => i_type(fetch_result, register_file),
+        },
+        _ => bad_instruction(fetch_result),
     }
 }
 
@@ -111,6 +115,14 @@ fn i_type(fetch_result: FetchResult, register_file: &RegisterFile) -> Result<Ins
         full_opcode_constants::XORI => Ok(Alu(XORI(decoded))),
         full_opcode_constants::ORI => Ok(Alu(ORI(decoded))),
         full_opcode_constants::ANDI => Ok(Alu(ANDI(decoded))),
+        full_opcode_constants::FENCE => Ok(Fence(FENCE(decoded))),
+        full_opcode_constants::FENCE_I => Ok(Fence(FENCE_I(decoded))),
+        full_opcode_constants::CSRRW => Ok(System(CSRRW(decoded))),
+        full_opcode_constants::CSRRS => Ok(System(CSRRS(decoded))),
+        full_opcode_constants::CSRRC => Ok(System(CSRRC(decoded))),
+        full_opcode_constants::CSRRWI => Ok(System(CSRRWI(decoded))),
+        full_opcode_constants::CSRRSI => Ok(System(CSRRSI(decoded))),
+        full_opcode_constants::CSRRCI => Ok(System(CSRRCI(decoded))),
         _ => bad_instruction(fetch_result),
     }
 }
@@ -122,7 +134,7 @@ fn s_type(fetch_result: FetchResult, register_file: &RegisterFile) -> Result<Ins
 
     let immediate_lower = register_destination_index(instruction);
     let immediate_upper = funct_7(instruction);
-    let immediate = sign_extend((immediate_upper << 7) | immediate_lower, 12, 32);
+    let immediate = sign_extend((immediate_upper << 5) | immediate_lower, 12, 32);
 
     let rs1 = register_source_one_index(instruction);
     let rs2 = register_source_two_index(instruction);
@@ -155,7 +167,7 @@ fn u_type(fetch_result: FetchResult) -> Result<Instruction, DecodeError> {
         opcode,
         full_opcode: opcode,
         register_destination_index: register_destination_index(instruction),
-        immediate: (instruction & 0xffff_f000) as u32,
+        immediate: instruction & 0xffff_f000,
     };
 
     match opcode {
@@ -173,12 +185,13 @@ fn b_type(fetch_result: FetchResult, register_file: &RegisterFile) -> Result<Ins
 
     let register_destination_index = register_destination_index(instruction);
 
+    // Offsets are in multiples of two bytes, bit 0 is not encoded.
     let immediate = sign_extend(
-        ((funct_7 >> 6) << 11)
-            | ((register_destination_index & 0x1) << 10)
-            | ((funct_7 & 0x3f) << 4)
-            | ((register_destination_index & 0x1e) >> 1),
-        12,
+        ((funct_7 >> 6) << 12)
+            | ((register_destination_index & 0x1) << 11)
+            | ((funct_7 & 0x3f) << 5)
+            | (register_destination_index & 0x1e),
+        13,
         32,
     );
 
@@ -216,7 +229,7 @@ fn j_type(fetch_result: FetchResult) -> Result<Instruction, DecodeError> {
     let imm_12_to_19 = (instruction & 0xFF000) >> 12;
     let imm_20 = (instruction & 0x80000000) >> 31;
 
-    let immediate = sign_extend((imm_20 << 20) | (imm_12_to_19 << 12) | (imm_11 << 11) | (imm_1_to_10 << 1), 20, 32);
+    let immediate = sign_extend((imm_20 << 20) | (imm_12_to_19 << 12) | (imm_11 << 11) | (imm_1_to_10 << 1), 21, 32);
 
     let decoded = JType {
         opcode,
@@ -231,6 +244,44 @@ fn j_type(fetch_result: FetchResult) -> Result<Instruction, DecodeError> {
     }
 }
 
+fn privileged_type(fetch_result: FetchResult, register_file: &RegisterFile) -> Result<Instruction, DecodeError> {
+    let instruction = fetch_result.instruction;
+    let opcode = opcode(instruction);
+    let funct_7 = funct_7(instruction);
+
+    let rs1 = register_source_one_index(instruction);
+    let rs2 = register_source_two_index(instruction);
+
+    let rs1_value = register_file.read(rs1 as usize);
+    let rs2_value = register_file.read(rs2 as usize);
+
+    // Only SFENCE.VMA takes operands, every other privileged instruction is identified by its whole encoding.
+    let full_opcode = match build_full_opcode(opcode, 0, funct_7) {
+        full_opcode_constants::SFENCE_VMA if register_destination_index(instruction) == 0 => {
+            full_opcode_constants::SFENCE_VMA
+        }
+        _ => instruction,
+    };
+
+    let decoded = RType {
+        opcode,
+        full_opcode,
+        register_destination_index: register_destination_index(instruction),
+        register_source_one: DecodedRegisterValue { index: rs1, value: rs1_value },
+        register_source_two: DecodedRegisterValue { index: rs2, value: rs2_value },
+    };
+
+    match full_opcode {
+        full_opcode_constants::ECALL => Ok(System(ECALL(decoded))),
+        full_opcode_constants::EBREAK => Ok(System(EBREAK(decoded))),
+        full_opcode_constants::SRET => Ok(System(SRET(decoded))),
+        full_opcode_constants::MRET => Ok(System(MRET(decoded))),
+        full_opcode_constants::WFI => Ok(System(WFI(decoded))),
+        full_opcode_constants::SFENCE_VMA => Ok(System(SFENCE_VMA(decoded))),
+        _ => bad_instruction(fetch_result),
+    }
+}
+
 fn opcode(instruction: u32) -> u32 {
     instruction & 0x7F
 }
@@ -264,7 +315,7 @@ fn sign_extend(value: u32, bits: usize, width: usize) -> u32 {
     let shift_by = width - bits;
     let value: i32 = (cast << shift_by) >> shift_by;
 
-    return value as u32;
+    value as u32
 }
 
 fn bad_instruction(fetch_result: FetchResult) -> Result<Instruction, DecodeError> {
@@ -1,30 +1,96 @@
 use super::super::bus::BusInterface;
 use super::RegisterWrite;
-use crate::core::{instruction::{MemoryLoadInstruction, MemoryStoreInstruction}, bus::BusReadResponse};
+use crate::core::cache::Cache;
+use crate::core::commit_log::OverwrittenMemory;
+use crate::core::csr::ControlStatusRegisters;
+use crate::core::exception::Exception;
+use crate::core::mmu::{AccessType, Mmu};
+use crate::core::pmp;
+use crate::core::{
+    bus::{AccessError, BusReadResponse, BusWriteResponse},
+    instruction::{MemoryLoadInstruction, MemoryStoreInstruction},
+};
 
 use MemoryLoadInstruction::*;
 use MemoryStoreInstruction::*;
 
-pub fn store<M>(decode_result: MemoryStoreInstruction, memory: &mut M)
+/// What loads and stores do when their address is not a multiple of the access size.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MisalignedAccessPolicy {
+    /// Raise the load or store address-misaligned exception so firmware can emulate the access.
+    #[default]
+    Trap,
+    /// Split the access into single bytes, each translated and checked on its own.
+    Emulate,
+}
+
+/// How far a store got. It starts out as the default, with `record` set to have what the store overwrites recorded,
+/// and is kept across the retries of a deferred store.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StoreProgress {
+    /// The bytes of an emulated misaligned store that already reached the bus, which are not written again.
+    pub stored_bytes: u32,
+    /// Still to read what the store overwrites before it writes anything.
+    pub record: bool,
+    /// Stays `None` when the bytes could not be read right away, e.g. from write only or slow devices.
+    pub overwritten: Option<OverwrittenMemory>,
+}
+
+pub fn store<M>(
+    decode_result: MemoryStoreInstruction,
+    mmu: &mut Mmu,
+    csrs: &ControlStatusRegisters,
+    misaligned_access_policy: MisalignedAccessPolicy,
+    data_cache: &mut Option<Cache>,
+    memory: &mut M,
+    progress: &mut StoreProgress,
+) -> Result<(), AccessError>
 where
     M: BusInterface<u32, u8>,
     M: BusInterface<u32, u16>,
     M: BusInterface<u32, u32>,
 {
-    match decode_result {
-        SB(instr) => {
-            memory.write(instr.register_source_one.value + instr.immediate, instr.register_source_two.value as u8);
-        }
-        SH(instr) => {
-            memory.write(instr.register_source_one.value + instr.immediate, instr.register_source_two.value as u16);
-        }
-        SW(instr) => {
-            memory.write(instr.register_source_one.value + instr.immediate, instr.register_source_two.value as u32);
-        }
+    let (SB(instr) | SH(instr) | SW(instr)) = decode_result;
+    let virtual_address = instr.register_source_one.value.wrapping_add(instr.immediate);
+    let size = match decode_result {
+        SB(_) => 1,
+        SH(_) => 2,
+        SW(_) => 4,
     };
+
+    if !virtual_address.is_multiple_of(size) {
+        return match misaligned_access_policy {
+            MisalignedAccessPolicy::Trap => Err(Exception::StoreAddressMisaligned { address: virtual_address }.into()),
+            MisalignedAccessPolicy::Emulate => {
+                let value = instr.register_source_two.value;
+                store_bytes(virtual_address, size, value, mmu, csrs, data_cache, memory, progress)
+            }
+        };
+    }
+
+    let address = mmu.translate(virtual_address, AccessType::Store, csrs, memory)?;
+    pmp::check(csrs, virtual_address, address, size, AccessType::Store)?;
+    let addresses = (0..size).map(|offset| address.wrapping_add(offset));
+    record_overwritten(addresses, data_cache, memory, progress);
+
+    match write_data(address, size, instr.register_source_two.value, data_cache, memory) {
+        BusWriteResponse::Success => Ok(()),
+        BusWriteResponse::Deferred => {
+            mmu.hold_translation(virtual_address, AccessType::Store, address);
+            Err(AccessError::Deferred)
+        }
+        _ => Err(Exception::StoreAccessFault { address: virtual_address }.into()),
+    }
 }
 
-pub fn load<M>(decode_result: MemoryLoadInstruction, memory: &M) -> RegisterWrite
+pub fn load<M>(
+    decode_result: MemoryLoadInstruction,
+    mmu: &mut Mmu,
+    csrs: &ControlStatusRegisters,
+    misaligned_access_policy: MisalignedAccessPolicy,
+    data_cache: &mut Option<Cache>,
+    memory: &mut M,
+) -> Result<RegisterWrite, AccessError>
 where
     M: BusInterface<u32, i8>,
     M: BusInterface<u32, u8>,
@@ -32,20 +98,295 @@ where
     M: BusInterface<u32, u16>,
     M: BusInterface<u32, u32>,
 {
-    let (index, memory_read) = match decode_result {
-        LB(instr) => (instr.register_destination_index, BusInterface::<u32, i8>::read(memory, instr.register_source_one.value + instr.immediate)),
-        LBU(instr) => (instr.register_destination_index, BusInterface::<u32, u8>::read(memory, instr.register_source_one.value + instr.immediate)),
-        LH(instr) => (instr.register_destination_index, BusInterface::<u32, i16>::read(memory, instr.register_source_one.value + instr.immediate)),
-        LHU(instr) => (instr.register_destination_index, BusInterface::<u32, u16>::read(memory, instr.register_source_one.value + instr.immediate)),
-        LW(instr) => (instr.register_destination_index, BusInterface::<u32, u32>::read(memory, instr.register_source_one.value + instr.immediate)),
+    let (LB(instr) | LBU(instr) | LH(instr) | LHU(instr) | LW(instr)) = decode_result;
+    let virtual_address = instr.register_source_one.value.wrapping_add(instr.immediate);
+    let size = match decode_result {
+        LB(_) | LBU(_) => 1,
+        LH(_) | LHU(_) => 2,
+        LW(_) => 4,
     };
 
-    if let BusReadResponse::Success(value) = memory_read {
-        RegisterWrite {
-            index,
-            value
+    if !virtual_address.is_multiple_of(size) {
+        return match misaligned_access_policy {
+            MisalignedAccessPolicy::Trap => Err(Exception::LoadAddressMisaligned { address: virtual_address }.into()),
+            MisalignedAccessPolicy::Emulate => {
+                let value = load_bytes(virtual_address, size, mmu, csrs, data_cache, memory)?;
+                Ok(RegisterWrite { index: instr.register_destination_index, value: extend(decode_result, value) })
+            }
+        };
+    }
+
+    let address = mmu.translate(virtual_address, AccessType::Load, csrs, memory)?;
+    pmp::check(csrs, virtual_address, address, size, AccessType::Load)?;
+
+    match read_data(address, size, data_cache, memory) {
+        BusReadResponse::Success(value) => {
+            Ok(RegisterWrite { index: instr.register_destination_index, value: extend(decode_result, value) })
+        }
+        BusReadResponse::Deferred => {
+            mmu.hold_translation(virtual_address, AccessType::Load, address);
+            Err(AccessError::Deferred)
         }
-    } else {
-        panic!("Invalid memory read");
+        _ => Err(Exception::LoadAccessFault { address: virtual_address }.into()),
+    }
+}
+
+fn load_bytes<M>(
+    virtual_address: u32,
+    size: u32,
+    mmu: &mut Mmu,
+    csrs: &ControlStatusRegisters,
+    data_cache: &mut Option<Cache>,
+    memory: &mut M,
+) -> Result<u32, AccessError>
+where
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, u16>,
+    M: BusInterface<u32, u32>,
+{
+    let mut value = 0;
+
+    for offset in 0..size {
+        let byte_address = virtual_address.wrapping_add(offset);
+        let address = mmu.translate(byte_address, AccessType::Load, csrs, memory)?;
+        pmp::check(csrs, virtual_address, address, 1, AccessType::Load)?;
+
+        match read_data(address, 1, data_cache, memory) {
+            BusReadResponse::Success(byte) => value |= byte << (8 * offset),
+            BusReadResponse::Deferred => return Err(AccessError::Deferred),
+            _ => return Err(Exception::LoadAccessFault { address: virtual_address }.into()),
+        }
+    }
+
+    Ok(value)
+}
+
+/// Every byte is translated and checked before the first one is written, so page and PMP faults leave memory
+/// untouched. A bus error part way through can still leave the leading bytes written.
+#[allow(clippy::too_many_arguments)]
+fn store_bytes<M>(
+    virtual_address: u32,
+    size: u32,
+    value: u32,
+    mmu: &mut Mmu,
+    csrs: &ControlStatusRegisters,
+    data_cache: &mut Option<Cache>,
+    memory: &mut M,
+    progress: &mut StoreProgress,
+) -> Result<(), AccessError>
+where
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, u16>,
+    M: BusInterface<u32, u32>,
+{
+    let mut addresses = Vec::with_capacity(size as usize);
+
+    for offset in 0..size {
+        let byte_address = virtual_address.wrapping_add(offset);
+        let address = mmu.translate(byte_address, AccessType::Store, csrs, memory)?;
+        pmp::check(csrs, virtual_address, address, 1, AccessType::Store)?;
+        addresses.push(address);
+    }
+    record_overwritten(addresses.iter().copied(), data_cache, memory, progress);
+
+    for (offset, address) in addresses.into_iter().enumerate().skip(progress.stored_bytes as usize) {
+        match write_data(address, 1, value >> (8 * offset), data_cache, memory) {
+            BusWriteResponse::Success => progress.stored_bytes += 1,
+            BusWriteResponse::Deferred => return Err(AccessError::Deferred),
+            _ => return Err(Exception::StoreAccessFault { address: virtual_address }.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads what the bytes at `addresses` hold, as loads would see them, before a store that is asked to record them
+/// overwrites them. Nothing is recorded when a byte cannot be read right away.
+fn record_overwritten<M: BusInterface<u32, u8>>(
+    addresses: impl IntoIterator<Item = u32>,
+    data_cache: &Option<Cache>,
+    memory: &M,
+    progress: &mut StoreProgress,
+) {
+    if !progress.record {
+        return;
+    }
+
+    let mut overwritten = OverwrittenMemory::default();
+    for address in addresses {
+        let byte = match data_cache.as_ref().and_then(|cache| cache.peek(address)) {
+            Some(byte) => byte,
+            // Waiting for a slow device would change the timing of the store.
+            None => match memory.read(address) {
+                BusReadResponse::Success(byte) => byte as u8,
+                _ => {
+                    progress.record = false;
+                    return;
+                }
+            },
+        };
+        overwritten.push(address, byte);
+    }
+    progress.record = false;
+    progress.overwritten = Some(overwritten);
+}
+
+/// Sign extends the zero extended value of LB and LH.
+fn extend(decode_result: MemoryLoadInstruction, value: u32) -> u32 {
+    match decode_result {
+        LB(_) => value as u8 as i8 as u32,
+        LH(_) => value as u16 as i16 as u32,
+        _ => value,
+    }
+}
+
+/// Reads `size` bytes zero extended, through the data cache unless the address is uncacheable.
+fn read_data<M>(address: u32, size: u32, data_cache: &mut Option<Cache>, memory: &mut M) -> BusReadResponse<u32>
+where
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, u16>,
+    M: BusInterface<u32, u32>,
+{
+    match data_cache {
+        Some(cache) if cache.is_cacheable(address) => cache.read(address, size, memory),
+        _ => match size {
+            1 => BusInterface::<u32, u8>::read(memory, address),
+            2 => BusInterface::<u32, u16>::read(memory, address),
+            _ => BusInterface::<u32, u32>::read(memory, address),
+        },
+    }
+}
+
+fn write_data<M>(
+    address: u32,
+    size: u32,
+    value: u32,
+    data_cache: &mut Option<Cache>,
+    memory: &mut M,
+) -> BusWriteResponse
+where
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, u16>,
+    M: BusInterface<u32, u32>,
+{
+    match data_cache {
+        Some(cache) if cache.is_cacheable(address) => cache.write(address, size, value, memory),
+        _ => match size {
+            1 => memory.write(address, value as u8),
+            2 => memory.write(address, value as u16),
+            _ => memory.write(address, value),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::instruction::{DecodedRegisterValue, IType, SType};
+    use crate::memory::Memory;
+
+    fn load_word(address: u32) -> MemoryLoadInstruction {
+        LW(IType {
+            opcode: 0,
+            full_opcode: 0,
+            register_destination_index: 1,
+            register_source_one: DecodedRegisterValue { index: 2, value: address },
+            immediate: 0,
+        })
+    }
+
+    fn store_word(address: u32, value: u32) -> MemoryStoreInstruction {
+        SW(SType {
+            opcode: 0,
+            full_opcode: 0,
+            register_source_one: DecodedRegisterValue { index: 2, value: address },
+            register_source_two: DecodedRegisterValue { index: 3, value },
+            immediate: 0,
+        })
+    }
+
+    #[test]
+    fn reads_outside_memory_raise_load_access_faults() {
+        let mut memory = Memory::new(0x100);
+        let csrs = ControlStatusRegisters::new();
+
+        let result =
+            load(load_word(0x200), &mut Mmu::default(), &csrs, MisalignedAccessPolicy::Trap, &mut None, &mut memory);
+
+        assert_eq!(result.err(), Some(Exception::LoadAccessFault { address: 0x200 }.into()));
+    }
+
+    #[test]
+    fn writes_to_read_only_memory_raise_store_access_faults() {
+        let mut memory = Memory::read_only(vec![0; 0x100]);
+        let csrs = ControlStatusRegisters::new();
+        let mut mmu = Mmu::default();
+
+        let result = store(
+            store_word(0x10, 0xdead_beef),
+            &mut mmu,
+            &csrs,
+            MisalignedAccessPolicy::Trap,
+            &mut None,
+            &mut memory,
+            &mut StoreProgress::default(),
+        );
+        let read_back = load(load_word(0x10), &mut mmu, &csrs, MisalignedAccessPolicy::Trap, &mut None, &mut memory);
+
+        assert_eq!(result, Err(Exception::StoreAccessFault { address: 0x10 }.into()));
+        assert_eq!(read_back.map(|register_write| register_write.value).ok(), Some(0));
+    }
+
+    #[test]
+    fn emulated_accesses_are_split_into_bytes() {
+        let mut memory = Memory::new(0x100);
+        let csrs = ControlStatusRegisters::new();
+        let mut mmu = Mmu::default();
+
+        let policy = MisalignedAccessPolicy::Emulate;
+        store(
+            store_word(0x21, 0x1122_3344),
+            &mut mmu,
+            &csrs,
+            policy,
+            &mut None,
+            &mut memory,
+            &mut StoreProgress::default(),
+        )
+        .unwrap();
+        let bytes = (0x20..0x26).map(|address| BusInterface::<u32, u8>::read(&memory, address));
+        let bytes: Vec<u8> = bytes
+            .map(|response| match response {
+                BusReadResponse::Success(byte) => byte as u8,
+                _ => panic!("the bytes are in memory"),
+            })
+            .collect();
+        assert_eq!(bytes, [0, 0x44, 0x33, 0x22, 0x11, 0]);
+
+        let loaded = load(load_word(0x21), &mut mmu, &csrs, policy, &mut None, &mut memory);
+        assert_eq!(loaded.map(|register_write| register_write.value).ok(), Some(0x1122_3344));
+        let trapped = load(load_word(0x21), &mut mmu, &csrs, MisalignedAccessPolicy::Trap, &mut None, &mut memory);
+        assert_eq!(trapped.err(), Some(Exception::LoadAddressMisaligned { address: 0x21 }.into()));
+    }
+
+    #[test]
+    fn emulated_accesses_fault_when_any_byte_is_unmapped() {
+        let mut memory = Memory::new(0x100);
+        let csrs = ControlStatusRegisters::new();
+        let mut mmu = Mmu::default();
+
+        let stored = store(
+            store_word(0xfe, 1),
+            &mut mmu,
+            &csrs,
+            MisalignedAccessPolicy::Emulate,
+            &mut None,
+            &mut memory,
+            &mut StoreProgress::default(),
+        );
+        let loaded = load(load_word(0xfe), &mut mmu, &csrs, MisalignedAccessPolicy::Emulate, &mut None, &mut memory);
+
+        assert_eq!(stored, Err(Exception::StoreAccessFault { address: 0xfe }.into()));
+        assert_eq!(loaded.err(), Some(Exception::LoadAccessFault { address: 0xfe }.into()));
     }
 }
@@ -0,0 +1,135 @@
+use super::super::csr::address_constants::SATP;
+use super::super::csr::{
+    is_read_only, required_privilege_level, ControlStatusRegisters, PrivilegeLevel, MSTATUS_TSR, MSTATUS_TVM,
+    MSTATUS_TW,
+};
+use super::super::exception::Exception;
+use super::super::instruction::*;
+use super::super::mmu::Mmu;
+use super::{machine_trap_return, supervisor_trap_return, FetchResult, RegisterWrite};
+use SystemInstruction::*;
+
+#[derive(Clone, Copy)]
+pub struct SystemResult {
+    pub register_write: Option<RegisterWrite>,
+    /// The CSR an instruction wrote and its value read back afterwards.
+    pub csr_write: Option<(u32, u32)>,
+    pub next_pc: u32,
+}
+
+/// Runs a system instruction at commit. These instructions change state that younger instructions depend on,
+/// so the pipeline restarts fetching at `next_pc` afterwards.
+pub fn execute_system(
+    fetch_result: FetchResult,
+    decode_result: SystemInstruction,
+    csrs: &mut ControlStatusRegisters,
+    mmu: &mut Mmu,
+) -> Result<SystemResult, Exception> {
+    let privilege_level = csrs.privilege_level();
+    let mstatus = csrs.mstatus();
+    let illegal = Exception::IllegalInstruction { instruction: fetch_result.instruction };
+    let next_pc = fetch_result.captured_pc.wrapping_add(4);
+
+    let trap_return = |next_pc| Ok(SystemResult { register_write: None, csr_write: None, next_pc });
+    let complete = |register_write| Ok(SystemResult { register_write, csr_write: None, next_pc });
+
+    match decode_result {
+        ECALL(_) => Err(match privilege_level {
+            PrivilegeLevel::User => Exception::EnvironmentCallFromUMode,
+            PrivilegeLevel::Supervisor => Exception::EnvironmentCallFromSMode,
+            PrivilegeLevel::Machine => Exception::EnvironmentCallFromMMode,
+        }),
+        EBREAK(_) => Err(Exception::Breakpoint { address: fetch_result.captured_pc }),
+        MRET(_) => match privilege_level {
+            PrivilegeLevel::Machine => trap_return(machine_trap_return(csrs)),
+            _ => Err(illegal),
+        },
+        SRET(_) => match privilege_level {
+            PrivilegeLevel::User => Err(illegal),
+            PrivilegeLevel::Supervisor if mstatus & MSTATUS_TSR != 0 => Err(illegal),
+            _ => trap_return(supervisor_trap_return(csrs)),
+        },
+        WFI(_) => match privilege_level {
+            PrivilegeLevel::User => Err(illegal),
+            PrivilegeLevel::Supervisor if mstatus & MSTATUS_TW != 0 => Err(illegal),
+            _ => complete(None),
+        },
+        SFENCE_VMA(instr) => match privilege_level {
+            PrivilegeLevel::User => Err(illegal),
+            PrivilegeLevel::Supervisor if mstatus & MSTATUS_TVM != 0 => Err(illegal),
+            _ => {
+                let address = (instr.register_source_one.index != 0).then_some(instr.register_source_one.value);
+                let asid = (instr.register_source_two.index != 0).then_some(instr.register_source_two.value & 0x1ff);
+                mmu.flush(address, asid);
+                complete(None)
+            }
+        },
+        CSRRW(instr) => {
+            csr_operation(instr, instr.register_source_one.value, CsrOperation::Write, csrs, illegal, next_pc)
+        }
+        CSRRS(instr) => {
+            csr_operation(instr, instr.register_source_one.value, CsrOperation::Set, csrs, illegal, next_pc)
+        }
+        CSRRC(instr) => {
+            csr_operation(instr, instr.register_source_one.value, CsrOperation::Clear, csrs, illegal, next_pc)
+        }
+        CSRRWI(instr) => {
+            csr_operation(instr, instr.register_source_one.index, CsrOperation::Write, csrs, illegal, next_pc)
+        }
+        CSRRSI(instr) => {
+            csr_operation(instr, instr.register_source_one.index, CsrOperation::Set, csrs, illegal, next_pc)
+        }
+        CSRRCI(instr) => {
+            csr_operation(instr, instr.register_source_one.index, CsrOperation::Clear, csrs, illegal, next_pc)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CsrOperation {
+    Write,
+    Set,
+    Clear,
+}
+
+fn csr_operation(
+    instr: IType,
+    operand: u32,
+    operation: CsrOperation,
+    csrs: &mut ControlStatusRegisters,
+    illegal: Exception,
+    next_pc: u32,
+) -> Result<SystemResult, Exception> {
+    let address = instr.immediate & 0xfff;
+    let privilege_level = csrs.privilege_level();
+
+    // Set and clear with x0 (or a zero immediate) only read, CSRRW always writes.
+    let writes = operation == CsrOperation::Write || instr.register_source_one.index != 0;
+
+    if required_privilege_level(address) > privilege_level
+        || (writes && is_read_only(address))
+        || !csrs.counter_enabled(address)
+    {
+        return Err(illegal);
+    }
+
+    if address == SATP && privilege_level == PrivilegeLevel::Supervisor && csrs.mstatus() & MSTATUS_TVM != 0 {
+        return Err(illegal);
+    }
+
+    let current = csrs.read(address).ok_or(illegal)?;
+
+    let mut csr_write = None;
+    if writes {
+        let value = match operation {
+            CsrOperation::Write => operand,
+            CsrOperation::Set => current | operand,
+            CsrOperation::Clear => current & !operand,
+        };
+        csrs.write(address, value);
+        csr_write = csrs.read(address).map(|value| (address, value));
+    }
+
+    let register_write = Some(RegisterWrite { index: instr.register_destination_index, value: current });
+    Ok(SystemResult { register_write, csr_write, next_pc })
+}
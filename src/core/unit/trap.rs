@@ -0,0 +1,87 @@
+use super::super::csr::address_constants::*;
+use super::super::csr::{
+    ControlStatusRegisters, PrivilegeLevel, MSTATUS_MIE, MSTATUS_MPIE, MSTATUS_MPP, MSTATUS_MPRV, MSTATUS_SIE,
+    MSTATUS_SPIE, MSTATUS_SPP,
+};
+use super::super::exception::Exception;
+
+/// Enters the trap handler for `exception` raised by the instruction at `pc` and returns the handler address.
+/// Exceptions from S or U mode go to S mode when `medeleg` delegates their cause.
+pub fn take_trap(pc: u32, exception: Exception, csrs: &mut ControlStatusRegisters) -> u32 {
+    let cause = exception.cause();
+    let privilege_level = csrs.privilege_level();
+    let mstatus = csrs.mstatus();
+    let medeleg = csrs.read(MEDELEG).unwrap_or(0);
+
+    if privilege_level <= PrivilegeLevel::Supervisor && (medeleg >> cause) & 1 == 1 {
+        let mut mstatus = mstatus & !(MSTATUS_SPIE | MSTATUS_SIE | MSTATUS_SPP);
+        if mstatus_bit(csrs.mstatus(), MSTATUS_SIE) {
+            mstatus |= MSTATUS_SPIE;
+        }
+        if privilege_level == PrivilegeLevel::Supervisor {
+            mstatus |= MSTATUS_SPP;
+        }
+
+        csrs.write(SEPC, pc);
+        csrs.write(SCAUSE, cause);
+        csrs.write(STVAL, exception.trap_value());
+        csrs.write(MSTATUS, mstatus);
+        csrs.set_privilege_level(PrivilegeLevel::Supervisor);
+
+        csrs.read(STVEC).unwrap_or(0) & !0b11
+    } else {
+        let mut mstatus = mstatus & !(MSTATUS_MPIE | MSTATUS_MIE | MSTATUS_MPP);
+        if mstatus_bit(csrs.mstatus(), MSTATUS_MIE) {
+            mstatus |= MSTATUS_MPIE;
+        }
+        mstatus |= privilege_level.bits() << 11;
+
+        csrs.write(MEPC, pc);
+        csrs.write(MCAUSE, cause);
+        csrs.write(MTVAL, exception.trap_value());
+        csrs.write(MSTATUS, mstatus);
+        csrs.set_privilege_level(PrivilegeLevel::Machine);
+
+        csrs.read(MTVEC).unwrap_or(0) & !0b11
+    }
+}
+
+/// MRET: restores the privilege level and interrupt enable saved on trap entry and returns `mepc`.
+pub fn machine_trap_return(csrs: &mut ControlStatusRegisters) -> u32 {
+    let mstatus = csrs.mstatus();
+    let previous_privilege_level = PrivilegeLevel::from_bits((mstatus & MSTATUS_MPP) >> 11);
+
+    let mut updated = (mstatus & !(MSTATUS_MIE | MSTATUS_MPP)) | MSTATUS_MPIE;
+    if mstatus_bit(mstatus, MSTATUS_MPIE) {
+        updated |= MSTATUS_MIE;
+    }
+    if previous_privilege_level != PrivilegeLevel::Machine {
+        updated &= !MSTATUS_MPRV;
+    }
+
+    csrs.write(MSTATUS, updated);
+    csrs.set_privilege_level(previous_privilege_level);
+    csrs.read(MEPC).unwrap_or(0)
+}
+
+/// SRET: the supervisor counterpart of `machine_trap_return`, returning `sepc`.
+pub fn supervisor_trap_return(csrs: &mut ControlStatusRegisters) -> u32 {
+    let mstatus = csrs.mstatus();
+    let previous_privilege_level = match mstatus_bit(mstatus, MSTATUS_SPP) {
+        true => PrivilegeLevel::Supervisor,
+        false => PrivilegeLevel::User,
+    };
+
+    let mut updated = (mstatus & !(MSTATUS_SIE | MSTATUS_SPP | MSTATUS_MPRV)) | MSTATUS_SPIE;
+    if mstatus_bit(mstatus, MSTATUS_SPIE) {
+        updated |= MSTATUS_SIE;
+    }
+
+    csrs.write(MSTATUS, updated);
+    csrs.set_privilege_level(previous_privilege_level);
+    csrs.read(SEPC).unwrap_or(0)
+}
+
+fn mstatus_bit(mstatus: u32, bit: u32) -> bool {
+    mstatus & bit != 0
+}
@@ -0,0 +1,1036 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, BufRead, Write};
+use std::mem::size_of;
+
+use crate::core::bus::{BusInterface, Clocked};
+use crate::core::commit_log::{MemoryAccess, Retirement, Undo};
+use crate::core::csr::{csr_name, PrivilegeLevel};
+use crate::core::hart::Hart;
+use crate::core::register_file::ABI_NAMES;
+use crate::core::snapshot::{self, Snapshot};
+use crate::core::unit::RegisterWrite;
+use crate::disassembler::disassemble_word;
+use crate::simple_pipeline::{Latch, SimplePipeline};
+use crate::symbols::Symbols;
+
+/// How long `step` waits for an instruction to retire, e.g. while the hart traps over and over on its own handler.
+const STEP_CYCLE_LIMIT: u64 = 10_000;
+/// Cycles between the snapshots taken while recording history, going back replays at most this many.
+const SNAPSHOT_INTERVAL: u64 = 10_000;
+
+const HELP: &str = "step, s [n]            retire n instructions
+cycle [n]              run n pipeline cycles
+continue, c [cycles]   run until a breakpoint or watchpoint, at most `cycles` when given
+reverse-step, rs [n]   go back n instructions, when recording history
+reverse-continue, rc   go back to the previous breakpoint or watchpoint hit
+break, b <address>     stop when the instruction at the address is about to retire
+delete, d <address>    remove a breakpoint
+watch <address> [n]    stop after a store to the n bytes at the address, 4 by default
+rwatch <address> [n]   stop after a load from them
+awatch <address> [n]   stop after either
+unwatch <address>      remove the watchpoints at the address
+info                   list breakpoints and watchpoints
+registers, regs        show the pc, privilege level and integer registers
+csr [name | address]   show a CSR, or every CSR that is not zero
+x <address> [n]        hexdump n bytes of physical memory, 64 by default
+disassemble [address [n]]
+                       disassemble n instructions, by default around the pc
+pipeline               show the fetch pc and each stage latch
+quit, q
+";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: u32,
+    pub length: u32,
+    pub kind: WatchKind,
+}
+
+impl Watchpoint {
+    fn matches(&self, access: MemoryAccess) -> bool {
+        let (address, size, kind_matches) = match access {
+            MemoryAccess::Load { address, size } => (address, size, self.kind != WatchKind::Write),
+            MemoryAccess::Store { address, size, .. } => (address, size, self.kind != WatchKind::Read),
+        };
+
+        kind_matches
+            && (address as u64) < self.address as u64 + self.length as u64
+            && (self.address as u64) < address as u64 + size as u64
+    }
+}
+
+/// Why running stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stop {
+    /// Ran the requested instructions or cycles.
+    Done,
+    /// The instruction at a breakpoint became the oldest in flight, so it is the next to retire.
+    Breakpoint { pc: u32 },
+    /// A load or store that touched a watched range retired.
+    Watchpoint {
+        pc: u32,
+        access: MemoryAccess,
+        watchpoint: Watchpoint,
+    },
+    /// Ran out of cycles first.
+    CycleLimit,
+    /// Going back reached the oldest state still recorded.
+    HistoryStart,
+}
+
+/// Runs a hart under control of breakpoints and watchpoints, and shows its state.
+///
+/// The pipeline overlaps instructions, so the debugger's pc is that of the oldest instruction in flight: everything
+/// before it has retired, and it retires next unless it traps. An instruction in write back has already made its
+/// memory access.
+pub struct Debugger<M>
+where
+    M: BusInterface<u32, i8>,
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, i16>,
+    M: BusInterface<u32, u16>,
+    M: BusInterface<u32, u32>,
+    M: Clocked,
+{
+    hart: Hart<M, SimplePipeline>,
+    memory: M,
+    breakpoints: BTreeSet<u32>,
+    watchpoints: Vec<Watchpoint>,
+    history: Option<History<M>>,
+    symbols: Symbols,
+}
+
+/// Where the hart has been, kept while recording so that execution can go backwards.
+///
+/// A snapshot is taken every `SNAPSHOT_INTERVAL` cycles next to a log of what each cycle retired, including what the
+/// pipeline saw each instruction overwrite at write back and store. Stepping back over instructions that only wrote
+/// registers and memory undoes their writes, leaving caches, predictor and counters as they are, and takes a snapshot
+/// where it lands. Traps and instructions that did more, such as CSR accesses, are barriers: an earlier cycle is then
+/// reached by restoring the snapshot before it and running forward again, which lands in exactly the state the hart
+/// was in. The oldest snapshots and their part of the log are dropped to stay within the budget.
+struct History<M>
+where
+    M: BusInterface<u32, i8>,
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, i16>,
+    M: BusInterface<u32, u16>,
+    M: BusInterface<u32, u32>,
+    M: Clocked,
+{
+    budget: usize,
+    /// Cycles run since recording started.
+    cycle: u64,
+    snapshots: VecDeque<(u64, Vec<u8>)>,
+    /// The cycle each instruction retired in, with its writes.
+    retirements: VecDeque<(u64, Retirement)>,
+    /// The cycles that took a trap or retired an instruction that cannot be undone.
+    barriers: VecDeque<u64>,
+    /// Traps taken by the hart as of `cycle`.
+    traps: u64,
+    save: fn(&Hart<M, SimplePipeline>, &M) -> Vec<u8>,
+    restore: fn(&mut Hart<M, SimplePipeline>, &mut M, &[u8]),
+}
+
+impl<M> History<M>
+where
+    M: BusInterface<u32, i8>,
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, i16>,
+    M: BusInterface<u32, u16>,
+    M: BusInterface<u32, u32>,
+    M: Clocked,
+{
+    fn record(&mut self, hart: &Hart<M, SimplePipeline>, memory: &M) {
+        self.cycle += 1;
+        self.retirements.extend(hart.retired().iter().map(|&retirement| (self.cycle, retirement)));
+        let traps = hart.state().traps();
+        if traps != self.traps || hart.retired().iter().any(|retirement| retirement.undo.is_none()) {
+            self.barriers.push_back(self.cycle);
+        }
+        self.traps = traps;
+
+        if self.cycle.is_multiple_of(SNAPSHOT_INTERVAL) {
+            self.snapshots.push_back((self.cycle, (self.save)(hart, memory)));
+            self.trim();
+        }
+    }
+
+    /// Drops the oldest snapshots and their part of the log until the history fits the budget.
+    fn trim(&mut self) {
+        while self.size() > self.budget && self.snapshots.len() > 1 {
+            self.snapshots.pop_front();
+            let start = self.snapshots[0].0;
+            while self.retirements.front().is_some_and(|&(cycle, _)| cycle < start) {
+                self.retirements.pop_front();
+            }
+            while self.barriers.front().is_some_and(|&cycle| cycle < start) {
+                self.barriers.pop_front();
+            }
+        }
+    }
+
+    /// Starts over from the current state, after it was changed in a way the log does not lead to.
+    fn rebase(&mut self, hart: &Hart<M, SimplePipeline>, memory: &M) {
+        self.snapshots.clear();
+        self.retirements.clear();
+        self.barriers.clear();
+        self.traps = hart.state().traps();
+        self.snapshots.push_back((self.cycle, (self.save)(hart, memory)));
+    }
+
+    /// Restores the hart to how it was after `cycle`, forgetting what came after as it is about to run again.
+    fn go_to(&mut self, cycle: u64, hart: &mut Hart<M, SimplePipeline>, memory: &mut M) {
+        let (start, snapshot) = self.snapshots.iter().rev().find(|(start, _)| *start <= cycle).unwrap();
+        (self.restore)(hart, memory, snapshot);
+        for _ in *start..cycle {
+            hart.execute(memory);
+        }
+
+        self.snapshots.retain(|(start, _)| *start <= cycle);
+        self.retirements.retain(|(retired, _)| *retired <= cycle);
+        self.barriers.retain(|&barrier| barrier <= cycle);
+        self.traps = hart.state().traps();
+        self.cycle = cycle;
+    }
+
+    /// Like `go_to`, from the hart as it is after `self.cycle` with `next_pc` to retire next, but undoes what happened
+    /// since when no barrier is in the way. The instructions in flight are discarded and fetched again.
+    fn back_to(&mut self, cycle: u64, next_pc: u32, hart: &mut Hart<M, SimplePipeline>, memory: &mut M) {
+        if !self.undo_to(cycle, next_pc, hart, memory) {
+            self.go_to(cycle, hart, memory);
+        }
+    }
+
+    fn undo_to(&mut self, cycle: u64, next_pc: u32, hart: &mut Hart<M, SimplePipeline>, memory: &mut M) -> bool {
+        if self.barriers.back().is_some_and(|&barrier| barrier > cycle) {
+            return false;
+        }
+        let Some(in_flight) = hart.pipeline().overwritten_in_flight() else {
+            return false;
+        };
+
+        let undone: Vec<Retirement> = self
+            .retirements
+            .iter()
+            .filter(|&&(retired, _)| retired > cycle)
+            .map(|&(_, retirement)| retirement)
+            .collect();
+        let pc = undone.first().map_or(next_pc, |retirement| retirement.pc);
+        let undos = undone.iter().rev().map(|retirement| retirement.undo.unwrap_or_default());
+        for undo in in_flight.into_iter().map(|memory| Undo { register: None, memory }).chain(undos) {
+            if let Some(RegisterWrite { index, value }) = undo.register {
+                hart.state_mut().register_file.write(index as usize, value);
+            }
+            for &(address, byte) in undo.memory.bytes() {
+                // The snapshot restored instead covers whatever was undone already.
+                if !hart.state_mut().write_byte(memory, address, byte) {
+                    return false;
+                }
+            }
+        }
+        hart.pipeline_mut().flush();
+        hart.set_program_counter(pc);
+
+        self.snapshots.retain(|(start, _)| *start < cycle);
+        self.retirements.retain(|(retired, _)| *retired <= cycle);
+        self.snapshots.push_back((cycle, (self.save)(hart, memory)));
+        self.cycle = cycle;
+        self.trim();
+        true
+    }
+
+    fn oldest(&self) -> u64 {
+        self.snapshots[0].0
+    }
+
+    fn size(&self) -> usize {
+        let snapshots: usize = self.snapshots.iter().map(|(_, snapshot)| snapshot.len()).sum();
+        snapshots + self.retirements.len() * size_of::<(u64, Retirement)>() + self.barriers.len() * size_of::<u64>()
+    }
+}
+
+impl<M> Debugger<M>
+where
+    M: BusInterface<u32, i8>,
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, i16>,
+    M: BusInterface<u32, u16>,
+    M: BusInterface<u32, u32>,
+    M: Clocked,
+{
+    pub fn new(mut hart: Hart<M, SimplePipeline>, memory: M) -> Self {
+        hart.record_retirements();
+        Debugger {
+            hart,
+            memory,
+            breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            history: None,
+            symbols: Symbols::default(),
+        }
+    }
+
+    pub fn hart(&self) -> &Hart<M, SimplePipeline> {
+        &self.hart
+    }
+
+    pub fn hart_mut(&mut self) -> &mut Hart<M, SimplePipeline> {
+        &mut self.hart
+    }
+
+    pub fn memory(&self) -> &M {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut M {
+        &mut self.memory
+    }
+
+    /// The pc of the next instruction to retire.
+    pub fn pc(&self) -> u32 {
+        self.oldest().map_or(self.hart.program_counter(), |latch| latch.pc)
+    }
+
+    /// Writes an integer register. Instructions in flight may have read the old value, so they are fetched again.
+    pub fn write_register(&mut self, index: usize, value: u32) {
+        let pc = self.pc();
+        self.hart.state_mut().register_file.write(index, value);
+        self.restart(pc);
+    }
+
+    pub fn write_csr(&mut self, address: u32, value: u32) {
+        let pc = self.pc();
+        self.hart.state_mut().csrs.write(address, value);
+        self.restart(pc);
+    }
+
+    /// Continues execution at `pc`, discarding the instructions in flight. An instruction that was in write back
+    /// makes its memory access again.
+    pub fn restart(&mut self, pc: u32) {
+        self.hart.pipeline_mut().flush();
+        self.hart.set_program_counter(pc);
+        if let Some(history) = &mut self.history {
+            history.rebase(&self.hart, &self.memory);
+        }
+    }
+
+    /// Reads physical memory as loads see it, dirty data cache lines included. `None` where the bus does not return a
+    /// byte.
+    pub fn read_memory(&self, address: u32, length: u32) -> Vec<Option<u8>> {
+        (0..length).map(|offset| self.read_byte(address.wrapping_add(offset))).collect()
+    }
+
+    /// Writes physical memory and the cached copies of it, and fetches the instructions in flight again in case they
+    /// were overwritten. Returns false if the bus rejected a byte.
+    pub fn write_memory(&mut self, address: u32, bytes: &[u8]) -> bool {
+        let state = self.hart.state_mut();
+        let written = bytes
+            .iter()
+            .enumerate()
+            .all(|(offset, &byte)| state.write_byte(&mut self.memory, address.wrapping_add(offset as u32), byte));
+
+        let pc = self.pc();
+        self.restart(pc);
+        written
+    }
+
+    /// Annotates the addresses the debugger shows with the function and source line they are in.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    /// Returns false when there already was one.
+    pub fn add_breakpoint(&mut self, address: u32) -> bool {
+        self.breakpoints.insert(address)
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u32) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u32> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.watchpoints.push(watchpoint);
+    }
+
+    /// Removes every watchpoint of `kind` starting at `address`, returning whether there were any.
+    pub fn remove_watchpoints(&mut self, address: u32, kind: Option<WatchKind>) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints
+            .retain(|watchpoint| watchpoint.address != address || kind.is_some_and(|kind| kind != watchpoint.kind));
+        self.watchpoints.len() != count
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    pub fn step_cycles(&mut self, cycles: u64) -> Stop {
+        for _ in 0..cycles {
+            match self.cycle() {
+                Stop::Done => {}
+                stop => return stop,
+            }
+        }
+        Stop::Done
+    }
+
+    pub fn step_instructions(&mut self, count: u64) -> Stop {
+        for _ in 0..count {
+            let mut cycles = 0;
+            while self.hart.retired().is_empty() || cycles == 0 {
+                if cycles == STEP_CYCLE_LIMIT {
+                    return Stop::CycleLimit;
+                }
+                match self.cycle() {
+                    Stop::Done => cycles += 1,
+                    stop => return stop,
+                }
+            }
+        }
+        Stop::Done
+    }
+
+    /// Runs until a breakpoint or watchpoint, or for at most `cycle_limit` cycles.
+    pub fn resume(&mut self, cycle_limit: Option<u64>) -> Stop {
+        let mut cycles = 0;
+        while cycle_limit.is_none_or(|limit| cycles < limit) {
+            match self.cycle() {
+                Stop::Done => cycles += 1,
+                stop => return stop,
+            }
+        }
+        Stop::CycleLimit
+    }
+
+    /// Whether history is recorded, so that execution can go backwards.
+    pub fn is_recording(&self) -> bool {
+        self.history.is_some()
+    }
+
+    /// Goes back `count` instructions, to the cycle the one before them retired in.
+    pub fn reverse_step(&mut self, count: u64) -> Stop {
+        let next_pc = self.pc();
+        let Some(history) = &mut self.history else {
+            return Stop::HistoryStart;
+        };
+        if count == 0 {
+            return Stop::Done;
+        }
+
+        let now = history.cycle;
+        let mut retired = history.retirements.iter().rev().map(|&(cycle, _)| cycle).filter(|&cycle| cycle < now);
+        match retired.nth(count as usize - 1) {
+            Some(cycle) => {
+                history.back_to(cycle, next_pc, &mut self.hart, &mut self.memory);
+                Stop::Done
+            }
+            None => {
+                history.back_to(history.oldest(), next_pc, &mut self.hart, &mut self.memory);
+                Stop::HistoryStart
+            }
+        }
+    }
+
+    /// Goes back to the last time running forward stopped at a breakpoint or watchpoint, as they are set now.
+    pub fn reverse_resume(&mut self) -> Stop {
+        let Some(mut history) = self.history.take() else {
+            return Stop::HistoryStart;
+        };
+
+        // Replays one snapshot interval after another, newest first, until one stops before the current cycle.
+        let now = history.cycle;
+        let mut found = None;
+        for (index, (start, snapshot)) in history.snapshots.iter().enumerate().rev() {
+            if *start >= now {
+                continue;
+            }
+            // Up to and including the cycle the next snapshot was taken after, which only its own replay would miss.
+            let last = history.snapshots.get(index + 1).map_or(now, |(next, _)| *next).min(now - 1);
+            (history.restore)(&mut self.hart, &mut self.memory, snapshot);
+            for cycle in start + 1..=last {
+                match self.cycle() {
+                    Stop::Done => {}
+                    stop => found = Some((cycle, stop)),
+                }
+            }
+            if found.is_some() {
+                break;
+            }
+        }
+
+        let stop = match found {
+            Some((cycle, stop)) => {
+                history.go_to(cycle, &mut self.hart, &mut self.memory);
+                stop
+            }
+            None => {
+                history.go_to(history.oldest(), &mut self.hart, &mut self.memory);
+                Stop::HistoryStart
+            }
+        };
+        self.history = Some(history);
+        stop
+    }
+
+    /// Reads commands from `input` until it ends or says quit, writing the answers and a prompt to `output`.
+    pub fn repl(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        write!(output, "{}", self.location())?;
+        loop {
+            write!(output, "(debug) ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            if matches!(line.trim(), "quit" | "q") {
+                return Ok(());
+            }
+
+            match self.command(&line) {
+                Ok(text) => write!(output, "{text}")?,
+                Err(message) => writeln!(output, "{message}")?,
+            }
+        }
+    }
+
+    /// Runs one REPL command and returns what it prints.
+    pub fn command(&mut self, line: &str) -> Result<String, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, arguments)) = words.split_first() else {
+            return Ok(String::new());
+        };
+
+        let watch_kind = match command {
+            "watch" => Some(WatchKind::Write),
+            "rwatch" => Some(WatchKind::Read),
+            "awatch" => Some(WatchKind::Access),
+            _ => None,
+        };
+
+        match command {
+            "step" | "s" => {
+                let count = numbers(arguments, 0, 1)?.first().copied().unwrap_or(1);
+                let stop = self.step_instructions(count as u64);
+                Ok(self.report(stop))
+            }
+            "cycle" => {
+                let cycles = numbers(arguments, 0, 1)?.first().copied().unwrap_or(1);
+                let stop = self.step_cycles(cycles as u64);
+                Ok(self.report(stop))
+            }
+            "continue" | "c" => {
+                let cycle_limit = numbers(arguments, 0, 1)?.first().map(|&limit| limit as u64);
+                let stop = self.resume(cycle_limit);
+                Ok(self.report(stop))
+            }
+            "reverse-step" | "rs" | "reverse-continue" | "rc" if !self.is_recording() => {
+                Err("history is not recorded, start the debugger with --record".to_string())
+            }
+            "reverse-step" | "rs" => {
+                let count = numbers(arguments, 0, 1)?.first().copied().unwrap_or(1);
+                let stop = self.reverse_step(count as u64);
+                Ok(self.report(stop))
+            }
+            "reverse-continue" | "rc" => {
+                numbers(arguments, 0, 0)?;
+                let stop = self.reverse_resume();
+                Ok(self.report(stop))
+            }
+            "break" | "b" => {
+                let address = numbers(arguments, 1, 1)?[0];
+                self.add_breakpoint(address);
+                Ok(format!("breakpoint at {}\n", self.symbols.describe(address)))
+            }
+            "delete" | "d" => {
+                let address = numbers(arguments, 1, 1)?[0];
+                match self.remove_breakpoint(address) {
+                    true => Ok(String::new()),
+                    false => Err(format!("no breakpoint at {address:08x}")),
+                }
+            }
+            "watch" | "rwatch" | "awatch" => {
+                let numbers = numbers(arguments, 1, 2)?;
+                let length = numbers.get(1).copied().unwrap_or(4);
+                if length == 0 {
+                    return Err("cannot watch 0 bytes".to_string());
+                }
+                let watchpoint = Watchpoint { address: numbers[0], length, kind: watch_kind.unwrap() };
+                self.add_watchpoint(watchpoint);
+                Ok(format!("{}\n", describe_watchpoint(watchpoint)))
+            }
+            "unwatch" => {
+                let address = numbers(arguments, 1, 1)?[0];
+                match self.remove_watchpoints(address, None) {
+                    true => Ok(String::new()),
+                    false => Err(format!("no watchpoint at {address:08x}")),
+                }
+            }
+            "info" => Ok(self.info()),
+            "registers" | "regs" => Ok(self.registers()),
+            "csr" => self.csrs(arguments),
+            "x" => {
+                let numbers = numbers(arguments, 1, 2)?;
+                Ok(self.hexdump(numbers[0], numbers.get(1).copied().unwrap_or(64)))
+            }
+            "disassemble" | "disas" => {
+                let numbers = numbers(arguments, 0, 2)?;
+                // Without an address the listing starts three instructions before the pc.
+                let start = numbers.first().copied().unwrap_or(self.pc().wrapping_sub(12));
+                Ok(self.disassembly(start, numbers.get(1).copied().unwrap_or(8)))
+            }
+            "pipeline" => Ok(self.pipeline()),
+            "help" => Ok(HELP.to_string()),
+            _ => Err(format!("unknown command {command}, try help")),
+        }
+    }
+
+    fn cycle(&mut self) -> Stop {
+        let before = self.pc();
+        self.hart.execute(&mut self.memory);
+        if let Some(history) = &mut self.history {
+            history.record(&self.hart, &self.memory);
+        }
+
+        for retirement in self.hart.retired() {
+            let Some(access) = retirement.memory_access else {
+                continue;
+            };
+            if let Some(&watchpoint) = self.watchpoints.iter().find(|watchpoint| watchpoint.matches(access)) {
+                return Stop::Watchpoint { pc: retirement.pc, access, watchpoint };
+            }
+        }
+
+        // An instruction that stays the oldest while stalled is only reported once, while a loop branching to
+        // itself reports every time the instruction comes around again.
+        let pc = self.pc();
+        match self.breakpoints.contains(&pc) && (pc != before || !self.hart.retired().is_empty()) {
+            true => Stop::Breakpoint { pc },
+            false => Stop::Done,
+        }
+    }
+
+    fn oldest(&self) -> Option<Latch> {
+        self.hart.pipeline().latches().into_iter().rev().find_map(|(_, latch)| latch)
+    }
+
+    fn report(&self, stop: Stop) -> String {
+        let reason = match stop {
+            Stop::Done => String::new(),
+            Stop::Breakpoint { pc } => format!("breakpoint at {}\n", self.symbols.describe(pc)),
+            Stop::Watchpoint { pc, access: MemoryAccess::Load { address, size }, .. } => {
+                format!("watchpoint: {size} byte load from {address:08x} at {}\n", self.symbols.describe(pc))
+            }
+            Stop::Watchpoint { pc, access: MemoryAccess::Store { address, value, size }, .. } => {
+                let pc = self.symbols.describe(pc);
+                format!("watchpoint: {size} byte store of {value:#x} to {address:08x} at {pc}\n")
+            }
+            Stop::CycleLimit => "stopped at the cycle limit\n".to_string(),
+            Stop::HistoryStart => "reached the start of the recorded history\n".to_string(),
+        };
+        reason + &self.location()
+    }
+
+    /// The next instruction to retire, as it was fetched when it is already in flight.
+    fn location(&self) -> String {
+        let pc = self.pc();
+        let location = self.symbols.describe(pc);
+        match self.oldest().map(|latch| latch.instruction).or_else(|| self.read_word(pc)) {
+            Some(word) => format!("=> {location}:\t{}\n", disassemble_word(pc, word)),
+            None => format!("=> {location}:\t<unreadable>\n"),
+        }
+    }
+
+    fn info(&self) -> String {
+        let breakpoints =
+            self.breakpoints.iter().map(|&address| format!("breakpoint at {}\n", self.symbols.describe(address)));
+        let watchpoints = self.watchpoints.iter().map(|&watchpoint| format!("{}\n", describe_watchpoint(watchpoint)));
+        let text: String = breakpoints.chain(watchpoints).collect();
+        match text.is_empty() {
+            true => "no breakpoints or watchpoints\n".to_string(),
+            false => text,
+        }
+    }
+
+    fn registers(&self) -> String {
+        let state = self.hart.state();
+        let privilege_level = match state.csrs.privilege_level() {
+            PrivilegeLevel::User => "U",
+            PrivilegeLevel::Supervisor => "S",
+            PrivilegeLevel::Machine => "M",
+        };
+
+        let mut text = format!("pc   {:08x}  privilege {privilege_level}\n", self.pc());
+        for row in 0..ABI_NAMES.len() / 4 {
+            let columns: Vec<String> = (row * 4..row * 4 + 4)
+                .map(|index| format!("{:<4} {:08x}", ABI_NAMES[index], state.register_file.read(index)))
+                .collect();
+            text += &columns.join("  ");
+            text += "\n";
+        }
+        text
+    }
+
+    fn csrs(&self, arguments: &[&str]) -> Result<String, String> {
+        let csrs = &self.hart.state().csrs;
+        let line = |address: u32, value: u32| {
+            let name = csr_name(address).unwrap_or_else(|| format!("{address:#05x}"));
+            format!("{name:<14} {value:08x}\n")
+        };
+
+        match arguments {
+            [] => Ok((0..4096)
+                .filter_map(|address| csrs.read(address).filter(|&value| value != 0).map(|value| line(address, value)))
+                .collect()),
+            [name] => {
+                let address = match (0..4096).find(|&address| csr_name(address).as_deref() == Some(name)) {
+                    Some(address) => address,
+                    None => parse_number(name)?,
+                };
+                let value = csrs.read(address).ok_or_else(|| format!("no CSR at {address:#05x}"))?;
+                Ok(line(address, value))
+            }
+            _ => Err("usage: csr [name | address]".to_string()),
+        }
+    }
+
+    /// Sixteen bytes a line with their ASCII, `??` for bytes the bus does not return.
+    fn hexdump(&self, address: u32, length: u32) -> String {
+        let end = address as u64 + length as u64;
+        (address as u64..end)
+            .step_by(16)
+            .map(|line| {
+                let bytes = self.read_memory(line as u32, (end.min(line + 16) - line) as u32);
+                let hex: Vec<String> =
+                    bytes.iter().map(|byte| byte.map_or("??".to_string(), |byte| format!("{byte:02x}"))).collect();
+                let ascii: String = bytes
+                    .iter()
+                    .map(|byte| match byte {
+                        Some(byte) if byte.is_ascii_graphic() || *byte == b' ' => *byte as char,
+                        _ => '.',
+                    })
+                    .collect();
+                format!("{line:08x}  {:<47}  |{ascii}|\n", hex.join(" "))
+            })
+            .collect()
+    }
+
+    fn disassembly(&self, start: u32, count: u32) -> String {
+        let pc = self.pc();
+        (0..count)
+            .map(|index| {
+                let address = start.wrapping_add(4 * index);
+                let marker = if address == pc { "=>" } else { "  " };
+                let location = self.symbols.describe(address);
+                match self.read_word(address) {
+                    Some(word) => format!("{marker} {location}:\t{word:08x}\t{}\n", disassemble_word(address, word)),
+                    None => format!("{marker} {location}:\t<unreadable>\n"),
+                }
+            })
+            .collect()
+    }
+
+    fn pipeline(&self) -> String {
+        let mut text = format!("{:<10} {}\n", "fetch", self.symbols.describe(self.hart.program_counter()));
+        for (stage, latch) in self.hart.pipeline().latches() {
+            let Some(Latch { pc, instruction, exception, register_write }) = latch else {
+                text += &format!("{stage:<10} -\n");
+                continue;
+            };
+
+            text += &format!("{stage:<10} {}:\t{}", self.symbols.describe(pc), disassemble_word(pc, instruction));
+            if let Some(RegisterWrite { index, value }) = register_write {
+                text += &format!("\t{} = {value:08x}", ABI_NAMES[index as usize]);
+            }
+            if let Some(exception) = exception {
+                text += &format!("\ttraps with {exception:?}");
+            }
+            text += "\n";
+        }
+        text
+    }
+
+    fn read_byte(&self, address: u32) -> Option<u8> {
+        self.hart.state().read_byte(&self.memory, address)
+    }
+
+    fn read_word(&self, address: u32) -> Option<u32> {
+        let bytes: Option<Vec<u8>> = self.read_memory(address, 4).into_iter().collect();
+        Some(u32::from_le_bytes(bytes?.try_into().ok()?))
+    }
+}
+
+impl<M> Debugger<M>
+where
+    M: BusInterface<u32, i8>,
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, i16>,
+    M: BusInterface<u32, u16>,
+    M: BusInterface<u32, u32>,
+    M: Clocked,
+    M: Snapshot,
+{
+    /// Records history from now on, keeping at most about `budget` bytes of it but always the latest snapshot.
+    pub fn record_history(&mut self, budget: usize) {
+        let mut history = History {
+            budget,
+            cycle: 0,
+            snapshots: VecDeque::new(),
+            retirements: VecDeque::new(),
+            barriers: VecDeque::new(),
+            traps: 0,
+            save: |hart, memory| snapshot::save(&[hart, memory]),
+            restore: |hart, memory, bytes| {
+                snapshot::restore(&mut [hart, memory], bytes).expect("snapshots fit the machine they were taken of")
+            },
+        };
+        self.hart.state_mut().record_undo = true;
+        history.rebase(&self.hart, &self.memory);
+        self.history = Some(history);
+    }
+}
+
+fn describe_watchpoint(Watchpoint { address, length, kind }: Watchpoint) -> String {
+    let accesses = match kind {
+        WatchKind::Read => "loads from",
+        WatchKind::Write => "stores to",
+        WatchKind::Access => "loads from and stores to",
+    };
+    format!("watchpoint on {accesses} {length} bytes at {address:08x}")
+}
+
+/// Parses the arguments of a command that takes `min` to `max` numbers.
+fn numbers(arguments: &[&str], min: usize, max: usize) -> Result<Vec<u32>, String> {
+    if arguments.len() < min {
+        return Err("missing argument, try help".to_string());
+    }
+    if arguments.len() > max {
+        return Err("too many arguments, try help".to_string());
+    }
+    arguments.iter().map(|text| parse_number(text)).collect()
+}
+
+/// Reads decimal or `0x` prefixed hexadecimal, for debugger commands and command lines alike.
+pub fn parse_number(text: &str) -> Result<u32, String> {
+    let parsed = match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse(),
+    };
+    parsed.map_err(|_| format!("invalid number: {text}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::core::bus::BusReadResponse;
+    use crate::core::cache::{Cache, CacheConfiguration};
+    use crate::core::csr::address_constants::MEPC;
+    use crate::memory::Memory;
+
+    fn debugger(source: &str) -> Debugger<Memory> {
+        let program = assemble(source, 0).unwrap();
+        Debugger::new(Hart::new(), Memory::with_initial_values(program.memory_image(256)))
+    }
+
+    #[test]
+    fn stops_at_breakpoints_and_watchpoints() {
+        let mut debugger = debugger(
+            "
+            li      t0, 5
+            li      t1, 0x80
+        loop:
+            sw      t0, 0(t1)
+            addi    t0, t0, -1
+            bnez    t0, loop
+            lw      t2, 0(t1)
+            j       .
+            ",
+        );
+
+        debugger.add_breakpoint(0xc);
+        assert_eq!(debugger.resume(Some(100)), Stop::Breakpoint { pc: 0xc });
+        assert_eq!(debugger.hart().state().register_file.read(5), 5);
+        assert_eq!(debugger.resume(Some(100)), Stop::Breakpoint { pc: 0xc });
+        assert_eq!(debugger.hart().state().register_file.read(5), 4);
+
+        debugger.remove_breakpoint(0xc);
+        let watchpoint = Watchpoint { address: 0x82, length: 1, kind: WatchKind::Read };
+        debugger.add_watchpoint(watchpoint);
+        let access = MemoryAccess::Load { address: 0x80, size: 4 };
+        assert_eq!(debugger.resume(Some(100)), Stop::Watchpoint { pc: 0x14, access, watchpoint });
+        assert_eq!(debugger.resume(Some(100)), Stop::CycleLimit);
+    }
+
+    #[test]
+    fn steps_by_instruction() {
+        let mut debugger = debugger("addi a0, zero, 1\naddi a0, a0, 1\naddi a0, a0, 1\nj .\n");
+
+        assert_eq!(debugger.command("step 2").unwrap(), "=> 00000008:\taddi    a0,a0,1\n");
+        assert_eq!(debugger.hart().state().register_file.read(10), 2);
+        assert!(debugger
+            .command("pipeline")
+            .unwrap()
+            .contains("write back 00000008:\taddi    a0,a0,1\ta0 = 00000003\n"));
+        assert_eq!(debugger.command("x 0 4").unwrap(), format!("00000000  13 05 10 00{}  |....|\n", " ".repeat(36)));
+        assert!(debugger.command("step 1 2").is_err());
+    }
+
+    #[test]
+    fn sees_memory_through_the_data_cache() {
+        let program = assemble("li t1, 0x80\nli t0, 7\nsw t0, 0(t1)\nlw t2, 0(t1)\nj .\n", 0).unwrap();
+        let mut hart = Hart::new();
+        hart.state_mut().data_cache = Some(Cache::new(CacheConfiguration::default()));
+        let mut debugger = Debugger::new(hart, Memory::with_initial_values(program.memory_image(256)));
+
+        // The store stays in the write-back cache.
+        debugger.step_instructions(3);
+        assert_eq!(debugger.read_memory(0x80, 1), [Some(7)]);
+        assert!(matches!(BusInterface::<u32, u8>::read(debugger.memory(), 0x80), BusReadResponse::Success(0)));
+
+        assert!(debugger.write_memory(0x80, &[9]));
+        debugger.step_instructions(1);
+        assert_eq!(debugger.hart().state().register_file.read(7), 9);
+    }
+
+    #[test]
+    fn goes_back_to_earlier_stops() {
+        let mut debugger = debugger(
+            "
+            li      t1, 0x80
+            li      t0, 0
+        loop:
+            addi    t0, t0, 1
+            sw      t0, 0(t1)
+            j       loop
+            ",
+        );
+        assert_eq!(debugger.command("rc").unwrap_err(), "history is not recorded, start the debugger with --record");
+        debugger.record_history(16 << 20);
+
+        // Far enough to cross a few snapshots.
+        let watchpoint = Watchpoint { address: 0x80, length: 4, kind: WatchKind::Write };
+        debugger.add_breakpoint(0x8);
+        while debugger.hart().state().register_file.read(5) < 8000 {
+            debugger.resume(None);
+        }
+        let registers = debugger.command("regs").unwrap();
+        let t0 = debugger.hart().state().register_file.read(5);
+        debugger.step_instructions(3);
+
+        debugger.remove_breakpoint(0x8);
+        debugger.add_watchpoint(watchpoint);
+        let access = MemoryAccess::Store { address: 0x80, value: t0 + 1, size: 4 };
+        assert_eq!(debugger.reverse_resume(), Stop::Watchpoint { pc: 0xc, access, watchpoint });
+        assert_eq!(debugger.read_memory(0x80, 1), [Some((t0 + 1) as u8)]);
+        assert_eq!(debugger.reverse_step(1), Stop::Done);
+        assert_eq!(debugger.pc(), 0xc);
+        assert_eq!(debugger.reverse_step(1), Stop::Done);
+        assert_eq!(debugger.command("regs").unwrap(), registers);
+
+        // Running forward again retraces the same steps.
+        assert_eq!(debugger.resume(None), Stop::Watchpoint { pc: 0xc, access, watchpoint });
+        assert_eq!(debugger.reverse_step(u32::MAX as u64), Stop::HistoryStart);
+        assert_eq!(debugger.hart().state().register_file.read(5), 0);
+    }
+
+    #[test]
+    fn steps_back_by_undoing_writes_and_replaying_traps() {
+        let mut debugger = debugger(
+            "
+            la      t0, handler
+            csrw    mtvec, t0
+            li      t1, 0x80
+        loop:
+            addi    a0, a0, 1
+            sb      a0, 0(t1)
+            sh      a0, 2(t1)
+            ecall
+            j       loop
+        handler:
+            csrr    t2, mepc
+            addi    t2, t2, 4
+            csrw    mepc, t2
+            mret
+            ",
+        );
+        debugger.record_history(16 << 20);
+        // Up to the sb of the fourth time around the loop, the ecall traps rather than retires.
+        debugger.step_instructions(4 + 3 * 8 + 1);
+        let registers = debugger.command("regs").unwrap();
+
+        // Only writes to registers and memory, undone without replaying. The sb had stored already while waiting to
+        // retire, it is undone as well and stores again.
+        debugger.step_instructions(2);
+        assert_eq!(debugger.read_memory(0x80, 4), [Some(4), Some(0), Some(4), Some(0)]);
+        assert_eq!(debugger.reverse_step(2), Stop::Done);
+        let history = debugger.history.as_ref().unwrap();
+        assert_eq!(history.snapshots.back().unwrap().0, history.cycle);
+        assert_eq!(debugger.command("regs").unwrap(), registers);
+        assert_eq!(debugger.read_memory(0x80, 4), [Some(3), Some(0), Some(3), Some(0)]);
+        debugger.step_instructions(1);
+        let registers = debugger.command("regs").unwrap();
+
+        // Across the trap and the handler's CSR accesses.
+        debugger.step_instructions(8);
+        assert_eq!(debugger.reverse_step(8), Stop::Done);
+        assert_eq!(debugger.command("regs").unwrap(), registers);
+        debugger.step_instructions(1);
+        assert_eq!(debugger.read_memory(0x80, 4), [Some(4), Some(0), Some(4), Some(0)]);
+        assert_eq!(debugger.hart().state().csrs.read(MEPC).unwrap(), 0x20);
+    }
+
+    #[test]
+    fn finds_stops_in_the_cycle_a_snapshot_was_taken() {
+        let source = "
+            li      t0, 10000
+        delay:
+            addi    t0, t0, -1
+            bnez    t0, delay
+            li      t1, 0x80
+            sw      t1, 0(t1)
+            j       .
+            ";
+        let watchpoint = Watchpoint { address: 0x80, length: 4, kind: WatchKind::Write };
+        let stop = Stop::Watchpoint {
+            pc: 0x14,
+            access: MemoryAccess::Store { address: 0x80, value: 0x80, size: 4 },
+            watchpoint,
+        };
+
+        let mut debugger = debugger(source);
+        debugger.record_history(16 << 20);
+        debugger.add_watchpoint(watchpoint);
+        assert_eq!(debugger.resume(None), stop);
+        let cycle = debugger.history.as_ref().unwrap().cycle;
+
+        // The store retires in the cycle the second snapshot is taken after.
+        let mut debugger = self::debugger(source);
+        assert_eq!(debugger.resume(Some(cycle - SNAPSHOT_INTERVAL)), Stop::CycleLimit);
+        debugger.record_history(16 << 20);
+        debugger.add_watchpoint(watchpoint);
+        assert_eq!(debugger.resume(None), stop);
+        assert_eq!(debugger.history.as_ref().unwrap().cycle, SNAPSHOT_INTERVAL);
+
+        debugger.remove_watchpoints(0x80, None);
+        debugger.resume(Some(100));
+        debugger.add_watchpoint(watchpoint);
+        assert_eq!(debugger.reverse_resume(), stop);
+        assert_eq!(debugger.history.as_ref().unwrap().cycle, SNAPSHOT_INTERVAL);
+    }
+}
@@ -0,0 +1,334 @@
+use crate::core::csr::csr_name;
+use crate::core::instruction::*;
+use crate::core::register_file::{RegisterFile, ABI_NAMES};
+use crate::core::unit::{decode_instruction, FetchResult};
+
+use AluInstruction::*;
+use BranchingInstruction::*;
+use FenceInstruction::*;
+use MemoryLoadInstruction::*;
+use MemoryStoreInstruction::*;
+use SystemInstruction::*;
+
+/// Renders an instruction fetched from `pc` in GNU assembler syntax, preferring the pseudo-instructions objdump
+/// prints. Branch and jump targets are absolute addresses.
+pub fn disassemble(pc: u32, instruction: Instruction) -> String {
+    let (mnemonic, operands) = match instruction {
+        Instruction::Alu(instr) => alu(instr),
+        Instruction::Branching(instr) => branching(pc, instr),
+        Instruction::MemoryLoad(instr) => load(instr),
+        Instruction::MemoryStore(instr) => store(instr),
+        Instruction::Fence(instr) => fence(instr),
+        Instruction::System(instr) => system(instr),
+    };
+
+    match operands.is_empty() {
+        true => mnemonic.to_string(),
+        false => format!("{mnemonic:<7} {}", operands.join(",")),
+    }
+}
+
+/// Decodes and renders a raw instruction word, words that do not decode are shown as data.
+pub fn disassemble_word(pc: u32, word: u32) -> String {
+    let fetch_result = FetchResult { captured_pc: pc, instruction: word };
+    match decode_instruction(fetch_result, &RegisterFile::new(32)) {
+        Ok(instruction) => disassemble(pc, instruction),
+        Err(_) => format!("{:<7} 0x{word:08x}", ".word"),
+    }
+}
+
+/// Disassembles `bytes` loaded at `address` in objdump's address, word, instruction layout, one line per word.
+pub fn disassemble_bytes(address: u32, bytes: &[u8]) -> String {
+    bytes
+        .chunks(4)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let pc = address.wrapping_add(4 * index as u32);
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            let word = u32::from_le_bytes(word);
+            format!("{pc:8x}:\t{word:08x}\t{}\n", disassemble_word(pc, word))
+        })
+        .collect()
+}
+
+fn register(index: u32) -> String {
+    ABI_NAMES[index as usize & 0x1f].to_string()
+}
+
+fn signed(immediate: u32) -> String {
+    (immediate as i32).to_string()
+}
+
+fn target(pc: u32, offset: u32) -> String {
+    format!("{:x}", pc.wrapping_add(offset))
+}
+
+fn csr(address: u32) -> String {
+    csr_name(address & 0xfff).unwrap_or_else(|| format!("0x{:x}", address & 0xfff))
+}
+
+fn alu(instr: AluInstruction) -> (&'static str, Vec<String>) {
+    match instr {
+        LUI(UType { register_destination_index: rd, immediate, .. }) => {
+            ("lui", vec![register(rd), format!("0x{:x}", immediate >> 12)])
+        }
+        AUIPC(UType { register_destination_index: rd, immediate, .. }) => {
+            ("auipc", vec![register(rd), format!("0x{:x}", immediate >> 12)])
+        }
+        ADDI(IType {
+            register_destination_index: 0,
+            register_source_one: DecodedRegisterValue { index: 0, .. },
+            immediate: 0,
+            ..
+        }) => ("nop", vec![]),
+        ADDI(IType {
+            register_destination_index: rd,
+            register_source_one: DecodedRegisterValue { index: 0, .. },
+            immediate,
+            ..
+        }) => ("li", vec![register(rd), signed(immediate)]),
+        ADDI(IType { register_destination_index: rd, register_source_one: rs1, immediate: 0, .. }) => {
+            ("mv", vec![register(rd), register(rs1.index)])
+        }
+        SLTIU(IType { register_destination_index: rd, register_source_one: rs1, immediate: 1, .. }) => {
+            ("seqz", vec![register(rd), register(rs1.index)])
+        }
+        XORI(IType { register_destination_index: rd, register_source_one: rs1, immediate: u32::MAX, .. }) => {
+            ("not", vec![register(rd), register(rs1.index)])
+        }
+        ADDI(instr) => immediate_operation("addi", instr),
+        SLTI(instr) => immediate_operation("slti", instr),
+        SLTIU(instr) => immediate_operation("sltiu", instr),
+        XORI(instr) => immediate_operation("xori", instr),
+        ORI(instr) => immediate_operation("ori", instr),
+        ANDI(instr) => immediate_operation("andi", instr),
+        // The shift amount is encoded where rs2 would be.
+        SLLI(instr) => shift("slli", instr),
+        SRLI(instr) => shift("srli", instr),
+        SRAI(instr) => shift("srai", instr),
+        SUB(RType {
+            register_destination_index: rd,
+            register_source_one: DecodedRegisterValue { index: 0, .. },
+            register_source_two: rs2,
+            ..
+        }) => ("neg", vec![register(rd), register(rs2.index)]),
+        SLTU(RType {
+            register_destination_index: rd,
+            register_source_one: DecodedRegisterValue { index: 0, .. },
+            register_source_two: rs2,
+            ..
+        }) => ("snez", vec![register(rd), register(rs2.index)]),
+        SLT(RType {
+            register_destination_index: rd,
+            register_source_one: rs1,
+            register_source_two: DecodedRegisterValue { index: 0, .. },
+            ..
+        }) => ("sltz", vec![register(rd), register(rs1.index)]),
+        SLT(RType {
+            register_destination_index: rd,
+            register_source_one: DecodedRegisterValue { index: 0, .. },
+            register_source_two: rs2,
+            ..
+        }) => ("sgtz", vec![register(rd), register(rs2.index)]),
+        ADD(instr) => register_operation("add", instr),
+        SUB(instr) => register_operation("sub", instr),
+        SLL(instr) => register_operation("sll", instr),
+        SLT(instr) => register_operation("slt", instr),
+        SLTU(instr) => register_operation("sltu", instr),
+        XOR(instr) => register_operation("xor", instr),
+        SRL(instr) => register_operation("srl", instr),
+        SRA(instr) => register_operation("sra", instr),
+        OR(instr) => register_operation("or", instr),
+        AND(instr) => register_operation("and", instr),
+    }
+}
+
+fn immediate_operation(mnemonic: &'static str, instr: IType) -> (&'static str, Vec<String>) {
+    let IType { register_destination_index: rd, register_source_one: rs1, immediate, .. } = instr;
+    (mnemonic, vec![register(rd), register(rs1.index), signed(immediate)])
+}
+
+fn shift(mnemonic: &'static str, instr: RType) -> (&'static str, Vec<String>) {
+    let RType { register_destination_index: rd, register_source_one: rs1, register_source_two: shamt, .. } = instr;
+    (mnemonic, vec![register(rd), register(rs1.index), shamt.index.to_string()])
+}
+
+fn register_operation(mnemonic: &'static str, instr: RType) -> (&'static str, Vec<String>) {
+    let RType { register_destination_index: rd, register_source_one: rs1, register_source_two: rs2, .. } = instr;
+    (mnemonic, vec![register(rd), register(rs1.index), register(rs2.index)])
+}
+
+fn branching(pc: u32, instr: BranchingInstruction) -> (&'static str, Vec<String>) {
+    match instr {
+        JAL(JType { register_destination_index: 0, immediate, .. }) => ("j", vec![target(pc, immediate)]),
+        JAL(JType { register_destination_index: 1, immediate, .. }) => ("jal", vec![target(pc, immediate)]),
+        JAL(JType { register_destination_index: rd, immediate, .. }) => {
+            ("jal", vec![register(rd), target(pc, immediate)])
+        }
+        JALR(IType {
+            register_destination_index: 0,
+            register_source_one: DecodedRegisterValue { index: 1, .. },
+            immediate: 0,
+            ..
+        }) => ("ret", vec![]),
+        JALR(IType { register_destination_index: 0, register_source_one: rs1, immediate: 0, .. }) => {
+            ("jr", vec![register(rs1.index)])
+        }
+        JALR(IType { register_destination_index: 1, register_source_one: rs1, immediate: 0, .. }) => {
+            ("jalr", vec![register(rs1.index)])
+        }
+        JALR(IType { register_destination_index: rd, register_source_one: rs1, immediate, .. }) => {
+            ("jalr", vec![register(rd), format!("{}({})", signed(immediate), register(rs1.index))])
+        }
+        BEQ(instr) => conditional(pc, instr, "beq", Some("beqz"), None),
+        BNE(instr) => conditional(pc, instr, "bne", Some("bnez"), None),
+        BLT(instr) => conditional(pc, instr, "blt", Some("bltz"), Some("bgtz")),
+        BGE(instr) => conditional(pc, instr, "bge", Some("bgez"), Some("blez")),
+        BLTU(instr) => conditional(pc, instr, "bltu", None, None),
+        BGEU(instr) => conditional(pc, instr, "bgeu", None, None),
+    }
+}
+
+/// Comparisons against zero use the single register form: `against_zero` when rs2 is zero, `zero_against` when rs1 is.
+fn conditional(
+    pc: u32,
+    instr: BType,
+    mnemonic: &'static str,
+    against_zero: Option<&'static str>,
+    zero_against: Option<&'static str>,
+) -> (&'static str, Vec<String>) {
+    let BType { register_source_one: rs1, register_source_two: rs2, immediate, .. } = instr;
+    match (rs1.index, rs2.index, against_zero, zero_against) {
+        (_, 0, Some(alias), _) => (alias, vec![register(rs1.index), target(pc, immediate)]),
+        (0, _, _, Some(alias)) => (alias, vec![register(rs2.index), target(pc, immediate)]),
+        _ => (mnemonic, vec![register(rs1.index), register(rs2.index), target(pc, immediate)]),
+    }
+}
+
+fn load(instr: MemoryLoadInstruction) -> (&'static str, Vec<String>) {
+    let (mnemonic, IType { register_destination_index: rd, register_source_one: rs1, immediate, .. }) = match instr {
+        LB(instr) => ("lb", instr),
+        LH(instr) => ("lh", instr),
+        LW(instr) => ("lw", instr),
+        LBU(instr) => ("lbu", instr),
+        LHU(instr) => ("lhu", instr),
+    };
+    (mnemonic, vec![register(rd), format!("{}({})", signed(immediate), register(rs1.index))])
+}
+
+fn store(instr: MemoryStoreInstruction) -> (&'static str, Vec<String>) {
+    let (mnemonic, SType { register_source_one: rs1, register_source_two: rs2, immediate, .. }) = match instr {
+        SB(instr) => ("sb", instr),
+        SH(instr) => ("sh", instr),
+        SW(instr) => ("sw", instr),
+    };
+    (
+        mnemonic,
+        vec![
+            register(rs2.index),
+            format!("{}({})", signed(immediate), register(rs1.index)),
+        ],
+    )
+}
+
+fn fence(instr: FenceInstruction) -> (&'static str, Vec<String>) {
+    match instr {
+        FENCE(IType { immediate, .. }) => {
+            let (predecessor, successor) = ((immediate >> 4) & 0xf, immediate & 0xf);
+            match (predecessor, successor) {
+                (0xf, 0xf) => ("fence", vec![]),
+                _ => ("fence", vec![fence_set(predecessor), fence_set(successor)]),
+            }
+        }
+        FENCE_I(_) => ("fence.i", vec![]),
+    }
+}
+
+fn fence_set(bits: u32) -> String {
+    let set: String =
+        "iorw".chars().enumerate().filter(|(index, _)| bits & (0b1000 >> index) != 0).map(|(_, c)| c).collect();
+    match set.is_empty() {
+        true => "0".to_string(),
+        false => set,
+    }
+}
+
+fn system(instr: SystemInstruction) -> (&'static str, Vec<String>) {
+    match instr {
+        ECALL(_) => ("ecall", vec![]),
+        EBREAK(_) => ("ebreak", vec![]),
+        SRET(_) => ("sret", vec![]),
+        MRET(_) => ("mret", vec![]),
+        WFI(_) => ("wfi", vec![]),
+        SFENCE_VMA(RType { register_source_one: rs1, register_source_two: rs2, .. }) => match (rs1.index, rs2.index) {
+            (0, 0) => ("sfence.vma", vec![]),
+            (rs1, 0) => ("sfence.vma", vec![register(rs1)]),
+            (rs1, rs2) => ("sfence.vma", vec![register(rs1), register(rs2)]),
+        },
+        CSRRS(IType {
+            register_destination_index: rd,
+            register_source_one: DecodedRegisterValue { index: 0, .. },
+            immediate,
+            ..
+        }) => ("csrr", vec![register(rd), csr(immediate)]),
+        CSRRW(instr) => csr_operation(instr, "csrrw", "csrw", register),
+        CSRRS(instr) => csr_operation(instr, "csrrs", "csrs", register),
+        CSRRC(instr) => csr_operation(instr, "csrrc", "csrc", register),
+        CSRRWI(instr) => csr_operation(instr, "csrrwi", "csrwi", |immediate| immediate.to_string()),
+        CSRRSI(instr) => csr_operation(instr, "csrrsi", "csrsi", |immediate| immediate.to_string()),
+        CSRRCI(instr) => csr_operation(instr, "csrrci", "csrci", |immediate| immediate.to_string()),
+    }
+}
+
+/// Without a destination the write-only alias is used.
+fn csr_operation(
+    instr: IType,
+    mnemonic: &'static str,
+    write_only: &'static str,
+    source: fn(u32) -> String,
+) -> (&'static str, Vec<String>) {
+    let IType { register_destination_index: rd, register_source_one: rs1, immediate, .. } = instr;
+    match rd {
+        0 => (write_only, vec![csr(immediate), source(rs1.index)]),
+        _ => (mnemonic, vec![register(rd), csr(immediate), source(rs1.index)]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_gnu_syntax_with_aliases() {
+        let cases = [
+            (0x0000_0013, "nop"),
+            (0xff01_0113, "addi    sp,sp,-16"),
+            (0x0050_0513, "li      a0,5"),
+            (0x0005_8513, "mv      a0,a1"),
+            (0x0000_8067, "ret"),
+            (0x0081_2503, "lw      a0,8(sp)"),
+            (0x00a1_2423, "sw      a0,8(sp)"),
+            (0x0035_1513, "slli    a0,a0,3"),
+            (0x4035_5513, "srai    a0,a0,3"),
+            (0x1234_5537, "lui     a0,0x12345"),
+            (0x3400_2573, "csrr    a0,mscratch"),
+            (0x3405_9073, "csrw    mscratch,a1"),
+            (0x0ff0_000f, "fence"),
+            (0x0000_0000, ".word   0x00000000"),
+        ];
+
+        for (word, text) in cases {
+            assert_eq!(disassemble_word(0x8000_0000, word), text);
+        }
+    }
+
+    #[test]
+    fn branch_targets_are_absolute() {
+        // jal ra,-8; beq a0,zero,16; bne a0,a1,-4; jal zero,2048
+        assert_eq!(disassemble_word(0x8000_0010, 0xff9f_f0ef), "jal     80000008");
+        assert_eq!(disassemble_word(0x8000_0010, 0x0005_0863), "beqz    a0,80000020");
+        assert_eq!(disassemble_word(0x8000_0010, 0xfeb5_1ee3), "bne     a0,a1,8000000c");
+        assert_eq!(disassemble_word(0x8000_0010, 0x0010_006f), "j       80000810");
+    }
+}
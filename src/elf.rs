@@ -0,0 +1,314 @@
+use std::fmt;
+
+const MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const CLASS_32: u8 = 1;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const MACHINE_RISC_V: u16 = 243;
+const SYMBOL_SIZE: usize = 16;
+
+pub const SECTION_PROGBITS: u32 = 1;
+pub const SECTION_SYMBOL_TABLE: u32 = 2;
+pub const SECTION_NOBITS: u32 = 8;
+pub const SEGMENT_LOAD: u32 = 1;
+pub const SYMBOL_OBJECT: u8 = 1;
+pub const SYMBOL_FUNCTION: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ElfError {
+    NotElf,
+    /// Only 32 bit little endian RISC-V files are read.
+    Unsupported,
+    Truncated,
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::NotElf => write!(f, "not an ELF file"),
+            ElfError::Unsupported => write!(f, "not a 32 bit little endian RISC-V ELF file"),
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Section {
+    pub name: String,
+    pub kind: u32,
+    pub flags: u32,
+    pub address: u32,
+    pub offset: u32,
+    pub size: u32,
+    /// For a symbol table, the index of the string table holding its names.
+    pub link: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Segment {
+    pub kind: u32,
+    pub offset: u32,
+    pub virtual_address: u32,
+    pub physical_address: u32,
+    pub file_size: u32,
+    pub memory_size: u32,
+    pub flags: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u32,
+    pub size: u32,
+    /// `SYMBOL_FUNCTION`, `SYMBOL_OBJECT` and so on.
+    pub kind: u8,
+    /// The index of the section the symbol is defined in, 0 when it is undefined.
+    pub section: u16,
+}
+
+/// The parts of an ELF32 RISC-V file the simulator and its tools use, borrowing the file's bytes.
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    pub entry: u32,
+    /// Where the program headers are in the file, for loaders that pass them on to the program.
+    pub program_header_offset: u32,
+    pub sections: Vec<Section>,
+    pub segments: Vec<Segment>,
+    /// The named symbols of the symbol table, empty when the file is stripped.
+    pub symbols: Vec<Symbol>,
+}
+
+impl<'a> Elf<'a> {
+    pub fn is_elf(bytes: &[u8]) -> bool {
+        bytes.starts_with(&MAGIC)
+    }
+
+    pub fn parse(bytes: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if !Elf::is_elf(bytes) {
+            return Err(ElfError::NotElf);
+        }
+        if bytes.len() < 52 {
+            return Err(ElfError::Truncated);
+        }
+        if bytes[4] != CLASS_32 || bytes[5] != DATA_LITTLE_ENDIAN || half(bytes, 18)? != MACHINE_RISC_V {
+            return Err(ElfError::Unsupported);
+        }
+
+        let entry = word(bytes, 24)?;
+        let program_headers = word(bytes, 28)? as usize;
+        let section_headers = word(bytes, 32)? as usize;
+        let program_header_size = half(bytes, 42)? as usize;
+        let program_header_count = half(bytes, 44)? as usize;
+        let section_header_size = half(bytes, 46)? as usize;
+        let section_header_count = half(bytes, 48)? as usize;
+        let names_index = half(bytes, 50)? as usize;
+
+        let segments = (0..program_header_count)
+            .map(|index| {
+                let header = program_headers + index * program_header_size;
+                Ok(Segment {
+                    kind: word(bytes, header)?,
+                    offset: word(bytes, header + 4)?,
+                    virtual_address: word(bytes, header + 8)?,
+                    physical_address: word(bytes, header + 12)?,
+                    file_size: word(bytes, header + 16)?,
+                    memory_size: word(bytes, header + 20)?,
+                    flags: word(bytes, header + 24)?,
+                })
+            })
+            .collect::<Result<Vec<_>, ElfError>>()?;
+
+        let mut name_offsets = Vec::with_capacity(section_header_count);
+        let mut sections = (0..section_header_count)
+            .map(|index| {
+                let header = section_headers + index * section_header_size;
+                name_offsets.push(word(bytes, header)? as usize);
+                Ok(Section {
+                    name: String::new(),
+                    kind: word(bytes, header + 4)?,
+                    flags: word(bytes, header + 8)?,
+                    address: word(bytes, header + 12)?,
+                    offset: word(bytes, header + 16)?,
+                    size: word(bytes, header + 20)?,
+                    link: word(bytes, header + 24)?,
+                })
+            })
+            .collect::<Result<Vec<_>, ElfError>>()?;
+
+        if let Some(names) = sections.get(names_index).cloned() {
+            let names = data(bytes, &names)?;
+            for (section, name_offset) in sections.iter_mut().zip(name_offsets) {
+                section.name = string(names, name_offset)?;
+            }
+        }
+
+        let symbols = match sections.iter().find(|section| section.kind == SECTION_SYMBOL_TABLE) {
+            Some(table) => symbols(bytes, table, &sections)?,
+            None => Vec::new(),
+        };
+
+        let program_header_offset = program_headers as u32;
+        Ok(Elf { bytes, entry, program_header_offset, sections, segments, symbols })
+    }
+
+    pub fn section(&self, name: &str) -> Option<&Section> {
+        self.sections.iter().find(|section| section.name == name)
+    }
+
+    /// The first defined symbol with the name.
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name && symbol.section != 0)
+    }
+
+    /// The section's contents in the file, empty for sections that only take up memory like .bss.
+    pub fn section_data(&self, section: &Section) -> Result<&'a [u8], ElfError> {
+        data(self.bytes, section)
+    }
+
+    pub fn segment_data(&self, segment: &Segment) -> Result<&'a [u8], ElfError> {
+        slice(self.bytes, segment.offset as usize, segment.file_size as usize)
+    }
+}
+
+fn symbols(bytes: &[u8], table: &Section, sections: &[Section]) -> Result<Vec<Symbol>, ElfError> {
+    let names = sections.get(table.link as usize).ok_or(ElfError::Truncated)?;
+    let names = data(bytes, names)?;
+    let entries = data(bytes, table)?;
+
+    // The first entry is always the null symbol.
+    let mut symbols = Vec::new();
+    for entry in (SYMBOL_SIZE..entries.len()).step_by(SYMBOL_SIZE) {
+        let name = string(names, word(entries, entry)? as usize)?;
+        if name.is_empty() {
+            continue;
+        }
+        symbols.push(Symbol {
+            name,
+            value: word(entries, entry + 4)?,
+            size: word(entries, entry + 8)?,
+            kind: *entries.get(entry + 12).ok_or(ElfError::Truncated)? & 0xf,
+            section: half(entries, entry + 14)?,
+        });
+    }
+    Ok(symbols)
+}
+
+fn data<'a>(bytes: &'a [u8], section: &Section) -> Result<&'a [u8], ElfError> {
+    match section.kind {
+        SECTION_NOBITS => Ok(&[]),
+        _ => slice(bytes, section.offset as usize, section.size as usize),
+    }
+}
+
+fn slice(bytes: &[u8], offset: usize, size: usize) -> Result<&[u8], ElfError> {
+    bytes.get(offset..offset.checked_add(size).ok_or(ElfError::Truncated)?).ok_or(ElfError::Truncated)
+}
+
+fn half(bytes: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = slice(bytes, offset, 2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn word(bytes: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = slice(bytes, offset, 4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// A NUL terminated string from a string table.
+fn string(table: &[u8], offset: usize) -> Result<String, ElfError> {
+    let bytes = table.get(offset..).ok_or(ElfError::Truncated)?;
+    let length = bytes.iter().position(|&byte| byte == 0).ok_or(ElfError::Truncated)?;
+    Ok(String::from_utf8_lossy(&bytes[..length]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An executable with a .text section holding `text` at 0x8000_0000, a symbol `_start` there and the section
+    /// name table.
+    fn executable(text: &[u8]) -> Vec<u8> {
+        let names = b"\0.text\0.shstrtab\0.symtab\0.strtab\0";
+        let strings = b"\0_start\0";
+        let mut symbols = vec![0; 16];
+        for field in [1u32, 0x8000_0000, 0] {
+            symbols.extend_from_slice(&field.to_le_bytes());
+        }
+        symbols.extend_from_slice(&[0x10 | SYMBOL_FUNCTION, 0, 1, 0]);
+
+        let text_offset = 52;
+        let names_offset = text_offset + text.len();
+        let symbols_offset = names_offset + names.len();
+        let strings_offset = symbols_offset + symbols.len();
+        let section_headers = strings_offset + strings.len();
+
+        let mut bytes = vec![0; 52];
+        bytes[..6].copy_from_slice(&[0x7f, b'E', b'L', b'F', CLASS_32, DATA_LITTLE_ENDIAN]);
+        bytes[18..20].copy_from_slice(&MACHINE_RISC_V.to_le_bytes());
+        bytes[24..28].copy_from_slice(&0x8000_0000u32.to_le_bytes());
+        bytes[32..36].copy_from_slice(&(section_headers as u32).to_le_bytes());
+        bytes[46..48].copy_from_slice(&40u16.to_le_bytes());
+        bytes[48..50].copy_from_slice(&5u16.to_le_bytes());
+        bytes[50..52].copy_from_slice(&2u16.to_le_bytes());
+        bytes.extend_from_slice(text);
+        bytes.extend_from_slice(names);
+        bytes.extend_from_slice(&symbols);
+        bytes.extend_from_slice(strings);
+
+        // Name, type, flags, address, offset, size and link.
+        let headers = [
+            [0; 7],
+            [
+                1,
+                SECTION_PROGBITS,
+                0x6,
+                0x8000_0000,
+                text_offset as u32,
+                text.len() as u32,
+                0,
+            ],
+            [7, 3, 0, 0, names_offset as u32, names.len() as u32, 0],
+            [
+                17,
+                SECTION_SYMBOL_TABLE,
+                0,
+                0,
+                symbols_offset as u32,
+                symbols.len() as u32,
+                4,
+            ],
+            [25, 3, 0, 0, strings_offset as u32, strings.len() as u32, 0],
+        ];
+        for header in headers {
+            header.iter().for_each(|field| bytes.extend_from_slice(&field.to_le_bytes()));
+            bytes.extend_from_slice(&[0; 12]);
+        }
+        bytes
+    }
+
+    #[test]
+    fn reads_sections_by_name() {
+        let bytes = executable(&[0x13, 0, 0, 0]);
+        let elf = Elf::parse(&bytes).unwrap();
+
+        assert_eq!(elf.entry, 0x8000_0000);
+        let text = elf.section(".text").unwrap();
+        assert_eq!(text.address, 0x8000_0000);
+        assert_eq!(elf.section_data(text).unwrap(), &[0x13, 0, 0, 0]);
+        assert!(elf.section(".data").is_none());
+
+        let start =
+            Symbol { name: "_start".to_string(), value: 0x8000_0000, size: 0, kind: SYMBOL_FUNCTION, section: 1 };
+        assert_eq!(elf.symbol("_start"), Some(&start));
+        assert_eq!(elf.symbols.len(), 1);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert_eq!(Elf::parse(b"#!/bin/sh").err(), Some(ElfError::NotElf));
+        assert_eq!(Elf::parse(&executable(&[])[..40]).err(), Some(ElfError::Truncated));
+
+        let mut bytes = executable(&[]);
+        bytes[4] = 2;
+        assert_eq!(Elf::parse(&bytes).err(), Some(ElfError::Unsupported));
+    }
+}
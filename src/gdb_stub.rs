@@ -0,0 +1,521 @@
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+
+use crate::core::bus::{BusInterface, Clocked};
+use crate::core::commit_log::MemoryAccess;
+use crate::core::csr::csr_name;
+use crate::core::register_file::ABI_NAMES;
+use crate::debugger::{Debugger, Stop, WatchKind, Watchpoint};
+
+/// Cycles run between checks for a Ctrl-C from the debugger.
+const POLL_CYCLES: u64 = 10_000;
+const PC_REGISTER: usize = 32;
+/// GDB numbers the CSRs from here on, by address.
+const FIRST_CSR_REGISTER: usize = 65;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const INTERRUPT: u8 = 0x03;
+
+/// A byte stream to GDB that can be polled for a Ctrl-C while the hart runs.
+pub trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Serves GDB's remote serial protocol for one hart, so `target remote` can debug the guest.
+///
+/// Software and hardware breakpoints both stop the debugger without patching memory. Registers are the 32 integer
+/// registers and the pc, plus every implemented CSR through the target description.
+pub struct GdbStub<C: Connection, M>
+where
+    M: BusInterface<u32, i8>,
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, i16>,
+    M: BusInterface<u32, u16>,
+    M: BusInterface<u32, u32>,
+    M: Clocked,
+{
+    connection: C,
+    debugger: Debugger<M>,
+    acknowledge: bool,
+    /// Breakpoints GDB set as hardware ones, which it wants reported as such.
+    hardware_breakpoints: BTreeSet<u32>,
+}
+
+impl<C: Connection, M> GdbStub<C, M>
+where
+    M: BusInterface<u32, i8>,
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, i16>,
+    M: BusInterface<u32, u16>,
+    M: BusInterface<u32, u32>,
+    M: Clocked,
+{
+    pub fn new(connection: C, debugger: Debugger<M>) -> Self {
+        GdbStub { connection, debugger, acknowledge: true, hardware_breakpoints: BTreeSet::new() }
+    }
+
+    /// Answers packets until GDB detaches, kills the target or hangs up, then hands the debugger back.
+    pub fn serve(mut self) -> io::Result<Debugger<M>> {
+        while let Some(packet) = self.read_packet()? {
+            match packet.as_str() {
+                "D" => {
+                    self.write_packet("OK")?;
+                    break;
+                }
+                "k" => break,
+                _ => {
+                    let reply = self.reply(&packet)?;
+                    self.write_packet(&reply)?;
+                }
+            }
+        }
+        Ok(self.debugger)
+    }
+
+    fn reply(&mut self, packet: &str) -> io::Result<String> {
+        let (command, arguments) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+        let reply = match command {
+            "?" => format!("S{SIGTRAP:02x}"),
+            "g" => (0..=PC_REGISTER).map(|register| hex_word(self.read_register(register).unwrap())).collect(),
+            "G" => match words(arguments) {
+                Some(values) if values.len() == PC_REGISTER + 1 => {
+                    for (register, value) in values.into_iter().enumerate() {
+                        self.write_register(register, value);
+                    }
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(arguments, 16).ok().and_then(|register| self.read_register(register)) {
+                Some(value) => hex_word(value),
+                None => "E01".to_string(),
+            },
+            "P" => match arguments.split_once('=') {
+                Some((register, value)) => {
+                    let register = usize::from_str_radix(register, 16).ok();
+                    let value = words(value).filter(|values| values.len() == 1);
+                    match (register, value) {
+                        (Some(register), Some(value)) if self.write_register(register, value[0]) => "OK".to_string(),
+                        _ => "E01".to_string(),
+                    }
+                }
+                None => "E01".to_string(),
+            },
+            "m" => match address_and_length(arguments) {
+                Some((address, length)) => {
+                    let bytes: Option<Vec<u8>> = self.debugger.read_memory(address, length).into_iter().collect();
+                    bytes.map_or("E01".to_string(), |bytes| bytes.iter().map(|byte| format!("{byte:02x}")).collect())
+                }
+                None => "E01".to_string(),
+            },
+            "M" => {
+                let write = arguments.split_once(':').and_then(|(range, data)| {
+                    let (address, length) = address_and_length(range)?;
+                    let bytes = bytes(data).filter(|bytes| bytes.len() == length as usize)?;
+                    Some(self.debugger.write_memory(address, &bytes))
+                });
+                match write {
+                    Some(true) => "OK".to_string(),
+                    _ => "E01".to_string(),
+                }
+            }
+            "s" | "c" => {
+                // Either may resume somewhere else.
+                if !arguments.is_empty() {
+                    match u32::from_str_radix(arguments, 16) {
+                        Ok(address) => self.debugger.restart(address),
+                        Err(_) => return Ok("E01".to_string()),
+                    }
+                }
+                match command {
+                    "s" => {
+                        let stop = self.debugger.step_instructions(1);
+                        self.stop_reply(stop)
+                    }
+                    _ => self.resume()?,
+                }
+            }
+            // Reverse step and continue, offered when the debugger records history.
+            "b" => match arguments {
+                "s" => {
+                    let stop = self.debugger.reverse_step(1);
+                    self.stop_reply(stop)
+                }
+                "c" => {
+                    let stop = self.debugger.reverse_resume();
+                    self.stop_reply(stop)
+                }
+                _ => String::new(),
+            },
+            "Z" | "z" => self.breakpoint(command == "Z", arguments),
+            "H" => "OK".to_string(),
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        };
+        Ok(reply)
+    }
+
+    fn query(&self, packet: &str) -> String {
+        match packet {
+            "qAttached" => "1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            "qC" => "QC1".to_string(),
+            _ if packet.starts_with("qSupported") => {
+                let features = "PacketSize=4000;qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+";
+                match self.debugger.is_recording() {
+                    true => format!("{features};ReverseStep+;ReverseContinue+"),
+                    false => features.to_string(),
+                }
+            }
+            _ => match packet.strip_prefix("qXfer:features:read:target.xml:") {
+                Some(range) => match address_and_length(range) {
+                    Some((offset, length)) => {
+                        let description = self.target_description();
+                        let start = (offset as usize).min(description.len());
+                        let end = (start + length as usize).min(description.len());
+                        let marker = if end == description.len() { 'l' } else { 'm' };
+                        format!("{marker}{}", &description[start..end])
+                    }
+                    None => "E01".to_string(),
+                },
+                None => String::new(),
+            },
+        }
+    }
+
+    /// `Z`/`z` type,address,kind: 0 and 1 are breakpoints, 2 to 4 write, read and access watchpoints of `kind` bytes.
+    fn breakpoint(&mut self, insert: bool, arguments: &str) -> String {
+        let fields: Vec<&str> = arguments.splitn(3, ',').collect();
+        let [kind, address, length] = fields[..] else {
+            return "E01".to_string();
+        };
+        let (Ok(address), Ok(length)) = (u32::from_str_radix(address, 16), u32::from_str_radix(length, 16)) else {
+            return "E01".to_string();
+        };
+
+        let watch_kind = match kind {
+            "0" | "1" => {
+                match insert {
+                    true => self.debugger.add_breakpoint(address),
+                    false => self.debugger.remove_breakpoint(address),
+                };
+                match (insert, kind) {
+                    (true, "1") => self.hardware_breakpoints.insert(address),
+                    _ => self.hardware_breakpoints.remove(&address),
+                };
+                return "OK".to_string();
+            }
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            _ => return String::new(),
+        };
+
+        match insert {
+            true => self.debugger.add_watchpoint(Watchpoint { address, length, kind: watch_kind }),
+            false => {
+                self.debugger.remove_watchpoints(address, Some(watch_kind));
+            }
+        }
+        "OK".to_string()
+    }
+
+    /// Runs until a stop, checking for a Ctrl-C every `POLL_CYCLES` cycles.
+    fn resume(&mut self) -> io::Result<String> {
+        loop {
+            match self.debugger.resume(Some(POLL_CYCLES)) {
+                Stop::CycleLimit => {
+                    if self.interrupted()? {
+                        return Ok(format!("T{SIGINT:02x}"));
+                    }
+                }
+                stop => return Ok(self.stop_reply(stop)),
+            }
+        }
+    }
+
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.connection.set_nonblocking(true)?;
+        let mut byte = [0];
+        let read = self.connection.read(&mut byte);
+        self.connection.set_nonblocking(false)?;
+
+        match read {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) => Ok(byte[0] == INTERRUPT),
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn stop_reply(&self, stop: Stop) -> String {
+        match stop {
+            Stop::Breakpoint { pc } if self.hardware_breakpoints.contains(&pc) => format!("T{SIGTRAP:02x}hwbreak:;"),
+            Stop::Breakpoint { .. } => format!("T{SIGTRAP:02x}swbreak:;"),
+            Stop::Watchpoint { access, watchpoint, .. } => {
+                let (MemoryAccess::Load { address, .. } | MemoryAccess::Store { address, .. }) = access;
+                let kind = match watchpoint.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                format!("T{SIGTRAP:02x}{kind}:{address:x};")
+            }
+            Stop::Done | Stop::CycleLimit => format!("T{SIGTRAP:02x}"),
+            Stop::HistoryStart => format!("T{SIGTRAP:02x}replaylog:begin;"),
+        }
+    }
+
+    fn read_register(&self, register: usize) -> Option<u32> {
+        let state = self.debugger.hart().state();
+        match register {
+            0..PC_REGISTER => Some(state.register_file.read(register)),
+            PC_REGISTER => Some(self.debugger.pc()),
+            _ => state.csrs.read(register.checked_sub(FIRST_CSR_REGISTER)? as u32),
+        }
+    }
+
+    /// Returns false for registers that do not exist.
+    fn write_register(&mut self, register: usize, value: u32) -> bool {
+        match register {
+            0..PC_REGISTER => self.debugger.write_register(register, value),
+            PC_REGISTER => self.debugger.restart(value),
+            _ if self.read_register(register).is_some() => {
+                self.debugger.write_csr((register - FIRST_CSR_REGISTER) as u32, value)
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// The registers GDB reads with `g` in the standard RISC-V feature, followed by every implemented CSR.
+    fn target_description(&self) -> String {
+        let cpu: String = ABI_NAMES
+            .iter()
+            .enumerate()
+            .map(|(register, name)| {
+                let kind = match *name {
+                    "ra" => "code_ptr",
+                    "sp" | "gp" | "tp" | "s0" => "data_ptr",
+                    _ => "int",
+                };
+                format!("<reg name=\"{name}\" bitsize=\"32\" type=\"{kind}\" regnum=\"{register}\"/>")
+            })
+            .collect();
+
+        let csrs = &self.debugger.hart().state().csrs;
+        let csr: String = (0..4096)
+            .filter(|&address| csrs.read(address).is_some())
+            .filter_map(|address| {
+                let name = csr_name(address)?;
+                let register = FIRST_CSR_REGISTER + address as usize;
+                Some(format!("<reg name=\"{name}\" bitsize=\"32\" type=\"int\" regnum=\"{register}\"/>"))
+            })
+            .collect();
+
+        format!(
+            "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target version=\"1.0\">\
+             <architecture>riscv:rv32</architecture>\
+             <feature name=\"org.gnu.gdb.riscv.cpu\">{cpu}\
+             <reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{PC_REGISTER}\"/></feature>\
+             <feature name=\"org.gnu.gdb.riscv.csr\">{csr}</feature></target>"
+        )
+    }
+
+    /// Skips acknowledgements and stray bytes up to the next packet and returns its data, `None` once the
+    /// connection is closed. Packets with a bad checksum are asked for again.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                Some(b'$') => {}
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let (Some(high), Some(low)) = (self.read_byte()?, self.read_byte()?) else {
+                return Ok(None);
+            };
+
+            let checksum = std::str::from_utf8(&[high, low]).ok().and_then(|text| u8::from_str_radix(text, 16).ok());
+            if checksum != Some(checksum_of(&data)) {
+                self.connection.write_all(b"-")?;
+                continue;
+            }
+            if self.acknowledge {
+                self.connection.write_all(b"+")?;
+            }
+
+            let packet = String::from_utf8_lossy(&data).into_owned();
+            // Acknowledged before acknowledgements stop.
+            if packet == "QStartNoAckMode" {
+                self.write_packet("OK")?;
+                self.acknowledge = false;
+                continue;
+            }
+            return Ok(Some(packet));
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        write!(self.connection, "${data}#{:02x}", checksum_of(data.as_bytes()))?;
+        self.connection.flush()
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.connection.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum: u8, &byte| sum.wrapping_add(byte))
+}
+
+/// Registers travel as target endian, so little endian, bytes.
+fn hex_word(value: u32) -> String {
+    value.to_le_bytes().iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok()).collect()
+}
+
+fn words(hex: &str) -> Option<Vec<u32>> {
+    let bytes = bytes(hex).filter(|bytes| bytes.len().is_multiple_of(4))?;
+    Some(bytes.chunks(4).map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]])).collect())
+}
+
+/// `address,length` in hex.
+fn address_and_length(text: &str) -> Option<(u32, u32)> {
+    let (address, length) = text.split_once(',')?;
+    Some((u32::from_str_radix(address, 16).ok()?, u32::from_str_radix(length, 16).ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::core::hart::Hart;
+    use crate::memory::Memory;
+    use std::thread;
+
+    /// Serves `source` assembled at 0 on one end of a socket pair and returns the other end.
+    fn connect(source: &'static str) -> (UnixStream, thread::JoinHandle<()>) {
+        let (client, server) = UnixStream::pair().unwrap();
+        let stub = thread::spawn(move || {
+            let program = assemble(source, 0).unwrap();
+            let debugger = Debugger::new(Hart::new(), Memory::with_initial_values(program.memory_image(256)));
+            GdbStub::new(server, debugger).serve().unwrap();
+        });
+        (client, stub)
+    }
+
+    fn read_until(client: &mut UnixStream, end: u8) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut byte = [0];
+        while bytes.last() != Some(&end) {
+            client.read_exact(&mut byte).unwrap();
+            bytes.push(byte[0]);
+        }
+        bytes
+    }
+
+    /// Sends a packet and returns the reply's data, acknowledging it.
+    fn exchange(client: &mut UnixStream, packet: &str) -> String {
+        write!(client, "${packet}#{:02x}", checksum_of(packet.as_bytes())).unwrap();
+        assert_eq!(read_until(client, b'+'), b"+");
+        receive(client)
+    }
+
+    fn receive(client: &mut UnixStream) -> String {
+        let reply = read_until(client, b'#');
+        let mut checksum = [0; 2];
+        client.read_exact(&mut checksum).unwrap();
+        client.write_all(b"+").unwrap();
+
+        assert_eq!(reply[0], b'$');
+        let data = &reply[1..reply.len() - 1];
+        assert_eq!(std::str::from_utf8(&checksum).unwrap(), format!("{:02x}", checksum_of(data)));
+        String::from_utf8(data.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn reads_and_writes_state_and_stops_at_breakpoints() {
+        let (mut client, stub) = connect(
+            "
+            li      a0, 3
+            sw      a0, 0x80(zero)
+            addi    a0, a0, 1
+            j       .
+            ",
+        );
+
+        assert!(exchange(&mut client, "qSupported:swbreak+").contains("qXfer:features:read+"));
+        assert!(exchange(&mut client, "qXfer:features:read:target.xml:0,2000").starts_with("m<?xml"));
+        assert_eq!(exchange(&mut client, "?"), "S05");
+        assert_eq!(exchange(&mut client, "Z0,c,4"), "OK");
+        assert_eq!(exchange(&mut client, "Z2,80,4"), "OK");
+
+        assert_eq!(exchange(&mut client, "c"), "T05watch:80;");
+        assert_eq!(exchange(&mut client, "m80,4"), "03000000");
+        assert_eq!(exchange(&mut client, "c"), "T05swbreak:;");
+        assert_eq!(exchange(&mut client, "p20"), "0c000000");
+        assert_eq!(exchange(&mut client, "pa"), "04000000");
+        assert_eq!(exchange(&mut client, "Pa=09000000"), "OK");
+        assert_eq!(exchange(&mut client, "s"), "T05swbreak:;");
+        let registers = exchange(&mut client, "g");
+        assert_eq!(&registers[10 * 8..11 * 8], "09000000");
+        assert_eq!(&registers[32 * 8..], "0c000000");
+        assert_eq!(exchange(&mut client, "M80,2:aabb"), "OK");
+        assert_eq!(exchange(&mut client, "m7f,3"), "00aabb");
+        assert_eq!(exchange(&mut client, "p342"), "00011440", "misa is register 65 + 0x301");
+
+        // The stub hangs up after detaching, so the reply is not acknowledged.
+        client.write_all(b"$D#44").unwrap();
+        assert_eq!(read_until(&mut client, b'#'), b"+$OK#");
+        stub.join().unwrap();
+    }
+
+    #[test]
+    fn ctrl_c_interrupts_a_running_hart() {
+        let (mut client, stub) = connect("j .");
+
+        assert_eq!(exchange(&mut client, "QStartNoAckMode"), "OK");
+        client.write_all(b"$c#63").unwrap();
+        client.write_all(&[INTERRUPT]).unwrap();
+        assert_eq!(receive(&mut client), "T02");
+
+        drop(client);
+        stub.join().unwrap();
+    }
+}
@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use crate::core::bus::{BusInterface, BusReadResponse, BusWriteResponse, Clocked, Value};
+use crate::core::snapshot::{Snapshot, SnapshotError, SnapshotReader, SnapshotWriter};
+use crate::elf::Elf;
+use num::PrimInt;
+
+/// Cycles an odd value written to the low half of `tohost` waits for the high half before it is taken as an exit.
+/// riscv-arch-test and older riscv-tests only write the low half, while 64 bit commands write both.
+const LOW_HALF_GRACE_CYCLES: u32 = 16;
+
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+const COMMAND_GETCHAR: u64 = 0;
+const COMMAND_PUTCHAR: u64 = 1;
+
+const SYS_OPENAT: u64 = 56;
+const SYS_CLOSE: u64 = 57;
+const SYS_LSEEK: u64 = 62;
+const SYS_READ: u64 = 63;
+const SYS_WRITE: u64 = 64;
+const SYS_EXIT: u64 = 93;
+
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+const EINVAL: i64 = 22;
+const ENOSYS: i64 = 38;
+
+const O_WRONLY: u64 = 0o1;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+
+/// The Berkeley host-target interface: the `tohost` and `fromhost` words through which riscv-tests, riscv-arch-test
+/// and the proxy kernel talk to the simulator, in front of the memory holding them.
+///
+/// A command written to `tohost` is a device in bits 63:56, a command in 55:48 and a payload below. Device 0 exits
+/// with `payload >> 1` when the payload is odd and otherwise runs the system call described by the eight words the
+/// payload points to, device 1 is the console. Completed commands are acknowledged through `fromhost`. System calls
+/// work on the host's files like spike's do, with the console as descriptors 0 to 2.
+///
+/// The two words have to be uncacheable when the hart has a write-back data cache, stores to them must reach the bus.
+pub struct Htif<M> {
+    inner: M,
+    tohost_address: u32,
+    fromhost_address: Option<u32>,
+    tohost: u64,
+    fromhost: u64,
+    /// Cycles left until an odd low half of `tohost` is taken as an exit.
+    low_half_grace: Option<u32>,
+    exit_code: Option<u32>,
+    console_input: Box<dyn Read>,
+    console_output: Box<dyn Write>,
+    files: HashMap<u64, File>,
+    next_descriptor: u64,
+}
+
+impl<M> Htif<M>
+where
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, u32>,
+{
+    /// With the console on the simulator's standard input and output.
+    pub fn new(inner: M, tohost_address: u32, fromhost_address: Option<u32>) -> Self {
+        Htif {
+            inner,
+            tohost_address,
+            fromhost_address,
+            tohost: 0,
+            fromhost: 0,
+            low_half_grace: None,
+            exit_code: None,
+            console_input: Box::new(io::stdin()),
+            console_output: Box::new(io::stdout()),
+            files: HashMap::new(),
+            next_descriptor: 3,
+        }
+    }
+
+    /// At the addresses of `host_addresses`, `None` for programs without them.
+    pub fn from_elf(inner: M, elf: &Elf) -> Option<Self> {
+        let (tohost, fromhost) = host_addresses(elf)?;
+        Some(Htif::new(inner, tohost, fromhost))
+    }
+
+    pub fn set_console(&mut self, input: impl Read + 'static, output: impl Write + 'static) {
+        self.console_input = Box::new(input);
+        self.console_output = Box::new(output);
+    }
+
+    /// What the program exited with, once it has.
+    pub fn exit_code(&self) -> Option<u32> {
+        self.exit_code
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.inner
+    }
+
+    /// The register an access falls in, with the offset into it. Accesses have to stay within one register.
+    fn register(&self, address: u32, width: usize) -> Option<(bool, usize)> {
+        let within = |start: u32| {
+            let offset = address.wrapping_sub(start) as usize;
+            (offset + width <= 8).then_some(offset)
+        };
+        match (within(self.tohost_address), self.fromhost_address.and_then(within)) {
+            (Some(offset), _) => Some((true, offset)),
+            (None, Some(offset)) => Some((false, offset)),
+            (None, None) => None,
+        }
+    }
+
+    fn run_command(&mut self) {
+        let command = std::mem::take(&mut self.tohost);
+        self.low_half_grace = None;
+        let (device, code, payload) = (command >> 56, (command >> 48) & 0xff, command & 0xffff_ffff_ffff);
+
+        match (device, code) {
+            (DEVICE_SYSCALL, 0) if payload & 1 == 1 => self.exit_code = Some((payload >> 1) as u32),
+            (DEVICE_SYSCALL, 0) => {
+                self.proxy_syscall(payload as u32);
+                self.fromhost = 1;
+            }
+            (DEVICE_CONSOLE, COMMAND_PUTCHAR) => {
+                let _ = self.console_output.write_all(&[payload as u8]).and_then(|_| self.console_output.flush());
+                self.fromhost = DEVICE_CONSOLE << 56 | COMMAND_PUTCHAR << 48;
+            }
+            // Answered with the character, or not at all once the input has ended.
+            (DEVICE_CONSOLE, COMMAND_GETCHAR) => {
+                let mut byte = [0];
+                if let Ok(1) = self.console_input.read(&mut byte) {
+                    self.fromhost = DEVICE_CONSOLE << 56 | COMMAND_GETCHAR << 48 | 0x100 | byte[0] as u64;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Runs the system call in the eight 64 bit words at `address`, number first, and returns its result in the first.
+    fn proxy_syscall(&mut self, address: u32) {
+        let mut words = [0; 8];
+        for (index, word) in words.iter_mut().enumerate() {
+            let Some(value) = self.read_u64(address + 8 * index as u32) else {
+                return;
+            };
+            *word = value;
+        }
+
+        let [number, arguments @ ..] = words;
+        let result = self.syscall(number, arguments).unwrap_or_else(|error| -error);
+        self.write_u64(address, result as u64);
+    }
+
+    fn syscall(&mut self, number: u64, arguments: [u64; 7]) -> Result<i64, i64> {
+        let [a0, a1, a2, a3, ..] = arguments;
+        match number {
+            SYS_EXIT => {
+                self.exit_code = Some(a0 as u32);
+                Ok(0)
+            }
+            SYS_WRITE => {
+                let data = self.read_bytes(a1 as u32, a2 as u32).ok_or(EFAULT)?;
+                let written = match a0 {
+                    1 | 2 => self.console_output.write_all(&data).and_then(|_| self.console_output.flush()),
+                    _ => self.files.get_mut(&a0).ok_or(EBADF)?.write_all(&data),
+                };
+                written.map(|_| data.len() as i64).map_err(errno)
+            }
+            SYS_READ => {
+                let mut data = vec![0; a2 as usize];
+                let read = match a0 {
+                    0 => self.console_input.read(&mut data),
+                    _ => self.files.get_mut(&a0).ok_or(EBADF)?.read(&mut data),
+                }
+                .map_err(errno)?;
+                match self.write_bytes(a1 as u32, &data[..read]) {
+                    true => Ok(read as i64),
+                    false => Err(EFAULT),
+                }
+            }
+            // The path is passed with its length, including the terminating NUL. Only paths relative to the working
+            // directory or absolute ones are supported, whatever directory descriptor comes along.
+            SYS_OPENAT => {
+                let path = self.read_bytes(a1 as u32, a2 as u32).ok_or(EFAULT)?;
+                let path = String::from_utf8_lossy(path.strip_suffix(&[0]).unwrap_or(&path)).into_owned();
+                let file = OpenOptions::new()
+                    .read(a3 & (O_WRONLY | O_RDWR) != O_WRONLY)
+                    .write(a3 & (O_WRONLY | O_RDWR) != 0)
+                    .append(a3 & O_APPEND != 0)
+                    .truncate(a3 & O_TRUNC != 0)
+                    .create(a3 & O_CREAT != 0)
+                    .open(path)
+                    .map_err(errno)?;
+                let descriptor = self.next_descriptor;
+                self.next_descriptor += 1;
+                self.files.insert(descriptor, file);
+                Ok(descriptor as i64)
+            }
+            SYS_CLOSE => match a0 {
+                0..=2 => Ok(0),
+                _ => self.files.remove(&a0).map(|_| 0).ok_or(EBADF),
+            },
+            SYS_LSEEK => {
+                let file = self.files.get_mut(&a0).ok_or(EBADF)?;
+                let position = match a2 {
+                    0 => SeekFrom::Start(a1),
+                    1 => SeekFrom::Current(a1 as i64),
+                    2 => SeekFrom::End(a1 as i64),
+                    _ => return Err(EINVAL),
+                };
+                file.seek(position).map(|offset| offset as i64).map_err(errno)
+            }
+            _ => Err(ENOSYS),
+        }
+    }
+
+    fn read_u64(&self, address: u32) -> Option<u64> {
+        let low = self.read_word(address)?;
+        let high = self.read_word(address + 4)?;
+        Some(low as u64 | (high as u64) << 32)
+    }
+
+    fn write_u64(&mut self, address: u32, value: u64) {
+        self.write_bytes(address, &value.to_le_bytes());
+    }
+
+    fn read_word(&self, address: u32) -> Option<u32> {
+        match <M as BusInterface<u32, u32>>::read(&self.inner, address) {
+            BusReadResponse::Success(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn read_bytes(&self, address: u32, length: u32) -> Option<Vec<u8>> {
+        (0..length)
+            .map(|offset| match <M as BusInterface<u32, u8>>::read(&self.inner, address.wrapping_add(offset)) {
+                BusReadResponse::Success(value) => Some(value as u8),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn write_bytes(&mut self, address: u32, bytes: &[u8]) -> bool {
+        bytes.iter().enumerate().all(|(offset, &byte)| {
+            let address = address.wrapping_add(offset as u32);
+            matches!(<M as BusInterface<u32, u8>>::write(&mut self.inner, address, byte), BusWriteResponse::Success)
+        })
+    }
+}
+
+/// `tohost` and `fromhost` from the ELF's symbol table, `None` without `tohost`.
+pub fn host_addresses(elf: &Elf) -> Option<(u32, Option<u32>)> {
+    let tohost = elf.symbol("tohost")?.value;
+    let fromhost = elf.symbol("fromhost").map(|symbol| symbol.value);
+    Some((tohost, fromhost))
+}
+
+fn errno(error: io::Error) -> i64 {
+    error.raw_os_error().map_or(EINVAL, |code| code as i64)
+}
+
+impl<V: PrimInt + Value, M> BusInterface<u32, V> for Htif<M>
+where
+    M: BusInterface<u32, V>,
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, u32>,
+{
+    fn read(&self, address: u32) -> BusReadResponse<u32> {
+        match self.register(address, V::WIDTH) {
+            Some((tohost, offset)) => {
+                let register = if tohost { self.tohost } else { self.fromhost };
+                let value = (register >> (8 * offset)) & (u64::MAX >> (64 - 8 * V::WIDTH));
+                BusReadResponse::Success(value as u32)
+            }
+            None => BusInterface::<u32, V>::read(&self.inner, address),
+        }
+    }
+
+    fn write(&mut self, address: u32, value: V) -> BusWriteResponse {
+        let Some((tohost, offset)) = self.register(address, V::WIDTH) else {
+            return self.inner.write(address, value);
+        };
+
+        let register = if tohost { &mut self.tohost } else { &mut self.fromhost };
+        let mut bytes = register.to_le_bytes();
+        bytes[offset..offset + V::WIDTH].copy_from_slice(&value.to_bytes());
+        *register = u64::from_le_bytes(bytes);
+
+        if tohost {
+            match (offset + V::WIDTH > 4, self.tohost) {
+                (true, _) => self.run_command(),
+                // Tests keep rewriting the low half in a loop, that must not hold the exit off.
+                (false, command) if command & 1 == 1 => {
+                    self.low_half_grace.get_or_insert(LOW_HALF_GRACE_CYCLES);
+                }
+                (false, _) => self.low_half_grace = None,
+            }
+        }
+        BusWriteResponse::Success
+    }
+}
+
+impl<M> Clocked for Htif<M>
+where
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, u32>,
+    M: Clocked,
+{
+    fn tick(&mut self) {
+        match self.low_half_grace {
+            Some(0) => self.run_command(),
+            Some(cycles) => self.low_half_grace = Some(cycles - 1),
+            None => {}
+        }
+        self.inner.tick();
+    }
+}
+
+/// Files opened through system calls and the console are not part of snapshots.
+impl<M: Snapshot> Snapshot for Htif<M> {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("htif");
+        snapshot.u64(self.tohost);
+        snapshot.u64(self.fromhost);
+        snapshot.option(&self.low_half_grace, |snapshot, &cycles| snapshot.u32(cycles));
+        snapshot.option(&self.exit_code, |snapshot, &code| snapshot.u32(code));
+        self.inner.save(snapshot);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        snapshot.section("htif")?;
+        self.tohost = snapshot.u64()?;
+        self.fromhost = snapshot.u64()?;
+        self.low_half_grace = snapshot.option(SnapshotReader::u32)?;
+        self.exit_code = snapshot.option(SnapshotReader::u32)?;
+        self.inner.restore(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn htif() -> (Htif<Memory>, Output) {
+        let mut htif = Htif::new(Memory::at(0x8000_0000, vec![0; 4096]), 0x8000_1000, Some(0x8000_1008));
+        let output = Output::default();
+        htif.set_console(&b"x"[..], output.clone());
+        (htif, output)
+    }
+
+    fn write(htif: &mut Htif<Memory>, address: u32, value: u64) {
+        BusInterface::<u32, u32>::write(htif, address, value as u32);
+        BusInterface::<u32, u32>::write(htif, address + 4, (value >> 32) as u32);
+    }
+
+    fn fromhost(htif: &Htif<Memory>) -> u32 {
+        match BusInterface::<u32, u32>::read(htif, 0x8000_100c) {
+            BusReadResponse::Success(high) => high,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn runs_console_commands_and_exits() {
+        let (mut htif, output) = htif();
+        write(&mut htif, 0x8000_1000, 1 << 56 | 1 << 48 | b'a' as u64);
+        assert_eq!(output.0.borrow().as_slice(), b"a");
+        assert_eq!(fromhost(&htif), 0x0101_0000);
+        write(&mut htif, 0x8000_1008, 0);
+
+        write(&mut htif, 0x8000_1000, 1 << 56);
+        assert_eq!(fromhost(&htif), 0x0100_0000);
+
+        // riscv-arch-test only writes the low half.
+        BusInterface::<u32, u32>::write(&mut htif, 0x8000_1000, 3 << 1 | 1);
+        (0..LOW_HALF_GRACE_CYCLES).for_each(|_| htif.tick());
+        assert_eq!(htif.exit_code(), None);
+        htif.tick();
+        assert_eq!(htif.exit_code(), Some(3));
+    }
+
+    #[test]
+    fn proxies_system_calls() {
+        let (mut htif, output) = htif();
+        BusInterface::<u32, u32>::write(htif.inner_mut(), 0x8000_0100, u32::from_le_bytes(*b"hi\n\0"));
+        let call = [SYS_WRITE, 1, 0x8000_0100, 3, 0, 0, 0, 0];
+        call.iter().enumerate().for_each(|(index, &word)| htif.write_u64(0x8000_0200 + 8 * index as u32, word));
+
+        write(&mut htif, 0x8000_1000, 0x8000_0200);
+        assert_eq!(output.0.borrow().as_slice(), b"hi\n");
+        assert_eq!(htif.read_u64(0x8000_0200), Some(3));
+        assert_eq!(fromhost(&htif), 0);
+
+        htif.write_u64(0x8000_0200, SYS_CLOSE);
+        htif.write_u64(0x8000_0208, 7);
+        write(&mut htif, 0x8000_1000, 0x8000_0200);
+        assert_eq!(htif.read_u64(0x8000_0200), Some(-EBADF as u64));
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::core::bus::{BusInterface, BusWriteResponse, Clocked};
+use crate::elf::{Elf, ElfError, SEGMENT_LOAD};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageError {
+    /// A line that is not a record of the format, or a record type it does not have.
+    Malformed {
+        line: usize,
+    },
+    Checksum {
+        line: usize,
+    },
+    /// The record gives data for an address an earlier record already did.
+    Overlap {
+        line: usize,
+        address: u32,
+    },
+    /// The record reaches past the end of the 32 bit address space.
+    OutOfRange {
+        line: usize,
+        address: u32,
+    },
+    /// The file ends without an end of file or termination record.
+    Truncated,
+    /// S-records: the count record disagrees with the number of data records before it.
+    RecordCount {
+        line: usize,
+    },
+    /// The ELF file the segments are read from is malformed.
+    Elf(ElfError),
+    /// Loading: the address is not part of the target.
+    OutsideTarget {
+        address: u32,
+    },
+    /// Loading: the target refused the write, e.g. because it is read only.
+    NotWritable {
+        address: u32,
+    },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Malformed { line } => write!(f, "line {line}: malformed record"),
+            ImageError::Checksum { line } => write!(f, "line {line}: checksum mismatch"),
+            ImageError::Overlap { line, address } => {
+                write!(f, "line {line}: data at {address:#x} overlaps earlier data")
+            }
+            ImageError::OutOfRange { line, address } => {
+                write!(f, "line {line}: data at {address:#x} runs past the end of the address space")
+            }
+            ImageError::Truncated => write!(f, "image ends without an end record"),
+            ImageError::RecordCount { line } => write!(f, "line {line}: record count does not match"),
+            ImageError::Elf(error) => write!(f, "{error}"),
+            ImageError::OutsideTarget { address } => write!(f, "{address:#x} is outside of the target"),
+            ImageError::NotWritable { address } => write!(f, "{address:#x} cannot be written"),
+        }
+    }
+}
+
+/// Data to load at an address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+/// A program or firmware image: what goes where, and where to start when the format says so.
+///
+/// Images are read from flat binaries, Intel HEX, Motorola S-records and ELF files, then loaded into any bus target
+/// with writes through the bus, so devices like flash that take writes from the bus can be programmed as well.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Image {
+    /// In the order the file gives them, consecutive records merged.
+    pub chunks: Vec<Chunk>,
+    pub entry: Option<u32>,
+}
+
+impl Image {
+    /// A flat binary loaded at `base`.
+    pub fn binary(base: u32, bytes: &[u8]) -> Result<Image, ImageError> {
+        let mut image = Image::default();
+        image.add(&mut BTreeMap::new(), 1, base, bytes)?;
+        Ok(image)
+    }
+
+    /// The data of the loadable segments at their physical addresses, starting at the ELF entry. Errors give the
+    /// number of the program header as their line.
+    pub fn from_elf(elf: &Elf) -> Result<Image, ImageError> {
+        let mut image = Image { chunks: Vec::new(), entry: Some(elf.entry) };
+        let mut written = BTreeMap::new();
+        for (index, segment) in elf.segments.iter().enumerate().filter(|(_, segment)| segment.kind == SEGMENT_LOAD) {
+            let data = elf.segment_data(segment).map_err(ImageError::Elf)?;
+            image.add(&mut written, index + 1, segment.physical_address, data)?;
+        }
+        Ok(image)
+    }
+
+    /// Intel HEX with 16 bit segment or 32 bit linear addressing. The start address record gives the entry, as
+    /// `CS * 16 + IP` for segment addressing.
+    pub fn intel_hex(text: &str) -> Result<Image, ImageError> {
+        let mut image = Image::default();
+        let mut written = BTreeMap::new();
+        let mut base = 0u32;
+
+        for (index, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let line_number = index + 1;
+            let malformed = ImageError::Malformed { line: line_number };
+            let bytes = line.trim().strip_prefix(':').and_then(hex_bytes).ok_or(malformed)?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(malformed);
+            }
+            if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0 {
+                return Err(ImageError::Checksum { line: line_number });
+            }
+
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+            let data = &bytes[4..bytes.len() - 1];
+            let value = data.iter().fold(0u32, |value, &byte| value << 8 | byte as u32);
+            match (bytes[3], data.len()) {
+                (0x00, _) => image.add(&mut written, line_number, base.wrapping_add(offset), data)?,
+                (0x01, 0) => return Ok(image),
+                (0x02, 2) => base = value << 4,
+                (0x03, 4) => image.entry = Some((value >> 16) * 16 + (value & 0xffff)),
+                (0x04, 2) => base = value << 16,
+                (0x05, 4) => image.entry = Some(value),
+                _ => return Err(malformed),
+            }
+        }
+        Err(ImageError::Truncated)
+    }
+
+    /// Motorola S-records with 16, 24 or 32 bit addresses. The termination record gives the entry, count records are
+    /// checked against the data records before them.
+    pub fn s_record(text: &str) -> Result<Image, ImageError> {
+        let mut image = Image::default();
+        let mut written = BTreeMap::new();
+        let mut data_records = 0;
+
+        for (index, line) in text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let line_number = index + 1;
+            let malformed = ImageError::Malformed { line: line_number };
+            let line = line.trim();
+            let kind = line.strip_prefix('S').and_then(|rest| rest.chars().next()).ok_or(malformed)?;
+            let bytes = line.get(2..).and_then(hex_bytes).ok_or(malformed)?;
+            if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+                return Err(malformed);
+            }
+            if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) != 0xff {
+                return Err(ImageError::Checksum { line: line_number });
+            }
+
+            let address_size = match kind {
+                '0' | '1' | '5' | '9' => 2,
+                '2' | '6' | '8' => 3,
+                '3' | '7' => 4,
+                _ => return Err(malformed),
+            };
+            if bytes.len() < 2 + address_size {
+                return Err(malformed);
+            }
+            let address = bytes[1..1 + address_size].iter().fold(0u32, |value, &byte| value << 8 | byte as u32);
+            let data = &bytes[1 + address_size..bytes.len() - 1];
+
+            match kind {
+                '0' => {}
+                '1' | '2' | '3' => {
+                    image.add(&mut written, line_number, address, data)?;
+                    data_records += 1;
+                }
+                '5' | '6' if address != data_records => return Err(ImageError::RecordCount { line: line_number }),
+                '5' | '6' => {}
+                _ => {
+                    image.entry = Some(address);
+                    return Ok(image);
+                }
+            }
+        }
+        Err(ImageError::Truncated)
+    }
+
+    /// The lowest address the image has data for.
+    pub fn start(&self) -> Option<u32> {
+        self.chunks.iter().map(|chunk| chunk.address).min()
+    }
+
+    /// Writes the image byte by byte through the bus, waiting out deferred writes.
+    pub fn load<M: BusInterface<u32, u8> + Clocked>(&self, target: &mut M) -> Result<(), ImageError> {
+        for chunk in &self.chunks {
+            for (offset, &byte) in chunk.data.iter().enumerate() {
+                let address = chunk.address.checked_add(offset as u32);
+                let address = address.ok_or(ImageError::OutsideTarget { address: chunk.address })?;
+                loop {
+                    match target.write(address, byte) {
+                        BusWriteResponse::Success => break,
+                        BusWriteResponse::Deferred => target.tick(),
+                        BusWriteResponse::WriteOutOfBounds => return Err(ImageError::OutsideTarget { address }),
+                        BusWriteResponse::InvalidAddress => return Err(ImageError::NotWritable { address }),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the data of a record after checking it against the address space and the data so far, which `written`
+    /// keeps as the end of each range by its start.
+    fn add(
+        &mut self,
+        written: &mut BTreeMap<u32, u32>,
+        line: usize,
+        address: u32,
+        data: &[u8],
+    ) -> Result<(), ImageError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let end = address.checked_add(data.len() as u32 - 1).ok_or(ImageError::OutOfRange { line, address })?;
+        let before = written.range(..=end).next_back();
+        if let Some((&start, &last)) = before {
+            if last >= address {
+                return Err(ImageError::Overlap { line, address: address.max(start) });
+            }
+        }
+        written.insert(address, end);
+
+        match self.chunks.last_mut() {
+            Some(chunk) if chunk.address as u64 + chunk.data.len() as u64 == address as u64 => {
+                chunk.data.extend_from_slice(data)
+            }
+            _ => self.chunks.push(Chunk { address, data: data.to_vec() }),
+        }
+        Ok(())
+    }
+}
+
+fn hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|index| u8::from_str_radix(&text[index..index + 2], 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::bus::BusReadResponse;
+    use crate::memory::Memory;
+    use crate::wait_states::WaitStates;
+
+    fn byte(memory: &Memory, address: u32) -> u8 {
+        match <Memory as BusInterface<u32, u8>>::read(memory, address) {
+            BusReadResponse::Success(value) => value as u8,
+            _ => panic!("{address:#x} is not in memory"),
+        }
+    }
+
+    #[test]
+    fn reads_intel_hex_and_s_records() {
+        let hex = "
+            :020000040800F2
+            :0400100001020304E2
+            :02001400AABB85
+            :0400000508000010DF
+            :00000001FF
+        ";
+        let image = Image::intel_hex(hex).unwrap();
+        assert_eq!(image.chunks, vec![Chunk { address: 0x0800_0010, data: vec![1, 2, 3, 4, 0xaa, 0xbb] }]);
+        assert_eq!(image.entry, Some(0x0800_0010));
+
+        let records = "
+            S00600004844521B
+            S3090800001001020304D4
+            S2060000200506CE
+            S5030002FA
+            S70508000010E2
+        ";
+        let image = Image::s_record(records).unwrap();
+        assert_eq!(image.chunks[0], Chunk { address: 0x0800_0010, data: vec![1, 2, 3, 4] });
+        assert_eq!(image.chunks[1], Chunk { address: 0x20, data: vec![5, 6] });
+        assert_eq!(image.entry, Some(0x0800_0010));
+
+        assert_eq!(Image::intel_hex(":0400100001020304E3\n"), Err(ImageError::Checksum { line: 1 }));
+        assert_eq!(Image::intel_hex(":0400100001020304E2\n"), Err(ImageError::Truncated));
+        assert_eq!(
+            Image::intel_hex(":0400100001020304E2\n:02001200AABB87\n"),
+            Err(ImageError::Overlap { line: 2, address: 0x12 })
+        );
+        assert_eq!(Image::s_record("S5030003F9\nS9030000FC\n"), Err(ImageError::RecordCount { line: 1 }));
+        assert_eq!(
+            Image::s_record("S307FFFFFFFF0102F9\nS70500000000FA\n"),
+            Err(ImageError::OutOfRange { line: 1, address: 0xffff_ffff })
+        );
+    }
+
+    #[test]
+    fn loads_through_the_bus() {
+        let image = Image::binary(0x8000_0100, &[1, 2, 3]).unwrap();
+        let mut memory = WaitStates::new(Memory::at(0x8000_0000, vec![0; 512]), 3);
+        image.load(&mut memory).unwrap();
+        assert_eq!([0x100, 0x101, 0x102].map(|offset| byte(memory.inner(), 0x8000_0000 + offset)), [1, 2, 3]);
+
+        let mut small = Memory::at(0x8000_0000, vec![0; 0x101]);
+        assert_eq!(image.load(&mut small), Err(ImageError::OutsideTarget { address: 0x8000_0101 }));
+        let mut rom = Memory::read_only(vec![0; 512]);
+        let image = Image::binary(0x10, &[1]).unwrap();
+        assert_eq!(image.load(&mut rom), Err(ImageError::NotWritable { address: 0x10 }));
+    }
+}
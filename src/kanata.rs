@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::symbols::Symbols;
+
+/// Writes pipeline occupancy in the Kanata log format read by the Konata visualizer.
+///
+/// Instructions get an id when they are fetched. Entering a stage closes the previous one, so a pipeline only has
+/// to report the cycle an instruction moves and how it leaves: retired or flushed. Write errors are kept and
+/// returned by `finish`, tracing stops at the first one.
+pub struct KanataTracer {
+    output: Box<dyn Write>,
+    next_id: u64,
+    next_retire_id: u64,
+    stages: HashMap<u64, &'static str>,
+    symbols: Option<Rc<Symbols>>,
+    error: Option<io::Error>,
+}
+
+impl KanataTracer {
+    pub fn new(output: impl Write + 'static) -> Self {
+        let mut tracer = KanataTracer {
+            output: Box::new(output),
+            next_id: 0,
+            next_retire_id: 0,
+            stages: HashMap::new(),
+            symbols: None,
+            error: None,
+        };
+        tracer.emit(format_args!("Kanata\t0004\nC=\t0\n"));
+        tracer
+    }
+
+    /// Labels instructions with their function and source line as well.
+    pub fn set_symbols(&mut self, symbols: Rc<Symbols>) {
+        self.symbols = Some(symbols);
+    }
+
+    /// Starts an instruction in the first stage and returns its id.
+    pub fn fetch(&mut self, pc: u32, instruction: u32, stage: &'static str) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let pc = match &self.symbols {
+            Some(symbols) => symbols.describe(pc),
+            None => format!("{pc:08x}"),
+        };
+        self.emit(format_args!("I\t{id}\t{id}\t0\nL\t{id}\t0\t{pc}: {instruction:08x}\n"));
+        self.stage(id, stage);
+        id
+    }
+
+    /// Moves an instruction into `stage`. Reporting the stage it is already in, e.g. while stalled, does nothing.
+    pub fn stage(&mut self, id: u64, stage: &'static str) {
+        if self.stages.insert(id, stage) != Some(stage) {
+            self.emit(format_args!("S\t{id}\t0\t{stage}\n"));
+        }
+    }
+
+    /// Adds a note shown when hovering over the instruction, e.g. why it stalled.
+    pub fn annotate(&mut self, id: u64, note: &str) {
+        if self.stages.contains_key(&id) {
+            self.emit(format_args!("L\t{id}\t1\t{note}\n"));
+        }
+    }
+
+    pub fn retire(&mut self, id: u64) {
+        self.leave(id, 0);
+    }
+
+    pub fn flush(&mut self, id: u64) {
+        self.leave(id, 1);
+    }
+
+    /// Ends the current cycle.
+    pub fn cycle(&mut self) {
+        self.emit(format_args!("C\t1\n"));
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => self.output.flush(),
+        }
+    }
+
+    fn leave(&mut self, id: u64, kind: u32) {
+        if let Some(stage) = self.stages.remove(&id) {
+            let retire_id = self.next_retire_id;
+            self.next_retire_id += 1;
+            self.emit(format_args!("E\t{id}\t0\t{stage}\nR\t{id}\t{retire_id}\t{kind}\n"));
+        }
+    }
+
+    fn emit(&mut self, arguments: std::fmt::Arguments) {
+        if self.error.is_none() {
+            self.error = self.output.write_fmt(arguments).err();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stages_are_only_reported_when_they_change() {
+        let buffer = SharedBuffer::default();
+        let mut tracer = KanataTracer::new(buffer.clone());
+
+        let id = tracer.fetch(0x80, 0x13, "F");
+        tracer.cycle();
+        tracer.stage(id, "D");
+        tracer.cycle();
+        tracer.stage(id, "D");
+        tracer.annotate(id, "stall");
+        tracer.flush(id);
+        tracer.retire(id);
+        tracer.finish().unwrap();
+
+        let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(
+            trace,
+            "Kanata\t0004\nC=\t0\nI\t0\t0\t0\nL\t0\t0\t00000080: 00000013\nS\t0\t0\tF\nC\t1\nS\t0\t0\tD\nC\t1\n\
+             L\t0\t1\tstall\nE\t0\t0\tD\nR\t0\t0\t1\n"
+        );
+    }
+}
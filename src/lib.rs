@@ -0,0 +1,17 @@
+pub mod assembler;
+pub mod compliance;
+pub mod core;
+pub mod debugger;
+pub mod disassembler;
+pub mod elf;
+pub mod gdb_stub;
+pub mod htif;
+pub mod image;
+pub mod kanata;
+pub mod linux_user;
+pub mod memory;
+pub mod profiler;
+pub mod semihosting;
+pub mod simple_pipeline;
+pub mod symbols;
+pub mod wait_states;
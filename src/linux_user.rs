@@ -0,0 +1,865 @@
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::core::bus::{BusInterface, BusReadResponse, BusWriteResponse};
+use crate::core::csr::address_constants::*;
+use crate::core::csr::PrivilegeLevel;
+use crate::core::exception::Exception;
+use crate::core::hart::Hart;
+use crate::elf::{Elf, SEGMENT_LOAD};
+use crate::memory::Memory;
+use crate::simple_pipeline::SimplePipeline;
+
+pub const DEFAULT_MEMORY_SIZE: usize = 64 << 20;
+const STACK_SIZE: u32 = 8 << 20;
+const PAGE_SIZE: u32 = 4096;
+/// Where U-mode traps go. Nothing is mapped there, the runner takes over once the hart arrives.
+const TRAP_VECTOR: u32 = 0xffff_f000;
+const PATH_MAX: u32 = 4096;
+/// Host reads on the guest's behalf go through a buffer of at most this many bytes, however much it asks for.
+const CHUNK_SIZE: u32 = 64 << 10;
+
+const REGISTER_SP: usize = 2;
+const REGISTER_A0: usize = 10;
+const REGISTER_A7: usize = 17;
+
+const AT_FDCWD: u32 = -100i32 as u32;
+const AT_EMPTY_PATH: u32 = 0x1000;
+const O_WRONLY: u32 = 0o1;
+const O_RDWR: u32 = 0o2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+const MAP_FIXED: u32 = 0x10;
+const MAP_ANONYMOUS: u32 = 0x20;
+const CLOCK_REALTIME: u32 = 0;
+
+const AT_NULL: u32 = 0;
+const AT_PHDR: u32 = 3;
+const AT_PHENT: u32 = 4;
+const AT_PHNUM: u32 = 5;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+const AT_UID: u32 = 11;
+const AT_EUID: u32 = 12;
+const AT_GID: u32 = 13;
+const AT_EGID: u32 = 14;
+const AT_HWCAP: u32 = 16;
+const AT_CLKTCK: u32 = 17;
+const AT_RANDOM: u32 = 25;
+const PROGRAM_HEADER_SIZE: u32 = 32;
+/// The base integer ISA, all the core implements.
+const HWCAP: u32 = 1 << (b'I' - b'A');
+
+const EPERM: i32 = 1;
+const ENOENT: i32 = 2;
+const EBADF: i32 = 9;
+const ENOMEM: i32 = 12;
+const EACCES: i32 = 13;
+const EFAULT: i32 = 14;
+const EINVAL: i32 = 22;
+const ENOTTY: i32 = 25;
+const ENOSYS: i32 = 38;
+
+const SYS_IOCTL: u32 = 29;
+const SYS_FACCESSAT: u32 = 48;
+const SYS_OPENAT: u32 = 56;
+const SYS_CLOSE: u32 = 57;
+const SYS_LLSEEK: u32 = 62;
+const SYS_READ: u32 = 63;
+const SYS_WRITE: u32 = 64;
+const SYS_READV: u32 = 65;
+const SYS_WRITEV: u32 = 66;
+const SYS_FSTAT64: u32 = 80;
+const SYS_EXIT: u32 = 93;
+const SYS_EXIT_GROUP: u32 = 94;
+const SYS_SET_TID_ADDRESS: u32 = 96;
+const SYS_SET_ROBUST_LIST: u32 = 99;
+const SYS_RT_SIGACTION: u32 = 134;
+const SYS_RT_SIGPROCMASK: u32 = 135;
+const SYS_UNAME: u32 = 160;
+const SYS_GETPID: u32 = 172;
+const SYS_GETPPID: u32 = 173;
+const SYS_GETUID: u32 = 174;
+const SYS_GETEUID: u32 = 175;
+const SYS_GETGID: u32 = 176;
+const SYS_GETEGID: u32 = 177;
+const SYS_GETTID: u32 = 178;
+const SYS_BRK: u32 = 214;
+const SYS_MUNMAP: u32 = 215;
+const SYS_MMAP2: u32 = 222;
+const SYS_MPROTECT: u32 = 226;
+const SYS_GETRANDOM: u32 = 278;
+const SYS_STATX: u32 = 291;
+const SYS_CLOCK_GETTIME64: u32 = 403;
+
+const PROCESS_ID: u32 = 1;
+
+#[derive(Clone, Debug)]
+pub struct LinuxConfiguration {
+    /// The directory the program sees as `/`. Its files are the only host files it can reach.
+    pub root: PathBuf,
+    /// `argv`, starting with the program name.
+    pub arguments: Vec<String>,
+    /// `envp` as `NAME=value` entries.
+    pub environment: Vec<String>,
+    /// Memory from address 0, the stack is at its top.
+    pub memory_size: usize,
+}
+
+impl Default for LinuxConfiguration {
+    fn default() -> Self {
+        LinuxConfiguration {
+            root: PathBuf::from("."),
+            arguments: Vec::new(),
+            environment: Vec::new(),
+            memory_size: DEFAULT_MEMORY_SIZE,
+        }
+    }
+}
+
+/// How a process stopped running.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Termination {
+    Exited(u32),
+    /// An exception other than a system call, which a kernel would turn into a signal.
+    Faulted {
+        pc: u32,
+        exception: Option<Exception>,
+    },
+    Timeout,
+}
+
+/// Runs a statically linked rv32 Linux program in U-mode without a kernel, the way qemu-user does: `ecall` traps to
+/// the runner, which serves the system call from the host and returns to the program.
+///
+/// Memory is flat and starts at 0, the program break follows the loaded segments and anonymous mappings are taken
+/// from below the stack. Mappings are never reused, `munmap` and `mprotect` only succeed. The usual calls of glibc,
+/// musl and newlib start-up, stdio and the clock are served, anything else fails with `ENOSYS`. Paths resolve inside
+/// the configured root, which also is the working directory.
+pub struct LinuxProcess {
+    hart: Hart<Memory, SimplePipeline>,
+    memory: Memory,
+    root: PathBuf,
+    program_break: u32,
+    /// The end of the loaded segments, the break cannot go below it.
+    program_break_start: u32,
+    /// The lowest mapping so far, the break cannot grow into it.
+    mapping_start: u32,
+    files: HashMap<u32, OpenFile>,
+    next_descriptor: u32,
+    input: Box<dyn Read>,
+    output: Box<dyn Write>,
+    error: Box<dyn Write>,
+    started: Instant,
+    termination: Option<Termination>,
+}
+
+struct OpenFile {
+    file: File,
+    /// The host path, for resolving paths relative to the descriptor.
+    path: PathBuf,
+}
+
+/// The part of an executable the process is built from.
+struct Image<'a> {
+    segments: Vec<(u32, &'a [u8], u32)>,
+    entry: u32,
+    program_headers: Option<(u32, u32)>,
+}
+
+impl LinuxProcess {
+    pub fn from_elf(elf: &Elf, configuration: LinuxConfiguration) -> Result<Self, String> {
+        let mut segments = Vec::new();
+        let mut program_headers = None;
+        for segment in elf.segments.iter().filter(|segment| segment.kind == SEGMENT_LOAD) {
+            let data = elf.segment_data(segment).map_err(|error| error.to_string())?;
+            segments.push((segment.virtual_address, data, segment.memory_size));
+
+            let offset = elf.program_header_offset.wrapping_sub(segment.offset);
+            if offset < segment.file_size {
+                let address = segment.virtual_address.checked_add(offset);
+                let address = address.ok_or_else(|| format!("program headers at {offset:#x} do not fit"))?;
+                program_headers = Some((address, elf.segments.len() as u32));
+            }
+        }
+        LinuxProcess::new(Image { segments, entry: elf.entry, program_headers }, configuration)
+    }
+
+    fn new(image: Image, configuration: LinuxConfiguration) -> Result<Self, String> {
+        let LinuxConfiguration { root, arguments, environment, memory_size } = configuration;
+        let root = root.canonicalize().map_err(|error| format!("{}: {error}", root.display()))?;
+        if memory_size > TRAP_VECTOR as usize || memory_size < 2 * STACK_SIZE as usize {
+            return Err(format!("{memory_size:#x} bytes of memory do not fit a process"));
+        }
+
+        let mut bytes = vec![0; memory_size];
+        let mut program_break_start = 0;
+        for &(address, data, size) in &image.segments {
+            let end = address as usize + size.max(data.len() as u32) as usize;
+            let destination = bytes.get_mut(address as usize..address as usize + data.len());
+            match destination {
+                Some(destination) if end <= memory_size - STACK_SIZE as usize => destination.copy_from_slice(data),
+                _ => return Err(format!("segment at {address:#x} does not fit in {memory_size:#x} bytes of memory")),
+            }
+            program_break_start = program_break_start.max(end as u32);
+        }
+        let program_break_start = align_up(program_break_start, PAGE_SIZE);
+        let stack_pointer = initial_stack(&mut bytes, &image, &arguments, &environment);
+
+        let mut hart = Hart::<Memory, SimplePipeline>::new();
+        let state = hart.state_mut();
+        state.register_file.write(REGISTER_SP, stack_pointer);
+        state.csrs.write(MTVEC, TRAP_VECTOR);
+        state.csrs.write(PMPADDR0, u32::MAX);
+        state.csrs.write(PMPCFG0, 0x1f);
+        state.csrs.set_privilege_level(PrivilegeLevel::User);
+        hart.set_program_counter(image.entry);
+
+        Ok(LinuxProcess {
+            hart,
+            memory: Memory::with_initial_values(bytes),
+            root,
+            program_break: program_break_start,
+            program_break_start,
+            mapping_start: memory_size as u32 - STACK_SIZE,
+            files: HashMap::new(),
+            next_descriptor: 3,
+            input: Box::new(io::stdin()),
+            output: Box::new(io::stdout()),
+            error: Box::new(io::stderr()),
+            started: Instant::now(),
+            termination: None,
+        })
+    }
+
+    /// Replaces the host's standard input, output and error the program reads and writes as descriptors 0 to 2.
+    pub fn set_standard_streams(
+        &mut self,
+        input: impl Read + 'static,
+        output: impl Write + 'static,
+        error: impl Write + 'static,
+    ) {
+        self.input = Box::new(input);
+        self.output = Box::new(output);
+        self.error = Box::new(error);
+    }
+
+    pub fn hart(&self) -> &Hart<Memory, SimplePipeline> {
+        &self.hart
+    }
+
+    pub fn hart_mut(&mut self) -> &mut Hart<Memory, SimplePipeline> {
+        &mut self.hart
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /// Runs until the program exits or faults, or for at most `cycle_limit` cycles.
+    pub fn run(&mut self, cycle_limit: Option<u64>) -> Termination {
+        let mut cycles = 0;
+        while cycle_limit.is_none_or(|limit| cycles < limit) {
+            self.hart.execute(&mut self.memory);
+            cycles += 1;
+
+            if self.hart.program_counter() == TRAP_VECTOR {
+                self.trap();
+            }
+            if let Some(termination) = self.termination {
+                return termination;
+            }
+        }
+        Termination::Timeout
+    }
+
+    fn trap(&mut self) {
+        let csrs = &self.hart.state().csrs;
+        let pc = csrs.read(MEPC).unwrap_or(0);
+        let cause = csrs.read(MCAUSE).unwrap_or(0);
+        let exception = Exception::from_cause(cause, csrs.read(MTVAL).unwrap_or(0));
+        if exception != Some(Exception::EnvironmentCallFromUMode) {
+            self.termination = Some(Termination::Faulted { pc, exception });
+            return;
+        }
+
+        let registers = &self.hart.state().register_file;
+        let number = registers.read(REGISTER_A7);
+        let arguments = std::array::from_fn(|index| registers.read(REGISTER_A0 + index));
+        let result = self.syscall(number, arguments).unwrap_or_else(|error| -error as u32);
+
+        let state = self.hart.state_mut();
+        state.register_file.write(REGISTER_A0, result);
+        state.csrs.set_privilege_level(PrivilegeLevel::User);
+        self.hart.pipeline_mut().flush();
+        self.hart.set_program_counter(pc.wrapping_add(4));
+    }
+
+    fn syscall(&mut self, number: u32, arguments: [u32; 6]) -> Result<u32, i32> {
+        let [a0, a1, a2, a3, a4, a5] = arguments;
+        match number {
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                self.termination = Some(Termination::Exited(a0));
+                Ok(0)
+            }
+            SYS_READ => self.read(a0, a1, a2),
+            SYS_WRITE => self.write(a0, a1, a2),
+            SYS_READV | SYS_WRITEV => {
+                let mut total = 0;
+                for vector in 0..a2 {
+                    let base = self.read_word(a1.wrapping_add(8 * vector))?;
+                    let length = self.read_word(a1.wrapping_add(8 * vector + 4))?;
+                    let done = match number {
+                        SYS_READV => self.read(a0, base, length)?,
+                        _ => self.write(a0, base, length)?,
+                    };
+                    total += done;
+                    if done < length {
+                        break;
+                    }
+                }
+                Ok(total)
+            }
+            SYS_OPENAT => {
+                let path = self.host_path(a0, a1)?;
+                let access = a2 & (O_WRONLY | O_RDWR);
+                let file = OpenOptions::new()
+                    .read(access != O_WRONLY)
+                    .write(access != 0)
+                    .append(a2 & O_APPEND != 0)
+                    .truncate(a2 & O_TRUNC != 0)
+                    .create(a2 & O_CREAT != 0 && a2 & O_EXCL == 0)
+                    .create_new(a2 & O_CREAT != 0 && a2 & O_EXCL != 0)
+                    .open(&path)
+                    .map_err(errno)?;
+                let descriptor = self.next_descriptor;
+                self.next_descriptor += 1;
+                self.files.insert(descriptor, OpenFile { file, path });
+                Ok(descriptor)
+            }
+            SYS_CLOSE => match a0 {
+                0..=2 => Ok(0),
+                _ => self.files.remove(&a0).map(|_| 0).ok_or(EBADF),
+            },
+            // The offset comes in two halves and the result is stored, the return value only reports success.
+            SYS_LLSEEK => {
+                let offset = (a1 as u64) << 32 | a2 as u64;
+                let position = match a4 {
+                    0 => SeekFrom::Start(offset),
+                    1 => SeekFrom::Current(offset as i64),
+                    2 => SeekFrom::End(offset as i64),
+                    _ => return Err(EINVAL),
+                };
+                let file = &mut self.files.get_mut(&a0).ok_or(EBADF)?.file;
+                let position = file.seek(position).map_err(errno)?;
+                self.write_bytes(a3, &position.to_le_bytes())?;
+                Ok(0)
+            }
+            SYS_FACCESSAT => fs::metadata(self.host_path(a0, a1)?).map(|_| 0).map_err(errno),
+            SYS_FSTAT64 => {
+                let status = self.metadata(a0)?.map(|metadata| stat64(&metadata)).unwrap_or_else(character_device);
+                self.write_bytes(a1, &status)?;
+                Ok(0)
+            }
+            SYS_STATX => {
+                let metadata = match (a2 & AT_EMPTY_PATH != 0, self.read_string(a1)?.is_empty()) {
+                    (true, true) => self.metadata(a0)?,
+                    _ => Some(fs::metadata(self.host_path(a0, a1)?).map_err(errno)?),
+                };
+                let stat = metadata.map(|metadata| stat64(&metadata)).unwrap_or_else(character_device);
+                self.write_bytes(a4, &statx(&stat))?;
+                Ok(0)
+            }
+            SYS_IOCTL => Err(ENOTTY),
+            SYS_BRK => {
+                if a0 >= self.program_break_start && a0 < self.mapping_start {
+                    if a0 > self.program_break {
+                        self.zero_bytes(self.program_break, a0 - self.program_break)?;
+                    }
+                    self.program_break = a0;
+                }
+                Ok(self.program_break)
+            }
+            // Offsets are in pages.
+            SYS_MMAP2 => {
+                let length = a1.checked_next_multiple_of(PAGE_SIZE).ok_or(ENOMEM)?;
+                let address = match a3 & MAP_FIXED {
+                    0 => {
+                        let start = self.mapping_start.checked_sub(length).ok_or(ENOMEM)?;
+                        if start < self.program_break || length == 0 {
+                            return Err(ENOMEM);
+                        }
+                        self.mapping_start = start;
+                        start
+                    }
+                    _ => {
+                        self.check_range(a0, length).map_err(|_| ENOMEM)?;
+                        a0
+                    }
+                };
+
+                let anonymous = a3 & MAP_ANONYMOUS != 0;
+                if !anonymous {
+                    let file = &mut self.files.get_mut(&a4).ok_or(EBADF)?.file;
+                    file.seek(SeekFrom::Start(a5 as u64 * PAGE_SIZE as u64)).map_err(errno)?;
+                }
+                self.zero_bytes(address, length)?;
+                if !anonymous {
+                    self.read(a4, address, length)?;
+                }
+                Ok(address)
+            }
+            SYS_MUNMAP | SYS_MPROTECT | SYS_SET_ROBUST_LIST | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK => Ok(0),
+            SYS_SET_TID_ADDRESS | SYS_GETPID | SYS_GETTID => Ok(PROCESS_ID),
+            SYS_GETPPID | SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => Ok(0),
+            SYS_UNAME => {
+                let mut fields = vec![0; 6 * 65];
+                for (index, value) in ["Linux", "risc_v_vm", "6.1.0", "#1", "riscv32", ""].iter().enumerate() {
+                    fields[index * 65..index * 65 + value.len()].copy_from_slice(value.as_bytes());
+                }
+                self.write_bytes(a0, &fields)?;
+                Ok(0)
+            }
+            SYS_CLOCK_GETTIME64 => {
+                let elapsed = match a0 {
+                    CLOCK_REALTIME => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+                    _ => self.started.elapsed(),
+                };
+                let mut time = elapsed.as_secs().to_le_bytes().to_vec();
+                time.extend_from_slice(&(elapsed.subsec_nanos() as u64).to_le_bytes());
+                self.write_bytes(a1, &time)?;
+                Ok(0)
+            }
+            SYS_GETRANDOM => {
+                self.check_range(a0, a1)?;
+                for offset in (0..a1).step_by(CHUNK_SIZE as usize) {
+                    self.write_bytes(a0 + offset, &host_random((a1 - offset).min(CHUNK_SIZE) as usize))?;
+                }
+                Ok(a1)
+            }
+            _ => Err(ENOSYS),
+        }
+    }
+
+    /// Reads files until `length` bytes or their end, in chunks, and standard input only once as it may not have more.
+    /// The range is checked first, so nothing is read from the host that the guest does not get.
+    fn read(&mut self, descriptor: u32, address: u32, length: u32) -> Result<u32, i32> {
+        self.check_range(address, length)?;
+        let mut data = vec![0; length.min(CHUNK_SIZE) as usize];
+        let mut total = 0;
+        while total < length {
+            let wanted = (length - total).min(CHUNK_SIZE) as usize;
+            let read = match descriptor {
+                0 => self.input.read(&mut data[..wanted]),
+                1 | 2 => return Err(EBADF),
+                _ => self.files.get_mut(&descriptor).ok_or(EBADF)?.file.read(&mut data[..wanted]),
+            };
+            let read = match read {
+                Ok(read) => read,
+                Err(error) if total == 0 => return Err(errno(error)),
+                Err(_) => break,
+            };
+            self.write_bytes(address + total, &data[..read])?;
+            total += read as u32;
+            if read < wanted || descriptor == 0 {
+                break;
+            }
+        }
+        Ok(total)
+    }
+
+    fn write(&mut self, descriptor: u32, address: u32, length: u32) -> Result<u32, i32> {
+        let data = self.read_bytes(address, length)?;
+        let written = match descriptor {
+            0 => return Err(EBADF),
+            1 => self.output.write_all(&data).and_then(|_| self.output.flush()),
+            2 => self.error.write_all(&data).and_then(|_| self.error.flush()),
+            _ => self.files.get_mut(&descriptor).ok_or(EBADF)?.file.write_all(&data),
+        };
+        written.map(|_| length).map_err(errno)
+    }
+
+    /// The host file behind a descriptor, `None` for the standard streams.
+    fn metadata(&self, descriptor: u32) -> Result<Option<Metadata>, i32> {
+        match descriptor {
+            0..=2 => Ok(None),
+            _ => self.files.get(&descriptor).ok_or(EBADF)?.file.metadata().map(Some).map_err(errno),
+        }
+    }
+
+    /// Resolves the guest path at `address` like `openat` does, relative to the descriptor unless it is absolute.
+    fn host_path(&self, directory: u32, address: u32) -> Result<PathBuf, i32> {
+        let path = self.read_string(address)?;
+        let base = match (path.starts_with('/'), directory) {
+            (true, _) | (false, AT_FDCWD) => self.root.clone(),
+            (false, descriptor) => self.files.get(&descriptor).ok_or(EBADF)?.path.clone(),
+        };
+        self.resolve(base, &path)
+    }
+
+    /// Joins `path` onto `base` without leaving the root: `..` stops at it, like it does at `/`. Symbolic links can
+    /// still point out of it, paths that end up outside through one are refused.
+    fn resolve(&self, base: PathBuf, path: &str) -> Result<PathBuf, i32> {
+        if path.is_empty() {
+            return Err(ENOENT);
+        }
+
+        let mut resolved = base;
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => resolved.push(name),
+                Component::ParentDir if resolved != self.root => {
+                    resolved.pop();
+                }
+                _ => {}
+            }
+        }
+
+        // Files that are about to be created do not exist yet, the closest existing directory decides. A dangling
+        // link would be followed by a create wherever it points, so it is refused.
+        let existing = resolved
+            .ancestors()
+            .find_map(|path| match path.canonicalize() {
+                Ok(path) => Some(Ok(path)),
+                Err(_) if path.symlink_metadata().is_ok() => Some(Err(EACCES)),
+                Err(_) => None,
+            })
+            .ok_or(ENOENT)??;
+        match existing.starts_with(&self.root) {
+            true => Ok(resolved),
+            false => Err(EACCES),
+        }
+    }
+
+    fn read_word(&self, address: u32) -> Result<u32, i32> {
+        let bytes = self.read_bytes(address, 4)?;
+        Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn read_string(&self, address: u32) -> Result<String, i32> {
+        let mut bytes = Vec::new();
+        for offset in 0..PATH_MAX {
+            match self.read_bytes(address.wrapping_add(offset), 1)?[0] {
+                0 => return Ok(String::from_utf8_lossy(&bytes).into_owned()),
+                byte => bytes.push(byte),
+            }
+        }
+        Err(EFAULT)
+    }
+
+    /// Fails with `EFAULT` unless `length` bytes from `address` are all in memory, which starts at 0.
+    fn check_range(&self, address: u32, length: u32) -> Result<(), i32> {
+        match length {
+            0 => Ok(()),
+            _ => self.read_bytes(address.checked_add(length - 1).ok_or(EFAULT)?, 1).map(|_| ()),
+        }
+    }
+
+    fn read_bytes(&self, address: u32, length: u32) -> Result<Vec<u8>, i32> {
+        (0..length)
+            .map(|offset| match <Memory as BusInterface<u32, u8>>::read(&self.memory, address.wrapping_add(offset)) {
+                BusReadResponse::Success(value) => Ok(value as u8),
+                _ => Err(EFAULT),
+            })
+            .collect()
+    }
+
+    fn zero_bytes(&mut self, address: u32, length: u32) -> Result<(), i32> {
+        (0..length).try_for_each(|offset| self.write_bytes(address.wrapping_add(offset), &[0]))
+    }
+
+    fn write_bytes(&mut self, address: u32, bytes: &[u8]) -> Result<(), i32> {
+        for (offset, &byte) in bytes.iter().enumerate() {
+            let address = address.wrapping_add(offset as u32);
+            if !matches!(self.memory.write(address, byte), BusWriteResponse::Success) {
+                return Err(EFAULT);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lays out the stack a Linux program starts with below the top of memory and returns the stack pointer: `argc`,
+/// the `argv` and `envp` pointers each ending with a null pointer, then the auxiliary vector, with the strings and
+/// the `AT_RANDOM` bytes above.
+fn initial_stack(memory: &mut [u8], image: &Image, arguments: &[String], environment: &[String]) -> u32 {
+    let mut top = memory.len();
+    let mut push = |bytes: &[u8]| {
+        top -= bytes.len();
+        memory[top..top + bytes.len()].copy_from_slice(bytes);
+        top as u32
+    };
+
+    let random = push(&host_random(16));
+    let mut string_pointers = |strings: &[String]| {
+        let mut pointers: Vec<u32> = strings.iter().map(|string| push(&[string.as_bytes(), &[0]].concat())).collect();
+        pointers.push(0);
+        pointers
+    };
+    let argument_pointers = string_pointers(arguments);
+    let environment_pointers = string_pointers(environment);
+
+    let mut auxiliary_vector = vec![
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, image.entry),
+        (AT_UID, 0),
+        (AT_EUID, 0),
+        (AT_GID, 0),
+        (AT_EGID, 0),
+        (AT_HWCAP, HWCAP),
+        (AT_CLKTCK, 100),
+        (AT_RANDOM, random),
+    ];
+    if let Some((address, count)) = image.program_headers {
+        auxiliary_vector.extend([(AT_PHDR, address), (AT_PHENT, PROGRAM_HEADER_SIZE), (AT_PHNUM, count)]);
+    }
+    auxiliary_vector.push((AT_NULL, 0));
+
+    let mut words = vec![arguments.len() as u32];
+    words.extend(argument_pointers);
+    words.extend(environment_pointers);
+    words.extend(auxiliary_vector.into_iter().flat_map(|(kind, value)| [kind, value]));
+
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    let stack_pointer = (top - bytes.len()) & !0xf;
+    memory[stack_pointer..stack_pointer + bytes.len()].copy_from_slice(&bytes);
+    stack_pointer as u32
+}
+
+/// `struct stat64` of the generic 32 bit ABI.
+fn stat64(metadata: &Metadata) -> Vec<u8> {
+    let mut stat = Vec::with_capacity(104);
+    stat.extend_from_slice(&metadata.dev().to_le_bytes());
+    stat.extend_from_slice(&metadata.ino().to_le_bytes());
+    stat.extend_from_slice(&metadata.mode().to_le_bytes());
+    stat.extend_from_slice(&(metadata.nlink() as u32).to_le_bytes());
+    stat.extend_from_slice(&metadata.uid().to_le_bytes());
+    stat.extend_from_slice(&metadata.gid().to_le_bytes());
+    stat.extend_from_slice(&metadata.rdev().to_le_bytes());
+    stat.extend_from_slice(&0u64.to_le_bytes());
+    stat.extend_from_slice(&metadata.size().to_le_bytes());
+    stat.extend_from_slice(&(metadata.blksize() as u32).to_le_bytes());
+    stat.extend_from_slice(&0u32.to_le_bytes());
+    stat.extend_from_slice(&metadata.blocks().to_le_bytes());
+    for (seconds, nanoseconds) in [
+        (metadata.atime(), metadata.atime_nsec()),
+        (metadata.mtime(), metadata.mtime_nsec()),
+        (metadata.ctime(), metadata.ctime_nsec()),
+    ] {
+        stat.extend_from_slice(&(seconds as u32).to_le_bytes());
+        stat.extend_from_slice(&(nanoseconds as u32).to_le_bytes());
+    }
+    stat.resize(104, 0);
+    stat
+}
+
+/// What the standard streams report, a terminal-like character device.
+fn character_device() -> Vec<u8> {
+    let mut stat = vec![0; 104];
+    stat[16..20].copy_from_slice(&0o20620u32.to_le_bytes());
+    stat[20..24].copy_from_slice(&1u32.to_le_bytes());
+    stat[56..60].copy_from_slice(&1024u32.to_le_bytes());
+    stat
+}
+
+/// `struct statx` with the fields of a `stat64`.
+fn statx(stat: &[u8]) -> Vec<u8> {
+    let field = |offset: usize, size: usize| &stat[offset..offset + size];
+    let mut statx = vec![0; 256];
+    let mut put = |offset: usize, bytes: &[u8]| statx[offset..offset + bytes.len()].copy_from_slice(bytes);
+
+    // Everything in the basic stats: type, mode, links, owner, times, inode, size and blocks.
+    put(0, &0x7ffu32.to_le_bytes());
+    put(4, field(56, 4));
+    put(16, field(20, 4));
+    put(20, field(24, 8));
+    put(28, &field(16, 2)[..2]);
+    put(32, field(8, 8));
+    put(40, field(48, 8));
+    put(48, field(64, 8));
+    for (statx_offset, stat_offset) in [(64, 72), (96, 88), (112, 80)] {
+        put(statx_offset, &(u32::from_le_bytes(field(stat_offset, 4).try_into().unwrap()) as u64).to_le_bytes());
+        put(statx_offset + 8, field(stat_offset + 4, 4));
+    }
+    let rdev = u64::from_le_bytes(field(32, 8).try_into().unwrap());
+    let dev = u64::from_le_bytes(field(0, 8).try_into().unwrap());
+    put(128, &major(rdev).to_le_bytes());
+    put(132, &minor(rdev).to_le_bytes());
+    put(136, &major(dev).to_le_bytes());
+    put(140, &minor(dev).to_le_bytes());
+    statx
+}
+
+fn major(device: u64) -> u32 {
+    ((device >> 8) & 0xfff | (device >> 32) & !0xfff) as u32
+}
+
+fn minor(device: u64) -> u32 {
+    (device & 0xff | (device >> 12) & !0xff) as u32
+}
+
+/// Random bytes from the host, zeros when it has none to give.
+fn host_random(length: usize) -> Vec<u8> {
+    let mut bytes = vec![0; length];
+    let _ = File::open("/dev/urandom").and_then(|mut source| source.read_exact(&mut bytes));
+    bytes
+}
+
+fn errno(error: io::Error) -> i32 {
+    error.raw_os_error().unwrap_or(EPERM)
+}
+
+fn align_up(value: u32, alignment: u32) -> u32 {
+    value.div_ceil(alignment) * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn spawn(source: &str, root: &Path) -> (LinuxProcess, Output) {
+        let program = assemble(source, 0x10000).unwrap();
+        let image = Image {
+            segments: vec![(program.origin, &program.bytes, program.bytes.len() as u32)],
+            entry: program.origin,
+            program_headers: None,
+        };
+        let configuration = LinuxConfiguration {
+            root: root.to_path_buf(),
+            arguments: vec!["echo".to_string(), "hello".to_string()],
+            environment: vec!["TERM=dumb".to_string()],
+            memory_size: 32 << 20,
+        };
+        let mut process = LinuxProcess::new(image, configuration).unwrap();
+        let output = Output::default();
+        process.set_standard_streams(io::empty(), output.clone(), io::sink());
+        (process, output)
+    }
+
+    #[test]
+    fn serves_system_calls_with_the_arguments_on_the_stack() {
+        // Writes argv[1] with a newline, grows the break by a page and exits with argc plus the first byte there.
+        let source = "
+            lw      s0, 0(sp)
+            lw      a1, 8(sp)
+            mv      a2, zero
+        1:
+            add     t0, a1, a2
+            lbu     t0, 0(t0)
+            beqz    t0, 2f
+            addi    a2, a2, 1
+            j       1b
+        2:
+            li      a0, 1
+            li      a7, 64
+            ecall
+            la      a1, newline
+            li      a2, 1
+            li      a0, 1
+            li      a7, 64
+            ecall
+
+            mv      a0, zero
+            li      a7, 214
+            ecall
+            mv      s1, a0
+            li      t0, 4096
+            add     a0, a0, t0
+            ecall
+            sub     t0, a0, s1
+            li      t1, 4096
+            bne     t0, t1, 3f
+            lbu     t0, 0(s1)
+            add     s0, s0, t0
+
+        3:
+            mv      a0, s0
+            li      a7, 94
+            ecall
+
+            .data
+        newline:
+            .byte   10
+        ";
+        let (mut process, output) = spawn(source, &std::env::temp_dir());
+        assert_eq!(process.run(Some(10_000)), Termination::Exited(2));
+        assert_eq!(output.0.borrow().as_slice(), b"hello\n");
+
+        let (mut process, _) = spawn("li a7, 999\necall\nli a7, 93\necall\n.word 0\n", &std::env::temp_dir());
+        assert_eq!(process.run(Some(1_000)), Termination::Exited(-ENOSYS as u32));
+        let (mut process, _) = spawn(".word 0\n", &std::env::temp_dir());
+        assert_eq!(
+            process.run(Some(1_000)),
+            Termination::Faulted { pc: 0x10000, exception: Some(Exception::IllegalInstruction { instruction: 0 }) }
+        );
+    }
+
+    #[test]
+    fn checks_guest_buffers_before_using_the_lengths() {
+        let root = std::env::temp_dir().join(format!("risc_v_vm_linux_buffers_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let contents: Vec<u8> = (0..3 * CHUNK_SIZE).map(|index| index as u8).collect();
+        fs::write(root.join("file"), &contents).unwrap();
+
+        let (mut process, _) = spawn("ecall\n", &root);
+        process.set_standard_streams(io::Cursor::new(b"input"), io::sink(), io::sink());
+        assert_eq!(process.syscall(SYS_READ, [0, 0x10_0000, u32::MAX, 0, 0, 0]), Err(EFAULT));
+        assert_eq!(process.syscall(SYS_READ, [0, 0x10_0000, 64, 0, 0, 0]), Ok(5));
+        assert_eq!(process.read_bytes(0x10_0000, 5), Ok(b"input".to_vec()));
+        assert_eq!(process.syscall(SYS_GETRANDOM, [0x10_0000, u32::MAX, 0, 0, 0, 0]), Err(EFAULT));
+
+        process.write_bytes(0x10_0000, b"file\0").unwrap();
+        let file = process.syscall(SYS_OPENAT, [AT_FDCWD, 0x10_0000, 0, 0, 0, 0]).unwrap();
+        assert_eq!(process.syscall(SYS_READ, [file, 0x10_0000, 3 * CHUNK_SIZE + 1, 0, 0, 0]), Ok(3 * CHUNK_SIZE));
+        assert_eq!(process.read_bytes(0x10_0000, 3 * CHUNK_SIZE), Ok(contents));
+
+        assert_eq!(process.syscall(SYS_MMAP2, [0, u32::MAX, 3, MAP_ANONYMOUS, 0, 0]), Err(ENOMEM));
+        assert_eq!(
+            process.syscall(SYS_MMAP2, [0xffff_0000, PAGE_SIZE, 3, MAP_ANONYMOUS | MAP_FIXED, 0, 0]),
+            Err(ENOMEM)
+        );
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn keeps_paths_inside_the_root() {
+        let root = std::env::temp_dir().join(format!("risc_v_vm_linux_{}", std::process::id()));
+        fs::create_dir_all(root.join("data")).unwrap();
+        fs::write(root.join("data/file"), "inside").unwrap();
+        std::os::unix::fs::symlink("/etc", root.join("escape")).unwrap();
+        std::os::unix::fs::symlink("/nonexistent/risc_v_vm", root.join("dangling")).unwrap();
+
+        let (process, _) = spawn("ecall\n", &root);
+        let root = process.root.clone();
+        assert_eq!(process.resolve(root.clone(), "/data/file"), Ok(root.join("data/file")));
+        assert_eq!(process.resolve(root.join("data"), "../../../data/./file"), Ok(root.join("data/file")));
+        assert_eq!(process.resolve(root.clone(), "/data/new"), Ok(root.join("data/new")));
+        assert_eq!(process.resolve(root.clone(), "escape/passwd"), Err(EACCES));
+        assert_eq!(process.resolve(root.clone(), "dangling"), Err(EACCES));
+        assert_eq!(process.resolve(root.clone(), "data/../dangling/file"), Err(EACCES));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
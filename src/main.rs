@@ -1,27 +1,726 @@
-use crate::core::hart::Hart;
+use std::fs::File;
+use std::io::{self, BufWriter, Read};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
 
-use crate::memory::Memory;
-use crate::simple_pipeline::SimplePipeline;
+use risc_v_vm::assembler::assemble;
+use risc_v_vm::compliance::{self, format_signature, Outcome, TestProgram};
+use risc_v_vm::core::branch_predictor::{
+    AlwaysNotTaken, AlwaysTaken, BackwardTakenForwardNotTaken, Bimodal, BranchPredictor, BranchStatistics,
+    DirectionPredictor, Gshare, Tournament,
+};
+use risc_v_vm::core::bus::{BusInterface, Clocked};
+use risc_v_vm::core::cache::{Cache, CacheConfiguration, Replacement, WritePolicy};
+use risc_v_vm::core::commit_log::CommitLog;
+use risc_v_vm::core::csr::address_constants::{MHPMCOUNTER3, MHPMCOUNTER3H, MHPMEVENT3};
+use risc_v_vm::core::csr::HpmEvent;
+use risc_v_vm::core::hart::{Hart, HartState};
+use risc_v_vm::core::snapshot::{self, Snapshot};
+use risc_v_vm::debugger::{parse_number, Debugger};
+use risc_v_vm::disassembler::disassemble_bytes;
+use risc_v_vm::elf::Elf;
+use risc_v_vm::gdb_stub::GdbStub;
+use risc_v_vm::htif::{host_addresses, Htif};
+use risc_v_vm::image::{Chunk, Image};
+use risc_v_vm::kanata::KanataTracer;
+use risc_v_vm::linux_user::{LinuxConfiguration, LinuxProcess, Termination};
+use risc_v_vm::memory::Memory;
+use risc_v_vm::profiler::{Metric, Profiler};
+use risc_v_vm::semihosting::Semihosting;
+use risc_v_vm::simple_pipeline::SimplePipeline;
+use risc_v_vm::symbols::Symbols;
+use risc_v_vm::wait_states::WaitStates;
 
-mod core;
-mod memory;
-mod simple_pipeline;
+const USAGE: &str =
+    "usage: risc_v_vm [--log-commits [--annotate]] [--cycles <count>] [--restore-snapshot <path>]
+                 [--save-snapshot <path>] [--profile <path>] [--profile-interval <cycles>] [--icache <cache>]
+                 [--dcache <cache>] [--uncacheable <start>,<size>]... [--predictor <predictor>] [--trace <path>]
+                 [--wait-states <cycles>] [--wait-region <start>,<size>,<cycles>]... [--base <address>]
+                 [<file> [<argument>...]]
+       risc_v_vm disassemble <file> [--section <name>] [--base <address>]
+       risc_v_vm test <file or directory>... [--cycles <count>] [--signatures <directory>]
+       risc_v_vm linux [--root <directory>] [--env <name=value>]... [--memory <MiB>] [--cycles <count>]
+                       <file> [<argument>...]
+       risc_v_vm debug [<file>] [--base <address>] [--record <MiB>] [--trace <path>]
+       risc_v_vm gdb [<file>] [--base <address>] [--record <MiB>] [--port <port> | --socket <path>]
+
+caches are <size>[,<line size>[,<ways>]] in bytes, then lru, fifo or random replacement and for --dcache
+write-back or write-through and allocate or no-allocate on write misses
+predictors are not-taken, taken, btfn, bimodal[,<entries>], gshare[,<entries>[,<history bits>]]
+or tournament[,<entries>[,<history bits>]]";
+
+/// mhpmcounter3 to mhpmcounter31.
+const HPM_COUNTERS: u32 = 29;
+
+/// Table sizes of `--predictor` when it leaves them out.
+const DEFAULT_PREDICTOR_ENTRIES: u32 = 1024;
+const DEFAULT_HISTORY_BITS: u32 = 10;
+
+/// Memory for programs loaded from files, from their lowest address on.
+const PROGRAM_MEMORY_SIZE: usize = 1 << 20;
+
+/// `tohost` and `fromhost`, as `host_addresses` finds them.
+type HostAddresses = (u32, Option<u32>);
+
+/// Keeps a counter and stores the counter plus eleven to `result` through a chain of calls.
+const DEMO: &str = "
+        .text
+        .globl  _start
+_start:
+        li      sp, 192
+loop:
+        jal     add_eleven
+        lw      t1, %lo(counter)(zero)
+        addi    t1, t1, 1
+        sw      t1, %lo(counter)(zero)
+        j       loop
+
+store_result:
+        addi    sp, sp, -4
+        sw      ra, 0(sp)
+        sw      a1, %lo(result)(zero)
+        lw      ra, 0(sp)
+        addi    sp, sp, 4
+        ret
+
+add_eleven:
+        addi    sp, sp, -4
+        sw      ra, 0(sp)
+        lw      t1, %lo(counter)(zero)
+        li      t2, 11
+        add     a1, t1, t2
+        jal     store_result
+        lw      ra, 0(sp)
+        addi    sp, sp, 4
+        ret
+
+        .data
+result:
+        .word   0
+counter:
+        .word   0
+";
 
 fn main() {
-    let program = vec![
-        19u8, 1, 0, 12, 239, 0, 192, 2, 3, 35, 0, 9, 19, 3, 19, 0, 35, 40, 96, 8, 111, 240, 31, 255, 19, 1, 193, 255,
-        35, 32, 17, 0, 35, 32, 176, 8, 131, 32, 1, 0, 19, 1, 65, 0, 103, 128, 0, 0, 19, 1, 193, 255, 35, 32, 17, 0, 3,
-        35, 0, 9, 147, 3, 176, 0, 179, 5, 115, 0, 239, 240, 95, 253, 131, 32, 1, 0, 19, 1, 65, 0, 103, 128, 0, 0, 0, 0,
-        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-    ];
+    let arguments: Vec<String> = std::env::args().skip(1).collect();
 
-    let mut full_memory = vec![0u8; 1024];
-    full_memory[0..program.len()].clone_from_slice(&program);
+    let result = match arguments.first().map(String::as_str) {
+        Some("disassemble") => disassemble(&arguments[1..]),
+        Some("debug") => debug(&arguments[1..]),
+        Some("gdb") => gdb(&arguments[1..]),
+        Some("test") => test(&arguments[1..]),
+        Some("linux") => linux(&arguments[1..]),
+        _ => run(&arguments),
+    };
 
-    let mut memory = Memory::with_initial_values(full_memory);
-    let mut hart = Hart::<Memory, SimplePipeline>::new();
+    if let Err(message) = result {
+        eprintln!("{message}");
+        process::exit(1);
+    }
+}
+
+/// Runs the demo program, or a program loaded like `debug` loads it with semihosting served and the arguments after it
+/// as its command line. ELF files with a `tohost` symbol talk to the host through HTIF as well. Programs run until they
+/// exit through either and the simulator exits with their code, the demo runs for 1000 cycles. With `--profile` the
+/// cycles are profiled, exactly or sampled every `--profile-interval` cycles, into folded stacks for flamegraphs at the
+/// path and a summary on stderr. `--icache` and `--dcache` add caches, which leave out the `--uncacheable` ranges, and
+/// `--predictor` a branch predictor, whose statistics are printed on stderr at the end. Cache misses are counted by
+/// hardware performance counters as well, on the first ones the program leaves free. Memory accesses take
+/// `--wait-states` extra cycles, or those of the first `--wait-region` they fall into. `--trace` writes a pipeline
+/// trace for Konata. `--log-commits` writes a commit log like spike's, which `--annotate` ends every line of with the
+/// function and source line of the pc.
+fn run(arguments: &[String]) -> Result<(), String> {
+    let mut state = HartState::new();
+    let mut options = RunOptions::default();
+    let mut base = 0;
+    let mut uncacheable = Vec::new();
+    let mut command_line = Vec::new();
 
-    for _ in 0..1000 {
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--log-commits" => options.log_commits = true,
+            "--annotate" => options.annotate = true,
+            "--cycles" => options.cycle_limit = Some(parse_number(arguments.next().ok_or(USAGE)?)? as u64),
+            "--save-snapshot" => options.save_path = Some(arguments.next().ok_or(USAGE)?),
+            "--restore-snapshot" => options.restore_path = Some(arguments.next().ok_or(USAGE)?),
+            "--profile" => options.profile_path = Some(arguments.next().ok_or(USAGE)?),
+            "--profile-interval" => {
+                options.profile_interval = Some(parse_number(arguments.next().ok_or(USAGE)?)? as u64)
+            }
+            "--icache" => state.instruction_cache = Some(parse_cache(arguments.next().ok_or(USAGE)?, false)?),
+            "--dcache" => state.data_cache = Some(parse_cache(arguments.next().ok_or(USAGE)?, true)?),
+            "--uncacheable" => uncacheable.push(parse_range(arguments.next().ok_or(USAGE)?)?),
+            "--trace" => options.trace_path = Some(arguments.next().ok_or(USAGE)?),
+            "--predictor" => state.branch_predictor = Some(parse_predictor(arguments.next().ok_or(USAGE)?)?),
+            "--wait-states" => options.wait_states = parse_number(arguments.next().ok_or(USAGE)?)?,
+            "--wait-region" => options.wait_regions.push(parse_wait_region(arguments.next().ok_or(USAGE)?)?),
+            "--base" => base = parse_number(arguments.next().ok_or(USAGE)?)?,
+            _ => {
+                command_line = std::iter::once(argument).chain(arguments.by_ref()).cloned().collect();
+            }
+        }
+    }
+
+    let (memory, entry, symbols, host_addresses) = match command_line.first() {
+        Some(path) => load_program(path, base).map_err(|error| format!("{path}: {error}"))?,
+        None => {
+            options.cycle_limit.get_or_insert(1000);
+            let (memory, entry, symbols) = demo()?;
+            (memory, entry, symbols, None)
+        }
+    };
+    state.semihosting = true;
+    count_cache_misses(&mut state);
+    for cache in state.instruction_cache.iter_mut().chain(&mut state.data_cache) {
+        for &(start, size) in &uncacheable {
+            cache.add_uncacheable_region(start, size);
+        }
+    }
+
+    let exit_code = match host_addresses {
+        Some((tohost, fromhost)) => {
+            // Stores to them have to reach the bus.
+            if let Some(cache) = &mut state.data_cache {
+                cache.add_uncacheable_region(tohost, 8);
+                if let Some(fromhost) = fromhost {
+                    cache.add_uncacheable_region(fromhost, 8);
+                }
+            }
+            let htif = Htif::new(memory, tohost, fromhost);
+            simulate(state, htif, entry, Rc::new(symbols), &command_line, &options, Htif::exit_code)?
+        }
+        None => simulate(state, memory, entry, Rc::new(symbols), &command_line, &options, |_| None)?,
+    };
+    match exit_code {
+        Some(code) => process::exit(code as i32),
+        None => Ok(()),
+    }
+}
+
+/// What `run` does besides running the program.
+#[derive(Default)]
+struct RunOptions<'a> {
+    log_commits: bool,
+    annotate: bool,
+    cycle_limit: Option<u64>,
+    save_path: Option<&'a String>,
+    restore_path: Option<&'a String>,
+    profile_path: Option<&'a String>,
+    profile_interval: Option<u64>,
+    trace_path: Option<&'a String>,
+    wait_states: u32,
+    wait_regions: Vec<(u32, u32, u32)>,
+}
+
+/// Runs a hart from `entry` for `run` until the program exits, through semihosting or as `exit_code` of the device
+/// says, or the cycle limit is reached. The hart reaches the device through the wait states of the options, the host
+/// directly. Returns the exit code.
+fn simulate<M>(
+    state: HartState,
+    device: M,
+    entry: u32,
+    symbols: Rc<Symbols>,
+    command_line: &[String],
+    options: &RunOptions,
+    exit_code: fn(&M) -> Option<u32>,
+) -> Result<Option<u32>, String>
+where
+    M: BusInterface<u32, i8>,
+    M: BusInterface<u32, u8>,
+    M: BusInterface<u32, i16>,
+    M: BusInterface<u32, u16>,
+    M: BusInterface<u32, u32>,
+    M: Clocked,
+    M: Snapshot,
+{
+    let mut hart = Hart::<_, SimplePipeline>::with_state(state);
+    let mut memory = WaitStates::new(device, options.wait_states);
+    for &(start, size, wait_states) in &options.wait_regions {
+        memory.add_region(start, size, wait_states);
+    }
+    if options.log_commits {
+        // Written to stderr like spike's, so the two can be diffed unless annotated.
+        let mut commit_log = CommitLog::new(io::stderr());
+        if options.annotate {
+            commit_log.set_symbols(symbols.clone());
+        }
+        hart.set_commit_log(commit_log);
+    }
+    let mut profiler = options.profile_path.map(|_| match options.profile_interval {
+        Some(interval) => Profiler::sampling(symbols.clone(), interval),
+        None => Profiler::exact(symbols.clone()),
+    });
+    if profiler.is_some() {
+        hart.record_retirements();
+    }
+    if let Some(path) = options.trace_path {
+        hart.pipeline_mut().set_tracer(tracer(path, symbols.clone())?);
+    }
+    hart.set_program_counter(entry);
+    let mut semihosting = Semihosting::new(command_line.join(" "));
+
+    if let Some(path) = options.restore_path {
+        let bytes = std::fs::read(path).map_err(|error| format!("{path}: {error}"))?;
+        snapshot::restore(&mut [&mut hart, &mut memory], &bytes).map_err(|error| format!("{path}: {error}"))?;
+    }
+
+    let mut cycles = 0;
+    let exited = |semihosting: &Semihosting, memory: &WaitStates<M>| {
+        semihosting.exit_code().or_else(|| exit_code(memory.inner()))
+    };
+    while options.cycle_limit.is_none_or(|limit| cycles < limit) && exited(&semihosting, &memory).is_none() {
         hart.execute(&mut memory);
+        if let Some(profiler) = &mut profiler {
+            profiler.cycle(hart.retired());
+        }
+        semihosting.serve(&mut hart, memory.inner_mut());
+        cycles += 1;
+    }
+
+    if let Some(path) = options.save_path {
+        let bytes = snapshot::save(&[&hart, &memory]);
+        std::fs::write(path, bytes).map_err(|error| format!("{path}: {error}"))?;
+    }
+
+    if let (Some(path), Some(profiler)) = (options.profile_path, profiler) {
+        let mut file = File::create(path).map_err(|error| format!("{path}: {error}"))?;
+        profiler.write_folded(&mut file, Metric::Cycles).map_err(|error| format!("{path}: {error}"))?;
+        eprint!("{}", profiler.summary());
+    }
+    print_statistics(hart.state());
+
+    if let (Some(path), Some(tracer)) = (options.trace_path, hart.pipeline_mut().take_tracer()) {
+        tracer.finish().map_err(|error| format!("{path}: {error}"))?;
+    }
+
+    if let Some(commit_log) = hart.take_commit_log() {
+        commit_log.finish().map_err(|error| format!("failed to write the commit log: {error}"))?;
+    }
+    Ok(exited(&semihosting, &memory))
+}
+
+/// Prints a raw binary loaded at `--base`, or a section of an ELF file at its own address, `.text` by default.
+fn disassemble(arguments: &[String]) -> Result<(), String> {
+    let mut path = None;
+    let mut section = ".text".to_string();
+    let mut base = 0;
+
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--section" => section = arguments.next().ok_or(USAGE)?.clone(),
+            "--base" => base = parse_number(arguments.next().ok_or(USAGE)?)?,
+            _ if path.is_none() => path = Some(argument),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    let path = path.ok_or(USAGE)?;
+    let bytes = std::fs::read(path).map_err(|error| format!("{path}: {error}"))?;
+
+    let listing = match Elf::is_elf(&bytes) {
+        true => {
+            let elf = Elf::parse(&bytes).map_err(|error| format!("{path}: {error}"))?;
+            let found = elf.section(&section).ok_or_else(|| format!("{path}: no section named {section}"))?;
+            let data = elf.section_data(found).map_err(|error| format!("{path}: {error}"))?;
+            disassemble_bytes(found.address, data)
+        }
+        false => disassemble_bytes(base, &bytes),
+    };
+
+    print!("{listing}");
+    Ok(())
+}
+
+/// Runs riscv-tests or riscv-arch-test ELFs, given directly or as directories holding them, and reports each. With
+/// `--signatures` the signature of every test that has one is written there as `<test>.signature`. The hart only
+/// implements RV32I, so the suites of other extensions such as rv32um and rv32uc are not supported and fail.
+fn test(arguments: &[String]) -> Result<(), String> {
+    let mut paths = Vec::new();
+    let mut cycle_limit = compliance::DEFAULT_CYCLE_LIMIT;
+    let mut signatures = None;
+
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--cycles" => cycle_limit = parse_number(arguments.next().ok_or(USAGE)?)? as u64,
+            "--signatures" => signatures = Some(Path::new(arguments.next().ok_or(USAGE)?)),
+            _ => paths.push(PathBuf::from(argument)),
+        }
+    }
+    if paths.is_empty() {
+        return Err(USAGE.to_string());
+    }
+
+    // Directories also hold the .dump listings next to the tests, only ELF files are run.
+    let mut tests = Vec::new();
+    for path in paths {
+        match path.is_dir() {
+            true => {
+                let entries = std::fs::read_dir(&path).map_err(|error| format!("{}: {error}", path.display()))?;
+                let mut entries: Vec<PathBuf> =
+                    entries.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect();
+                entries.sort();
+                tests.extend(entries.into_iter().filter(|path| is_elf_file(path)));
+            }
+            false => tests.push(path),
+        }
+    }
+
+    let mut failed = 0;
+    for path in &tests {
+        let name = path.file_name().map_or(path.display().to_string(), |name| name.to_string_lossy().into_owned());
+        let report = std::fs::read(path)
+            .map_err(|error| error.to_string())
+            .and_then(|bytes| TestProgram::from_elf(&Elf::parse(&bytes).map_err(|error| error.to_string())?))
+            .map(|program| compliance::run(&program, cycle_limit));
+        let report = match report {
+            Ok(report) => report,
+            Err(error) => {
+                println!("{name:<32} error: {error}");
+                failed += 1;
+                continue;
+            }
+        };
+
+        let result = match report.outcome {
+            Outcome::Pass => "pass".to_string(),
+            Outcome::Fail { test_number } => format!("FAIL at test {test_number}"),
+            Outcome::Timeout => "FAIL timed out".to_string(),
+        };
+        println!("{name:<32} {result} after {} cycles", report.cycles);
+        if report.outcome != Outcome::Pass {
+            failed += 1;
+        }
+
+        if let (Some(directory), false) = (signatures, report.signature.is_empty()) {
+            let file = directory.join(format!("{name}.signature"));
+            std::fs::write(&file, format_signature(&report.signature))
+                .map_err(|error| format!("{}: {error}", file.display()))?;
+        }
+    }
+
+    println!("{} passed, {failed} failed", tests.len() - failed);
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("{failed} of {} tests failed", tests.len())),
+    }
+}
+
+/// Runs a statically linked rv32 Linux program with the arguments after it and exits with its exit code. Options go
+/// before the program, everything after it is passed on.
+fn linux(arguments: &[String]) -> Result<(), String> {
+    let mut configuration = LinuxConfiguration::default();
+    let mut cycle_limit = None;
+
+    let mut arguments = arguments.iter();
+    let path = loop {
+        let argument = arguments.next().ok_or(USAGE)?;
+        match argument.as_str() {
+            "--root" => configuration.root = PathBuf::from(arguments.next().ok_or(USAGE)?),
+            "--env" => configuration.environment.push(arguments.next().ok_or(USAGE)?.clone()),
+            "--memory" => configuration.memory_size = (parse_number(arguments.next().ok_or(USAGE)?)? as usize) << 20,
+            "--cycles" => cycle_limit = Some(parse_number(arguments.next().ok_or(USAGE)?)? as u64),
+            _ => break argument,
+        }
+    };
+    configuration.arguments = std::iter::once(path).chain(arguments).cloned().collect();
+
+    let bytes = std::fs::read(path).map_err(|error| format!("{path}: {error}"))?;
+    let elf = Elf::parse(&bytes).map_err(|error| format!("{path}: {error}"))?;
+    let mut process = LinuxProcess::from_elf(&elf, configuration).map_err(|error| format!("{path}: {error}"))?;
+    let symbols = Symbols::from_elf(&elf);
+
+    match process.run(cycle_limit) {
+        Termination::Exited(code) => process::exit(code as i32),
+        Termination::Faulted { pc, exception: Some(exception) } => {
+            Err(format!("{path}: {exception:?} at {}", symbols.describe(pc)))
+        }
+        Termination::Faulted { pc, exception: None } => {
+            Err(format!("{path}: unknown trap at {}", symbols.describe(pc)))
+        }
+        Termination::Timeout => Err(format!("{path}: still running after the cycle limit")),
+    }
+}
+
+fn is_elf_file(path: &Path) -> bool {
+    let mut magic = [0; 4];
+    File::open(path).and_then(|mut file| file.read_exact(&mut magic)).is_ok() && Elf::is_elf(&magic)
+}
+
+/// Opens the debugger on an ELF file, assembly source ending in `.s`, or raw binary loaded at `--base`. Without a
+/// file it debugs the demo program.
+fn debug(arguments: &[String]) -> Result<(), String> {
+    let mut path = None;
+    let mut base = 0;
+    let mut history_budget = None;
+    let mut trace_path = None;
+
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--base" => base = parse_number(arguments.next().ok_or(USAGE)?)?,
+            "--record" => history_budget = Some(parse_number(arguments.next().ok_or(USAGE)?)?),
+            "--trace" => trace_path = Some(arguments.next().ok_or(USAGE)?),
+            _ if path.is_none() => path = Some(argument),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    let mut debugger = debugger(path, base, history_budget)?;
+    if let Some(path) = trace_path {
+        let tracer = tracer(path, Rc::new(debugger.symbols().clone()))?;
+        debugger.hart_mut().pipeline_mut().set_tracer(tracer);
+    }
+    debugger.repl(io::stdin().lock(), io::stdout()).map_err(|error| format!("debug: {error}"))?;
+
+    match (trace_path, debugger.hart_mut().pipeline_mut().take_tracer()) {
+        (Some(path), Some(tracer)) => tracer.finish().map_err(|error| format!("{path}: {error}")),
+        _ => Ok(()),
+    }
+}
+
+/// Serves one GDB session on the program `debug` would load, over localhost TCP or a Unix socket.
+fn gdb(arguments: &[String]) -> Result<(), String> {
+    let mut path = None;
+    let mut base = 0;
+    let mut port = 1234;
+    let mut socket = None;
+    let mut history_budget = None;
+
+    let mut arguments = arguments.iter();
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--base" => base = parse_number(arguments.next().ok_or(USAGE)?)?,
+            "--record" => history_budget = Some(parse_number(arguments.next().ok_or(USAGE)?)?),
+            "--port" => port = parse_number(arguments.next().ok_or(USAGE)?)?,
+            "--socket" => socket = Some(arguments.next().ok_or(USAGE)?),
+            _ if path.is_none() => path = Some(argument),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+
+    let debugger = debugger(path, base, history_budget)?;
+    let served = match socket {
+        Some(socket) => {
+            let listener = UnixListener::bind(socket).map_err(|error| format!("{socket}: {error}"))?;
+            eprintln!("waiting for gdb on {socket}");
+            let accepted = listener.accept().map(|(connection, _)| connection);
+            std::fs::remove_file(socket).map_err(|error| format!("{socket}: {error}"))?;
+            accepted.and_then(|connection| GdbStub::new(connection, debugger).serve())
+        }
+        None => {
+            let address = format!("127.0.0.1:{port}");
+            let listener = TcpListener::bind(&address).map_err(|error| format!("{address}: {error}"))?;
+            eprintln!("waiting for gdb on {address}");
+            listener.accept().and_then(|(connection, _)| GdbStub::new(connection, debugger).serve())
+        }
+    };
+    served.map(|_| ()).map_err(|error| format!("gdb: {error}"))
+}
+
+/// A debugger stopped at the entry of the program in `path`, or of the demo program without one. It records history
+/// for reverse execution when given a budget in MiB.
+fn debugger(path: Option<&String>, base: u32, history_budget: Option<u32>) -> Result<Debugger<Memory>, String> {
+    let (memory, entry, symbols) = match path {
+        Some(path) => {
+            let (memory, entry, symbols, _) = load_program(path, base).map_err(|error| format!("{path}: {error}"))?;
+            (memory, entry, symbols)
+        }
+        None => demo()?,
+    };
+
+    let mut hart = Hart::<Memory, SimplePipeline>::new();
+    hart.set_program_counter(entry);
+    let mut debugger = Debugger::new(hart, memory);
+    debugger.set_symbols(symbols);
+    if let Some(budget) = history_budget {
+        debugger.record_history((budget as usize) << 20);
+    }
+    Ok(debugger)
+}
+
+/// A pipeline tracer writing to `path`, labelling instructions with their symbols.
+fn tracer(path: &str, symbols: Rc<Symbols>) -> Result<KanataTracer, String> {
+    let file = File::create(path).map_err(|error| format!("{path}: {error}"))?;
+    let mut tracer = KanataTracer::new(BufWriter::new(file));
+    tracer.set_symbols(symbols);
+    Ok(tracer)
+}
+
+/// Returns memory holding the program, its entry point and its symbols. The entry is the one the file gives, `_start`
+/// or the lowest address loaded. ELF, Intel HEX (`.hex`, `.ihex`) and S-record (`.srec`, `.s19`, `.s28`, `.s37`,
+/// `.mot`) files say where they go and memory starts at the page of their lowest address, assembly (`.s`) and raw
+/// binaries go to `base`. Also returns the addresses of `tohost` and `fromhost` for ELF files that have them.
+fn load_program(path: &str, base: u32) -> Result<(Memory, u32, Symbols, Option<HostAddresses>), String> {
+    let bytes = std::fs::read(path).map_err(|error| error.to_string())?;
+    let text = || String::from_utf8_lossy(&bytes).into_owned();
+    let extension = Path::new(path).extension().and_then(|extension| extension.to_str()).unwrap_or("");
+
+    let mut host = None;
+    let (image, placed, symbols) = match extension {
+        _ if Elf::is_elf(&bytes) => {
+            let elf = Elf::parse(&bytes).map_err(|error| error.to_string())?;
+            host = host_addresses(&elf);
+            (Image::from_elf(&elf).map_err(|error| error.to_string())?, true, Symbols::from_elf(&elf))
+        }
+        "hex" | "ihex" => (Image::intel_hex(&text()).map_err(|error| error.to_string())?, true, Symbols::default()),
+        "srec" | "s19" | "s28" | "s37" | "mot" => {
+            (Image::s_record(&text()).map_err(|error| error.to_string())?, true, Symbols::default())
+        }
+        "s" => {
+            let program = assemble(&text(), base).map_err(|error| error.to_string())?;
+            let entry = program.symbol("_start");
+            let symbols = Symbols::from_program(&program);
+            (Image { chunks: vec![Chunk { address: program.origin, data: program.bytes }], entry }, false, symbols)
+        }
+        _ => (Image::binary(base, &bytes).map_err(|error| error.to_string())?, false, Symbols::default()),
+    };
+
+    let start = image.start().ok_or("nothing to load")?;
+    let base = if placed { start & !0xfff } else { base };
+    let mut memory = Memory::at(base, vec![0; PROGRAM_MEMORY_SIZE]);
+    image
+        .load(&mut memory)
+        .map_err(|error| format!("{error}, memory is {PROGRAM_MEMORY_SIZE:#x} bytes from {base:#x}"))?;
+    Ok((memory, image.entry.unwrap_or(start), symbols, host))
+}
+
+/// The demo program in 1 KiB of memory, with its entry and labels.
+fn demo() -> Result<(Memory, u32, Symbols), String> {
+    let program = assemble(DEMO, 0).map_err(|error| format!("demo program: {error}"))?;
+    Ok((Memory::with_initial_values(program.memory_image(1024)), 0, Symbols::from_program(&program)))
+}
+
+/// Reads `<size>[,<line size>[,<ways>]]` and the policies after the sizes, the write ones only for caches that take
+/// `writes`, with the rest of the configuration the default one.
+fn parse_cache(text: &str, writes: bool) -> Result<Cache, String> {
+    let defaults = CacheConfiguration::default();
+    let fields = text.split(',').collect::<Vec<_>>();
+    let (sizes, policies) =
+        fields.split_at(fields.iter().take_while(|field| !field.starts_with(char::is_alphabetic)).count());
+    let values = sizes.iter().copied().map(parse_number).collect::<Result<Vec<_>, _>>()?;
+    let (size, line_size, associativity) = match values[..] {
+        [size] => (size, defaults.line_size, defaults.associativity),
+        [size, line_size] => (size, line_size, defaults.associativity),
+        [size, line_size, associativity] => (size, line_size, associativity),
+        _ => return Err(format!("invalid cache: {text}")),
+    };
+    let mut configuration = CacheConfiguration { size, line_size, associativity, ..defaults };
+    for &policy in policies {
+        match policy {
+            "lru" => configuration.replacement = Replacement::Lru,
+            "fifo" => configuration.replacement = Replacement::Fifo,
+            "random" => configuration.replacement = Replacement::Random,
+            "write-back" if writes => configuration.write_policy = WritePolicy::WriteBack,
+            "write-through" if writes => configuration.write_policy = WritePolicy::WriteThrough,
+            "allocate" if writes => configuration.write_allocate = true,
+            "no-allocate" if writes => configuration.write_allocate = false,
+            _ => return Err(format!("invalid cache: {text}, unknown policy {policy}")),
+        }
+    }
+    let realizable = line_size.is_power_of_two()
+        && line_size >= 4
+        && associativity.is_power_of_two()
+        && size.is_power_of_two()
+        && size >= line_size.saturating_mul(associativity);
+    if !realizable {
+        return Err(format!("invalid cache: {text}, sizes must be powers of two and hold at least one set"));
+    }
+    Ok(Cache::new(configuration))
+}
+
+/// Reads `<start>,<size>`.
+fn parse_range(text: &str) -> Result<(u32, u32), String> {
+    match text.split_once(',') {
+        Some((start, size)) => Ok((parse_number(start)?, parse_number(size)?)),
+        None => Err(format!("invalid range: {text}")),
+    }
+}
+
+/// Reads `<start>,<size>,<wait states>`.
+fn parse_wait_region(text: &str) -> Result<(u32, u32, u32), String> {
+    match text.split(',').map(parse_number).collect::<Result<Vec<_>, _>>()?[..] {
+        [start, size, wait_states] => Ok((start, size, wait_states)),
+        _ => Err(format!("invalid wait state region: {text}")),
+    }
+}
+
+/// Reads `<kind>[,<entries>[,<history bits>]]`, sizes only going to the predictors with tables.
+fn parse_predictor(text: &str) -> Result<BranchPredictor, String> {
+    let mut parts = text.split(',');
+    let kind = parts.next().unwrap_or_default();
+    let sizes = parts.map(parse_number).collect::<Result<Vec<_>, _>>()?;
+    let (entries, history_bits) = match sizes[..] {
+        [] => (DEFAULT_PREDICTOR_ENTRIES, DEFAULT_HISTORY_BITS),
+        [entries] => (entries, DEFAULT_HISTORY_BITS),
+        [entries, history_bits] => (entries, history_bits),
+        _ => return Err(format!("invalid branch predictor: {text}")),
+    };
+    if !entries.is_power_of_two() || history_bits > 32 {
+        return Err(format!("invalid branch predictor: {text}, entries must be a power of two"));
+    }
+
+    let entries = entries as usize;
+    let direction: Box<dyn DirectionPredictor> = match kind {
+        "not-taken" => Box::new(AlwaysNotTaken),
+        "taken" => Box::new(AlwaysTaken),
+        "btfn" => Box::new(BackwardTakenForwardNotTaken),
+        "bimodal" => Box::new(Bimodal::new(entries)),
+        "gshare" => Box::new(Gshare::new(entries, history_bits)),
+        "tournament" => Box::new(Tournament::new(entries, history_bits)),
+        _ => return Err(format!("unknown branch predictor: {kind}")),
+    };
+    Ok(BranchPredictor::new(direction))
+}
+
+/// Has the first hardware performance counters that count nothing count the misses of the caches the hart has, for
+/// `print_statistics` to report along with the statistics of the caches.
+fn count_cache_misses(state: &mut HartState) {
+    let events = [
+        (state.instruction_cache.is_some(), HpmEvent::InstructionCacheMiss),
+        (state.data_cache.is_some(), HpmEvent::DataCacheMiss),
+    ];
+    let free: Vec<u32> = (0..HPM_COUNTERS).filter(|&index| state.csrs.read(MHPMEVENT3 + index) == Some(0)).collect();
+    let events = events.into_iter().filter(|&(present, _)| present);
+    for (index, (_, event)) in free.into_iter().zip(events) {
+        state.csrs.write(MHPMEVENT3 + index, event as u32);
+    }
+}
+
+/// Prints what the caches and the branch predictor of a run did on stderr.
+fn print_statistics(state: &HartState) {
+    for (name, cache, event) in [
+        ("instruction cache", &state.instruction_cache, HpmEvent::InstructionCacheMiss),
+        ("data cache", &state.data_cache, HpmEvent::DataCacheMiss),
+    ] {
+        if let Some(cache) = cache {
+            let statistics = cache.statistics();
+            let counters: String = (0..HPM_COUNTERS)
+                .filter(|&index| state.csrs.read(MHPMEVENT3 + index) == Some(event as u32))
+                .map(|index| {
+                    let low = state.csrs.read(MHPMCOUNTER3 + index).unwrap_or(0) as u64;
+                    let high = state.csrs.read(MHPMCOUNTER3H + index).unwrap_or(0) as u64;
+                    format!(", {} misses in hpmcounter{}", (high << 32) | low, index + 3)
+                })
+                .collect();
+            eprintln!(
+                "{name}: {} hits, {} misses ({:.2}% hits), {} evictions, {} writebacks{counters}",
+                statistics.hits,
+                statistics.misses,
+                statistics.hit_rate() * 100.0,
+                statistics.evictions,
+                statistics.writebacks
+            );
+        }
+    }
+    if let Some(predictor) = &state.branch_predictor {
+        let BranchStatistics { branches, mispredictions } = predictor.statistics();
+        let correct = branches.saturating_sub(mispredictions) as f64 * 100.0 / branches.max(1) as f64;
+        eprintln!("branch predictor: {branches} branches, {mispredictions} mispredictions ({correct:.2}% correct)");
     }
 }
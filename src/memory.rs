@@ -1,27 +1,47 @@
-use crate::core::bus::{BusInterface, Value, BusReadResponse, BusWriteResponse};
+use crate::core::bus::{BusInterface, Clocked, Value, BusReadResponse, BusWriteResponse};
+use crate::core::snapshot::{Snapshot, SnapshotError, SnapshotReader, SnapshotWriter};
 use num::PrimInt;
 
 pub struct Memory {
     bytes: Box<[u8]>,
+    read_only: bool,
+    /// The address of the first byte.
+    base: usize,
 }
 
 impl Memory {
     pub fn new(size: usize) -> Self {
-        Memory { bytes: vec![0; size].into_boxed_slice() }
+        Memory { bytes: vec![0; size].into_boxed_slice(), read_only: false, base: 0 }
     }
 
     pub fn with_initial_values(initial_values: Vec<u8>) -> Self {
-        Memory { bytes: initial_values.into_boxed_slice() }
+        Memory { bytes: initial_values.into_boxed_slice(), read_only: false, base: 0 }
+    }
+
+    /// Memory starting at `base` rather than 0, e.g. RAM at 0x8000_0000 as most RISC-V platforms have it.
+    pub fn at(base: u32, initial_values: Vec<u8>) -> Self {
+        Memory { bytes: initial_values.into_boxed_slice(), read_only: false, base: base as usize }
+    }
+
+    /// Memory that rejects every write, e.g. to model a boot ROM.
+    pub fn read_only(initial_values: Vec<u8>) -> Self {
+        Memory { bytes: initial_values.into_boxed_slice(), read_only: true, base: 0 }
+    }
+
+    /// The range of `bytes` an access covers, `None` outside of them.
+    fn range<A: PrimInt, V: PrimInt>(&self, address: A) -> Option<(usize, usize)> {
+        let (start, end) = range_info::<A, V>(address);
+        let start = start.checked_sub(self.base)?;
+        let end = end - self.base;
+        (end <= self.bytes.len()).then_some((start, end))
     }
 }
 
 impl<A: PrimInt, V: PrimInt + Value> BusInterface<A, V> for Memory {
     fn read(&self, address: A) -> BusReadResponse<A> {
-        let (start, end) = range_info::<A, V>(address);
-
-        if (end - 1) >= self.bytes.len() {
+        let Some((start, end)) = self.range::<A, V>(address) else {
             return BusReadResponse::ReadOutOfBounds
-        }
+        };
 
 
         let bytes = &self.bytes[start..end];
@@ -30,10 +50,12 @@ impl<A: PrimInt, V: PrimInt + Value> BusInterface<A, V> for Memory {
     }
 
     fn write(&mut self, address: A, value: V) -> BusWriteResponse {
-        let (start, end) = range_info::<A, V>(address);
-
-        if (end - 1) >= self.bytes.len() {
+        let Some((start, end)) = self.range::<A, V>(address) else {
             return BusWriteResponse::WriteOutOfBounds
+        };
+
+        if self.read_only {
+            return BusWriteResponse::InvalidAddress
         }
 
         let bytes = value.to_bytes();
@@ -42,6 +64,32 @@ impl<A: PrimInt, V: PrimInt + Value> BusInterface<A, V> for Memory {
     }
 }
 
+impl Clocked for Memory {
+    fn tick(&mut self) {}
+}
+
+impl Snapshot for Memory {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("memory");
+        snapshot.usize(self.base);
+        snapshot.bool(self.read_only);
+        snapshot.bytes(&self.bytes);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        snapshot.section("memory")?;
+        snapshot.expect("the memory base address", self.base, SnapshotReader::usize)?;
+        snapshot.expect("whether memory is read only", self.read_only, SnapshotReader::bool)?;
+        let bytes = snapshot.bytes()?;
+        if bytes.len() != self.bytes.len() {
+            let message = format!("memory is {} bytes, the snapshot has {}", self.bytes.len(), bytes.len());
+            return Err(SnapshotError::Mismatch(message));
+        }
+        self.bytes.copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
 fn range_info<A: PrimInt, V: PrimInt>(address: A) -> (usize, usize) {
     let size: usize = (V::zero().count_zeros() / 8) as usize;
     let address_start = address.to_usize().unwrap();
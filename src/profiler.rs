@@ -0,0 +1,292 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::core::commit_log::Retirement;
+use crate::symbols::Symbols;
+
+const OPCODE_JAL: u32 = 0b110_1111;
+const OPCODE_JALR: u32 = 0b110_0111;
+/// Deeper stacks lose their outermost frames, so runaway recursion or calls that never return cannot grow them
+/// without bound.
+const MAX_DEPTH: usize = 1024;
+
+/// What an address, a function or a stack was charged with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub instructions: u64,
+    /// The cycles from the previous retirement up to and including the one of the instruction, so stalls are charged
+    /// to the instruction that waited.
+    pub cycles: u64,
+}
+
+impl Counts {
+    fn add(&mut self, other: Counts) {
+        self.instructions += other.instructions;
+        self.cycles += other.cycles;
+    }
+}
+
+/// Which of the counts folded stacks are weighted by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Metric {
+    Instructions,
+    Cycles,
+}
+
+/// A row of the per function summary.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionProfile {
+    pub name: String,
+    /// Charged to the function's own instructions.
+    pub own: Counts,
+    /// Charged while the function was on the stack, including the functions it called.
+    pub total: Counts,
+    pub calls: u64,
+}
+
+/// Attributes retired instructions and cycles to the addresses they were spent at and the call stacks they were
+/// spent under.
+///
+/// Call stacks are rebuilt from the jumps that retire, following the link register conventions of the RISC-V
+/// calling convention: a JAL or JALR that writes `ra` or `t0` is a call, a JALR through either of them that does not
+/// is a return. Frames are functions from the symbols, or the address a call went to when there are none.
+///
+/// Exact profiles charge every retirement. Sampling profiles charge one sample every `interval` cycles instead, to
+/// the instruction that retires when the interval is up, and only count cycles.
+pub struct Profiler {
+    symbols: Rc<Symbols>,
+    sample_interval: Option<u64>,
+    /// The functions called and not returned from, outermost first and ending with the one running.
+    frames: Vec<u32>,
+    /// The last instruction was a call, the next one is the first of the function it called.
+    entering: bool,
+    cycles_since_retirement: u64,
+    cycles_since_sample: u64,
+    pcs: HashMap<u32, Counts>,
+    /// By `frames` at the time.
+    stacks: HashMap<Vec<u32>, Counts>,
+    calls: HashMap<u32, u64>,
+}
+
+impl Profiler {
+    pub fn exact(symbols: Rc<Symbols>) -> Self {
+        Profiler::new(symbols, None)
+    }
+
+    pub fn sampling(symbols: Rc<Symbols>, interval: u64) -> Self {
+        Profiler::new(symbols, Some(interval.max(1)))
+    }
+
+    fn new(symbols: Rc<Symbols>, sample_interval: Option<u64>) -> Self {
+        Profiler {
+            symbols,
+            sample_interval,
+            frames: Vec::new(),
+            entering: false,
+            cycles_since_retirement: 0,
+            cycles_since_sample: 0,
+            pcs: HashMap::new(),
+            stacks: HashMap::new(),
+            calls: HashMap::new(),
+        }
+    }
+
+    /// Accounts for one cycle and the instructions it retired, from `Hart::retired` with retirements recorded.
+    pub fn cycle(&mut self, retired: &[Retirement]) {
+        self.cycles_since_retirement += 1;
+        self.cycles_since_sample += 1;
+        for retirement in retired {
+            self.retire(retirement);
+        }
+    }
+
+    /// What each address was charged with.
+    pub fn pcs(&self) -> &HashMap<u32, Counts> {
+        &self.pcs
+    }
+
+    /// Every function charged with anything, the most expensive first.
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let mut functions: HashMap<u32, FunctionProfile> = HashMap::new();
+        for (stack, &counts) in &self.stacks {
+            self.profile(&mut functions, *stack.last().unwrap()).own.add(counts);
+            // Recursive functions are charged once per stack.
+            let mut seen = HashSet::new();
+            for &function in stack.iter().filter(|&&function| seen.insert(function)) {
+                self.profile(&mut functions, function).total.add(counts);
+            }
+        }
+
+        let mut functions: Vec<FunctionProfile> = functions.into_values().collect();
+        functions.sort_by(|a, b| {
+            (b.own.cycles, b.own.instructions, &a.name).cmp(&(a.own.cycles, a.own.instructions, &b.name))
+        });
+        functions
+    }
+
+    /// One line per stack, its frames from the outermost on separated by semicolons and followed by its weight, as
+    /// flamegraph.pl, inferno and speedscope read them.
+    pub fn write_folded(&self, output: &mut impl Write, metric: Metric) -> io::Result<()> {
+        let mut lines: HashMap<String, u64> = HashMap::new();
+        for (stack, counts) in &self.stacks {
+            let weight = match metric {
+                Metric::Instructions => counts.instructions,
+                Metric::Cycles => counts.cycles,
+            };
+            if weight > 0 {
+                let frames: Vec<String> = stack.iter().map(|&function| self.name(function)).collect();
+                *lines.entry(frames.join(";")).or_default() += weight;
+            }
+        }
+
+        let mut lines: Vec<(String, u64)> = lines.into_iter().collect();
+        lines.sort();
+        for (stack, weight) in lines {
+            writeln!(output, "{stack} {weight}")?;
+        }
+        Ok(())
+    }
+
+    /// The per function table: own and total cycles with their share of all cycles, instructions and calls.
+    pub fn summary(&self) -> String {
+        let functions = self.functions();
+        let cycles: u64 = functions.iter().map(|function| function.own.cycles).sum();
+        let share = |count: u64| count as f64 * 100.0 / cycles.max(1) as f64;
+
+        let mut text = format!(
+            "{:>12} {:>6}  {:>12} {:>6}  {:>12}  {:>8}  function\n",
+            "own cycles", "%", "total cycles", "%", "instructions", "calls"
+        );
+        for FunctionProfile { name, own, total, calls } in functions {
+            text += &format!(
+                "{:>12} {:>6.2}  {:>12} {:>6.2}  {:>12}  {calls:>8}  {name}\n",
+                own.cycles,
+                share(own.cycles),
+                total.cycles,
+                share(total.cycles),
+                own.instructions
+            );
+        }
+        text
+    }
+
+    fn retire(&mut self, retirement: &Retirement) {
+        let known = self.symbols.function(retirement.pc).map(|(_, offset)| retirement.pc - offset);
+        if self.entering || self.frames.is_empty() {
+            if self.frames.len() == MAX_DEPTH {
+                self.frames.remove(0);
+            }
+            let function = known.unwrap_or(retirement.pc);
+            if self.entering {
+                *self.calls.entry(function).or_default() += 1;
+            }
+            self.frames.push(function);
+            self.entering = false;
+        } else if let Some(function) = known {
+            // Tail calls and other jumps into another function replace the running one.
+            *self.frames.last_mut().unwrap() = function;
+        }
+
+        let counts = match self.sample_interval {
+            None => Counts { instructions: 1, cycles: self.cycles_since_retirement },
+            Some(interval) if self.cycles_since_sample >= interval => {
+                let samples = self.cycles_since_sample / interval;
+                self.cycles_since_sample %= interval;
+                Counts { instructions: 0, cycles: samples * interval }
+            }
+            Some(_) => Counts::default(),
+        };
+        self.cycles_since_retirement = 0;
+        if counts != Counts::default() {
+            self.pcs.entry(retirement.pc).or_default().add(counts);
+            match self.stacks.get_mut(self.frames.as_slice()) {
+                Some(stack) => stack.add(counts),
+                None => {
+                    self.stacks.insert(self.frames.clone(), counts);
+                }
+            }
+        }
+
+        let (call, ret) = jump(retirement.instruction);
+        if ret {
+            self.frames.pop();
+        }
+        self.entering = call;
+    }
+
+    fn profile<'a>(&self, functions: &'a mut HashMap<u32, FunctionProfile>, function: u32) -> &'a mut FunctionProfile {
+        functions.entry(function).or_insert_with(|| FunctionProfile {
+            name: self.name(function),
+            own: Counts::default(),
+            total: Counts::default(),
+            calls: self.calls.get(&function).copied().unwrap_or(0),
+        })
+    }
+
+    fn name(&self, function: u32) -> String {
+        match self.symbols.function(function) {
+            Some((name, 0)) => name.to_string(),
+            _ => format!("{function:08x}"),
+        }
+    }
+}
+
+/// Whether an instruction calls, returns or both, as the hints in the JAL and JALR descriptions of the unprivileged
+/// specification say: pushing when the destination is a link register, popping when the source of a JALR is one
+/// other than the destination.
+fn jump(instruction: u32) -> (bool, bool) {
+    let is_link = |register: u32| register == 1 || register == 5;
+    let destination = (instruction >> 7) & 0x1f;
+    let source = (instruction >> 15) & 0x1f;
+    match instruction & 0x7f {
+        OPCODE_JAL => (is_link(destination), false),
+        OPCODE_JALR => (is_link(destination), is_link(source) && source != destination),
+        _ => (false, false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::csr::PrivilegeLevel;
+
+    fn retirement(pc: u32, instruction: u32) -> Retirement {
+        Retirement {
+            privilege_level: PrivilegeLevel::Machine,
+            pc,
+            instruction,
+            register_write: None,
+            csr_write: None,
+            memory_access: None,
+            undo: None,
+        }
+    }
+
+    #[test]
+    fn charges_stacks_rebuilt_from_calls_and_returns() {
+        let program = crate::assembler::assemble("main:\nnop\nleaf:\nnop\n", 0).unwrap();
+        let mut profiler = Profiler::exact(Rc::new(Symbols::from_program(&program)));
+
+        // main calls leaf, which stalls for two cycles on its first instruction and returns.
+        let jal_ra = 0x0000_00ef;
+        let ret = 0x0000_8067;
+        let nop = 0x0000_0013;
+        profiler.cycle(&[retirement(0x0, jal_ra)]);
+        profiler.cycle(&[]);
+        profiler.cycle(&[]);
+        profiler.cycle(&[retirement(0x4, nop)]);
+        profiler.cycle(&[retirement(0x8, ret)]);
+        profiler.cycle(&[retirement(0x0, nop)]);
+
+        let mut folded = Vec::new();
+        profiler.write_folded(&mut folded, Metric::Cycles).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "main 2\nmain;leaf 4\n");
+        assert_eq!(profiler.pcs()[&0x4], Counts { instructions: 1, cycles: 3 });
+
+        let functions = profiler.functions();
+        assert_eq!(functions[0].name, "leaf");
+        assert_eq!((functions[0].own.cycles, functions[0].calls), (4, 1));
+        assert_eq!((functions[1].name.as_str(), functions[1].own.cycles, functions[1].total.cycles), ("main", 2, 6));
+    }
+}
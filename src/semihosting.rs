@@ -0,0 +1,447 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::core::bus::BusInterface;
+use crate::core::hart::{Hart, HartState};
+use crate::core::pipeline::Pipeline;
+
+const REGISTER_A0: usize = 10;
+const REGISTER_A1: usize = 11;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_READ: u32 = 0x06;
+const SYS_READC: u32 = 0x07;
+const SYS_ISERROR: u32 = 0x08;
+const SYS_ISTTY: u32 = 0x09;
+const SYS_SEEK: u32 = 0x0a;
+const SYS_FLEN: u32 = 0x0c;
+const SYS_REMOVE: u32 = 0x0e;
+const SYS_RENAME: u32 = 0x0f;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_TIME: u32 = 0x11;
+const SYS_ERRNO: u32 = 0x13;
+const SYS_GET_CMDLINE: u32 = 0x15;
+const SYS_HEAPINFO: u32 = 0x16;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+const SYS_ELAPSED: u32 = 0x30;
+const SYS_TICKFREQ: u32 = 0x31;
+
+/// The reason `SYS_EXIT` gives for a program that ran to completion, any other is a failure.
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+/// The console, opened with mode `r` for input, `w` for output and `a` for errors.
+const CONSOLE: &str = ":tt";
+/// `SYS_ELAPSED` counts microseconds.
+const TICKS_PER_SECOND: u32 = 1_000_000;
+/// Host reads on the guest's behalf go through a buffer of at most this many bytes, however much it asks for.
+const CHUNK_SIZE: u32 = 64 << 10;
+
+const EBADF: i32 = 9;
+const EFAULT: i32 = 14;
+const EINVAL: i32 = 22;
+
+enum Handle {
+    Input,
+    Output,
+    Error,
+    File(File),
+}
+
+/// Serves the semihosting calls of bare-metal programs, as defined for Arm and adopted by RISC-V: `a0` holds the
+/// operation, `a1` its argument or the address of a block of word arguments, and the result goes back to `a0`.
+///
+/// The hart has to have semihosting turned on so it leaves the calls to the host, which serves them after every
+/// cycle with `serve`. Files are the host's, relative to its working directory, `:tt` is the console.
+/// `SYS_SYSTEM` and `SYS_TMPNAM` are refused.
+pub struct Semihosting {
+    handles: HashMap<u32, Handle>,
+    next_handle: u32,
+    errno: i32,
+    command_line: String,
+    input: Box<dyn Read>,
+    output: Box<dyn Write>,
+    error: Box<dyn Write>,
+    started: Instant,
+    exit_code: Option<u32>,
+}
+
+impl Semihosting {
+    /// `command_line` is what `SYS_GET_CMDLINE` hands the program.
+    pub fn new(command_line: impl Into<String>) -> Self {
+        Semihosting {
+            handles: HashMap::new(),
+            next_handle: 1,
+            errno: 0,
+            command_line: command_line.into(),
+            input: Box::new(io::stdin()),
+            output: Box::new(io::stdout()),
+            error: Box::new(io::stderr()),
+            started: Instant::now(),
+            exit_code: None,
+        }
+    }
+
+    /// Replaces the host's standard input, output and error behind `:tt`.
+    pub fn set_standard_streams(
+        &mut self,
+        input: impl Read + 'static,
+        output: impl Write + 'static,
+        error: impl Write + 'static,
+    ) {
+        self.input = Box::new(input);
+        self.output = Box::new(output);
+        self.error = Box::new(error);
+    }
+
+    /// What the program exited with, once it has.
+    pub fn exit_code(&self) -> Option<u32> {
+        self.exit_code
+    }
+
+    /// Serves the call the hart made in its last cycle, if it made one. `memory` is the memory of the hart or the
+    /// device behind it, e.g. without the wait states the hart sees.
+    pub fn serve<M, D, P: Pipeline<M>>(&mut self, hart: &mut Hart<M, P>, memory: &mut D)
+    where
+        M: BusInterface<u32, i8>,
+        M: BusInterface<u32, u8>,
+        M: BusInterface<u32, i16>,
+        M: BusInterface<u32, u16>,
+        M: BusInterface<u32, u32>,
+        D: BusInterface<u32, u8>,
+    {
+        let state = hart.state_mut();
+        if !state.take_semihosting_call() {
+            return;
+        }
+
+        let operation = state.register_file.read(REGISTER_A0);
+        let argument = state.register_file.read(REGISTER_A1);
+        let result = self.call(operation, argument, state, memory).unwrap_or_else(|error| {
+            self.errno = error;
+            u32::MAX
+        });
+        state.register_file.write(REGISTER_A0, result);
+    }
+
+    /// Guest memory is accessed through `state` so data the data cache has not written back yet is seen.
+    fn call<M>(&mut self, operation: u32, argument: u32, state: &mut HartState, memory: &mut M) -> Result<u32, i32>
+    where
+        M: BusInterface<u32, u8>,
+    {
+        let parameter = |index: u32| read_word(state, memory, argument.wrapping_add(4 * index));
+        match operation {
+            SYS_OPEN => {
+                let (name, mode, length) = (parameter(0)?, parameter(1)?, parameter(2)?);
+                let name = String::from_utf8_lossy(&read_bytes(state, memory, name, length)?).into_owned();
+                let handle = match (name.as_str(), mode / 4) {
+                    (CONSOLE, 0) => Handle::Input,
+                    (CONSOLE, 1) => Handle::Output,
+                    (CONSOLE, _) => Handle::Error,
+                    // r, w and a, then the same with + for reading and writing. The b variants are the same.
+                    (_, kind) => Handle::File(
+                        OpenOptions::new()
+                            .read(kind == 0 || mode & 2 != 0)
+                            .write(kind != 0 || mode & 2 != 0)
+                            .truncate(kind == 1)
+                            .append(kind == 2)
+                            .create(kind != 0)
+                            .open(name)
+                            .map_err(errno)?,
+                    ),
+                };
+                let number = self.next_handle;
+                self.next_handle += 1;
+                self.handles.insert(number, handle);
+                Ok(number)
+            }
+            SYS_CLOSE => self.handles.remove(&parameter(0)?).map(|_| 0).ok_or(EBADF),
+            SYS_WRITEC => {
+                let character = read_bytes(state, memory, argument, 1)?;
+                self.output.write_all(&character).and_then(|_| self.output.flush()).map(|_| 0).map_err(errno)
+            }
+            SYS_WRITE0 => {
+                let mut text = Vec::new();
+                while let [byte] = read_bytes(state, memory, argument.wrapping_add(text.len() as u32), 1)?[..] {
+                    match byte {
+                        0 => break,
+                        byte => text.push(byte),
+                    }
+                }
+                self.output.write_all(&text).and_then(|_| self.output.flush()).map(|_| 0).map_err(errno)
+            }
+            // Both answer with the number of bytes left over, 0 when all of them were transferred.
+            SYS_WRITE => {
+                let (handle, address, length) = (parameter(0)?, parameter(1)?, parameter(2)?);
+                let data = read_bytes(state, memory, address, length)?;
+                let written = match self.handles.get_mut(&handle).ok_or(EBADF)? {
+                    Handle::Input => return Err(EBADF),
+                    Handle::Output => self.output.write_all(&data).and_then(|_| self.output.flush()),
+                    Handle::Error => self.error.write_all(&data).and_then(|_| self.error.flush()),
+                    Handle::File(file) => file.write_all(&data),
+                };
+                written.map(|_| 0).map_err(errno)
+            }
+            // Files are read until the buffer is full or they end, the console only once as it may not have more.
+            SYS_READ => {
+                let (handle, address, length) = (parameter(0)?, parameter(1)?, parameter(2)?);
+                check_range(state, memory, address, length)?;
+                let mut data = vec![0; length.min(CHUNK_SIZE) as usize];
+                let mut total = 0;
+                while total < length {
+                    let wanted = (length - total).min(CHUNK_SIZE) as usize;
+                    let read = match self.handles.get_mut(&handle).ok_or(EBADF)? {
+                        Handle::Input => self.input.read(&mut data[..wanted]),
+                        Handle::Output | Handle::Error => return Err(EBADF),
+                        Handle::File(file) => file.read(&mut data[..wanted]),
+                    };
+                    let read = match read {
+                        Ok(read) => read,
+                        Err(error) if total == 0 => return Err(errno(error)),
+                        Err(_) => break,
+                    };
+                    write_bytes(state, memory, address + total, &data[..read])?;
+                    total += read as u32;
+                    if read < wanted || matches!(self.handles[&handle], Handle::Input) {
+                        break;
+                    }
+                }
+                Ok(length - total)
+            }
+            SYS_READC => {
+                let mut character = [0];
+                self.input.read_exact(&mut character).map_err(errno)?;
+                Ok(character[0] as u32)
+            }
+            SYS_ISERROR => Ok(((parameter(0)? as i32) < 0) as u32),
+            SYS_ISTTY => match self.handles.get(&parameter(0)?).ok_or(EBADF)? {
+                Handle::File(_) => Ok(0),
+                _ => Ok(1),
+            },
+            SYS_SEEK => {
+                let (handle, position) = (parameter(0)?, parameter(1)?);
+                match self.handles.get_mut(&handle).ok_or(EBADF)? {
+                    Handle::File(file) => file.seek(SeekFrom::Start(position as u64)).map(|_| 0).map_err(errno),
+                    _ => Err(EINVAL),
+                }
+            }
+            SYS_FLEN => match self.handles.get(&parameter(0)?).ok_or(EBADF)? {
+                Handle::File(file) => file.metadata().map(|metadata| metadata.len() as u32).map_err(errno),
+                _ => Ok(0),
+            },
+            // These two answer with the host's error number rather than -1.
+            SYS_REMOVE | SYS_RENAME => {
+                let name = |index| -> Result<String, i32> {
+                    let bytes = read_bytes(state, memory, parameter(index)?, parameter(index + 1)?)?;
+                    Ok(String::from_utf8_lossy(&bytes).into_owned())
+                };
+                let result = match operation {
+                    SYS_REMOVE => fs::remove_file(name(0)?),
+                    _ => fs::rename(name(0)?, name(2)?),
+                };
+                Ok(result.map_or_else(|error| errno(error) as u32, |_| 0))
+            }
+            SYS_CLOCK => Ok((self.started.elapsed().as_millis() / 10) as u32),
+            SYS_TIME => Ok(SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() as u32),
+            SYS_ERRNO => Ok(self.errno as u32),
+            // The block holds the buffer and its size, which is updated to the length of the command line.
+            SYS_GET_CMDLINE => {
+                let (address, size) = (parameter(0)?, parameter(1)?);
+                let command_line = [self.command_line.as_bytes(), &[0]].concat();
+                if command_line.len() > size as usize {
+                    return Err(EINVAL);
+                }
+                write_bytes(state, memory, address, &command_line)?;
+                write_bytes(state, memory, argument.wrapping_add(4), &(command_line.len() as u32 - 1).to_le_bytes())?;
+                Ok(0)
+            }
+            // Zeros leave the heap and stack where the program's start-up code would put them.
+            SYS_HEAPINFO => {
+                let block = parameter(0)?;
+                write_bytes(state, memory, block, &[0; 16])?;
+                Ok(0)
+            }
+            // On 32 bit targets the argument is the reason itself rather than a block.
+            SYS_EXIT => {
+                self.exit_code = Some((argument != ADP_STOPPED_APPLICATION_EXIT) as u32);
+                Ok(0)
+            }
+            SYS_EXIT_EXTENDED => {
+                let (reason, code) = (parameter(0)?, parameter(1)?);
+                self.exit_code = Some(match reason {
+                    ADP_STOPPED_APPLICATION_EXIT => code,
+                    _ => 1,
+                });
+                Ok(0)
+            }
+            SYS_ELAPSED => {
+                let ticks = self.started.elapsed().as_micros() as u64;
+                write_bytes(state, memory, argument, &ticks.to_le_bytes())?;
+                Ok(0)
+            }
+            SYS_TICKFREQ => Ok(TICKS_PER_SECOND),
+            _ => Err(EINVAL),
+        }
+    }
+}
+
+fn read_word<M: BusInterface<u32, u8>>(state: &HartState, memory: &M, address: u32) -> Result<u32, i32> {
+    let bytes = read_bytes(state, memory, address, 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Fails with `EFAULT` unless all `length` bytes from `address` are there, before anything is read for them.
+fn check_range<M: BusInterface<u32, u8>>(state: &HartState, memory: &M, address: u32, length: u32) -> Result<(), i32> {
+    if length > 0 {
+        address.checked_add(length - 1).ok_or(EFAULT)?;
+    }
+    match (0..length).all(|offset| state.read_byte(memory, address + offset).is_some()) {
+        true => Ok(()),
+        false => Err(EFAULT),
+    }
+}
+
+fn read_bytes<M: BusInterface<u32, u8>>(
+    state: &HartState,
+    memory: &M,
+    address: u32,
+    length: u32,
+) -> Result<Vec<u8>, i32> {
+    (0..length).map(|offset| state.read_byte(memory, address.wrapping_add(offset)).ok_or(EFAULT)).collect()
+}
+
+fn write_bytes<M: BusInterface<u32, u8>>(
+    state: &mut HartState,
+    memory: &mut M,
+    address: u32,
+    bytes: &[u8],
+) -> Result<(), i32> {
+    for (offset, &byte) in bytes.iter().enumerate() {
+        if !state.write_byte(memory, address.wrapping_add(offset as u32), byte) {
+            return Err(EFAULT);
+        }
+    }
+    Ok(())
+}
+
+fn errno(error: io::Error) -> i32 {
+    error.raw_os_error().unwrap_or(EINVAL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::memory::Memory;
+    use crate::simple_pipeline::SimplePipeline;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Opens the console, writes to it through the handle and with `SYS_WRITE0`, then exits with the number of
+    /// bytes `SYS_WRITE` left over plus 40. An EBREAK outside the sequence still traps to `handler`.
+    const PROGRAM: &str = "
+        la      t0, handler
+        csrw    mtvec, t0
+        li      a0, 0x01
+        la      a1, open
+        call    semihost
+        la      t0, write
+        sw      a0, 0(t0)
+        li      a0, 0x05
+        la      a1, write
+        call    semihost
+        addi    s0, a0, 40
+        li      a0, 0x04
+        la      a1, world
+        call    semihost
+        ebreak
+        li      a0, 0x20
+        la      a1, exit
+        sw      s0, 4(a1)
+        call    semihost
+        j       .
+
+    semihost:
+        slli    zero, zero, 0x1f
+        ebreak
+        srai    zero, zero, 7
+        ret
+
+    handler:
+        csrr    t0, mepc
+        addi    t0, t0, 4
+        csrw    mepc, t0
+        addi    s0, s0, 1
+        mret
+
+        .data
+    open:
+        .word   console, 4, 3
+    write:
+        .word   0, hello, 6
+    exit:
+        .word   0x20026, 0
+    console:
+        .asciz  \":tt\"
+    hello:
+        .asciz  \"hello \"
+    world:
+        .asciz  \"world\\n\"
+    ";
+
+    #[test]
+    fn serves_calls_in_the_semihosting_sequence() {
+        let program = assemble(PROGRAM, 0).unwrap();
+        let mut memory = Memory::with_initial_values(program.memory_image(1024));
+        let mut hart = Hart::<Memory, SimplePipeline>::new();
+        hart.state_mut().semihosting = true;
+
+        let mut semihosting = Semihosting::new("program");
+        let output = Output::default();
+        semihosting.set_standard_streams(io::empty(), output.clone(), io::sink());
+        for _ in 0..1_000 {
+            hart.execute(&mut memory);
+            semihosting.serve(&mut hart, &mut memory);
+        }
+        assert_eq!(output.0.borrow().as_slice(), b"hello world\n");
+        assert_eq!(semihosting.exit_code(), Some(41));
+    }
+
+    #[test]
+    fn checks_the_buffer_before_reading_into_it() {
+        let mut memory = Memory::new(4096);
+        let mut hart = Hart::<Memory, SimplePipeline>::new();
+        let mut semihosting = Semihosting::new("program");
+        semihosting.set_standard_streams(io::Cursor::new(b"input"), io::sink(), io::sink());
+        write_bytes(hart.state_mut(), &mut memory, 0x200, b":tt").unwrap();
+        let mut call = |operation, block: [u32; 3]| {
+            let state = hart.state_mut();
+            write_bytes(state, &mut memory, 0x100, &block.map(u32::to_le_bytes).concat()).unwrap();
+            semihosting.call(operation, 0x100, state, &mut memory)
+        };
+
+        let console = call(SYS_OPEN, [0x200, 0, 3]).unwrap();
+        assert_eq!(call(SYS_READ, [console, 0x800, u32::MAX]), Err(EFAULT));
+        assert_eq!(call(SYS_READ, [console, 0x800, 64]), Ok(59));
+        assert_eq!(read_bytes(hart.state(), &memory, 0x800, 5), Ok(b"input".to_vec()));
+    }
+}
@@ -1,47 +1,169 @@
 use std::mem::size_of;
 
-use crate::core::bus::{BusInterface, BusReadResponse};
+use crate::core::branch_predictor::BranchKind;
+use crate::core::bus::{AccessError, BusInterface, BusReadResponse, BusWriteResponse};
+use crate::core::commit_log::{MemoryAccess, OverwrittenMemory, Retirement, Undo};
+use crate::core::csr::HpmEvent;
+use crate::core::exception::Exception;
+use crate::core::hart::HartState;
+use crate::core::mmu::AccessType;
+use crate::core::pmp;
 use crate::core::unit::{
-    branch, decode_instruction, execute, load, store, write_back, DecodeError, FetchResult,
-    RegisterWrite,
+    branch, decode_instruction, execute, execute_system, link, load, store, take_trap, write_back, DecodeError,
+    FetchResult, RegisterWrite, StoreProgress, SystemResult,
 };
 
 use crate::core::pipeline::Pipeline;
 use crate::core::register_file::RegisterFile;
+use crate::core::snapshot::{Snapshot, SnapshotError, SnapshotReader, SnapshotWriter};
 
-use crate::core::instruction::Instruction;
+use crate::core::instruction::{FenceInstruction, Instruction, INSTRUCTION_ALIGNMENT};
+use crate::kanata::KanataTracer;
 
 #[derive(Clone, Copy)]
 struct DecodedInput {
     fetch_result: FetchResult,
+    fetch_exception: Option<Exception>,
+    /// Where fetch continued after this instruction.
+    predicted_next_pc: u32,
+    trace_id: u64,
 }
 
 #[derive(Clone, Copy)]
 struct AluInput {
     fetch_result: FetchResult,
-    decoded_instruction: Instruction,
+    decoded_instruction: Result<Instruction, Exception>,
+    trace_id: u64,
 }
 
 #[derive(Clone, Copy)]
 struct MemoryAccessInput {
     fetch_result: FetchResult,
-    decoded_instruction: Instruction,
+    decoded_instruction: Result<Instruction, Exception>,
     operation: Option<RegisterWrite>,
+    store_progress: StoreProgress,
+    trace_id: u64,
 }
 
 #[derive(Clone, Copy)]
 struct WriteBackInput {
     fetch_result: FetchResult,
-    decoded_instruction: Instruction,
+    decoded_instruction: Result<Instruction, Exception>,
     operation: Option<RegisterWrite>,
+    memory_access: Option<MemoryAccess>,
+    overwritten: Option<OverwrittenMemory>,
+    trace_id: u64,
 }
 
+/// How the instruction in write back left the pipeline.
+struct Commit {
+    /// Set when the instruction retired rather than trapped or was held back.
+    retirement: Option<Retirement>,
+    /// Set when younger instructions are discarded and fetching restarts here.
+    restart_at: Option<u32>,
+}
+
+/// What a stage latch holds, as shown by debuggers.
 #[derive(Clone, Copy)]
+pub struct Latch {
+    pub pc: u32,
+    pub instruction: u32,
+    /// Set once the instruction is known to trap when it reaches write back.
+    pub exception: Option<Exception>,
+    /// The register result computed so far, written back when the instruction retires.
+    pub register_write: Option<RegisterWrite>,
+}
+
 pub struct SimplePipeline {
     decode_input: Option<DecodedInput>,
     execute_input: Option<AluInput>,
     memory_access_input: Option<MemoryAccessInput>,
     write_back_input: Option<WriteBackInput>,
+    tracer: Option<KanataTracer>,
+}
+
+impl SimplePipeline {
+    /// Records every instruction's way through the stages from the next cycle on.
+    pub fn set_tracer(&mut self, tracer: KanataTracer) {
+        self.tracer = Some(tracer);
+    }
+
+    pub fn take_tracer(&mut self) -> Option<KanataTracer> {
+        self.tracer.take()
+    }
+
+    /// The latches in front of decode, execute, memory and write back, named after the stage they feed.
+    pub fn latches(&self) -> [(&'static str, Option<Latch>); 4] {
+        let latch = |fetch_result: FetchResult, decoded_instruction: Result<Instruction, Exception>, operation| Latch {
+            pc: fetch_result.captured_pc,
+            instruction: fetch_result.instruction,
+            exception: decoded_instruction.err(),
+            register_write: operation,
+        };
+
+        [
+            (
+                "decode",
+                self.decode_input.map(|input| Latch {
+                    pc: input.fetch_result.captured_pc,
+                    instruction: input.fetch_result.instruction,
+                    exception: input.fetch_exception,
+                    register_write: None,
+                }),
+            ),
+            ("execute", self.execute_input.map(|input| latch(input.fetch_result, input.decoded_instruction, None))),
+            (
+                "memory",
+                self.memory_access_input
+                    .map(|input| latch(input.fetch_result, input.decoded_instruction, input.operation)),
+            ),
+            (
+                "write back",
+                self.write_back_input
+                    .map(|input| latch(input.fetch_result, input.decoded_instruction, input.operation)),
+            ),
+        ]
+    }
+
+    /// What the stores in flight overwrote before retiring, the youngest first. `None` when one of them wrote memory
+    /// without recording it.
+    pub fn overwritten_in_flight(&self) -> Option<Vec<OverwrittenMemory>> {
+        let memory = self.memory_access_input.map(|input| input.store_progress);
+        let write_back =
+            self.write_back_input.filter(|input| matches!(input.memory_access, Some(MemoryAccess::Store { .. })));
+        let mut overwritten = Vec::new();
+        if let Some(StoreProgress { stored_bytes, overwritten: recorded, .. }) = memory {
+            match recorded {
+                Some(recorded) => overwritten.push(recorded),
+                None if stored_bytes > 0 => return None,
+                None => {}
+            }
+        }
+        if let Some(input) = write_back {
+            overwritten.push(input.overwritten?);
+        }
+        Some(overwritten)
+    }
+
+    /// Discards every instruction in flight, as a trap does. Debuggers use it after changing state they may have read.
+    pub fn flush(&mut self) {
+        let trace_ids = [
+            self.decode_input.take().map(|input| input.trace_id),
+            self.execute_input.take().map(|input| input.trace_id),
+            self.memory_access_input.take().map(|input| input.trace_id),
+            self.write_back_input.take().map(|input| input.trace_id),
+        ];
+
+        for trace_id in trace_ids.into_iter().flatten() {
+            self.trace(|tracer| tracer.flush(trace_id));
+        }
+    }
+
+    fn trace(&mut self, record: impl FnOnce(&mut KanataTracer)) {
+        if let Some(tracer) = &mut self.tracer {
+            record(tracer);
+        }
+    }
 }
 
 impl<M> Pipeline<M> for SimplePipeline
@@ -53,80 +175,473 @@ where
     M: BusInterface<u32, u32>,
 {
     fn new() -> Self {
-        SimplePipeline { decode_input: None, execute_input: None, memory_access_input: None, write_back_input: None }
+        SimplePipeline {
+            decode_input: None,
+            execute_input: None,
+            memory_access_input: None,
+            write_back_input: None,
+            tracer: None,
+        }
     }
 
-    fn execute(&mut self, pc: u32, register_file: &mut RegisterFile, memory: &mut M) -> u32 {
-        if let Some(WriteBackInput { fetch_result: _, decoded_instruction: _, operation }) = self.write_back_input {
-            operation.map(|op| write_back(op, register_file));
+    fn execute(&mut self, pc: u32, state: &mut HartState, memory: &mut M) -> u32 {
+        let next_pc = self.cycle(pc, state, memory);
+        self.trace(|tracer| tracer.cycle());
+        next_pc
+    }
+}
+
+/// The tracer is an output and not part of a snapshot, restoring reports the instructions it was following as flushed.
+impl Snapshot for SimplePipeline {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("pipeline");
+        snapshot.option(&self.decode_input, |snapshot, input| {
+            save_fetch_result(snapshot, input.fetch_result);
+            snapshot.option(&input.fetch_exception, save_exception);
+            snapshot.u32(input.predicted_next_pc);
+            snapshot.u64(input.trace_id);
+        });
+        snapshot.option(&self.execute_input, |snapshot, input| {
+            save_decoded_instruction(snapshot, input.fetch_result, &input.decoded_instruction);
+            snapshot.u64(input.trace_id);
+        });
+        snapshot.option(&self.memory_access_input, |snapshot, input| {
+            save_decoded_instruction(snapshot, input.fetch_result, &input.decoded_instruction);
+            snapshot.option(&input.operation, save_register_write);
+            let StoreProgress { stored_bytes, record, overwritten } = input.store_progress;
+            snapshot.u32(stored_bytes);
+            snapshot.bool(record);
+            snapshot.option(&overwritten, save_overwritten);
+            snapshot.u64(input.trace_id);
+        });
+        snapshot.option(&self.write_back_input, |snapshot, input| {
+            save_decoded_instruction(snapshot, input.fetch_result, &input.decoded_instruction);
+            snapshot.option(&input.operation, save_register_write);
+            snapshot.option(&input.memory_access, |snapshot, access| match *access {
+                MemoryAccess::Load { address, size } => {
+                    snapshot.u8(0);
+                    snapshot.u32(address);
+                    snapshot.u32(size);
+                }
+                MemoryAccess::Store { address, value, size } => {
+                    snapshot.u8(1);
+                    snapshot.u32(address);
+                    snapshot.u32(value);
+                    snapshot.u32(size);
+                }
+            });
+            snapshot.option(&input.overwritten, save_overwritten);
+            snapshot.u64(input.trace_id);
+        });
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        snapshot.section("pipeline")?;
+        self.flush();
+        self.decode_input = snapshot.option(|snapshot| {
+            Ok(DecodedInput {
+                fetch_result: restore_fetch_result(snapshot)?,
+                fetch_exception: snapshot.option(restore_exception)?,
+                predicted_next_pc: snapshot.u32()?,
+                trace_id: snapshot.u64()?,
+            })
+        })?;
+        self.execute_input = snapshot.option(|snapshot| {
+            let (fetch_result, decoded_instruction) = restore_decoded_instruction(snapshot)?;
+            Ok(AluInput { fetch_result, decoded_instruction, trace_id: snapshot.u64()? })
+        })?;
+        self.memory_access_input = snapshot.option(|snapshot| {
+            let (fetch_result, decoded_instruction) = restore_decoded_instruction(snapshot)?;
+            Ok(MemoryAccessInput {
+                fetch_result,
+                decoded_instruction,
+                operation: snapshot.option(restore_register_write)?,
+                store_progress: StoreProgress {
+                    stored_bytes: snapshot.u32()?,
+                    record: snapshot.bool()?,
+                    overwritten: snapshot.option(restore_overwritten)?,
+                },
+                trace_id: snapshot.u64()?,
+            })
+        })?;
+        self.write_back_input = snapshot.option(|snapshot| {
+            let (fetch_result, decoded_instruction) = restore_decoded_instruction(snapshot)?;
+            Ok(WriteBackInput {
+                fetch_result,
+                decoded_instruction,
+                operation: snapshot.option(restore_register_write)?,
+                memory_access: snapshot.option(|snapshot| match snapshot.u8()? {
+                    0 => Ok(MemoryAccess::Load { address: snapshot.u32()?, size: snapshot.u32()? }),
+                    _ => Ok(MemoryAccess::Store {
+                        address: snapshot.u32()?,
+                        value: snapshot.u32()?,
+                        size: snapshot.u32()?,
+                    }),
+                })?,
+                overwritten: snapshot.option(restore_overwritten)?,
+                trace_id: snapshot.u64()?,
+            })
+        })?;
+        Ok(())
+    }
+}
+
+fn save_overwritten(snapshot: &mut SnapshotWriter, overwritten: &OverwrittenMemory) {
+    snapshot.usize(overwritten.bytes().len());
+    for &(address, byte) in overwritten.bytes() {
+        snapshot.u32(address);
+        snapshot.u8(byte);
+    }
+}
+
+fn restore_overwritten(snapshot: &mut SnapshotReader) -> Result<OverwrittenMemory, SnapshotError> {
+    let mut overwritten = OverwrittenMemory::default();
+    for _ in 0..snapshot.usize()?.min(size_of::<u32>()) {
+        overwritten.push(snapshot.u32()?, snapshot.u8()?);
+    }
+    Ok(overwritten)
+}
+
+fn save_fetch_result(snapshot: &mut SnapshotWriter, fetch_result: FetchResult) {
+    snapshot.u32(fetch_result.captured_pc);
+    snapshot.u32(fetch_result.instruction);
+}
+
+fn restore_fetch_result(snapshot: &mut SnapshotReader) -> Result<FetchResult, SnapshotError> {
+    Ok(FetchResult { captured_pc: snapshot.u32()?, instruction: snapshot.u32()? })
+}
+
+fn save_exception(snapshot: &mut SnapshotWriter, exception: &Exception) {
+    snapshot.u32(exception.cause());
+    snapshot.u32(exception.trap_value());
+}
+
+fn restore_exception(snapshot: &mut SnapshotReader) -> Result<Exception, SnapshotError> {
+    let cause = snapshot.u32()?;
+    let trap_value = snapshot.u32()?;
+    Exception::from_cause(cause, trap_value)
+        .ok_or_else(|| SnapshotError::Mismatch(format!("{cause} is not an exception cause")))
+}
+
+fn save_register_write(snapshot: &mut SnapshotWriter, register_write: &RegisterWrite) {
+    snapshot.u32(register_write.index);
+    snapshot.u32(register_write.value);
+}
+
+fn restore_register_write(snapshot: &mut SnapshotReader) -> Result<RegisterWrite, SnapshotError> {
+    Ok(RegisterWrite { index: snapshot.u32()?, value: snapshot.u32()? })
+}
+
+/// A decoded instruction is saved as its word and the register values decode read, and restored by decoding again.
+fn save_decoded_instruction(
+    snapshot: &mut SnapshotWriter,
+    fetch_result: FetchResult,
+    decoded_instruction: &Result<Instruction, Exception>,
+) {
+    save_fetch_result(snapshot, fetch_result);
+    match decoded_instruction {
+        Ok(instruction) => {
+            snapshot.bool(true);
+            for value in instruction.register_source_values() {
+                snapshot.option(&value, |snapshot, value| {
+                    snapshot.u32(value.index);
+                    snapshot.u32(value.value);
+                });
+            }
+        }
+        Err(exception) => {
+            snapshot.bool(false);
+            save_exception(snapshot, exception);
+        }
+    }
+}
+
+fn restore_decoded_instruction(
+    snapshot: &mut SnapshotReader,
+) -> Result<(FetchResult, Result<Instruction, Exception>), SnapshotError> {
+    let fetch_result = restore_fetch_result(snapshot)?;
+    if !snapshot.bool()? {
+        return Ok((fetch_result, Err(restore_exception(snapshot)?)));
+    }
+
+    let mut register_file = RegisterFile::new(32);
+    for _ in 0..2 {
+        if let Some((index, value)) = snapshot.option(|snapshot| Ok((snapshot.u32()?, snapshot.u32()?)))? {
+            if index >= 32 {
+                return Err(SnapshotError::Mismatch(format!("x{index} is not a register")));
+            }
+            register_file.write(index as usize, value);
+        }
+    }
+    match decode_instruction(fetch_result, &register_file) {
+        Ok(instruction) => Ok((fetch_result, Ok(instruction))),
+        Err(_) => Err(SnapshotError::Mismatch(format!("{:#010x} does not decode", fetch_result.instruction))),
+    }
+}
+
+impl SimplePipeline {
+    fn cycle<M>(&mut self, pc: u32, state: &mut HartState, memory: &mut M) -> u32
+    where
+        M: BusInterface<u32, i8>,
+        M: BusInterface<u32, u8>,
+        M: BusInterface<u32, i16>,
+        M: BusInterface<u32, u16>,
+        M: BusInterface<u32, u32>,
+    {
+        // Traps and system instructions are handled at write back, where everything older has completed. Younger
+        // instructions are discarded and fetching restarts at the returned address.
+        if let Some(write_back_input) = self.write_back_input.take() {
+            let trace_id = write_back_input.trace_id;
+            self.trace(|tracer| tracer.stage(trace_id, "W"));
+
+            let Commit { retirement, restart_at } = write_back_stage(write_back_input, state, memory);
+            if let Some(retirement) = retirement {
+                state.retire(retirement);
+                self.trace(|tracer| tracer.retire(trace_id));
+            } else {
+                self.trace(|tracer| tracer.flush(trace_id));
+            }
+
+            if let Some(restart_at) = restart_at {
+                state.csrs.count_event(HpmEvent::Flush);
+                self.flush();
+                return restart_at;
+            }
+        }
+
+        if let Some(MemoryAccessInput { trace_id, .. }) = self.memory_access_input {
+            self.trace(|tracer| tracer.stage(trace_id, "M"));
+        }
+        let next_write_back_input = match &mut self.memory_access_input {
+            Some(memory_access_input) => {
+                let trace_id = memory_access_input.trace_id;
+                match memory_stage(memory_access_input, state, memory) {
+                    Some(write_back_input) => Some(write_back_input),
+                    // The access is still on the bus, so everything behind it holds and write back gets a bubble.
+                    None => {
+                        state.csrs.count_event(HpmEvent::Stall);
+                        self.trace(|tracer| tracer.annotate(trace_id, "memory stall"));
+                        return pc;
+                    }
+                }
+            }
+            None => None,
+        };
+
+        if let Some(AluInput { trace_id, .. }) = self.execute_input {
+            self.trace(|tracer| tracer.stage(trace_id, "X"));
+        }
+        let next_memory_access_input = self.execute_input.map(|input| execute_stage(input, state.record_undo));
+
+        // Results leaving execute and memory this cycle are forwarded to decode, the younger one taking precedence.
+        let mut forwarded_register_file = state.register_file.clone();
+        for RegisterWrite { index, value } in [
+            next_write_back_input,
+            next_memory_access_input.map(memory_to_write_back),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|input| input.operation)
+        {
+            forwarded_register_file.write(index as usize, value);
+        }
+
+        if let Some(DecodedInput { trace_id, .. }) = self.decode_input {
+            self.trace(|tracer| tracer.stage(trace_id, "D"));
+        }
+        let next_execute_input =
+            self.decode_input.map(|decoded_input| decode_stage(decoded_input, &forwarded_register_file));
+
+        // A load result is only known after the memory stage, so an instruction depending on it waits a cycle.
+        if load_use_hazard(self.execute_input, next_execute_input) {
+            state.csrs.count_event(HpmEvent::Stall);
+            if let Some(DecodedInput { trace_id, .. }) = self.decode_input {
+                self.trace(|tracer| tracer.annotate(trace_id, "load-use stall"));
+            }
+            self.execute_input = None;
+            self.memory_access_input = next_memory_access_input;
+            self.write_back_input = next_write_back_input;
+            return pc;
         }
 
-        let next_decode_input = fetch_stage(pc, memory);
-        let next_execute_input = self.decode_input.map(|decoded_input| decode_stage(decoded_input, register_file));
-        let next_memory_access_input = self.execute_input.map(execute_stage);
-        let next_write_back_input =
-            self.memory_access_input.map(|memory_access_input| memory_stage(memory_access_input, memory));
+        // A fetch still waiting on the bus leaves a bubble in decode and is retried at the same address.
+        let mut next_decode_input = fetch_stage(pc, state, memory);
+        if let (Some(input), Some(tracer)) = (&mut next_decode_input, &mut self.tracer) {
+            input.trace_id = tracer.fetch(pc, input.fetch_result.instruction, "F");
+        }
+        let next_pc = match next_decode_input {
+            Some(input) => input.predicted_next_pc,
+            None => pc,
+        };
 
-        self.decode_input = Some(next_decode_input);
+        let decode_input = self.decode_input;
+        self.decode_input = next_decode_input;
         self.execute_input = next_execute_input;
         self.memory_access_input = next_memory_access_input;
         self.write_back_input = next_write_back_input;
 
-        match next_execute_input.and_then(will_branch) {
-            Some(jump_to_address) => {
-                self.decode_input = None;
-                self.execute_input = None;
-                jump_to_address
+        let (Some(decode_input), Some(next_execute_input)) = (decode_input, next_execute_input) else {
+            return next_pc;
+        };
+
+        // Branches resolve in decode. When fetch did not follow them, the instruction it fetched this cycle is
+        // discarded.
+        let resolved_pc = resolve_next_pc(next_execute_input);
+        // Instructions that already trap, like a fetch from a misaligned pc, are not jumps.
+        if next_execute_input.decoded_instruction.is_ok() && !resolved_pc.is_multiple_of(INSTRUCTION_ALIGNMENT) {
+            // A jump to a misaligned target traps on the jump itself, which then must not redirect fetch.
+            self.execute_input = Some(AluInput {
+                decoded_instruction: Err(Exception::InstructionAddressMisaligned { address: resolved_pc }),
+                ..next_execute_input
+            });
+            return next_pc;
+        }
+
+        if let Some(predictor) = &mut state.branch_predictor {
+            let AluInput { fetch_result, decoded_instruction, .. } = next_execute_input;
+            match decoded_instruction {
+                Ok(Instruction::Branching(instr)) => {
+                    let (kind, target) = BranchKind::of(fetch_result, instr);
+                    predictor.resolve(
+                        fetch_result.captured_pc,
+                        kind,
+                        target,
+                        resolved_pc,
+                        decode_input.predicted_next_pc,
+                    );
+                }
+                _ => predictor.record(resolved_pc, decode_input.predicted_next_pc),
+            }
+        }
+
+        match resolved_pc == decode_input.predicted_next_pc {
+            true => next_pc,
+            false => {
+                state.csrs.count_event(HpmEvent::BranchMispredict);
+                state.csrs.count_event(HpmEvent::Flush);
+                if let Some(DecodedInput { trace_id, .. }) = self.decode_input.take() {
+                    self.trace(|tracer| tracer.flush(trace_id));
+                }
+                resolved_pc
             }
-            None => pc + size_of::<u32>() as u32,
         }
     }
 }
 
-fn fetch_stage<M: BusInterface<u32, u32>>(pc: u32, memory: &M) -> DecodedInput {
-    let memory_read: BusReadResponse<u32> = memory.read(pc);
+/// Returns `None` while the fetch is waiting on the bus.
+fn fetch_stage<M: BusInterface<u32, u32>>(pc: u32, state: &mut HartState, memory: &mut M) -> Option<DecodedInput> {
+    let fetch = match pc.is_multiple_of(INSTRUCTION_ALIGNMENT) {
+        true => fetch_instruction(pc, state, memory)?,
+        false => Err(Exception::InstructionAddressMisaligned { address: pc }),
+    };
 
-    if let BusReadResponse::Success(value) = memory_read {
-        DecodedInput { 
-            fetch_result: FetchResult { captured_pc: pc, instruction: value }
-         }
-    } else {
-        panic!("Invalid memory read at address {:x}", pc);
-    }
+    let predicted_next_pc = match &state.branch_predictor {
+        Some(predictor) => predictor.predict(pc),
+        None => pc.wrapping_add(size_of::<u32>() as u32),
+    };
 
+    Some(match fetch {
+        Ok(instruction) => DecodedInput {
+            fetch_result: FetchResult { captured_pc: pc, instruction },
+            fetch_exception: None,
+            predicted_next_pc,
+            trace_id: 0,
+        },
+        Err(exception) => DecodedInput {
+            fetch_result: FetchResult { captured_pc: pc, instruction: 0 },
+            fetch_exception: Some(exception),
+            predicted_next_pc,
+            trace_id: 0,
+        },
+    })
 }
 
-fn decode_stage(DecodedInput { fetch_result }: DecodedInput, register_file: &RegisterFile) -> AluInput {
-    match decode_instruction(fetch_result, register_file) {
-        Ok(decoded_instruction) => AluInput { fetch_result, decoded_instruction },
-        Err(DecodeError::BadInstruction { address, instruction }) => {
-            panic!("Invalid instruction. address: {:x}, instruction: {:0>32b}", address, instruction)
-        }
+fn fetch_instruction<M: BusInterface<u32, u32>>(
+    pc: u32,
+    state: &mut HartState,
+    memory: &mut M,
+) -> Option<Result<u32, Exception>> {
+    let translated = state.mmu.translate(pc, AccessType::Instruction, &state.csrs, memory).and_then(|address| {
+        pmp::check(&state.csrs, pc, address, size_of::<u32>() as u32, AccessType::Instruction)?;
+        Ok(address)
+    });
+
+    match translated {
+        Ok(address) => match fetch_word(address, state, memory) {
+            BusReadResponse::Success(value) => Some(Ok(value)),
+            BusReadResponse::Deferred => {
+                state.mmu.hold_translation(pc, AccessType::Instruction, address);
+                None
+            }
+            _ => Some(Err(Exception::InstructionAccessFault { address: pc })),
+        },
+        Err(AccessError::Deferred) => None,
+        Err(AccessError::Exception(exception)) => Some(Err(exception)),
     }
 }
 
-fn execute_stage(AluInput { fetch_result, decoded_instruction }: AluInput) -> MemoryAccessInput {
+fn fetch_word<M: BusInterface<u32, u32>>(address: u32, state: &mut HartState, memory: &mut M) -> BusReadResponse<u32> {
+    match &mut state.instruction_cache {
+        Some(cache) if cache.is_cacheable(address) => cache.read(address, size_of::<u32>() as u32, memory),
+        _ => memory.read(address),
+    }
+}
+
+fn decode_stage(
+    DecodedInput { fetch_result, fetch_exception, trace_id, .. }: DecodedInput,
+    register_file: &RegisterFile,
+) -> AluInput {
+    let decoded_instruction = match fetch_exception {
+        Some(exception) => Err(exception),
+        None => decode_instruction(fetch_result, register_file)
+            .map_err(|DecodeError::BadInstruction { instruction, .. }| Exception::IllegalInstruction { instruction }),
+    };
+
+    AluInput { fetch_result, decoded_instruction, trace_id }
+}
+
+fn execute_stage(
+    AluInput { fetch_result, decoded_instruction, trace_id }: AluInput,
+    record_undo: bool,
+) -> MemoryAccessInput {
     MemoryAccessInput {
         fetch_result,
         decoded_instruction,
+        trace_id,
         operation: match decoded_instruction {
-            Instruction::Alu(instr) => Some(execute(fetch_result, instr)),
+            Ok(Instruction::Alu(instr)) => Some(execute(fetch_result, instr)),
+            Ok(Instruction::Branching(instr)) => link(fetch_result, instr),
             _ => None,
         },
+        store_progress: StoreProgress { record: record_undo, ..StoreProgress::default() },
     }
 }
 
-fn will_branch(AluInput { fetch_result, decoded_instruction }: AluInput) -> Option<u32> {
-    match decoded_instruction {
-        Instruction::Branching(instr) => branch(fetch_result, instr),
+/// The address of the instruction that follows in program order.
+fn resolve_next_pc(AluInput { fetch_result, decoded_instruction, .. }: AluInput) -> u32 {
+    let branch_target = match decoded_instruction {
+        Ok(Instruction::Branching(instr)) => branch(fetch_result, instr),
         _ => None,
+    };
+
+    branch_target.unwrap_or(fetch_result.captured_pc.wrapping_add(size_of::<u32>() as u32))
+}
+
+fn load_use_hazard(execute_input: Option<AluInput>, next_execute_input: Option<AluInput>) -> bool {
+    let load_destination = match execute_input.map(|input| input.decoded_instruction) {
+        Some(Ok(instruction @ Instruction::MemoryLoad(_))) => instruction.register_destination_index(),
+        _ => None,
+    };
+
+    match (load_destination, next_execute_input.map(|input| input.decoded_instruction)) {
+        (Some(destination), Some(Ok(instruction))) if destination != 0 => {
+            instruction.register_source_indices().contains(&Some(destination))
+        }
+        _ => false,
     }
 }
 
-fn memory_stage<M>(
-    MemoryAccessInput { fetch_result, decoded_instruction, operation }: MemoryAccessInput,
-    memory: &mut M,
-) -> WriteBackInput
+fn memory_stage<M>(input: &mut MemoryAccessInput, state: &mut HartState, memory: &mut M) -> Option<WriteBackInput>
 where
     M: BusInterface<u32, i8>,
     M: BusInterface<u32, u8>,
@@ -134,14 +649,368 @@ where
     M: BusInterface<u32, u16>,
     M: BusInterface<u32, u32>,
 {
-    let op = match decoded_instruction {
-        Instruction::MemoryLoad(instr) => Some(load(instr, memory)),
-        Instruction::MemoryStore(instr) => {
-            store(instr, memory);
-            None
+    let MemoryAccessInput { fetch_result, decoded_instruction, operation, trace_id, .. } = *input;
+    let result = match decoded_instruction {
+        Ok(Instruction::MemoryLoad(instr)) => {
+            load(instr, &mut state.mmu, &state.csrs, state.misaligned_access_policy, &mut state.data_cache, memory)
+                .map(Some)
         }
-        _ => operation,
+        Ok(Instruction::MemoryStore(instr)) => store(
+            instr,
+            &mut state.mmu,
+            &state.csrs,
+            state.misaligned_access_policy,
+            &mut state.data_cache,
+            memory,
+            &mut input.store_progress,
+        )
+        .map(|_| None),
+        _ => Ok(operation),
     };
 
-    WriteBackInput { fetch_result, decoded_instruction, operation: op }
+    match result {
+        Ok(op) => {
+            match decoded_instruction {
+                Ok(Instruction::MemoryLoad(_)) => state.csrs.count_event(HpmEvent::Load),
+                Ok(Instruction::MemoryStore(_)) => state.csrs.count_event(HpmEvent::Store),
+                _ => {}
+            }
+            let memory_access = decoded_instruction.ok().and_then(MemoryAccess::of);
+            let overwritten = input.store_progress.overwritten;
+            Some(WriteBackInput {
+                fetch_result,
+                decoded_instruction,
+                operation: op,
+                memory_access,
+                overwritten,
+                trace_id,
+            })
+        }
+        Err(AccessError::Deferred) => None,
+        Err(AccessError::Exception(exception)) => Some(WriteBackInput {
+            fetch_result,
+            decoded_instruction: Err(exception),
+            operation: None,
+            memory_access: None,
+            overwritten: None,
+            trace_id,
+        }),
+    }
+}
+
+fn memory_to_write_back(
+    MemoryAccessInput { fetch_result, decoded_instruction, operation, trace_id, .. }: MemoryAccessInput,
+) -> WriteBackInput {
+    WriteBackInput { fetch_result, decoded_instruction, operation, memory_access: None, overwritten: None, trace_id }
+}
+
+fn write_back_stage<M: BusInterface<u32, u32>>(
+    WriteBackInput { fetch_result, decoded_instruction, operation, memory_access, overwritten, .. }: WriteBackInput,
+    state: &mut HartState,
+    memory: &mut M,
+) -> Commit {
+    let pc = fetch_result.captured_pc;
+    let trap = |state: &mut HartState, exception| {
+        state.count_trap();
+        Commit { retirement: None, restart_at: Some(take_trap(pc, exception, &mut state.csrs)) }
+    };
+    let retirement = Retirement {
+        privilege_level: state.csrs.privilege_level(),
+        pc,
+        instruction: fetch_result.instruction,
+        register_write: operation,
+        csr_write: None,
+        memory_access,
+        undo: None,
+    };
+
+    match decoded_instruction {
+        Err(exception) => trap(state, exception),
+        Ok(Instruction::System(instr)) => match execute_system(fetch_result, instr, &mut state.csrs, &mut state.mmu) {
+            Ok(SystemResult { register_write, next_pc, csr_write }) => {
+                if let Some(register_write) = register_write {
+                    write_back(register_write, &mut state.register_file);
+                }
+                Commit {
+                    retirement: Some(Retirement { register_write, csr_write, ..retirement }),
+                    restart_at: Some(next_pc),
+                }
+            }
+            // Retired like a no-op, the host serves the call before the next instruction runs.
+            Err(Exception::Breakpoint { .. }) if state.is_semihosting_call() => {
+                state.request_semihosting_call();
+                Commit { retirement: Some(retirement), restart_at: Some(pc.wrapping_add(size_of::<u32>() as u32)) }
+            }
+            Err(exception) => trap(state, exception),
+        },
+        // Instructions fetched after FENCE.I may predate stores it orders, so they are fetched again. Dirty data is
+        // written back first so refills see it, a flush waiting on the bus restarts at the FENCE.I itself.
+        Ok(Instruction::Fence(FenceInstruction::FENCE_I(_))) => {
+            if let Some(cache) = &mut state.data_cache {
+                if let BusWriteResponse::Deferred = cache.flush(memory) {
+                    return Commit { retirement: None, restart_at: Some(pc) };
+                }
+            }
+            if let Some(cache) = &mut state.instruction_cache {
+                cache.invalidate();
+            }
+            Commit { retirement: Some(retirement), restart_at: Some(pc.wrapping_add(size_of::<u32>() as u32)) }
+        }
+        // Only these retire with what they overwrote, as it is all they change besides the pc.
+        Ok(_) => {
+            let undo = match (memory_access, overwritten) {
+                _ if !state.record_undo => None,
+                (Some(MemoryAccess::Store { .. }), None) => None,
+                (_, memory) => {
+                    let register = operation.map(|RegisterWrite { index, .. }| RegisterWrite {
+                        index,
+                        value: state.register_file.read(index as usize),
+                    });
+                    Some(Undo { register, memory: memory.unwrap_or_default() })
+                }
+            };
+            if let Some(operation) = operation {
+                write_back(operation, &mut state.register_file);
+            }
+            Commit { retirement: Some(Retirement { undo, ..retirement }), restart_at: None }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::assemble;
+    use crate::core::bus::{Clocked, Value};
+    use crate::core::csr::address_constants::{
+        MCAUSE, MEPC, MHPMCOUNTER3, MHPMEVENT3, MTVAL, MTVEC, PMPADDR0, PMPCFG0,
+    };
+    use crate::core::csr::PrivilegeLevel;
+    use crate::core::hart::Hart;
+    use crate::core::pmp::{PMP_R, PMP_TOR, PMP_X};
+    use crate::core::unit::MisalignedAccessPolicy;
+    use crate::memory::Memory;
+    use crate::wait_states::WaitStates;
+    use num::PrimInt;
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    type Machine = Hart<Memory, SimplePipeline>;
+
+    /// Runs `body` at address 0 after `ra` is set to 0x55 and traps are sent to a loop at `handler`, which the body
+    /// has to end with.
+    fn run(body: &str, cycles: usize) -> (Machine, Memory) {
+        let source = format!("la t0, handler\ncsrw mtvec, t0\nli ra, 0x55\n{body}\nhandler:\nj handler\n");
+        let program = assemble(&source, 0).unwrap();
+        let mut memory = Memory::with_initial_values(program.memory_image(1024));
+        let mut hart = Machine::new();
+        for _ in 0..cycles {
+            hart.execute(&mut memory);
+        }
+        (hart, memory)
+    }
+
+    /// mcause, mepc and mtval.
+    fn trap(hart: &Machine) -> [u32; 3] {
+        [MCAUSE, MEPC, MTVAL].map(|address| hart.state().csrs.read(address).unwrap())
+    }
+
+    #[test]
+    fn misaligned_loads_and_stores_trap_with_the_address() {
+        // The body starts at 16, after `la`, `csrw` and `li`.
+        let (hart, _) = run("li a0, 0x101\nlw a1, 0(a0)\n", 50);
+        assert_eq!(trap(&hart), [4, 20, 0x101]);
+
+        let (hart, _) = run("li a0, 0x102\nsw a1, 1(a0)\n", 50);
+        assert_eq!(trap(&hart), [6, 20, 0x103]);
+    }
+
+    #[test]
+    fn misaligned_jump_and_branch_targets_trap_on_the_jump() {
+        // jal ra, .+6 traps without linking.
+        let (hart, _) = run(".word 0x006000ef\n", 50);
+        assert_eq!(trap(&hart), [0, 16, 22]);
+        assert_eq!(hart.state().register_file.read(1), 0x55);
+
+        // jalr only clears bit 0 of the target.
+        let (hart, _) = run("la t1, handler\njalr ra, 2(t1)\n", 50);
+        assert_eq!(trap(&hart), [0, 24, 30]);
+        assert_eq!(hart.state().register_file.read(1), 0x55);
+
+        // beq zero, zero, .+6 is taken, bne zero, zero, .+6 is not and does not trap.
+        let (hart, _) = run(".word 0x00001363\n.word 0x00000363\n", 50);
+        assert_eq!(trap(&hart), [0, 20, 26]);
+    }
+
+    #[test]
+    fn misaligned_fetch_traps() {
+        let mut image = vec![0; 1024];
+        // j . as the handler.
+        image[0x100..0x104].copy_from_slice(&0x0000_006fu32.to_le_bytes());
+        let mut memory = Memory::with_initial_values(image);
+        let mut hart = Machine::new();
+        hart.state_mut().csrs.write(MTVEC, 0x100);
+        hart.set_program_counter(0x42);
+        for _ in 0..10 {
+            hart.execute(&mut memory);
+        }
+        // mepc reads with bit 1 clear, as instructions are 4 byte aligned.
+        assert_eq!(trap(&hart), [0, 0x40, 0x42]);
+    }
+
+    /// Runs `body` in user mode at 8, with [0, 0x200) readable and executable, [0x200, 0x300) only readable and the
+    /// rest of memory neither, and returns the trap it takes.
+    fn user_mode_trap(body: &str) -> [u32; 3] {
+        let program = assemble(&format!("j start\nhandler:\nj handler\nstart:\n{body}\n"), 0).unwrap();
+        let mut memory = Memory::with_initial_values(program.memory_image(1024));
+        let mut hart = Machine::new();
+        let csrs = &mut hart.state_mut().csrs;
+        csrs.write(MTVEC, 4);
+        csrs.write(PMPADDR0, 0x200 >> 2);
+        csrs.write(PMPADDR0 + 1, 0x300 >> 2);
+        csrs.write(PMPCFG0, u32::from_le_bytes([PMP_TOR | PMP_R | PMP_X, PMP_TOR | PMP_R, 0, 0]));
+        csrs.set_privilege_level(PrivilegeLevel::User);
+        hart.set_program_counter(8);
+        for _ in 0..50 {
+            hart.execute(&mut memory);
+        }
+        trap(&hart)
+    }
+
+    #[test]
+    fn user_mode_accesses_outside_the_pmp_regions_fault() {
+        assert_eq!(user_mode_trap("li a0, 0x300\nlw a1, 0(a0)"), [5, 12, 0x300]);
+        assert_eq!(user_mode_trap("li a0, 0x200\nlw a1, 0(a0)\nsw a1, 4(a0)"), [7, 16, 0x204]);
+        assert_eq!(user_mode_trap("li a0, 0x300\njr a0"), [1, 0x300, 0x300]);
+    }
+
+    /// Keeps the addresses of the writes that reach memory.
+    struct Writes {
+        memory: Memory,
+        addresses: Vec<u32>,
+    }
+
+    impl<V: PrimInt + Value> BusInterface<u32, V> for Writes {
+        fn read(&self, address: u32) -> BusReadResponse<u32> {
+            BusInterface::<u32, V>::read(&self.memory, address)
+        }
+
+        fn write(&mut self, address: u32, value: V) -> BusWriteResponse {
+            self.addresses.push(address);
+            self.memory.write(address, value)
+        }
+    }
+
+    impl Clocked for Writes {
+        fn tick(&mut self) {}
+    }
+
+    /// Runs `source` at address 0 until the instruction at `pc` retires, with `wait_states` on every access but those
+    /// to the data at 0x200, which take `data_wait_states`. Returns the cycles taken and the stalls counted.
+    fn cycles_until_retired(source: &str, pc: u32, wait_states: u32, data_wait_states: u32) -> (u32, u32) {
+        let program = assemble(source, 0).unwrap();
+        let mut memory = WaitStates::new(Memory::with_initial_values(program.memory_image(1024)), wait_states);
+        memory.add_region(0x200, 0x100, data_wait_states);
+        let mut hart = Hart::<_, SimplePipeline>::new();
+        hart.state_mut().csrs.write(MHPMEVENT3, HpmEvent::Stall as u32);
+        hart.record_retirements();
+        for cycle in 1..100 {
+            hart.execute(&mut memory);
+            if hart.retired().iter().any(|retirement| retirement.pc == pc) {
+                return (cycle, hart.state().csrs.read(MHPMCOUNTER3).unwrap());
+            }
+        }
+        panic!("{pc:#x} did not retire");
+    }
+
+    #[test]
+    fn wait_states_stall_fetch_and_memory() {
+        let source = "li a0, 0x200\nlw a1, 0(a0)\nnop\n";
+        let (cycles, stalls) = cycles_until_retired(source, 8, 0, 0);
+        assert_eq!((cycles, stalls), (7, 0));
+
+        // The load holds the pipeline in the memory stage for each wait state.
+        assert_eq!(cycles_until_retired(source, 8, 0, 3), (cycles + 3, 3));
+
+        // Fetches waiting on the bus leave bubbles instead of stalling what is ahead of them.
+        assert_eq!(cycles_until_retired(source, 8, 2, 0), (cycles + 6, 0));
+    }
+
+    #[test]
+    fn deferred_misaligned_stores_write_each_byte_once() {
+        let program = assemble("li a0, 0x201\nli a1, 0x11223344\nsw a1, 0(a0)\ndone:\nj done\n", 0).unwrap();
+        let memory = Writes { memory: Memory::with_initial_values(program.memory_image(1024)), addresses: Vec::new() };
+        let mut memory = WaitStates::new(memory, 2);
+        let mut hart = Hart::<_, SimplePipeline>::new();
+        hart.state_mut().misaligned_access_policy = MisalignedAccessPolicy::Emulate;
+        for _ in 0..100 {
+            hart.execute(&mut memory);
+        }
+
+        assert_eq!(memory.inner().addresses, [0x201, 0x202, 0x203, 0x204]);
+        let word = BusInterface::<u32, u32>::read(&memory.inner().memory, 0x200);
+        assert!(matches!(word, BusReadResponse::Success(0x2233_4400)));
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn traces_stages_stalls_and_flushes() {
+        // The add waits a cycle for the load, the jump discards the instruction fetched after it.
+        let source = "li a0, 0x40\nlw a1, 0(a0)\naddi a2, a1, 1\nj target\nnop\ntarget:\nj target\n";
+        let program = assemble(source, 0).unwrap();
+        let mut memory = Memory::with_initial_values(program.memory_image(1024));
+        let buffer = SharedBuffer::default();
+        let mut hart = Machine::new();
+        hart.pipeline_mut().set_tracer(KanataTracer::new(buffer.clone()));
+        for _ in 0..9 {
+            hart.execute(&mut memory);
+        }
+        hart.pipeline_mut().take_tracer().unwrap().finish().unwrap();
+
+        // What happened to each instruction, with the cycle it happened in.
+        let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        let mut cycle = 0;
+        let mut instructions: Vec<String> = Vec::new();
+        for line in trace.lines() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            let event = match fields[..] {
+                ["C", _] => {
+                    cycle += 1;
+                    continue;
+                }
+                ["L", _, "0", label] => label[..8].to_string(),
+                ["S", _, _, stage] => format!("{stage}{cycle}"),
+                ["L", _, "1", note] => format!("({note})"),
+                ["R", _, _, "0"] => "retired".to_string(),
+                ["R", _, _, _] => "flushed".to_string(),
+                _ => continue,
+            };
+            let id: usize = fields[1].parse().unwrap();
+            instructions.resize(instructions.len().max(id + 1), String::new());
+            instructions[id] = format!("{} {event}", instructions[id]).trim().to_string();
+        }
+        assert_eq!(
+            instructions[..5],
+            [
+                "00000000 F0 D1 X2 M3 W4 retired",
+                "00000004 F1 D2 X3 M4 W5 retired",
+                "00000008 F2 D3 (load-use stall) X5 M6 W7 retired",
+                "0000000c F4 D5 X6 M7 W8 retired",
+                "00000010 F5 flushed",
+            ]
+        );
+    }
 }
@@ -0,0 +1,473 @@
+use crate::assembler::Program;
+use crate::elf::{Elf, SYMBOL_FUNCTION};
+
+const SYMBOL_NO_TYPE: u8 = 0;
+const SECTION_EXECUTABLE: u32 = 0x4;
+
+const FORM_BLOCK: u64 = 0x09;
+const FORM_DATA1: u64 = 0x0b;
+const FORM_DATA2: u64 = 0x05;
+const FORM_DATA4: u64 = 0x06;
+const FORM_DATA8: u64 = 0x07;
+const FORM_DATA16: u64 = 0x1e;
+const FORM_STRING: u64 = 0x08;
+const FORM_STRP: u64 = 0x0e;
+const FORM_UDATA: u64 = 0x0f;
+const FORM_LINE_STRP: u64 = 0x1f;
+const CONTENT_PATH: u64 = 1;
+const CONTENT_DIRECTORY_INDEX: u64 = 2;
+
+/// A code symbol: a function, or a label in assembly that did not say what it is.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Function {
+    name: String,
+    address: u32,
+    /// 0 when unknown, the symbol then reaches up to the next one.
+    size: u32,
+    /// Typed as a function rather than a plain label, which it is preferred to at the same address.
+    is_function: bool,
+}
+
+/// A row of the DWARF line table: the instructions from `address` up to the next row come from `line` of `file`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Row {
+    address: u32,
+    file: usize,
+    line: u32,
+    /// The first address after a sequence of instructions, which no source line covers.
+    end: bool,
+}
+
+/// Maps code addresses back to the program: the function they are in, from the ELF symbol table, and the source
+/// line they came from, from the DWARF `.debug_line` section when the program was built with debug information.
+///
+/// Used to annotate the addresses traces, the debugger and error reports show. Without an ELF file it is empty and
+/// annotates nothing.
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    /// By address, a function after a label at the same address.
+    functions: Vec<Function>,
+    files: Vec<String>,
+    /// By address, the end of a sequence before the start of another at the same address.
+    rows: Vec<Row>,
+}
+
+impl Symbols {
+    /// Line information that cannot be read is left out, the symbols are still used.
+    pub fn from_elf(elf: &Elf) -> Symbols {
+        let executable = |section: u16| {
+            elf.sections.get(section as usize).is_some_and(|section| section.flags & SECTION_EXECUTABLE != 0)
+        };
+        let mut functions: Vec<Function> = elf
+            .symbols
+            .iter()
+            .filter(|symbol| symbol.kind == SYMBOL_FUNCTION || symbol.kind == SYMBOL_NO_TYPE)
+            .filter(|symbol| executable(symbol.section) && !is_local_label(&symbol.name))
+            .map(|symbol| Function {
+                name: symbol.name.clone(),
+                address: symbol.value,
+                size: symbol.size,
+                is_function: symbol.kind == SYMBOL_FUNCTION,
+            })
+            .collect();
+        functions.sort_by_key(|function| (function.address, function.is_function));
+
+        let mut symbols = Symbols { functions, ..Symbols::default() };
+        let section = |name| elf.section(name).and_then(|section| elf.section_data(section).ok());
+        if let Some(lines) = section(".debug_line") {
+            let strings = Strings { debug_str: section(".debug_str"), debug_line_str: section(".debug_line_str") };
+            if symbols.read_lines(lines, &strings).is_none() {
+                symbols.files.clear();
+                symbols.rows.clear();
+            }
+            symbols.rows.sort_by_key(|row| (row.address, !row.end));
+        }
+        symbols
+    }
+
+    /// The labels of an assembled program, without line information.
+    pub fn from_program(program: &Program) -> Symbols {
+        let mut functions: Vec<Function> = program
+            .symbols
+            .iter()
+            .filter(|(name, &address)| address < program.data_address && !is_local_label(name))
+            .map(|(name, &address)| Function { name: name.clone(), address, size: 0, is_function: false })
+            .collect();
+        functions.sort_by(|a, b| (a.address, &a.name).cmp(&(b.address, &b.name)));
+        Symbols { functions, ..Symbols::default() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.functions.is_empty() && self.rows.is_empty()
+    }
+
+    /// The function holding the address and how far into it the address is.
+    pub fn function(&self, address: u32) -> Option<(&str, u32)> {
+        let index = self.functions.partition_point(|function| function.address <= address).checked_sub(1)?;
+        let function = &self.functions[index];
+        let offset = address - function.address;
+        match function.size == 0 || offset < function.size {
+            true => Some((&function.name, offset)),
+            false => None,
+        }
+    }
+
+    /// The source file and line the instruction at the address was compiled from.
+    pub fn line(&self, address: u32) -> Option<(&str, u32)> {
+        let index = self.rows.partition_point(|row| row.address <= address).checked_sub(1)?;
+        let row = self.rows[index];
+        match row.end {
+            true => None,
+            false => Some((&self.files[row.file], row.line)),
+        }
+    }
+
+    /// `main+0x10 at main.c:12`, or as much of it as is known.
+    pub fn annotate(&self, address: u32) -> Option<String> {
+        let function = self.function(address).map(|(name, offset)| match offset {
+            0 => name.to_string(),
+            _ => format!("{name}+{offset:#x}"),
+        });
+        let line = self.line(address).map(|(file, line)| format!("{file}:{line}"));
+        match (function, line) {
+            (Some(function), Some(line)) => Some(format!("{function} at {line}")),
+            (function, line) => function.or(line),
+        }
+    }
+
+    /// The address in hex, followed by its annotation in angle brackets when there is one.
+    pub fn describe(&self, address: u32) -> String {
+        match self.annotate(address) {
+            Some(annotation) => format!("{address:08x} <{annotation}>"),
+            None => format!("{address:08x}"),
+        }
+    }
+
+    /// Reads every line number program of the section. `None` when the section is malformed.
+    fn read_lines(&mut self, section: &[u8], strings: &Strings) -> Option<()> {
+        let mut reader = Reader { bytes: section, position: 0, offset_size: 4 };
+        while reader.position < section.len() {
+            let mut length = reader.u32()? as u64;
+            reader.offset_size = 4;
+            if length == 0xffff_ffff {
+                length = reader.u64()?;
+                reader.offset_size = 8;
+            }
+            let end = reader.position.checked_add(usize::try_from(length).ok()?)?;
+            let mut unit = Reader { bytes: section.get(..end)?, ..reader };
+            self.read_unit(&mut unit, strings)?;
+            reader.position = end;
+        }
+        Some(())
+    }
+
+    fn read_unit(&mut self, reader: &mut Reader, strings: &Strings) -> Option<()> {
+        let version = reader.u16()?;
+        if !(2..=5).contains(&version) {
+            return None;
+        }
+        if version >= 5 {
+            let (address_size, _segment_selector_size) = (reader.u8()?, reader.u8()?);
+            if address_size != 4 {
+                return None;
+            }
+        }
+        let header_length = reader.offset()?;
+        let program = reader.position.checked_add(usize::try_from(header_length).ok()?)?;
+        let minimum_instruction_length = reader.u8()? as u32;
+        if version >= 4 {
+            reader.u8()?;
+        }
+        // Whether rows start statements does not matter here.
+        reader.u8()?;
+        let line_base = reader.u8()? as i8 as i64;
+        let line_range = reader.u8()?;
+        let opcode_base = reader.u8()?;
+        if line_range == 0 || opcode_base == 0 {
+            return None;
+        }
+        let standard_opcode_lengths: Vec<u8> = (1..opcode_base).map(|_| reader.u8()).collect::<Option<_>>()?;
+
+        // Files are numbered from 1 before version 5 and from 0 since, `files` maps those numbers to names.
+        let (directories, mut files) = match version {
+            5 => {
+                let directories = reader.entries(strings)?.into_iter().map(|(path, _)| path).collect::<Vec<_>>();
+                let files = reader.entries(strings)?;
+                (directories, files)
+            }
+            _ => {
+                let mut directories = vec![String::new()];
+                while let Some(directory) = reader.string().filter(|directory| !directory.is_empty()) {
+                    directories.push(directory);
+                }
+                let mut files = vec![(String::new(), 0)];
+                while let Some(name) = reader.string().filter(|name| !name.is_empty()) {
+                    let directory = reader.uleb()?;
+                    reader.uleb()?;
+                    reader.uleb()?;
+                    files.push((name, directory));
+                }
+                (directories, files)
+            }
+        };
+        let first_file = self.files.len();
+        for (name, directory) in files.drain(..) {
+            self.files.push(path(&directories, name, directory));
+        }
+
+        reader.position = program;
+        let start = Row { address: 0, file: 1, line: 1, end: false };
+        let mut row = start;
+        while reader.position < reader.bytes.len() {
+            let opcode = reader.u8()?;
+            match opcode {
+                0 => {
+                    let length = reader.uleb()? as usize;
+                    let next = reader.position.checked_add(length)?;
+                    match reader.u8()? {
+                        1 => {
+                            row.end = true;
+                            self.push_row(row, first_file)?;
+                            row = start;
+                        }
+                        2 => row.address = reader.u32()?,
+                        3 => {
+                            let name = reader.string()?;
+                            let directory = reader.uleb()?;
+                            self.files.push(path(&directories, name, directory));
+                        }
+                        _ => {}
+                    }
+                    reader.position = next;
+                }
+                1 => self.push_row(row, first_file)?,
+                2 => row.address = row.address.wrapping_add(reader.uleb()? as u32 * minimum_instruction_length),
+                3 => row.line = (row.line as i64 + reader.sleb()?) as u32,
+                4 => row.file = reader.uleb()? as usize,
+                5 => {
+                    reader.uleb()?;
+                }
+                6 | 7 | 10 | 11 => {}
+                8 => {
+                    let advance = (255 - opcode_base) / line_range;
+                    row.address = row.address.wrapping_add(advance as u32 * minimum_instruction_length);
+                }
+                9 => row.address = row.address.wrapping_add(reader.u16()? as u32),
+                _ if opcode < opcode_base => {
+                    for _ in 0..standard_opcode_lengths[opcode as usize - 1] {
+                        reader.uleb()?;
+                    }
+                }
+                _ => {
+                    let adjusted = opcode - opcode_base;
+                    let advance = (adjusted / line_range) as u32 * minimum_instruction_length;
+                    row.address = row.address.wrapping_add(advance);
+                    row.line = (row.line as i64 + line_base + (adjusted % line_range) as i64) as u32;
+                    self.push_row(row, first_file)?;
+                }
+            }
+        }
+        Some(())
+    }
+
+    fn push_row(&mut self, row: Row, first_file: usize) -> Option<()> {
+        let file = first_file + row.file;
+        if file >= self.files.len() {
+            return None;
+        }
+        self.rows.push(Row { file, ..row });
+        Some(())
+    }
+}
+
+/// Joins a file name to its directory, except to directory 0: that is the compilation directory, which only makes
+/// names longer.
+fn path(directories: &[String], name: String, directory: u64) -> String {
+    match directories.get(directory as usize) {
+        Some(directory_name) if directory != 0 && !name.starts_with('/') => format!("{directory_name}/{name}"),
+        _ => name,
+    }
+}
+
+/// Compiler generated labels like `.L3` and mapping symbols like `$x` say nothing about where code is.
+fn is_local_label(name: &str) -> bool {
+    name.starts_with(".L") || name.starts_with('$')
+}
+
+/// The string sections `.debug_line` refers to from version 5 on.
+struct Strings<'a> {
+    debug_str: Option<&'a [u8]>,
+    debug_line_str: Option<&'a [u8]>,
+}
+
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    /// 4 in 32 bit DWARF, 8 in 64 bit DWARF.
+    offset_size: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, count: usize) -> Option<&[u8]> {
+        let bytes = self.bytes.get(self.position..self.position.checked_add(count)?)?;
+        self.position += count;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+    }
+
+    fn offset(&mut self) -> Option<u64> {
+        match self.offset_size {
+            8 => self.u64(),
+            _ => self.u32().map(u64::from),
+        }
+    }
+
+    fn uleb(&mut self) -> Option<u64> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn sleb(&mut self) -> Option<i64> {
+        let mut value = 0i64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as i64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(match shift < 57 && byte & 0x40 != 0 {
+                    true => value | -1 << (shift + 7),
+                    false => value,
+                });
+            }
+        }
+        None
+    }
+
+    /// A NUL terminated string.
+    fn string(&mut self) -> Option<String> {
+        let rest = self.bytes.get(self.position..)?;
+        let length = rest.iter().position(|&byte| byte == 0)?;
+        self.position += length + 1;
+        Some(String::from_utf8_lossy(&rest[..length]).into_owned())
+    }
+
+    /// A version 5 directory or file name table: the format of its entries, then the entries. Returns each entry's
+    /// path and directory index.
+    fn entries(&mut self, strings: &Strings) -> Option<Vec<(String, u64)>> {
+        let format_count = self.u8()?;
+        let format: Vec<(u64, u64)> =
+            (0..format_count).map(|_| Some((self.uleb()?, self.uleb()?))).collect::<Option<_>>()?;
+        let count = self.uleb()?;
+
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            let (mut path, mut directory) = (String::new(), 0);
+            for &(content, form) in &format {
+                let value = match form {
+                    FORM_STRING => Value::String(self.string()?),
+                    FORM_STRP => Value::String(string_at(strings.debug_str?, self.offset()?)?),
+                    FORM_LINE_STRP => Value::String(string_at(strings.debug_line_str?, self.offset()?)?),
+                    FORM_UDATA => Value::Number(self.uleb()?),
+                    FORM_DATA1 => Value::Number(self.u8()? as u64),
+                    FORM_DATA2 => Value::Number(self.u16()? as u64),
+                    FORM_DATA4 => Value::Number(self.u32()? as u64),
+                    FORM_DATA8 => Value::Number(self.u64()?),
+                    FORM_DATA16 => {
+                        self.take(16)?;
+                        Value::Skipped
+                    }
+                    FORM_BLOCK => {
+                        let length = self.uleb()? as usize;
+                        self.take(length)?;
+                        Value::Skipped
+                    }
+                    _ => return None,
+                };
+                match (content, value) {
+                    (CONTENT_PATH, Value::String(value)) => path = value,
+                    (CONTENT_DIRECTORY_INDEX, Value::Number(value)) => directory = value,
+                    _ => {}
+                }
+            }
+            entries.push((path, directory));
+        }
+        Some(entries)
+    }
+}
+
+enum Value {
+    String(String),
+    Number(u64),
+    Skipped,
+}
+
+fn string_at(section: &[u8], offset: u64) -> Option<String> {
+    Reader { bytes: section, position: usize::try_from(offset).ok()?, offset_size: 4 }.string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A version 3 line number program for `src/a.c`: line 10 at 0x8000_0000, line 11 from 0x8000_0004 and the end
+    /// of the sequence at 0x8000_000c.
+    fn debug_line() -> Vec<u8> {
+        let mut header = vec![1, 1, 0xfb, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+        header.extend_from_slice(b"src\0\0a.c\0\x01\0\0\0");
+        let mut program = vec![0, 5, 2];
+        program.extend_from_slice(&0x8000_0000u32.to_le_bytes());
+        // Advance the line by 9 and copy, a special opcode for 4 bytes and 1 line on, advance by 8 and end.
+        program.extend_from_slice(&[3, 9, 1, 13 + 6 + 14 * 4, 2, 8, 0, 1, 1]);
+
+        let mut unit = 3u16.to_le_bytes().to_vec();
+        unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        unit.extend(header);
+        unit.extend(program);
+        let mut section = (unit.len() as u32).to_le_bytes().to_vec();
+        section.extend(unit);
+        section
+    }
+
+    #[test]
+    fn annotates_with_function_and_line() {
+        let mut symbols = Symbols::default();
+        symbols.read_lines(&debug_line(), &Strings { debug_str: None, debug_line_str: None }).unwrap();
+        symbols.functions = vec![
+            Function { name: "loop".to_string(), address: 0x8000_0000, size: 0, is_function: false },
+            Function { name: "main".to_string(), address: 0x8000_0000, size: 12, is_function: true },
+        ];
+
+        assert_eq!(symbols.line(0x8000_0000), Some(("src/a.c", 10)));
+        assert_eq!(symbols.line(0x8000_0008), Some(("src/a.c", 11)));
+        assert_eq!(symbols.line(0x8000_000c), None);
+        assert_eq!(symbols.describe(0x8000_0000), "80000000 <main at src/a.c:10>");
+        assert_eq!(symbols.describe(0x8000_0008), "80000008 <main+0x8 at src/a.c:11>");
+        assert_eq!(symbols.describe(0x8000_000c), "8000000c");
+        assert_eq!(symbols.describe(0x7fff_fffc), "7ffffffc");
+
+        let truncated = &debug_line()[..20];
+        assert!(Symbols::default().read_lines(truncated, &Strings { debug_str: None, debug_line_str: None }).is_none());
+    }
+}
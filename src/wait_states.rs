@@ -0,0 +1,247 @@
+use std::cell::RefCell;
+
+use crate::core::bus::{BusInterface, BusReadResponse, BusWriteResponse, Clocked, Value};
+use crate::core::snapshot::{Snapshot, SnapshotError, SnapshotReader, SnapshotWriter};
+use num::PrimInt;
+
+/// Wraps a device so that its transactions take extra cycles to complete.
+///
+/// The first request for an access is answered with `Deferred`. Repeating the same request on the following cycles
+/// keeps it going, and after the configured number of wait states it is passed on to the wrapped device. Writes only
+/// reach the device once they complete. A transaction that is not repeated keeps counting down and is dropped once
+/// it has completed without being asked for again.
+///
+/// A transaction that has been served is dropped at the end of the cycle, so the same access right after waits again.
+/// Only while a transaction requested after it is still waiting is it kept and served again without waiting, so a
+/// sequence of accesses that is restarted from the top, such as a page walk, only waits once for each access.
+pub struct WaitStates<M> {
+    inner: M,
+    default_wait_states: u32,
+    regions: Vec<WaitStateRegion>,
+    transactions: RefCell<Vec<Transaction>>,
+}
+
+struct WaitStateRegion {
+    start: u32,
+    size: u32,
+    wait_states: u32,
+}
+
+struct Transaction {
+    address: u32,
+    width: usize,
+    write: bool,
+    remaining: u32,
+    requested: bool,
+    served: bool,
+}
+
+impl<M> WaitStates<M> {
+    pub fn new(inner: M, default_wait_states: u32) -> Self {
+        WaitStates { inner, default_wait_states, regions: Vec::new(), transactions: RefCell::new(Vec::new()) }
+    }
+
+    /// Accesses to `[start, start + size)` take `wait_states` instead of the default. Earlier regions take priority.
+    pub fn add_region(&mut self, start: u32, size: u32, wait_states: u32) {
+        self.regions.push(WaitStateRegion { start, size, wait_states });
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.inner
+    }
+
+    fn wait_states(&self, address: u32) -> u32 {
+        self.regions
+            .iter()
+            .find(|region| address.wrapping_sub(region.start) < region.size)
+            .map_or(self.default_wait_states, |region| region.wait_states)
+    }
+
+    /// Records a request and reports whether it has waited long enough to be passed on.
+    fn ready(&self, address: u32, width: usize, write: bool) -> bool {
+        let wait_states = self.wait_states(address);
+        if wait_states == 0 {
+            return true;
+        }
+
+        let mut transactions = self.transactions.borrow_mut();
+        match transactions.iter_mut().find(|transaction| {
+            transaction.address == address && transaction.width == width && transaction.write == write
+        }) {
+            Some(transaction) => {
+                transaction.requested = true;
+                transaction.served |= transaction.remaining == 0;
+                transaction.served
+            }
+            None => {
+                let transaction =
+                    Transaction { address, width, write, remaining: wait_states, requested: true, served: false };
+                transactions.push(transaction);
+                false
+            }
+        }
+    }
+}
+
+impl<V: PrimInt + Value, M: BusInterface<u32, V>> BusInterface<u32, V> for WaitStates<M> {
+    fn read(&self, address: u32) -> BusReadResponse<u32> {
+        match self.ready(address, V::WIDTH, false) {
+            true => self.inner.read(address),
+            false => BusReadResponse::Deferred,
+        }
+    }
+
+    fn write(&mut self, address: u32, value: V) -> BusWriteResponse {
+        match self.ready(address, V::WIDTH, true) {
+            true => self.inner.write(address, value),
+            false => BusWriteResponse::Deferred,
+        }
+    }
+}
+
+impl<M: Clocked> Clocked for WaitStates<M> {
+    fn tick(&mut self) {
+        let transactions = self.transactions.get_mut();
+        // Transactions are in the order they started, so the served ones before a waiting one are earlier steps of its
+        // sequence.
+        let waiting = transactions.iter().rposition(|transaction| transaction.requested && !transaction.served);
+        let mut index = 0;
+        transactions.retain_mut(|transaction| {
+            let keep = match transaction.served {
+                true => transaction.requested && waiting.is_some_and(|waiting| index < waiting),
+                false => transaction.requested || transaction.remaining > 0,
+            };
+            index += 1;
+            transaction.remaining = transaction.remaining.saturating_sub(1);
+            transaction.requested = false;
+            keep
+        });
+        self.inner.tick();
+    }
+}
+
+impl<M: Snapshot> Snapshot for WaitStates<M> {
+    fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("wait states");
+        snapshot.u32(self.default_wait_states);
+        snapshot.usize(self.regions.len());
+        for region in &self.regions {
+            snapshot.u32(region.start);
+            snapshot.u32(region.size);
+            snapshot.u32(region.wait_states);
+        }
+        let transactions = self.transactions.borrow();
+        snapshot.usize(transactions.len());
+        for transaction in transactions.iter() {
+            snapshot.u32(transaction.address);
+            snapshot.usize(transaction.width);
+            snapshot.bool(transaction.write);
+            snapshot.u32(transaction.remaining);
+            snapshot.bool(transaction.requested);
+            snapshot.bool(transaction.served);
+        }
+        self.inner.save(snapshot);
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        snapshot.section("wait states")?;
+        snapshot.expect("the default number of wait states", self.default_wait_states, SnapshotReader::u32)?;
+        snapshot.expect("the number of wait state regions", self.regions.len(), SnapshotReader::usize)?;
+        for region in &self.regions {
+            let saved = [snapshot.u32()?, snapshot.u32()?, snapshot.u32()?];
+            if saved != [region.start, region.size, region.wait_states] {
+                return Err(SnapshotError::Mismatch(format!("wait state region at {:#x} differs", region.start)));
+            }
+        }
+        let length = snapshot.usize()?;
+        let transactions = (0..length)
+            .map(|_| {
+                Ok(Transaction {
+                    address: snapshot.u32()?,
+                    width: snapshot.usize()?,
+                    write: snapshot.bool()?,
+                    remaining: snapshot.u32()?,
+                    requested: snapshot.bool()?,
+                    served: snapshot.bool()?,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        *self.transactions.get_mut() = transactions;
+        self.inner.restore(snapshot)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::Memory;
+
+    fn read<D: BusInterface<u32, u32>>(device: &D, address: u32) -> Option<u32> {
+        match device.read(address) {
+            BusReadResponse::Success(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn reads_complete_after_the_wait_states() {
+        let mut device = WaitStates::new(Memory::with_initial_values(vec![1, 0, 0, 0]), 2);
+
+        assert_eq!(read(&device, 0), None);
+        device.tick();
+        assert_eq!(read(&device, 0), None);
+        device.tick();
+        assert_eq!(read(&device, 0), Some(1));
+    }
+
+    #[test]
+    fn writes_reach_the_device_when_they_complete() {
+        let mut device = WaitStates::new(Memory::new(4), 1);
+
+        assert!(matches!(device.write(0, 7u32), BusWriteResponse::Deferred));
+        assert_eq!(read(device.inner(), 0), Some(0));
+        device.tick();
+        assert!(matches!(device.write(0, 7u32), BusWriteResponse::Success));
+        assert_eq!(read(device.inner(), 0), Some(7));
+    }
+
+    #[test]
+    fn repeated_accesses_wait_each_time() {
+        let mut device = WaitStates::new(Memory::with_initial_values(vec![1, 0, 0, 0]), 1);
+
+        for _ in 0..2 {
+            assert_eq!(read(&device, 0), None);
+            device.tick();
+            assert_eq!(read(&device, 0), Some(1));
+            device.tick();
+        }
+    }
+
+    #[test]
+    fn restarted_sequences_wait_once_for_each_access() {
+        let mut device = WaitStates::new(Memory::with_initial_values(vec![1, 0, 0, 0, 2, 0, 0, 0]), 1);
+        // Both accesses every cycle, the second only once the first is served, the way a page walk is repeated.
+        let walk = |device: &WaitStates<Memory>| read(device, 0).and_then(|_| read(device, 4));
+
+        assert_eq!(walk(&device), None);
+        device.tick();
+        assert_eq!(walk(&device), None);
+        device.tick();
+        assert_eq!(walk(&device), Some(2));
+        device.tick();
+        assert_eq!(walk(&device), None);
+    }
+
+    #[test]
+    fn regions_override_the_default() {
+        let mut device = WaitStates::new(Memory::new(0x20), 3);
+        device.add_region(0x10, 0x10, 0);
+
+        assert_eq!(read(&device, 0x10), Some(0));
+        assert_eq!(read(&device, 0x0), None);
+    }
+}